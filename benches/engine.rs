@@ -1,9 +1,12 @@
 use criterion::BatchSize::SmallInput;
 use criterion::{criterion_group, criterion_main, Criterion};
-use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use kvs::{BTreeKvsEngine, KvStore, KvsEngine, LsmKvsEngine, ShardedKvStore, SledKvsEngine};
 use rand::prelude::*;
+use std::thread;
 use tempfile::TempDir;
 
+const SHARDS: usize = 8;
+
 fn write_benchmark(c: &mut Criterion) {
     let mut group = c.benchmark_group("write");
     let mut rng = &mut thread_rng();
@@ -14,9 +17,9 @@ fn write_benchmark(c: &mut Criterion) {
                 let temp_dir =
                     TempDir::new().expect("unable to create temporary working directory");
                 let store = KvStore::open(temp_dir.path()).expect("unable to init KvStore");
-                store
+                (temp_dir, store)
             },
-            |store| {
+            |(_temp_dir, store)| {
                 for i in &range {
                     store
                         .set(format!("key{}", i), format!("value{}", i))
@@ -26,6 +29,25 @@ fn write_benchmark(c: &mut Criterion) {
             SmallInput,
         )
     });
+    group.bench_function("kvs-sharded", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir =
+                    TempDir::new().expect("unable to create temporary working directory");
+                let store = ShardedKvStore::open(temp_dir.path(), SHARDS)
+                    .expect("unable to init ShardedKvStore");
+                (temp_dir, store)
+            },
+            |(_temp_dir, store)| {
+                for i in &range {
+                    store
+                        .set(format!("key{}", i), format!("value{}", i))
+                        .expect("unable to write ShardedKvStore");
+                }
+            },
+            SmallInput,
+        )
+    });
     group.bench_function("sled", |b| {
         b.iter_batched(
             || {
@@ -33,9 +55,9 @@ fn write_benchmark(c: &mut Criterion) {
                     TempDir::new().expect("unable to create temporary working directory");
                 let store =
                     SledKvsEngine::open(temp_dir.path()).expect("unable to init SledKvsEngine");
-                store
+                (temp_dir, store)
             },
-            |store| {
+            |(_temp_dir, store)| {
                 for i in &range {
                     store
                         .set(format!("key{}", i), format!("value{}", i))
@@ -45,6 +67,44 @@ fn write_benchmark(c: &mut Criterion) {
             SmallInput,
         )
     });
+    group.bench_function("lsm", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir =
+                    TempDir::new().expect("unable to create temporary working directory");
+                let store =
+                    LsmKvsEngine::open(temp_dir.path()).expect("unable to init LsmKvsEngine");
+                (temp_dir, store)
+            },
+            |(_temp_dir, store)| {
+                for i in &range {
+                    store
+                        .set(format!("key{}", i), format!("value{}", i))
+                        .expect("unable to write LsmKvsEngine");
+                }
+            },
+            SmallInput,
+        )
+    });
+    group.bench_function("btree", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir =
+                    TempDir::new().expect("unable to create temporary working directory");
+                let store =
+                    BTreeKvsEngine::open(temp_dir.path()).expect("unable to init BTreeKvsEngine");
+                (temp_dir, store)
+            },
+            |(_temp_dir, store)| {
+                for i in &range {
+                    store
+                        .set(format!("key{}", i), format!("value{}", i))
+                        .expect("unable to write BTreeKvsEngine");
+                }
+            },
+            SmallInput,
+        )
+    });
     group.finish()
 }
 
@@ -64,9 +124,9 @@ fn read_benchmark(c: &mut Criterion) {
                         .set(format!("key{}", i), format!("value{}", i))
                         .expect("unable to write KvStore");
                 }
-                store
+                (temp_dir, store)
             },
-            |store| {
+            |(_temp_dir, store)| {
                 for i in &read_range {
                     store
                         .get(format!("key{}", i))
@@ -88,9 +148,9 @@ fn read_benchmark(c: &mut Criterion) {
                         .set(format!("key{}", i), format!("value{}", i))
                         .expect("unable to write SledKvsEngine");
                 }
-                store
+                (temp_dir, store)
             },
-            |store| {
+            |(_temp_dir, store)| {
                 for i in &read_range {
                     store
                         .get(format!("key{}", i))
@@ -100,8 +160,111 @@ fn read_benchmark(c: &mut Criterion) {
             SmallInput,
         )
     });
+    group.bench_function("lsm", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir =
+                    TempDir::new().expect("unable to create temporary working directory");
+                let store =
+                    LsmKvsEngine::open(temp_dir.path()).expect("unable to init LsmKvsEngine");
+                for i in &write_range {
+                    store
+                        .set(format!("key{}", i), format!("value{}", i))
+                        .expect("unable to write LsmKvsEngine");
+                }
+                (temp_dir, store)
+            },
+            |(_temp_dir, store)| {
+                for i in &read_range {
+                    store
+                        .get(format!("key{}", i))
+                        .expect("unable to read LsmKvsEngine");
+                }
+            },
+            SmallInput,
+        )
+    });
+    group.bench_function("btree", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir =
+                    TempDir::new().expect("unable to create temporary working directory");
+                let store =
+                    BTreeKvsEngine::open(temp_dir.path()).expect("unable to init BTreeKvsEngine");
+                for i in &write_range {
+                    store
+                        .set(format!("key{}", i), format!("value{}", i))
+                        .expect("unable to write BTreeKvsEngine");
+                }
+                (temp_dir, store)
+            },
+            |(_temp_dir, store)| {
+                for i in &read_range {
+                    store
+                        .get(format!("key{}", i))
+                        .expect("unable to read BTreeKvsEngine");
+                }
+            },
+            SmallInput,
+        )
+    });
     group.finish()
 }
 
-criterion_group!(benches, write_benchmark, read_benchmark);
+// Writers on every core, which a single KvStore serializes on its writer
+fn parallel_write_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parallel_write");
+    let threads = num_cpus::get();
+    group.bench_function("kvs", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir =
+                    TempDir::new().expect("unable to create temporary working directory");
+                let store = KvStore::open(temp_dir.path()).expect("unable to init KvStore");
+                (temp_dir, store)
+            },
+            |(_temp_dir, store)| write_in_parallel(store, threads),
+            SmallInput,
+        )
+    });
+    group.bench_function("kvs-sharded", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir =
+                    TempDir::new().expect("unable to create temporary working directory");
+                let store = ShardedKvStore::open(temp_dir.path(), SHARDS)
+                    .expect("unable to init ShardedKvStore");
+                (temp_dir, store)
+            },
+            |(_temp_dir, store)| write_in_parallel(store, threads),
+            SmallInput,
+        )
+    });
+    group.finish()
+}
+
+fn write_in_parallel<E: KvsEngine>(store: E, threads: usize) {
+    let handles: Vec<_> = (0..threads)
+        .map(|thread| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..1000 {
+                    store
+                        .set(format!("key{}-{}", thread, i), format!("value{}", i))
+                        .expect("unable to write store");
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("writer thread panicked");
+    }
+}
+
+criterion_group!(
+    benches,
+    write_benchmark,
+    read_benchmark,
+    parallel_write_benchmark
+);
 criterion_main!(benches);
@@ -1,5 +1,5 @@
-use clap::{arg, command, ArgMatches, SubCommand};
-use kvs::{Client, Request, Result};
+use clap::{arg, command, value_parser, ArgMatches, SubCommand};
+use kvs::{Client, EngineStats, Operation, Request, Result};
 use std::string::String;
 use std::{env, process};
 
@@ -11,20 +11,67 @@ fn main() {
                 .about("Set the value of a string key to a string. Return an error if the value is not written successfully.")
                 .arg(arg!(<KEY>))
                 .arg(arg!(<VALUE>))
+                .arg(arg!(--namespace <NAME> "Use the keys of a namespace instead of the default namespace").required(false))
+                .arg(arg!(--"chunk-size" <BYTES> "Send the value in chunks of BYTES to a server which takes values in chunks").required(false).value_parser(value_parser!(u64).range(1..)))
                 .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
         )
         .subcommand(
             SubCommand::with_name("get")
                 .about("Get the string value of a string key. If the key does not exist, return None. Return an error if the value is not read successfully.")
                 .arg(arg!(<KEY>))
+                .arg(arg!(--namespace <NAME> "Use the keys of a namespace instead of the default namespace").required(false))
                 .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
         )
         .subcommand(
             SubCommand::with_name("rm")
                 .about("Remove a given key. Return an error if the key does not exist or is not removed successfully.c")
                 .arg(arg!(<KEY>))
+                .arg(arg!(--namespace <NAME> "Use the keys of a namespace instead of the default namespace").required(false))
                 .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
         )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("Print the statistics of the storage engine of the server.")
+                .arg(arg!(--namespace <NAME> "Use the keys of a namespace instead of the default namespace").required(false))
+                .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
+        )
+        .subcommand(
+            SubCommand::with_name("ns")
+                .about("Manage the namespaces of the server.")
+                .subcommand_required(true)
+                .subcommand(
+                    SubCommand::with_name("create")
+                        .about("Create an empty namespace.")
+                        .arg(arg!(<NAME>))
+                        .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
+                )
+                .subcommand(
+                    SubCommand::with_name("drop")
+                        .about("Drop a namespace with all of its keys.")
+                        .arg(arg!(<NAME>))
+                        .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
+                )
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .about("Print the names of the namespaces.")
+                        .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("compaction")
+                .about("Control the compaction of the storage engine of the server.")
+                .subcommand_required(true)
+                .subcommand(
+                    SubCommand::with_name("start")
+                        .about("Resume the compactions and compact now.")
+                        .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
+                )
+                .subcommand(
+                    SubCommand::with_name("pause")
+                        .about("Pause the compactions which start by themselves.")
+                        .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
+                ),
+        )
         .get_matches();
     if let Err(err) = send_request(matches) {
         eprintln!("{:?}", err);
@@ -39,13 +86,29 @@ fn send_request(matches: ArgMatches) -> Result<()> {
             let key = sub_matches.get_one::<String>("KEY").unwrap();
             let value = sub_matches.get_one::<String>("VALUE").unwrap();
             let mut client = Client::new(addr)?;
-            client.request(&Request::SET(key.to_owned(), value.to_owned()))?;
+            match sub_matches.get_one::<u64>("chunk-size") {
+                Some(chunk_size) => client.set_chunked(
+                    namespace(sub_matches),
+                    key.to_owned(),
+                    value,
+                    *chunk_size as usize,
+                )?,
+                None => {
+                    client.request(&Request::in_namespace(
+                        namespace(sub_matches),
+                        Operation::SET(key.to_owned(), value.to_owned()),
+                    ))?;
+                }
+            }
         }
         Some(("get", sub_matches)) => {
             let addr = sub_matches.get_one::<String>("addr").unwrap();
             let key = sub_matches.get_one::<String>("KEY").unwrap();
             let mut client = Client::new(addr)?;
-            match client.request(&Request::GET(key.to_owned()))? {
+            match client.request(&Request::in_namespace(
+                namespace(sub_matches),
+                Operation::GET(key.to_owned()),
+            ))? {
                 None => println!("Key not found"),
                 Some(value) => println!("{}", value),
             };
@@ -54,9 +117,67 @@ fn send_request(matches: ArgMatches) -> Result<()> {
             let addr = sub_matches.get_one::<String>("addr").unwrap();
             let key = sub_matches.get_one::<String>("KEY").unwrap();
             let mut client = Client::new(addr)?;
-            client.request(&Request::RM(key.to_owned()))?;
+            client.request(&Request::in_namespace(
+                namespace(sub_matches),
+                Operation::RM(key.to_owned()),
+            ))?;
+        }
+        Some(("stats", sub_matches)) => {
+            let addr = sub_matches.get_one::<String>("addr").unwrap();
+            let mut client = Client::new(addr)?;
+            if let Some(stats) = client.request(&Request::in_namespace(
+                namespace(sub_matches),
+                Operation::STATS,
+            ))? {
+                let stats: EngineStats = serde_json::from_str(&stats)?;
+                println!("{}", serde_json::to_string_pretty(&stats)?);
+            }
+        }
+        Some(("ns", sub_matches)) => send_namespace_request(sub_matches)?,
+        Some(("compaction", sub_matches)) => {
+            let (operation, sub_matches) = match sub_matches.subcommand() {
+                Some(("start", sub_matches)) => (Operation::COMPACTSTART, sub_matches),
+                Some(("pause", sub_matches)) => (Operation::COMPACTPAUSE, sub_matches),
+                _ => process::exit(-1),
+            };
+            let addr = sub_matches.get_one::<String>("addr").unwrap();
+            let mut client = Client::new(addr)?;
+            client.request(&Request::new(operation))?;
         }
         _ => process::exit(-1),
     }
     Ok(())
 }
+
+fn send_namespace_request(matches: &ArgMatches) -> Result<()> {
+    match matches.subcommand() {
+        Some(("create", sub_matches)) => {
+            let addr = sub_matches.get_one::<String>("addr").unwrap();
+            let name = sub_matches.get_one::<String>("NAME").unwrap();
+            let mut client = Client::new(addr)?;
+            client.request(&Request::new(Operation::NSCREATE(name.to_owned())))?;
+        }
+        Some(("drop", sub_matches)) => {
+            let addr = sub_matches.get_one::<String>("addr").unwrap();
+            let name = sub_matches.get_one::<String>("NAME").unwrap();
+            let mut client = Client::new(addr)?;
+            client.request(&Request::new(Operation::NSDROP(name.to_owned())))?;
+        }
+        Some(("list", sub_matches)) => {
+            let addr = sub_matches.get_one::<String>("addr").unwrap();
+            let mut client = Client::new(addr)?;
+            if let Some(names) = client.request(&Request::new(Operation::NSLIST))? {
+                let names: Vec<String> = serde_json::from_str(&names)?;
+                for name in names {
+                    println!("{}", name);
+                }
+            }
+        }
+        _ => process::exit(-1),
+    }
+    Ok(())
+}
+
+fn namespace(matches: &ArgMatches) -> Option<String> {
+    matches.get_one::<String>("namespace").cloned()
+}
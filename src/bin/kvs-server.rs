@@ -1,11 +1,25 @@
-use clap::{arg, command, ArgMatches};
+use clap::{arg, command, value_parser, ArgAction, ArgMatches};
 use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
-use kvs::{EngineType, KVStoreError, KvServer, KvStore, KvsEngine, Result, SledKvsEngine};
+use kvs::{
+    migrate, BTreeKvsEngine, BTreeOptions, CompactionOptions, Encryption, EngineType, KVStoreError,
+    KvServer, KvStore, KvStoreOptions, KvsEngine, LsmKvsEngine, LsmOptions, MemoryKvsEngine,
+    Result, ShardedKvStore, SizeLimits, SledKvsEngine,
+};
 use log::{info, LevelFilter};
+use std::fs::{self, remove_dir_all, remove_file, rename, File};
+use std::io::{self, Write};
+use std::path::Path;
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use std::{env, process};
 
+/// Environment variable holding the encryption keys when --key-file is not given.
+const KEY_ENV: &str = "KVS_ENCRYPTION_KEY";
+/// File recording that a migration copied every key: the target engine, and `keep` or
+/// `remove` for the old data. A migration which crashed after writing it is finished
+/// on the next start.
+const MIGRATED_FILE: &str = "migrated";
+
 fn main() -> Result<()> {
     env_logger::builder().filter_level(LevelFilter::Info).init();
     let matches = command!()
@@ -18,7 +32,61 @@ fn main() -> Result<()> {
         .arg(
             arg!(--engine <ENGINENAME>)
                 .required(false)
-                .value_parser(["kvs", "sled"]),
+                .value_parser(["kvs", "sled", "lsm", "btree", "memory"]),
+        )
+        .arg(
+            arg!(--migrate "Move the data of the other engine into the one given by --engine before starting")
+                .requires("engine")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"keep-old" "Keep the migrated directory as <engine>.bak for rollback")
+                .requires("migrate")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"key-file" <FILE> "Encrypt the kvs data files with the hex keys in FILE, the current key first. KVS_ENCRYPTION_KEY may hold the keys instead")
+                .required(false),
+        )
+        .arg(
+            arg!(--shards <N> "Split the keys of the kvs engine into N shards which are written in parallel. A sharded store keeps its number of shards")
+                .required(false)
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            arg!(--"max-size" <BYTES> "Refuse writes which would make the data files of the kvs engine larger than BYTES")
+                .required(false)
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            arg!(--"blob-threshold" <BYTES> "Keep values of at least BYTES in blob files of the kvs engine, so that compacting its data files does not copy them")
+                .required(false)
+                .value_parser(value_parser!(u64).range(1..)),
+        )
+        .arg(
+            arg!(--"max-key-size" <BYTES> "Refuse keys larger than BYTES [default: 65536]")
+                .required(false)
+                .value_parser(value_parser!(u64).range(1..)),
+        )
+        .arg(
+            arg!(--"max-value-size" <BYTES> "Refuse values larger than BYTES [default: 67108864]")
+                .required(false)
+                .value_parser(value_parser!(u64).range(1..)),
+        )
+        .arg(
+            arg!(--"value-chunk-size" <BYTES> "Take values in chunks of BYTES and read no request larger than a chunk, so that large values are not buffered in a single request")
+                .required(false)
+                .value_parser(value_parser!(u64).range(1..)),
+        )
+        .arg(
+            arg!(--"compaction-rate" <BYTES> "Copy at most BYTES per second when the kvs engine compacts its data files")
+                .required(false)
+                .value_parser(value_parser!(u64).range(1..)),
+        )
+        .arg(
+            arg!(--"compaction-window" <WINDOW> "Let the kvs engine compact by itself only between two UTC times like 22:30-05:00. May be given more than once")
+                .required(false)
+                .action(ArgAction::Append),
         )
         .get_matches();
     if let Err(err) = init(matches) {
@@ -30,55 +98,352 @@ fn main() -> Result<()> {
 
 fn init(matches: ArgMatches) -> Result<()> {
     let addr = matches.get_one::<String>("addr").unwrap();
-    let engine_type = judge_engine(matches.get_one::<String>("engine").cloned())?;
+    let engine = matches.get_one::<String>("engine").cloned();
+    let default_limits = SizeLimits::default();
+    let size_limits = SizeLimits {
+        max_key_size: matches
+            .get_one::<u64>("max-key-size")
+            .map_or(default_limits.max_key_size, |size| *size as usize),
+        max_value_size: matches
+            .get_one::<u64>("max-value-size")
+            .map_or(default_limits.max_value_size, |size| *size as usize),
+    };
+    let chunk_size = matches
+        .get_one::<u64>("value-chunk-size")
+        .map(|size| *size as usize);
+    let options = KvStoreOptions {
+        size_limits,
+        encryption: match matches.get_one::<String>("key-file") {
+            Some(path) => Some(Encryption::from_file(path)?),
+            None if env::var_os(KEY_ENV).is_some() => Some(Encryption::from_env(KEY_ENV)?),
+            None => None,
+        },
+        max_size: matches.get_one::<u64>("max-size").copied(),
+        blob_threshold: matches
+            .get_one::<u64>("blob-threshold")
+            .map(|size| *size as usize),
+        compaction: CompactionOptions {
+            rate_limit: matches.get_one::<u64>("compaction-rate").copied(),
+            windows: matches
+                .get_many::<String>("compaction-window")
+                .into_iter()
+                .flatten()
+                .map(|window| window.parse())
+                .collect::<Result<_>>()?,
+        },
+        ..KvStoreOptions::default()
+    };
+    let shards = matches.get_one::<usize>("shards").copied();
+    recover_migration(&env::current_dir()?)?;
+    if *matches.get_one::<bool>("migrate").unwrap() {
+        match engine.as_deref() {
+            Some("memory") => {
+                return Err(KVStoreError::CommonStringError(
+                    "the memory engine keeps no data to migrate".to_owned(),
+                ))
+            }
+            Some("lsm") | Some("btree") => {
+                return Err(KVStoreError::CommonStringError(
+                    "only the kvs and sled engines migrate".to_owned(),
+                ))
+            }
+            _ => {}
+        }
+        migrate_engine(
+            engine.as_deref(),
+            *matches.get_one::<bool>("keep-old").unwrap(),
+            &options,
+            shards,
+        )?;
+    }
+    let engine_type = judge_engine(engine)?;
+    if !matches!(engine_type, EngineType::KvStore) && options.encryption.is_some() {
+        return Err(KVStoreError::EncryptionKey(
+            "only the kvs engine encrypts its data files".to_owned(),
+        ));
+    }
+    if !matches!(engine_type, EngineType::KvStore) && shards.is_some() {
+        return Err(KVStoreError::CommonStringError(
+            "only the kvs engine is sharded".to_owned(),
+        ));
+    }
+    if !matches!(engine_type, EngineType::KvStore) && options.max_size.is_some() {
+        return Err(KVStoreError::CommonStringError(
+            "only the kvs engine has a size limit".to_owned(),
+        ));
+    }
+    if !matches!(engine_type, EngineType::KvStore) && options.blob_threshold.is_some() {
+        return Err(KVStoreError::CommonStringError(
+            "only the kvs engine keeps values in blob files".to_owned(),
+        ));
+    }
+    if !matches!(engine_type, EngineType::KvStore)
+        && (options.compaction.rate_limit.is_some() || !options.compaction.windows.is_empty())
+    {
+        return Err(KVStoreError::CommonStringError(
+            "only the kvs engine schedules its compactions".to_owned(),
+        ));
+    }
 
     info!("Version: [{}]", env!("CARGO_PKG_VERSION"));
     info!("Addr: [{}]", addr);
     info!("Engine: [{}]", engine_type);
 
     match engine_type {
-        EngineType::KvStore => run_server(
-            KvStore::open(env::current_dir()?.join(EngineType::KvStore.to_string()))?,
+        EngineType::KvStore => {
+            let dir = env::current_dir()?.join(EngineType::KvStore.to_string());
+            match kvs_shards(&dir, shards)? {
+                Some(shards) => {
+                    info!("Shards: [{}]", shards);
+                    run_server(
+                        ShardedKvStore::open_with_options(dir, shards, options)?,
+                        addr,
+                        size_limits,
+                        chunk_size,
+                    )
+                }
+                None => run_server(
+                    KvStore::open_with_options(dir, options)?,
+                    addr,
+                    size_limits,
+                    chunk_size,
+                ),
+            }
+        }
+        EngineType::SledKvsEngine => run_server(
+            SledKvsEngine::open_with_size_limits(
+                env::current_dir()?.join(EngineType::SledKvsEngine.to_string()),
+                size_limits,
+            )?,
             addr,
+            size_limits,
+            chunk_size,
         ),
-        EngineType::SledKvsEngine => run_server(
-            SledKvsEngine::open(env::current_dir()?.join(EngineType::SledKvsEngine.to_string()))?,
+        EngineType::MemoryKvsEngine => run_server(
+            MemoryKvsEngine::with_size_limits(size_limits),
+            addr,
+            size_limits,
+            chunk_size,
+        ),
+        EngineType::LsmKvsEngine => run_server(
+            LsmKvsEngine::open_with_options(
+                env::current_dir()?.join(EngineType::LsmKvsEngine.to_string()),
+                LsmOptions {
+                    size_limits,
+                    ..LsmOptions::default()
+                },
+            )?,
+            addr,
+            size_limits,
+            chunk_size,
+        ),
+        EngineType::BTreeKvsEngine => run_server(
+            BTreeKvsEngine::open_with_options(
+                env::current_dir()?.join(EngineType::BTreeKvsEngine.to_string()),
+                BTreeOptions {
+                    size_limits,
+                    ..BTreeOptions::default()
+                },
+            )?,
             addr,
+            size_limits,
+            chunk_size,
         ),
     }
 }
 
 fn judge_engine(engine: Option<String>) -> Result<EngineType> {
     let dir = env::current_dir()?;
+    // the engine which already keeps its data in the current directory
+    let existing = vec![
+        EngineType::SledKvsEngine,
+        EngineType::LsmKvsEngine,
+        EngineType::BTreeKvsEngine,
+        EngineType::KvStore,
+    ]
+    .into_iter()
+    .find(|engine_type| dir.join(engine_type.to_string()).exists());
     match engine {
-        None => {
-            if dir.join(EngineType::SledKvsEngine.to_string()).exists() {
-                return Ok(EngineType::SledKvsEngine);
+        None => Ok(existing.unwrap_or(EngineType::KvStore)),
+        Some(v) => {
+            let engine_type = match v.as_str() {
+                "kvs" => EngineType::KvStore,
+                "sled" => EngineType::SledKvsEngine,
+                "lsm" => EngineType::LsmKvsEngine,
+                "btree" => EngineType::BTreeKvsEngine,
+                // nothing on disk to conflict with
+                "memory" => return Ok(EngineType::MemoryKvsEngine),
+                _ => return Err(KVStoreError::UnknownEngineType),
+            };
+            match existing {
+                Some(existing) if existing.to_string() != v => Err(KVStoreError::ChangeEngineError),
+                _ => Ok(engine_type),
             }
-            Ok(EngineType::KvStore)
         }
-        Some(v) => {
-            if v == EngineType::KvStore.to_string() {
-                if dir.join(EngineType::SledKvsEngine.to_string()).exists() {
-                    return Err(KVStoreError::ChangeEngineError);
-                }
-                Ok(EngineType::KvStore)
-            } else {
-                if dir.join(EngineType::KvStore.to_string()).exists() {
-                    return Err(KVStoreError::ChangeEngineError);
+    }
+}
+
+/// Return the number of shards of the kvs engine in `dir`, None if it is not sharded.
+fn kvs_shards(dir: &Path, shards: Option<usize>) -> Result<Option<usize>> {
+    Ok(match shards {
+        Some(shards) => Some(shards),
+        None => match ShardedKvStore::existing_shards(dir)? {
+            0 => None,
+            existing => Some(existing),
+        },
+    })
+}
+
+fn migrate_engine(
+    engine: Option<&str>,
+    keep_old: bool,
+    options: &KvStoreOptions,
+    shards: Option<usize>,
+) -> Result<()> {
+    let dir = env::current_dir()?;
+    let to_sled = engine == Some("sled");
+    let (source_type, target_type) = if to_sled {
+        (EngineType::KvStore, EngineType::SledKvsEngine)
+    } else {
+        (EngineType::SledKvsEngine, EngineType::KvStore)
+    };
+    let source_dir = dir.join(source_type.to_string());
+    let target_dir = dir.join(target_type.to_string());
+    let staging_dir = dir.join(format!("{}.migrating", target_type));
+    let backup_dir = dir.join(format!("{}.bak", source_type));
+
+    if !source_dir.exists() {
+        info!("Nothing to migrate from [{}]", source_type);
+        return Ok(());
+    }
+    if target_dir.exists() {
+        return Err(KVStoreError::ChangeEngineError);
+    }
+    if keep_old && backup_dir.exists() {
+        return Err(KVStoreError::CommonStringError(format!(
+            "{:?} already exists",
+            backup_dir
+        )));
+    }
+
+    info!("Migrating [{}] to [{}]", source_type, target_type);
+    let migrated = if to_sled {
+        let target = SledKvsEngine::open(&staging_dir)?;
+        match kvs_shards(&source_dir, None)? {
+            Some(shards) => migrate(
+                &ShardedKvStore::open_with_options(&source_dir, shards, options.clone())?,
+                &target,
+            )?,
+            None => migrate(
+                &KvStore::open_with_options(&source_dir, options.clone())?,
+                &target,
+            )?,
+        }
+    } else {
+        let source = SledKvsEngine::open(&source_dir)?;
+        match shards {
+            Some(shards) => migrate(
+                &source,
+                &ShardedKvStore::open_with_options(&staging_dir, shards, options.clone())?,
+            )?,
+            None => migrate(
+                &source,
+                &KvStore::open_with_options(&staging_dir, options.clone())?,
+            )?,
+        }
+    };
+
+    // from here on a crash leaves both engines, so the migration is finished on startup
+    let marker = dir.join(MIGRATED_FILE);
+    let temp_marker = dir.join(format!("{}.tmp", MIGRATED_FILE));
+    let mut file = File::create(&temp_marker)?;
+    writeln!(
+        file,
+        "{} {}",
+        target_type,
+        if keep_old { "keep" } else { "remove" }
+    )?;
+    file.sync_all()?;
+    rename(&temp_marker, &marker)?;
+    finish_migration(&dir, source_type, target_type, keep_old)?;
+    info!("Migrated {} keys", migrated);
+    Ok(())
+}
+
+/// Move the migrated data into place and the old data out of the way, then remove the
+/// record of the migration. Each step is skipped if a crash came after it.
+fn finish_migration(
+    dir: &Path,
+    source_type: EngineType,
+    target_type: EngineType,
+    keep_old: bool,
+) -> Result<()> {
+    let source_dir = dir.join(source_type.to_string());
+    let staging_dir = dir.join(format!("{}.migrating", target_type));
+    let backup_dir = dir.join(format!("{}.bak", source_type));
+    if staging_dir.exists() {
+        rename(&staging_dir, dir.join(target_type.to_string()))?;
+    }
+    if source_dir.exists() {
+        if keep_old {
+            rename(&source_dir, &backup_dir)?;
+            info!("Kept old data in {:?}", backup_dir);
+        } else {
+            remove_dir_all(&source_dir)?;
+        }
+    }
+    remove_file(dir.join(MIGRATED_FILE))?;
+    Ok(())
+}
+
+/** Finish a migration which crashed after it copied every key, or roll back one which
+crashed before, of which only the staging directory is left.
+ */
+fn recover_migration(dir: &Path) -> Result<()> {
+    let record = match fs::read_to_string(dir.join(MIGRATED_FILE)) {
+        Ok(record) => record,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            for target_type in [EngineType::KvStore, EngineType::SledKvsEngine] {
+                let staging_dir = dir.join(format!("{}.migrating", target_type));
+                if staging_dir.exists() {
+                    info!("Removing {:?} of an interrupted migration", staging_dir);
+                    remove_dir_all(&staging_dir)?;
                 }
-                Ok(EngineType::SledKvsEngine)
             }
+            return Ok(());
         }
-    }
+        Err(err) => return Err(err.into()),
+    };
+    let mut words = record.split_whitespace();
+    let (source_type, target_type) = match words.next() {
+        Some("sled") => (EngineType::KvStore, EngineType::SledKvsEngine),
+        Some("kvs") => (EngineType::SledKvsEngine, EngineType::KvStore),
+        _ => {
+            return Err(KVStoreError::CommonStringError(format!(
+                "the migration record {:?} is corrupt",
+                dir.join(MIGRATED_FILE)
+            )))
+        }
+    };
+    info!("Finishing the migration to [{}]", target_type);
+    finish_migration(dir, source_type, target_type, words.next() == Some("keep"))
 }
 
-fn run_server<E: KvsEngine>(engine: E, addr: &String) -> Result<()> {
+fn run_server<E: KvsEngine>(
+    engine: E,
+    addr: &String,
+    size_limits: SizeLimits,
+    chunk_size: Option<usize>,
+) -> Result<()> {
     let mut server = KvServer::new(
         engine,
         SharedQueueThreadPool::new(num_cpus::get())?,
         Arc::new(AtomicBool::new(false)),
-    );
+    )
+    .with_size_limits(size_limits);
+    if let Some(chunk_size) = chunk_size {
+        server = server.with_value_chunks(chunk_size);
+    }
     server.serve(addr)?;
     Ok(())
 }
@@ -0,0 +1,190 @@
+use clap::{arg, command, value_parser, ArgMatches, SubCommand};
+use kvs::{
+    dump, dump_encrypted, repair, repair_encrypted, upgrade, upgrade_encrypted, verify,
+    verify_encrypted, Encryption, Result,
+};
+use std::{env, process};
+
+/// Environment variable holding the encryption keys when --key-file is not given.
+const KEY_ENV: &str = "KVS_ENCRYPTION_KEY";
+
+fn main() {
+    let matches = command!()
+        .name("kvs-tool")
+        .arg(
+            arg!(--"key-file" <FILE> "Decrypt the data files with the hex keys in FILE. KVS_ENCRYPTION_KEY may hold the keys instead")
+                .required(false)
+                .global(true),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Check every data file of a kvs directory. Exit with an error if any of them is corrupt.")
+                .arg(arg!(<DIR>)),
+        )
+        .subcommand(
+            SubCommand::with_name("repair")
+                .about("Salvage the readable records of a corrupt kvs directory into a new data file and quarantine the damaged files.")
+                .arg(arg!(<DIR>)),
+        )
+        .subcommand(
+            SubCommand::with_name("upgrade")
+                .about("Rewrite the legacy data files of a kvs directory into the current format. The server must be stopped.")
+                .arg(arg!(<DIR>)),
+        )
+        .subcommand(
+            SubCommand::with_name("dump")
+                .about("Print every record of the data files of a kvs directory and whether it is the live version of its key.")
+                .arg(arg!(<DIR>))
+                .arg(arg!(--key <KEY>).required(false))
+                .arg(
+                    arg!(--file <NUMBER>)
+                        .required(false)
+                        .value_parser(value_parser!(u64)),
+                )
+                .arg(
+                    arg!(--format <FORMAT>)
+                        .required(false)
+                        .value_parser(["table", "json"])
+                        .default_value("table"),
+                ),
+        )
+        .get_matches();
+    match run(matches) {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(err) => {
+            eprintln!("{:?}", err);
+            process::exit(-1);
+        }
+    }
+}
+
+fn run(matches: ArgMatches) -> Result<bool> {
+    let encryption = match matches.get_one::<String>("key-file") {
+        Some(path) => Some(Encryption::from_file(path)?),
+        None if env::var_os(KEY_ENV).is_some() => Some(Encryption::from_env(KEY_ENV)?),
+        None => None,
+    };
+    match matches.subcommand() {
+        Some(("verify", sub_matches)) => {
+            let dir = sub_matches.get_one::<String>("DIR").unwrap();
+            let report = match &encryption {
+                Some(encryption) => verify_encrypted(dir, encryption)?,
+                None => verify(dir)?,
+            };
+            for file in &report.files {
+                println!(
+                    "data_{}.txt: format {}, {} bytes, {} records, {} live keys, {} tombstones, {} garbage bytes",
+                    file.file_number,
+                    file.format_version,
+                    file.size,
+                    file.records,
+                    file.live_keys,
+                    file.tombstones,
+                    file.garbage_bytes
+                );
+                for range in &file.corrupt {
+                    println!(
+                        "  corrupt bytes {}..{}: {}",
+                        range.start, range.end, range.reason
+                    );
+                }
+            }
+            println!(
+                "{} files, {} live keys, {}",
+                report.files.len(),
+                report.live_keys(),
+                if report.is_corrupt() { "corrupt" } else { "ok" }
+            );
+            Ok(!report.is_corrupt())
+        }
+        Some(("repair", sub_matches)) => {
+            let dir = sub_matches.get_one::<String>("DIR").unwrap();
+            let report = match &encryption {
+                Some(encryption) => repair_encrypted(dir, encryption)?,
+                None => repair(dir)?,
+            };
+            let new_file_number = match report.new_file_number {
+                Some(new_file_number) => new_file_number,
+                None => {
+                    println!("{} files, nothing to repair", report.files.len());
+                    return Ok(true);
+                }
+            };
+            for file in &report.files {
+                println!(
+                    "data_{}.txt: {} records salvaged, {} corrupt ranges{}",
+                    file.file_number,
+                    file.salvaged_records,
+                    file.corrupt.len(),
+                    if file.quarantined {
+                        ", quarantined"
+                    } else {
+                        ""
+                    }
+                );
+            }
+            println!(
+                "{} keys salvaged into data_{}.txt",
+                report.salvaged_keys, new_file_number
+            );
+            if !report.suspect_keys.is_empty() {
+                println!("keys which may have lost updates:");
+                for key in &report.suspect_keys {
+                    println!("  {}", key);
+                }
+            }
+            Ok(true)
+        }
+        Some(("upgrade", sub_matches)) => {
+            let dir = sub_matches.get_one::<String>("DIR").unwrap();
+            let report = match &encryption {
+                Some(encryption) => upgrade_encrypted(dir, encryption)?,
+                None => upgrade(dir)?,
+            };
+            for file_number in &report.upgraded {
+                println!("data_{}.txt: upgraded", file_number);
+            }
+            println!(
+                "{} files upgraded, {} already current",
+                report.upgraded.len(),
+                report.current.len()
+            );
+            Ok(true)
+        }
+        Some(("dump", sub_matches)) => {
+            let dir = sub_matches.get_one::<String>("DIR").unwrap();
+            let key = sub_matches.get_one::<String>("key").map(String::as_str);
+            let file_number = sub_matches.get_one::<u64>("file").copied();
+            let records = match &encryption {
+                Some(encryption) => dump_encrypted(dir, key, file_number, encryption)?,
+                None => dump(dir, key, file_number)?,
+            };
+            if sub_matches.get_one::<String>("format").unwrap() == "json" {
+                println!("{}", serde_json::to_string_pretty(&records)?);
+                return Ok(true);
+            }
+            println!(
+                "{:>6} {:>10} {:>8} {:<4} {:>4} {:<5} {:>10} KEY",
+                "FILE", "OFFSET", "LENGTH", "TYPE", "NS", "LIVE", "VALUE_SIZE"
+            );
+            for record in &records {
+                println!(
+                    "{:>6} {:>10} {:>8} {:<4} {:>4} {:<5} {:>10} {}",
+                    record.file_number,
+                    record.offset,
+                    record.length,
+                    record.command,
+                    record.namespace_id,
+                    record.live,
+                    record
+                        .value_size
+                        .map_or("-".to_owned(), |size| size.to_string()),
+                    record.key
+                );
+            }
+            Ok(true)
+        }
+        _ => process::exit(-1),
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{KVStoreError, Request, Response, Result};
+use crate::{KVStoreError, Operation, Request, Response, Result};
 use serde::Deserialize;
 use serde_json::de::IoRead;
 use serde_json::Deserializer;
@@ -28,6 +28,38 @@ impl Client {
         match Response::deserialize(&mut self.reader)? {
             Response::Ok(value) => Ok(value),
             Response::Err(err) => Err(KVStoreError::CommonStringError(err)),
+            Response::Refused(code, message) => Err(KVStoreError::Refused(code, message)),
+        }
+    }
+
+    /// Set a value in chunks of at most `chunk_size` bytes, for a server which takes
+    /// values larger than a request in chunks. A chunk never splits a character.
+    pub fn set_chunked(
+        &mut self,
+        namespace: Option<String>,
+        key: String,
+        value: &str,
+        chunk_size: usize,
+    ) -> Result<()> {
+        let mut rest = value;
+        loop {
+            let mut end = chunk_size.min(rest.len());
+            while !rest.is_char_boundary(end) {
+                end -= 1;
+            }
+            if end == 0 {
+                end = rest.chars().next().map_or(0, char::len_utf8);
+            }
+            let (chunk, tail) = rest.split_at(end);
+            let last = tail.is_empty();
+            self.request(&Request::in_namespace(
+                namespace.clone(),
+                Operation::SETCHUNK(key.clone(), chunk.to_owned(), last),
+            ))?;
+            if last {
+                return Ok(());
+            }
+            rest = tail;
         }
     }
 }
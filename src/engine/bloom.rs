@@ -0,0 +1,66 @@
+use crate::{KVStoreError, Result};
+
+/** A bloom filter over byte keys, persisted next to the data it describes.
+
+A key which was inserted is always reported as maybe present, a key which was not is
+reported as maybe present with a false-positive rate which depends on the bits per key:
+about 1% for 10 bits. The bit positions come from two crc32 hashes of the key.
+ */
+#[derive(Clone, Debug)]
+pub(crate) struct BloomFilter {
+    bits: Vec<u8>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    /// Create an empty filter sized for `keys` keys with `bits_per_key` bits each.
+    pub(crate) fn new(keys: usize, bits_per_key: u32) -> BloomFilter {
+        let bits = (keys.max(1) * bits_per_key.max(1) as usize).max(64);
+        // ln 2 * bits per key hashes minimize the false-positive rate
+        let hashes = ((bits_per_key as f64 * 0.69).round() as u32).clamp(1, 30);
+        BloomFilter {
+            bits: vec![0; (bits + 7) / 8],
+            hashes,
+        }
+    }
+
+    pub(crate) fn insert(&mut self, key: &[u8]) {
+        for bit in self.bit_positions(key) {
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    /// Return false if the key was certainly never inserted.
+    pub(crate) fn may_contain(&self, key: &[u8]) -> bool {
+        self.bit_positions(key)
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    fn bit_positions(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let len = (self.bits.len() * 8) as u64;
+        let h1 = crc32fast::hash(key) as u64;
+        let mut hasher = crc32fast::Hasher::new_with_initial(0x9e37_79b9);
+        hasher.update(key);
+        // an odd step visits distinct positions
+        let h2 = hasher.finalize() as u64 | 1;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+
+    /// The number of hashes as a byte, then the bits.
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(1 + self.bits.len());
+        bytes.push(self.hashes as u8);
+        bytes.extend_from_slice(&self.bits);
+        bytes
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<BloomFilter> {
+        match bytes {
+            [hashes, bits @ ..] if *hashes > 0 && !bits.is_empty() => Ok(BloomFilter {
+                bits: bits.to_vec(),
+                hashes: *hashes as u32,
+            }),
+            _ => Err(KVStoreError::CorruptRecord("bad bloom filter".to_owned())),
+        }
+    }
+}
@@ -0,0 +1,668 @@
+use self::page::{
+    decode_free, decode_overflow, encode_free, encode_overflow, Meta, Node, Value,
+    MAX_INLINE_VALUE, MAX_KEY_SIZE, OVERFLOW_CHUNK, PAGE_SIZE,
+};
+use self::pager::Pager;
+use super::check_namespace_name;
+use crate::{EngineStats, KVStoreError, KvsEngine, Result, SizeLimits};
+use std::collections::{HashMap, HashSet};
+use std::fs::create_dir_all;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+mod page;
+mod pager;
+mod wal;
+
+// keys are prefixed with the id of their namespace, this one holds name -> id of every namespace
+const REGISTRY: u64 = u64::MAX;
+// number of keys a scan reads at a time
+const SCAN_BATCH: usize = 256;
+// number of keys of dropped namespaces a write removes along the way
+const SWEEP_BATCH: usize = 64;
+// number of keys of dropped namespaces a compaction removes in one write
+const DROP_BATCH: usize = 1024;
+
+/// Options of a BTreeKvsEngine.
+#[derive(Clone, Debug)]
+pub struct BTreeOptions {
+    /// number of pages of 4KB cached in memory
+    pub cache_pages: usize,
+    /// bytes of the write-ahead log which start a checkpoint into the tree file
+    pub checkpoint_size: u64,
+    /// largest keys and values a set takes, keys are also limited by the page size
+    pub size_limits: SizeLimits,
+    /// sync the write-ahead log after every write, so that a write is durable once it returns
+    pub sync_writes: bool,
+}
+
+impl Default for BTreeOptions {
+    /// A cache of 1024 pages, a checkpoint every 4MB of log, the default size limits and
+    /// writes which are not synced.
+    fn default() -> Self {
+        BTreeOptions {
+            cache_pages: 1024,
+            checkpoint_size: 4 * 1024 * 1024,
+            size_limits: SizeLimits::default(),
+            sync_writes: false,
+        }
+    }
+}
+
+/** A BTreeKvsEngine stores key/value pairs in a B+tree of 4KB pages in one file.
+
+Leaves hold the keys in order and link to the next leaf, so scans read pages in key
+order. Values larger than 512 bytes are kept in chains of overflow pages. Pages are
+read through a buffer pool, and every write is committed to a write-ahead log before
+its pages are written into the file at the next checkpoint.
+Keys take at most 504 bytes. Leaves are not merged when keys are removed. The keys of a
+dropped namespace are removed a batch at a time by the writes after the drop, and all
+at once by `compact`.
+# Example
+```
+use std::env;
+use kvs::{BTreeKvsEngine, Result};
+use crate::kvs::KvsEngine;
+# fn try_main() -> Result<()> {
+
+let store = BTreeKvsEngine::open(env::current_dir()?)?;
+
+store.set("1".to_owned(),"1".to_owned())?;
+assert_eq!(store.get("1".to_owned())?, Some("1".to_owned()));
+
+store.remove("1".to_owned())?;
+assert_eq!(store.get("1".to_owned())?, None);
+# Ok(())
+# }
+```
+ */
+#[derive(Clone)]
+pub struct BTreeKvsEngine {
+    tree: Arc<RwLock<Tree>>,
+    // the namespace of this handle
+    namespace: Arc<Namespace>,
+    // name -> namespace of the handles of every named namespace, shared by all handles
+    handles: Arc<Mutex<HashMap<String, Arc<Namespace>>>>,
+    size_limits: SizeLimits,
+}
+
+struct Namespace {
+    id: u64,
+    // None for the default namespace
+    name: Option<String>,
+    dropped: AtomicBool,
+}
+
+impl Namespace {
+    fn new(id: u64, name: Option<&str>) -> Arc<Namespace> {
+        Arc::new(Namespace {
+            id,
+            name: name.map(str::to_owned),
+            dropped: AtomicBool::new(false),
+        })
+    }
+
+    /// Return an error if the namespace was dropped.
+    fn check(&self) -> Result<()> {
+        if self.dropped.load(Ordering::SeqCst) {
+            return Err(KVStoreError::NamespaceNotFound(
+                self.name.clone().unwrap_or_default(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn internal_key(&self, key: &str) -> Vec<u8> {
+        prefixed(self.id, key)
+    }
+}
+
+/// Return the key in the tree: the namespace id in big-endian, then the key.
+fn prefixed(namespace_id: u64, key: &str) -> Vec<u8> {
+    let mut internal_key = namespace_id.to_be_bytes().to_vec();
+    internal_key.extend_from_slice(key.as_bytes());
+    internal_key
+}
+
+impl BTreeKvsEngine {
+    /// Open the BTreeKvsEngine at a given path. Return the BTreeKvsEngine.
+    pub fn open(path: impl Into<PathBuf>) -> Result<BTreeKvsEngine> {
+        Self::open_with_options(path, BTreeOptions::default())
+    }
+
+    /// Open the BTreeKvsEngine at a given path with the given options.
+    pub fn open_with_options(
+        path: impl Into<PathBuf>,
+        options: BTreeOptions,
+    ) -> Result<BTreeKvsEngine> {
+        let dir_path = path.into();
+        create_dir_all(&dir_path)?;
+        let tree = Tree::open(Pager::open(
+            &dir_path,
+            options.cache_pages,
+            options.checkpoint_size,
+            options.sync_writes,
+        )?)?;
+        let mut handles = HashMap::new();
+        for (name, id) in tree.namespaces()? {
+            let namespace = Namespace::new(id, Some(&name));
+            handles.insert(name, namespace);
+        }
+        Ok(BTreeKvsEngine {
+            tree: Arc::new(RwLock::new(tree)),
+            namespace: Namespace::new(0, None),
+            handles: Arc::new(Mutex::new(handles)),
+            size_limits: options.size_limits,
+        })
+    }
+}
+
+/// A B+tree over the pages of a Pager.
+struct Tree {
+    pager: Pager,
+    meta: Meta,
+    // ids of dropped namespaces whose keys may still be in the tree
+    orphans: Vec<u64>,
+}
+
+impl Tree {
+    fn open(mut pager: Pager) -> Result<Tree> {
+        if pager.is_empty()? {
+            // page 0 is the meta page, page 1 the empty root leaf
+            let meta = Meta {
+                root: 1,
+                page_count: 2,
+                free_head: 0,
+                next_namespace: 1,
+            };
+            pager.write(0, meta.encode());
+            pager.write(
+                1,
+                Node::Leaf {
+                    entries: Vec::new(),
+                    next: 0,
+                }
+                .encode(),
+            );
+            pager.commit()?;
+        }
+        let meta = Meta::decode(&pager.read(0)?)?;
+        let mut tree = Tree {
+            pager,
+            meta,
+            orphans: Vec::new(),
+        };
+        tree.orphans = tree.find_orphans()?;
+        Ok(tree)
+    }
+
+    /// Run a write which is committed as a whole, or not at all if it fails.
+    fn write<T>(&mut self, op: impl FnOnce(&mut Tree) -> Result<T>) -> Result<T> {
+        let meta = self.meta.clone();
+        let result = op(self).and_then(|value| {
+            if self.meta != meta {
+                self.pager.write(0, self.meta.encode());
+            }
+            self.pager.commit()?;
+            Ok(value)
+        });
+        if result.is_err() {
+            self.pager.abort();
+            self.meta = meta;
+        }
+        result
+    }
+
+    fn read_node(&self, id: u64) -> Result<Node> {
+        Node::decode(&self.pager.read(id)?)
+    }
+
+    fn write_node(&mut self, id: u64, node: &Node) {
+        self.pager.write(id, node.encode());
+    }
+
+    fn allocate(&mut self) -> Result<u64> {
+        if self.meta.free_head != 0 {
+            let id = self.meta.free_head;
+            self.meta.free_head = decode_free(&self.pager.read(id)?)?;
+            return Ok(id);
+        }
+        self.meta.page_count += 1;
+        Ok(self.meta.page_count - 1)
+    }
+
+    fn free(&mut self, id: u64) {
+        self.pager.write(id, encode_free(self.meta.free_head));
+        self.meta.free_head = id;
+    }
+
+    /// Return the leaf which holds a key, or would hold it.
+    fn find_leaf(&self, key: &[u8]) -> Result<u64> {
+        let mut id = self.meta.root;
+        loop {
+            match self.read_node(id)? {
+                Node::Leaf { .. } => return Ok(id),
+                Node::Internal { keys, children } => {
+                    id = children[keys.partition_point(|separator| separator.as_slice() <= key)];
+                }
+            }
+        }
+    }
+
+    fn find_value(&self, key: &[u8]) -> Result<Option<Value>> {
+        match self.read_node(self.find_leaf(key)?)? {
+            Node::Leaf { entries, .. } => Ok(entries
+                .binary_search_by(|(entry_key, _)| entry_key.as_slice().cmp(key))
+                .ok()
+                .map(|i| entries[i].1.clone())),
+            Node::Internal { .. } => unreachable!(),
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<String>> {
+        self.find_value(key)?
+            .map(|value| Ok(String::from_utf8(self.load_value(&value)?)?))
+            .transpose()
+    }
+
+    fn load_value(&self, value: &Value) -> Result<Vec<u8>> {
+        match value {
+            Value::Inline(value) => Ok(value.clone()),
+            Value::Overflow { page, length } => {
+                let mut bytes = Vec::with_capacity(*length as usize);
+                let mut id = *page;
+                while (bytes.len() as u64) < *length {
+                    let page = self.pager.read(id)?;
+                    let (next, chunk) = decode_overflow(&page)?;
+                    bytes.extend_from_slice(chunk);
+                    id = next;
+                }
+                Ok(bytes)
+            }
+        }
+    }
+
+    /// Keep a value inline, or write it into a chain of overflow pages.
+    fn store_value(&mut self, value: &[u8]) -> Result<Value> {
+        if value.len() <= MAX_INLINE_VALUE {
+            return Ok(Value::Inline(value.to_vec()));
+        }
+        let chunks: Vec<&[u8]> = value.chunks(OVERFLOW_CHUNK).collect();
+        let ids = (0..chunks.len())
+            .map(|_| self.allocate())
+            .collect::<Result<Vec<_>>>()?;
+        for (i, chunk) in chunks.iter().enumerate() {
+            let next = ids.get(i + 1).copied().unwrap_or(0);
+            self.pager.write(ids[i], encode_overflow(next, chunk));
+        }
+        Ok(Value::Overflow {
+            page: ids[0],
+            length: value.len() as u64,
+        })
+    }
+
+    fn free_value(&mut self, value: &Value) -> Result<()> {
+        if let Value::Overflow { page, .. } = value {
+            let mut id = *page;
+            while id != 0 {
+                let (next, _) = decode_overflow(&self.pager.read(id)?)?;
+                self.free(id);
+                id = next;
+            }
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        if key.len() > MAX_KEY_SIZE {
+            return Err(KVStoreError::CommonStringError(format!(
+                "a key of the btree engine takes at most {} bytes",
+                MAX_KEY_SIZE - 8
+            )));
+        }
+        if let Some(old) = self.find_value(key)? {
+            self.free_value(&old)?;
+        }
+        let value = self.store_value(value)?;
+        let root = self.meta.root;
+        if let Some((separator, right)) = self.insert_into(root, key, value)? {
+            let new_root = self.allocate()?;
+            self.write_node(
+                new_root,
+                &Node::Internal {
+                    keys: vec![separator],
+                    children: vec![root, right],
+                },
+            );
+            self.meta.root = new_root;
+        }
+        Ok(())
+    }
+
+    /// Insert into the subtree of a page. Return the separator and page of the new
+    /// sibling if the page was split.
+    fn insert_into(&mut self, id: u64, key: &[u8], value: Value) -> Result<Option<(Vec<u8>, u64)>> {
+        let mut node = self.read_node(id)?;
+        match &mut node {
+            Node::Leaf { entries, .. } => {
+                match entries.binary_search_by(|(entry_key, _)| entry_key.as_slice().cmp(key)) {
+                    Ok(i) => entries[i].1 = value,
+                    Err(i) => entries.insert(i, (key.to_vec(), value)),
+                }
+            }
+            Node::Internal { keys, children } => {
+                let i = keys.partition_point(|separator| separator.as_slice() <= key);
+                match self.insert_into(children[i], key, value)? {
+                    Some((separator, right)) => {
+                        keys.insert(i, separator);
+                        children.insert(i + 1, right);
+                    }
+                    None => return Ok(None),
+                }
+            }
+        }
+        if node.size() <= PAGE_SIZE {
+            self.write_node(id, &node);
+            return Ok(None);
+        }
+        let (separator, right) = node.split();
+        let right_id = self.allocate()?;
+        if let Node::Leaf { next, .. } = &mut node {
+            *next = right_id;
+        }
+        self.write_node(id, &node);
+        self.write_node(right_id, &right);
+        Ok(Some((separator, right_id)))
+    }
+
+    /// Remove a key from its leaf. Return false if the key does not exist.
+    fn delete(&mut self, key: &[u8]) -> Result<bool> {
+        let id = self.find_leaf(key)?;
+        let mut node = self.read_node(id)?;
+        let value = match &mut node {
+            Node::Leaf { entries, .. } => {
+                match entries.binary_search_by(|(entry_key, _)| entry_key.as_slice().cmp(key)) {
+                    Ok(i) => entries.remove(i).1,
+                    Err(_) => return Ok(false),
+                }
+            }
+            Node::Internal { .. } => unreachable!(),
+        };
+        self.write_node(id, &node);
+        self.free_value(&value)?;
+        Ok(true)
+    }
+
+    /// Return up to about `limit` pairs from the first key which is not less than `start`,
+    /// in key order. Fewer pairs are returned only at the end of the tree.
+    fn range(&self, start: &[u8], limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut pairs = Vec::new();
+        let mut id = self.find_leaf(start)?;
+        while id != 0 && pairs.len() < limit {
+            match self.read_node(id)? {
+                Node::Leaf { entries, next } => {
+                    for (key, value) in entries {
+                        if key.as_slice() >= start {
+                            pairs.push((key, self.load_value(&value)?));
+                        }
+                    }
+                    id = next;
+                }
+                Node::Internal { .. } => unreachable!(),
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// Return the name and id of every named namespace.
+    fn namespaces(&self) -> Result<Vec<(String, u64)>> {
+        let prefix = REGISTRY.to_be_bytes();
+        let mut namespaces = Vec::new();
+        for (key, id) in self.range(&prefix, usize::MAX)? {
+            namespaces.push((
+                String::from_utf8(key[8..].to_vec())?,
+                String::from_utf8(id)?
+                    .parse()
+                    .map_err(|_| KVStoreError::CorruptRecord("bad namespace id".to_owned()))?,
+            ));
+        }
+        Ok(namespaces)
+    }
+
+    /// Return the ids of the dropped namespaces which still have keys in the tree.
+    fn find_orphans(&self) -> Result<Vec<u64>> {
+        let live: HashSet<u64> = self.namespaces()?.into_iter().map(|(_, id)| id).collect();
+        let mut orphans = Vec::new();
+        // jump from the first key of one namespace to the next, past the default one
+        let mut next_id = 1;
+        while next_id < self.meta.next_namespace {
+            let key = match self.range(&next_id.to_be_bytes(), 1)?.into_iter().next() {
+                Some((key, _)) => key,
+                None => break,
+            };
+            let mut id = [0; 8];
+            id.copy_from_slice(&key[..8]);
+            let id = u64::from_be_bytes(id);
+            if id >= self.meta.next_namespace {
+                break;
+            }
+            if !live.contains(&id) {
+                orphans.push(id);
+            }
+            next_id = id + 1;
+        }
+        Ok(orphans)
+    }
+
+    /// Remove up to `limit` keys of dropped namespaces in one write. Return false once
+    /// none is left.
+    fn sweep(&mut self, limit: usize) -> Result<bool> {
+        while let Some(&id) = self.orphans.last() {
+            let prefix = id.to_be_bytes();
+            let keys: Vec<Vec<u8>> = self
+                .range(&prefix, limit)?
+                .into_iter()
+                .map(|(key, _)| key)
+                .take_while(|key| key.starts_with(&prefix))
+                .take(limit)
+                .collect();
+            if keys.is_empty() {
+                self.orphans.pop();
+                continue;
+            }
+            self.write(|tree| {
+                for key in &keys {
+                    tree.delete(key)?;
+                }
+                Ok(())
+            })?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+}
+
+impl BTreeKvsEngine {
+    /// Call `f` with the pairs of the namespace of this handle in key order, a batch
+    /// at a time, so that `f` may write to the store.
+    fn for_each_pair(&self, mut f: impl FnMut(String, String) -> Result<()>) -> Result<()> {
+        let prefix = self.namespace.id.to_be_bytes();
+        let mut start = prefix.to_vec();
+        loop {
+            let pairs = {
+                let tree = self.tree.read().unwrap();
+                self.namespace.check()?;
+                tree.range(&start, SCAN_BATCH)?
+            };
+            let is_last = pairs.len() < SCAN_BATCH;
+            for (key, value) in pairs {
+                if !key.starts_with(&prefix) {
+                    return Ok(());
+                }
+                f(
+                    String::from_utf8(key[8..].to_vec())?,
+                    String::from_utf8(value)?,
+                )?;
+                // the smallest key after this one
+                start = key;
+                start.push(0);
+            }
+            if is_last {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl KvsEngine for BTreeKvsEngine {
+    /// Set the value of a string key to a string.
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.size_limits.check(&key, &value)?;
+        let mut tree = self.tree.write().unwrap();
+        self.namespace.check()?;
+        let key = self.namespace.internal_key(&key);
+        tree.write(|tree| tree.insert(&key, value.as_bytes()))?;
+        tree.sweep(SWEEP_BATCH)?;
+        Ok(())
+    }
+
+    /// Get the string value of a string key. If the key does not exist, return None.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let tree = self.tree.read().unwrap();
+        self.namespace.check()?;
+        tree.get(&self.namespace.internal_key(&key))
+    }
+
+    /// Remove a given key. Return an error if the key does not exist.
+    fn remove(&self, key: String) -> Result<()> {
+        let mut tree = self.tree.write().unwrap();
+        self.namespace.check()?;
+        let key = self.namespace.internal_key(&key);
+        if !tree.write(|tree| tree.delete(&key))? {
+            return Err(KVStoreError::KeyNotFound);
+        }
+        tree.sweep(SWEEP_BATCH)?;
+        Ok(())
+    }
+
+    /// Call `f` with every key/value pair in the store, in key order.
+    fn scan<F>(&self, f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        self.for_each_pair(f)
+    }
+
+    /// Return the statistics of the store.
+    fn stats(&self) -> Result<EngineStats> {
+        let mut keys = 0;
+        self.for_each_pair(|_, _| {
+            keys += 1;
+            Ok(())
+        })?;
+        let tree = self.tree.read().unwrap();
+        let (hits, misses) = tree.pager.cache_stats();
+        Ok(EngineStats {
+            engine: "btree".to_owned(),
+            keys,
+            disk_size: tree.pager.disk_size()?,
+            data_files: Vec::new(),
+            garbage_bytes: None,
+            compactions: None,
+            compaction_millis: None,
+            compaction_paused: None,
+            read_only: None,
+            open_readers: None,
+            mapped_files: None,
+            cache_hits: Some(hits),
+            cache_misses: Some(misses),
+            bloom_skips: None,
+            bloom_false_positives: None,
+            bloom_false_positive_rate: None,
+            blob_bytes: None,
+            blob_garbage_bytes: None,
+            blob_collections: None,
+        })
+    }
+
+    /// Return a handle to the keys of the namespace.
+    fn namespace(&self, name: &str) -> Result<BTreeKvsEngine> {
+        let namespace = self
+            .handles
+            .lock()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| KVStoreError::NamespaceNotFound(name.to_owned()))?;
+        Ok(BTreeKvsEngine {
+            tree: Arc::clone(&self.tree),
+            namespace,
+            handles: Arc::clone(&self.handles),
+            size_limits: self.size_limits,
+        })
+    }
+
+    /// Create an empty namespace, which gets a new id.
+    fn create_namespace(&self, name: &str) -> Result<()> {
+        check_namespace_name(name)?;
+        let mut tree = self.tree.write().unwrap();
+        let mut handles = self.handles.lock().unwrap();
+        if handles.contains_key(name) {
+            return Err(KVStoreError::NamespaceExists(name.to_owned()));
+        }
+        let id = tree.write(|tree| {
+            let id = tree.meta.next_namespace;
+            tree.meta.next_namespace += 1;
+            tree.insert(&prefixed(REGISTRY, name), id.to_string().as_bytes())?;
+            Ok(id)
+        })?;
+        handles.insert(name.to_owned(), Namespace::new(id, Some(name)));
+        Ok(())
+    }
+
+    /// Drop a namespace. Its keys are removed from the tree by later writes.
+    fn drop_namespace(&self, name: &str) -> Result<()> {
+        let mut tree = self.tree.write().unwrap();
+        let mut handles = self.handles.lock().unwrap();
+        let namespace = handles
+            .get(name)
+            .cloned()
+            .ok_or_else(|| KVStoreError::NamespaceNotFound(name.to_owned()))?;
+        tree.write(|tree| tree.delete(&prefixed(REGISTRY, name)))?;
+        handles.remove(name);
+        drop(handles);
+        namespace.dropped.store(true, Ordering::SeqCst);
+        // ids are never used again, so the keys are unreachable until they are removed
+        tree.orphans.push(namespace.id);
+        Ok(())
+    }
+
+    /// Return the names of the namespaces.
+    fn list_namespaces(&self) -> Result<Vec<String>> {
+        let tree = self.tree.read().unwrap();
+        Ok(tree
+            .namespaces()?
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect())
+    }
+
+    /// Remove the keys of the dropped namespaces, then write the pages in the write-ahead
+    /// log into the tree file and empty the log.
+    fn compact(&self) -> Result<()> {
+        // the tree is locked for one batch at a time, so that other calls go on meanwhile
+        while self.tree.write().unwrap().sweep(DROP_BATCH)? {}
+        self.tree.write().unwrap().pager.checkpoint()
+    }
+
+    /// Return an error for a pause, since checkpoints are part of the writes.
+    fn set_compaction_paused(&self, paused: bool) -> Result<()> {
+        if paused {
+            return Err(KVStoreError::CommonStringError(
+                "the btree engine does not pause its checkpoints".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,353 @@
+use crate::{KVStoreError, Result};
+
+/// Size of every page of the tree file.
+pub(super) const PAGE_SIZE: usize = 4096;
+/// Largest key, with its namespace prefix, which a page holds.
+pub(super) const MAX_KEY_SIZE: usize = 512;
+/// Larger values are kept in a chain of overflow pages.
+pub(super) const MAX_INLINE_VALUE: usize = 512;
+
+const META: u8 = 0;
+const LEAF: u8 = 1;
+const INTERNAL: u8 = 2;
+const OVERFLOW: u8 = 3;
+const FREE: u8 = 4;
+
+const INLINE: u8 = 0;
+const OVERFLOWED: u8 = 1;
+
+const MAGIC: &[u8; 8] = b"KVSBTREE";
+// crc, page type, number of keys, and the next leaf or the first child
+const NODE_HEADER_SIZE: usize = 4 + 1 + 2 + 8;
+// crc, page type, next page and length of the chunk
+const OVERFLOW_HEADER_SIZE: usize = 4 + 1 + 8 + 2;
+/// Bytes of a value in one overflow page.
+pub(super) const OVERFLOW_CHUNK: usize = PAGE_SIZE - OVERFLOW_HEADER_SIZE;
+
+/// The value of a key in a leaf.
+#[derive(Clone, Debug)]
+pub(super) enum Value {
+    Inline(Vec<u8>),
+    /// the first page of the chain and the length of the value
+    Overflow {
+        page: u64,
+        length: u64,
+    },
+}
+
+impl Value {
+    fn size(&self) -> usize {
+        match self {
+            Value::Inline(value) => 1 + 4 + value.len(),
+            Value::Overflow { .. } => 1 + 8 + 8,
+        }
+    }
+}
+
+/// A page of the tree.
+pub(super) enum Node {
+    /// sorted keys with their values, and the page of the next leaf, 0 for the last leaf
+    Leaf {
+        entries: Vec<(Vec<u8>, Value)>,
+        next: u64,
+    },
+    /// `keys[i]` is the smallest key under `children[i + 1]`
+    Internal {
+        keys: Vec<Vec<u8>>,
+        children: Vec<u64>,
+    },
+}
+
+impl Node {
+    /// Return the number of bytes the node takes in a page.
+    pub(super) fn size(&self) -> usize {
+        match self {
+            Node::Leaf { entries, .. } => {
+                NODE_HEADER_SIZE
+                    + entries
+                        .iter()
+                        .map(|(key, value)| 2 + key.len() + value.size())
+                        .sum::<usize>()
+            }
+            Node::Internal { keys, .. } => {
+                NODE_HEADER_SIZE + keys.iter().map(|key| 2 + key.len() + 8).sum::<usize>()
+            }
+        }
+    }
+
+    /** Move the upper half of the node, by size, into a new node. Return the separator
+    key and the new node.
+
+    The separator of a leaf is the first key of the new leaf, which links to the leaf
+    after this one. The separator of an internal node moves up and leaves both halves.
+     */
+    pub(super) fn split(&mut self) -> (Vec<u8>, Node) {
+        match self {
+            Node::Leaf { entries, next } => {
+                let sizes: Vec<usize> = entries
+                    .iter()
+                    .map(|(key, value)| 2 + key.len() + value.size())
+                    .collect();
+                let at = split_point(&sizes);
+                let right = entries.split_off(at);
+                let separator = right[0].0.clone();
+                (
+                    separator,
+                    Node::Leaf {
+                        entries: right,
+                        next: *next,
+                    },
+                )
+            }
+            Node::Internal { keys, children } => {
+                let sizes: Vec<usize> = keys.iter().map(|key| 2 + key.len() + 8).collect();
+                // keep a key on both sides of the separator
+                let at = split_point(&sizes).min(keys.len() - 2);
+                let right_keys = keys.split_off(at + 1);
+                let separator = keys.pop().unwrap();
+                let right_children = children.split_off(at + 1);
+                (
+                    separator,
+                    Node::Internal {
+                        keys: right_keys,
+                        children: right_children,
+                    },
+                )
+            }
+        }
+    }
+
+    pub(super) fn decode(page: &[u8]) -> Result<Node> {
+        check(page)?;
+        let mut pos = 5;
+        let count = read_u16(page, &mut pos)? as usize;
+        let first = read_u64(page, &mut pos)?;
+        match page[4] {
+            LEAF => {
+                let mut entries = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let key = read_key(page, &mut pos)?;
+                    let value = match take(page, &mut pos, 1)?[0] {
+                        INLINE => {
+                            let length = read_u32(page, &mut pos)? as usize;
+                            Value::Inline(take(page, &mut pos, length)?.to_vec())
+                        }
+                        OVERFLOWED => Value::Overflow {
+                            page: read_u64(page, &mut pos)?,
+                            length: read_u64(page, &mut pos)?,
+                        },
+                        kind => return Err(corrupt(format!("unknown value kind {}", kind))),
+                    };
+                    entries.push((key, value));
+                }
+                Ok(Node::Leaf {
+                    entries,
+                    next: first,
+                })
+            }
+            INTERNAL => {
+                let mut keys = Vec::with_capacity(count);
+                let mut children = Vec::with_capacity(count + 1);
+                children.push(first);
+                for _ in 0..count {
+                    keys.push(read_key(page, &mut pos)?);
+                    children.push(read_u64(page, &mut pos)?);
+                }
+                Ok(Node::Internal { keys, children })
+            }
+            kind => Err(corrupt(format!("page of type {} is not a node", kind))),
+        }
+    }
+
+    /// Encode the node into a page. The node must fit.
+    pub(super) fn encode(&self) -> Vec<u8> {
+        let mut page = Vec::with_capacity(PAGE_SIZE);
+        page.extend_from_slice(&[0; 4]);
+        match self {
+            Node::Leaf { entries, next } => {
+                page.push(LEAF);
+                page.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+                page.extend_from_slice(&next.to_le_bytes());
+                for (key, value) in entries {
+                    write_key(&mut page, key);
+                    match value {
+                        Value::Inline(value) => {
+                            page.push(INLINE);
+                            page.extend_from_slice(&(value.len() as u32).to_le_bytes());
+                            page.extend_from_slice(value);
+                        }
+                        Value::Overflow {
+                            page: first,
+                            length,
+                        } => {
+                            page.push(OVERFLOWED);
+                            page.extend_from_slice(&first.to_le_bytes());
+                            page.extend_from_slice(&length.to_le_bytes());
+                        }
+                    }
+                }
+            }
+            Node::Internal { keys, children } => {
+                page.push(INTERNAL);
+                page.extend_from_slice(&(keys.len() as u16).to_le_bytes());
+                page.extend_from_slice(&children[0].to_le_bytes());
+                for (key, child) in keys.iter().zip(&children[1..]) {
+                    write_key(&mut page, key);
+                    page.extend_from_slice(&child.to_le_bytes());
+                }
+            }
+        }
+        seal(page)
+    }
+}
+
+/// Return the index which splits the items into halves of about the same size.
+fn split_point(sizes: &[usize]) -> usize {
+    let half = sizes.iter().sum::<usize>() / 2;
+    let mut size = 0;
+    for (i, item) in sizes.iter().enumerate() {
+        size += item;
+        if size >= half {
+            return (i + 1).clamp(1, sizes.len() - 1);
+        }
+    }
+    sizes.len() - 1
+}
+
+/// The first page of the file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(super) struct Meta {
+    /// page of the root node
+    pub(super) root: u64,
+    /// number of pages in the file
+    pub(super) page_count: u64,
+    /// first page of the list of free pages, 0 if there is none
+    pub(super) free_head: u64,
+    /// id of the next namespace
+    pub(super) next_namespace: u64,
+}
+
+impl Meta {
+    pub(super) fn decode(page: &[u8]) -> Result<Meta> {
+        check(page)?;
+        if page[4] != META || &page[5..13] != MAGIC {
+            return Err(corrupt("the first page is not a btree header".to_owned()));
+        }
+        let mut pos = 13;
+        Ok(Meta {
+            root: read_u64(page, &mut pos)?,
+            page_count: read_u64(page, &mut pos)?,
+            free_head: read_u64(page, &mut pos)?,
+            next_namespace: read_u64(page, &mut pos)?,
+        })
+    }
+
+    pub(super) fn encode(&self) -> Vec<u8> {
+        let mut page = vec![0; 4];
+        page.push(META);
+        page.extend_from_slice(MAGIC);
+        for field in [
+            self.root,
+            self.page_count,
+            self.free_head,
+            self.next_namespace,
+        ] {
+            page.extend_from_slice(&field.to_le_bytes());
+        }
+        seal(page)
+    }
+}
+
+/// Encode a page of an overflow chain.
+pub(super) fn encode_overflow(next: u64, chunk: &[u8]) -> Vec<u8> {
+    let mut page = vec![0; 4];
+    page.push(OVERFLOW);
+    page.extend_from_slice(&next.to_le_bytes());
+    page.extend_from_slice(&(chunk.len() as u16).to_le_bytes());
+    page.extend_from_slice(chunk);
+    seal(page)
+}
+
+/// Return the next page of an overflow chain and the chunk of the value in this page.
+pub(super) fn decode_overflow(page: &[u8]) -> Result<(u64, &[u8])> {
+    check(page)?;
+    if page[4] != OVERFLOW {
+        return Err(corrupt(format!(
+            "page of type {} is not an overflow page",
+            page[4]
+        )));
+    }
+    let mut pos = 5;
+    let next = read_u64(page, &mut pos)?;
+    let length = read_u16(page, &mut pos)? as usize;
+    Ok((next, take(page, &mut pos, length)?))
+}
+
+/// Encode a free page, which links to the next free page.
+pub(super) fn encode_free(next: u64) -> Vec<u8> {
+    let mut page = vec![0; 4];
+    page.push(FREE);
+    page.extend_from_slice(&next.to_le_bytes());
+    seal(page)
+}
+
+pub(super) fn decode_free(page: &[u8]) -> Result<u64> {
+    check(page)?;
+    if page[4] != FREE {
+        return Err(corrupt(format!("page of type {} is not free", page[4])));
+    }
+    read_u64(page, &mut 5)
+}
+
+/// Pad the page and write the crc32 of everything after it into its first 4 bytes.
+fn seal(mut page: Vec<u8>) -> Vec<u8> {
+    page.resize(PAGE_SIZE, 0);
+    let crc = crc32fast::hash(&page[4..]);
+    page[..4].copy_from_slice(&crc.to_le_bytes());
+    page
+}
+
+fn check(page: &[u8]) -> Result<()> {
+    if page.len() != PAGE_SIZE || crc32fast::hash(&page[4..]).to_le_bytes() != page[..4] {
+        return Err(corrupt("page checksum mismatch".to_owned()));
+    }
+    Ok(())
+}
+
+fn write_key(page: &mut Vec<u8>, key: &[u8]) {
+    page.extend_from_slice(&(key.len() as u16).to_le_bytes());
+    page.extend_from_slice(key);
+}
+
+fn read_key(page: &[u8], pos: &mut usize) -> Result<Vec<u8>> {
+    let length = read_u16(page, pos)? as usize;
+    Ok(take(page, pos, length)?.to_vec())
+}
+
+fn take<'a>(page: &'a [u8], pos: &mut usize, length: usize) -> Result<&'a [u8]> {
+    let bytes = page
+        .get(*pos..*pos + length)
+        .ok_or_else(|| corrupt("truncated page".to_owned()))?;
+    *pos += length;
+    Ok(bytes)
+}
+
+fn read_u16(page: &[u8], pos: &mut usize) -> Result<u16> {
+    let bytes = take(page, pos, 2)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(page: &[u8], pos: &mut usize) -> Result<u32> {
+    let bytes = take(page, pos, 4)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn read_u64(page: &[u8], pos: &mut usize) -> Result<u64> {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(take(page, pos, 8)?);
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn corrupt(reason: String) -> KVStoreError {
+    KVStoreError::CorruptRecord(reason)
+}
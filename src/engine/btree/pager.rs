@@ -0,0 +1,158 @@
+use super::page::PAGE_SIZE;
+use super::wal::Wal;
+use crate::engine::lru::LruCache;
+use crate::Result;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+const TREE_FILE: &str = "btree.db";
+
+pub(super) type Page = Arc<Vec<u8>>;
+
+/** The pages of the tree file with a buffer pool in front of it.
+
+Changed pages stay in memory until a checkpoint writes them into the file. Until then
+they are kept safe by the write-ahead log, which a write reaches when it commits.
+Pages which were not changed are read through an LRU cache of `cache_pages` pages.
+ */
+pub(super) struct Pager {
+    file: Mutex<File>,
+    wal: Wal,
+    // a checkpoint starts once the log holds this many bytes
+    checkpoint_size: u64,
+    // sync the log at every commit
+    sync_writes: bool,
+    // pages changed by the write in progress
+    pending: HashMap<u64, Page>,
+    // pages of committed writes which the file does not hold yet
+    dirty: HashMap<u64, Page>,
+    cache: Mutex<LruCache<u64, Page>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Pager {
+    /// Open the tree file of a directory and write the committed pages of its log into it.
+    pub(super) fn open(
+        dir_path: &Path,
+        cache_pages: usize,
+        checkpoint_size: u64,
+        sync_writes: bool,
+    ) -> Result<Pager> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(dir_path.join(TREE_FILE))?;
+        let (wal, dirty) = Wal::open(dir_path)?;
+        let mut pager = Pager {
+            file: Mutex::new(file),
+            wal,
+            checkpoint_size,
+            sync_writes,
+            pending: HashMap::new(),
+            dirty,
+            cache: Mutex::new(LruCache::new(cache_pages as u64)),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        };
+        pager.checkpoint()?;
+        Ok(pager)
+    }
+
+    /// Return true if the file holds no page.
+    pub(super) fn is_empty(&self) -> Result<bool> {
+        Ok(self.file.lock().unwrap().metadata()?.len() == 0 && self.pending.is_empty())
+    }
+
+    pub(super) fn read(&self, id: u64) -> Result<Page> {
+        if let Some(page) = self.pending.get(&id).or_else(|| self.dirty.get(&id)) {
+            return Ok(Arc::clone(page));
+        }
+        if let Some(page) = self.cache.lock().unwrap().get(&id) {
+            self.hits.fetch_add(1, Ordering::SeqCst);
+            return Ok(Arc::clone(page));
+        }
+        self.misses.fetch_add(1, Ordering::SeqCst);
+        let mut page = vec![0; PAGE_SIZE];
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(id * PAGE_SIZE as u64))?;
+            file.read_exact(&mut page)?;
+        }
+        let page = Arc::new(page);
+        self.cache.lock().unwrap().insert(id, Arc::clone(&page), 1);
+        Ok(page)
+    }
+
+    /// Change a page as part of the write in progress.
+    pub(super) fn write(&mut self, id: u64, page: Vec<u8>) {
+        self.pending.insert(id, Arc::new(page));
+    }
+
+    /// Make the pages of the write in progress durable by appending them to the log, and
+    /// by syncing it if every write is to be durable once it returns.
+    pub(super) fn commit(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let pages = std::mem::take(&mut self.pending);
+        self.wal.append(&pages)?;
+        if self.sync_writes {
+            self.wal.sync()?;
+        }
+        self.dirty.extend(pages);
+        if self.wal.size() >= self.checkpoint_size {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    /// Forget the pages of the write in progress.
+    pub(super) fn abort(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Write the changed pages into the file and empty the log.
+    pub(super) fn checkpoint(&mut self) -> Result<()> {
+        if self.wal.size() == 0 {
+            return Ok(());
+        }
+        // a page half written by a crash is only repaired from a log which is durable
+        self.wal.sync()?;
+        let mut ids: Vec<u64> = self.dirty.keys().copied().collect();
+        ids.sort_unstable();
+        {
+            let file = self.file.get_mut().unwrap();
+            for id in ids {
+                file.seek(SeekFrom::Start(id * PAGE_SIZE as u64))?;
+                file.write_all(&self.dirty[&id])?;
+            }
+            file.sync_all()?;
+        }
+        self.wal.clear()?;
+        let mut cache = self.cache.lock().unwrap();
+        for (id, page) in self.dirty.drain() {
+            cache.insert(id, page, 1);
+        }
+        Ok(())
+    }
+
+    /// Return the bytes of the tree file and its log.
+    pub(super) fn disk_size(&self) -> Result<u64> {
+        Ok(self.file.lock().unwrap().metadata()?.len() + self.wal.size())
+    }
+
+    /// Return the number of page reads which the cache served and missed.
+    pub(super) fn cache_stats(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::SeqCst),
+            self.misses.load(Ordering::SeqCst),
+        )
+    }
+}
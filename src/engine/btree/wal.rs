@@ -0,0 +1,113 @@
+use super::pager::Page;
+use crate::Result;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+const WAL_FILE: &str = "btree.wal";
+
+const PAGE: u8 = 0;
+const COMMIT: u8 = 1;
+
+/** The write-ahead log of the pages of a BTreeKvsEngine.
+
+A write appends the new image of every page it changed, then a commit record. Pages
+reach the tree file only at a checkpoint, which syncs the log before it writes the
+first page and empties the log afterwards, so a tree
+file which a crash left half written is repaired by writing the committed pages of
+the log again. Pages which are not followed by a commit record are ignored.
+ */
+pub(super) struct Wal {
+    file: File,
+    size: u64,
+}
+
+impl Wal {
+    /// Open the log of a directory. Return it with the pages of its committed writes.
+    pub(super) fn open(dir_path: &Path) -> Result<(Wal, HashMap<u64, Page>)> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(dir_path.join(WAL_FILE))?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+
+        let mut committed = HashMap::new();
+        let mut pending = HashMap::new();
+        let mut pos = 0;
+        while let Some((record, next)) = read_record(&buf, pos) {
+            match record {
+                [COMMIT] => committed.extend(pending.drain()),
+                [PAGE, rest @ ..] if rest.len() > 8 => {
+                    let mut id = [0; 8];
+                    id.copy_from_slice(&rest[..8]);
+                    pending.insert(u64::from_le_bytes(id), Arc::new(rest[8..].to_vec()));
+                }
+                _ => break,
+            }
+            pos = next;
+        }
+        let size = buf.len() as u64;
+        Ok((Wal { file, size }, committed))
+    }
+
+    /// Append the pages of a write and its commit record.
+    pub(super) fn append(&mut self, pages: &HashMap<u64, Page>) -> Result<()> {
+        let mut buf = Vec::new();
+        for (id, page) in pages {
+            let mut record = vec![PAGE];
+            record.extend_from_slice(&id.to_le_bytes());
+            record.extend_from_slice(page);
+            write_record(&mut buf, &record);
+        }
+        write_record(&mut buf, &[COMMIT]);
+        if let Err(err) = self.file.write_all(&buf) {
+            // drop what was written, so that later writes are not hidden behind it
+            let _ = self.file.set_len(self.size);
+            return Err(err.into());
+        }
+        self.size += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Make the writes appended so far durable.
+    pub(super) fn sync(&mut self) -> Result<()> {
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Return the bytes in the log.
+    pub(super) fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Empty the log, once the tree file holds all of its pages.
+    pub(super) fn clear(&mut self) -> Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Append a record: its length and crc32, then its bytes.
+fn write_record(buf: &mut Vec<u8>, record: &[u8]) {
+    buf.extend_from_slice(&(record.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&crc32fast::hash(record).to_le_bytes());
+    buf.extend_from_slice(record);
+}
+
+/// Return the whole record at `pos` and the position after it.
+fn read_record(buf: &[u8], pos: usize) -> Option<(&[u8], usize)> {
+    let header = buf.get(pos..pos + 8)?;
+    let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let crc = [header[4], header[5], header[6], header[7]];
+    let record = buf.get(pos + 8..pos + 8 + len)?;
+    if crc32fast::hash(record).to_le_bytes() != crc {
+        return None;
+    }
+    Some((record, pos + 8 + len))
+}
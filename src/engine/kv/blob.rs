@@ -0,0 +1,177 @@
+use super::compaction::RateLimiter;
+use super::encryption::Encryption;
+use super::namespace::Keyspace;
+use super::options::Compression;
+use super::record::{decode_command, encode_command, CommandIter, RecordPlace};
+use super::storage::{Storage, StorageFile};
+use super::{BufWriterWithPosition, CommandPosition, Reader};
+use crate::{BlobPointer, Command, KVStoreError, Result};
+use std::collections::BTreeMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+
+/** Return the path of the blob file with the given number.
+
+Values of at least the blob threshold of a KvStore are kept in blob files, so that
+compaction of the data files copies a small command which points at the value instead
+of the value. A blob file has the header and the records of a data file, with a set
+command of the key and the value for every blob. Blobs are only appended, and those no
+command points at any more stay in their file until the blob garbage collection copies
+the live blobs into a new file and deletes the old ones.
+ */
+pub(super) fn blob_file_path(dir_path: &Path, file_number: u64) -> PathBuf {
+    dir_path.join(format!("blob_{}.blob", file_number))
+}
+
+/// Return the numbers of all blob files in `dir_path`, in ascending order.
+pub(super) fn sorted_blob_numbers(storage: &dyn Storage, dir_path: &Path) -> Result<Vec<u64>> {
+    let mut numbers: Vec<u64> = storage
+        .read_dir(dir_path)?
+        .into_iter()
+        .filter_map(|path| {
+            path.file_name()?
+                .to_str()?
+                .strip_prefix("blob_")?
+                .strip_suffix(".blob")?
+                .parse()
+                .ok()
+        })
+        .collect();
+    numbers.sort_unstable();
+    Ok(numbers)
+}
+
+/// Return the bytes of all blob files.
+pub(super) fn blob_files_size(storage: &dyn Storage, dir_path: &Path) -> Result<u64> {
+    let mut size = 0;
+    for file_number in sorted_blob_numbers(storage, dir_path)? {
+        size += storage
+            .open(&blob_file_path(dir_path, file_number))?
+            .size()?;
+    }
+    Ok(size)
+}
+
+/// Read the record of a blob as it is in its file.
+pub(super) fn read_blob_record(
+    storage: &dyn Storage,
+    dir_path: &Path,
+    pointer: &BlobPointer,
+) -> Result<Vec<u8>> {
+    let mut file = storage.open(&blob_file_path(dir_path, pointer.file_number))?;
+    file.seek(SeekFrom::Start(pointer.offset))?;
+    let mut record = Vec::with_capacity(pointer.length as usize);
+    file.take(pointer.length).read_to_end(&mut record)?;
+    Ok(record)
+}
+
+/// Read the value of a blob.
+pub(super) fn read_blob(
+    storage: &dyn Storage,
+    dir_path: &Path,
+    pointer: &BlobPointer,
+    encryption: Option<&Encryption>,
+) -> Result<String> {
+    let record = read_blob_record(storage, dir_path, pointer)?;
+    let place = RecordPlace::Blob(pointer.file_number, pointer.offset);
+    match decode_command(&record, encryption, place)? {
+        Command::SET(_, value) | Command::NSSET(_, _, value) => Ok(value),
+        _ => Err(KVStoreError::CorruptRecord(format!(
+            "the record at offset {} of blob file {} holds no value",
+            pointer.offset, pointer.file_number
+        ))),
+    }
+}
+
+/// A copied blob: the namespace and key, the position of the command which pointed at the
+/// blob when it was copied, the blob and its copy.
+pub(super) type MovedBlob = (
+    Arc<Keyspace>,
+    String,
+    CommandPosition,
+    BlobPointer,
+    BlobPointer,
+);
+
+/** A blob garbage collection in progress, which copies the live blobs of the older blob
+files into a blob file of its own while new blobs go to the next one.
+
+Like a compaction it copies without the lock of the writer. A key which is written after
+its blob was copied keeps its new value once the collection is finished.
+ */
+pub(super) struct BlobCollection {
+    pub(super) file_number: u64,
+    pub(super) writer: BufWriterWithPosition<Box<dyn StorageFile>>,
+    // number and bytes of every older blob file, which is deleted when it is finished
+    pub(super) old_files: Vec<(u64, u64)>,
+    pub(super) reader: Reader,
+    pub(super) keyspaces: BTreeMap<u64, Arc<Keyspace>>,
+    pub(super) compression: Compression,
+    pub(super) limiter: RateLimiter,
+    pub(super) moved: Vec<MovedBlob>,
+    pub(super) started: Instant,
+}
+
+impl BlobCollection {
+    /// Copy the blobs of the older blob files which a command points at, and make the
+    /// copies durable.
+    pub(super) fn copy(&mut self) -> Result<()> {
+        let storage = Arc::clone(&self.reader.storage);
+        let encryption = self.reader.encryption.clone();
+        for (number, _) in self.old_files.clone() {
+            let iter = CommandIter::open_path(
+                storage.as_ref(),
+                blob_file_path(&self.reader.dir_path, number),
+                number,
+                0,
+                encryption.as_deref().cloned(),
+                RecordPlace::Blob,
+            )?;
+            for record in iter {
+                let (position, command) = record?;
+                let keyspace = match self.keyspaces.get(&command.namespace_id()) {
+                    Some(keyspace) if command.is_set() && keyspace.check().is_ok() => keyspace,
+                    _ => continue,
+                };
+                let blob = BlobPointer {
+                    file_number: number,
+                    offset: position.offset,
+                    length: position.length,
+                };
+                let command_position = match keyspace.index.get(command.key())? {
+                    Some(command_position)
+                        if self.reader.blob_pointer(Some(command_position))? == Some(blob) =>
+                    {
+                        command_position
+                    }
+                    _ => continue,
+                };
+
+                let offset = self.writer.get_position();
+                let record = encode_command(
+                    &command,
+                    self.compression,
+                    encryption.as_deref(),
+                    RecordPlace::Blob(self.file_number, offset),
+                )?;
+                self.limiter.consume(record.len() as u64);
+                self.writer.write_all(&record)?;
+                let copy = BlobPointer {
+                    file_number: self.file_number,
+                    offset,
+                    length: record.len() as u64,
+                };
+                self.moved.push((
+                    Arc::clone(keyspace),
+                    command.key().to_owned(),
+                    command_position,
+                    blob,
+                    copy,
+                ));
+            }
+        }
+        self.writer.sync()
+    }
+}
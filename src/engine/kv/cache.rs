@@ -0,0 +1,86 @@
+use super::CommandPosition;
+use crate::engine::lru::LruCache;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/** A size-bounded cache of recently read values, shared by all clones of a KvStore.
+
+Values are cached by namespace id and key, with the position of its command. A cached value is only
+returned for the position the index currently holds, so a value cached by a reader
+racing with the writer is never returned after the key changed.
+ */
+pub(super) struct ValueCache {
+    entries: Mutex<LruCache<(u64, String), (CommandPosition, String)>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl ValueCache {
+    /// Create a cache holding keys and values of up to `capacity` bytes.
+    pub(super) fn new(capacity: u64) -> ValueCache {
+        ValueCache {
+            entries: Mutex::new(LruCache::new(capacity)),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Return the cached value of the command at `position`.
+    pub(super) fn get(
+        &self,
+        namespace_id: u64,
+        key: &str,
+        position: &CommandPosition,
+    ) -> Option<String> {
+        let value = match self
+            .entries
+            .lock()
+            .unwrap()
+            .get(&(namespace_id, key.to_owned()))
+        {
+            Some((cached_position, value)) if cached_position == position => Some(value.clone()),
+            _ => None,
+        };
+        let counter = if value.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+
+    pub(super) fn insert(
+        &self,
+        namespace_id: u64,
+        key: String,
+        position: CommandPosition,
+        value: String,
+    ) {
+        let weight = (key.len() + value.len()) as u64;
+        self.entries
+            .lock()
+            .unwrap()
+            .insert((namespace_id, key), (position, value), weight);
+    }
+
+    pub(super) fn invalidate(&self, namespace_id: u64, key: &str) {
+        self.entries
+            .lock()
+            .unwrap()
+            .remove(&(namespace_id, key.to_owned()));
+    }
+
+    /// Drop every value, e.g. when compaction moved all commands.
+    pub(super) fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    pub(super) fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,241 @@
+use super::encryption::Encryption;
+use super::filter::KeyFilters;
+use super::namespace::Keyspace;
+use super::options::Compression;
+use super::record::{encode_command, sorted_file_numbers, CommandIter, RecordPlace};
+use super::storage::{Storage, StorageFile};
+use super::{BufWriterWithPosition, CommandPosition};
+use crate::{KVStoreError, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// When and how fast a KvStore compacts its data files.
+#[derive(Clone, Debug, Default)]
+pub struct CompactionOptions {
+    /// bytes per second compaction copies at most, None for no limit
+    pub rate_limit: Option<u64>,
+    /// times of day when compaction starts by itself, at any time if there are none
+    pub windows: Vec<CompactionWindow>,
+}
+
+impl CompactionOptions {
+    /// Return true if compaction may start by itself now.
+    pub(super) fn allows_now(&self) -> bool {
+        if self.windows.is_empty() {
+            return true;
+        }
+        let minute = minute_of_day(SystemTime::now());
+        self.windows.iter().any(|window| window.contains(minute))
+    }
+}
+
+/** A time of day in UTC when compaction may start by itself, from `start` up to `end`
+in minutes since midnight. A window whose end is before its start spans midnight.
+
+It is written as `HH:MM-HH:MM`, so `22:30-05:00` is the night from 22:30 to 5:00.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CompactionWindow {
+    /// first minute of the window
+    pub start: u32,
+    /// minute at which the window closes
+    pub end: u32,
+}
+
+impl CompactionWindow {
+    /// Return true if the window holds the given minute of the day.
+    pub fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            self.start <= minute && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+impl FromStr for CompactionWindow {
+    type Err = KVStoreError;
+
+    fn from_str(window: &str) -> Result<CompactionWindow> {
+        let invalid = || {
+            KVStoreError::CommonStringError(format!(
+                "{:?} is not a compaction window like 22:30-05:00",
+                window
+            ))
+        };
+        let parse_time = |time: &str| -> Option<u32> {
+            let (hours, minutes) = time.split_once(':')?;
+            let (hours, minutes) = (hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?);
+            if hours < 24 && minutes < 60 {
+                Some(hours * 60 + minutes)
+            } else {
+                None
+            }
+        };
+        let (start, end) = window.split_once('-').ok_or_else(invalid)?;
+        let start = parse_time(start.trim()).ok_or_else(invalid)?;
+        let end = parse_time(end.trim()).ok_or_else(invalid)?;
+        if start == end {
+            return Err(invalid());
+        }
+        Ok(CompactionWindow { start, end })
+    }
+}
+
+/// Return the minutes since midnight UTC.
+fn minute_of_day(time: SystemTime) -> u32 {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    ((seconds / 60) % MINUTES_PER_DAY as u64) as u32
+}
+
+/// Keeps the bytes a compaction copies below a rate by sleeping between records.
+pub(super) struct RateLimiter {
+    bytes_per_second: Option<u64>,
+    start: Instant,
+    bytes: u64,
+}
+
+impl RateLimiter {
+    pub(super) fn new(bytes_per_second: Option<u64>) -> RateLimiter {
+        RateLimiter {
+            bytes_per_second,
+            start: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    /// Count copied bytes, sleeping until the rate is kept.
+    pub(super) fn consume(&mut self, bytes: u64) {
+        let bytes_per_second = match self.bytes_per_second {
+            Some(rate) if rate > 0 => rate,
+            _ => return,
+        };
+        self.bytes += bytes;
+        let due = Duration::from_secs_f64(self.bytes as f64 / bytes_per_second as f64);
+        if let Some(wait) = due.checked_sub(self.start.elapsed()) {
+            thread::sleep(wait);
+        }
+    }
+}
+
+/// Return the path a compaction writes the data file with the given number at, until it
+/// is complete.
+pub(super) fn compaction_file_path(dir_path: &Path, file_number: u64) -> PathBuf {
+    dir_path.join(format!("data_{}.txt.tmp", file_number))
+}
+
+/// Delete the files of compactions which a crash interrupted.
+pub(super) fn remove_compaction_files(storage: &dyn Storage, dir_path: &Path) -> Result<()> {
+    for path in storage.read_dir(dir_path)? {
+        let is_compaction_file = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name.starts_with("data_") && name.ends_with(".txt.tmp"),
+            None => false,
+        };
+        if is_compaction_file {
+            storage.remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/** A compaction in progress, which copies the live commands of the sealed data files
+into a file of its own while the writer appends to the next one.
+
+It only reads the sealed files and the indexes, so it copies without the lock of the
+writer, and the writes go on meanwhile. A key which is written after its command was
+copied keeps its new position once the compaction is finished.
+ */
+pub(super) struct Compaction {
+    // the data files before it are compacted
+    pub(super) file_number: u64,
+    pub(super) writer: BufWriterWithPosition<Box<dyn StorageFile>>,
+    pub(super) storage: Arc<dyn Storage>,
+    pub(super) dir_path: Arc<PathBuf>,
+    pub(super) keyspaces: BTreeMap<u64, Arc<Keyspace>>,
+    pub(super) compression: Compression,
+    pub(super) encryption: Option<Arc<Encryption>>,
+    pub(super) filters: Option<Arc<KeyFilters>>,
+    pub(super) limiter: RateLimiter,
+    // namespace id -> key -> its position when it was copied and the position of the copy
+    pub(super) moved: HashMap<u64, HashMap<String, (CommandPosition, CommandPosition)>>,
+    pub(super) started: Instant,
+}
+
+impl Compaction {
+    /// Copy the commands of the sealed data files which the indexes point at, and make
+    /// the copies durable.
+    pub(super) fn copy(&mut self) -> Result<()> {
+        let storage = Arc::clone(&self.storage);
+        let encryption = self.encryption.clone();
+        for number in sorted_file_numbers(storage.as_ref(), &self.dir_path)? {
+            if number >= self.file_number {
+                break;
+            }
+            for record in CommandIter::open(
+                storage.as_ref(),
+                &self.dir_path,
+                number,
+                encryption.as_deref(),
+            )? {
+                let (position, command) = record?;
+                let keyspace = match self.keyspaces.get(&command.namespace_id()) {
+                    Some(keyspace) if command.is_set() && keyspace.check().is_ok() => keyspace,
+                    _ => continue,
+                };
+                if keyspace.index.get(command.key())? != Some(position) {
+                    continue;
+                }
+
+                let offset = self.writer.get_position();
+                let record = encode_command(
+                    &command,
+                    self.compression,
+                    encryption.as_deref(),
+                    RecordPlace::Data(self.file_number, offset),
+                )?;
+                self.limiter.consume(record.len() as u64);
+                self.writer.write_all(&record)?;
+                if let Some(filters) = &self.filters {
+                    filters.insert_command(self.file_number, &command);
+                }
+                let copy = CommandPosition {
+                    offset,
+                    length: record.len() as u64,
+                    file_number: self.file_number,
+                };
+                self.moved
+                    .entry(keyspace.id)
+                    .or_default()
+                    .insert(command.key().to_owned(), (position, copy));
+            }
+        }
+        self.writer.sync()
+    }
+
+    /// Return the position of a key once the compaction is finished.
+    pub(super) fn position(
+        &self,
+        namespace_id: u64,
+        key: &str,
+        position: &CommandPosition,
+    ) -> CommandPosition {
+        match self
+            .moved
+            .get(&namespace_id)
+            .and_then(|moved| moved.get(key))
+        {
+            Some((old, copy)) if old == position => *copy,
+            _ => *position,
+        }
+    }
+}
@@ -0,0 +1,774 @@
+use super::index::{Checkpoint, Index};
+use super::record::data_file_path;
+use super::storage::{Storage, StorageFile};
+use super::CommandPosition;
+use crate::engine::lru::LruCache;
+use crate::{KVStoreError, Result};
+use log::warn;
+use std::collections::BTreeMap;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+// the index of the default namespace is `index.dat`, the others `index_<namespace id>.dat`;
+// the runs written after them are `index-<n>.dat` and `index_<namespace id>-<n>.dat`
+const INDEX_STEM: &str = "index";
+const MAGIC: &[u8; 8] = b"KVSINDEX";
+// a block is written once its entries take at least this many bytes
+const BLOCK_SIZE: usize = 4096;
+// root offset, root length, count, checkpoint and magic
+const FOOTER_SIZE: u64 = 6 * 8 + 8;
+// a run is merged into the run before it unless that one is this many times larger
+const MERGE_RATIO: u64 = 2;
+
+/** An index which keeps the keys of a namespace in sorted, paged files in the store directory.
+
+Each file is a run: a tree of blocks written bottom-up, whose leaf blocks hold keys
+and their command positions, or a tombstone for a removed key, and whose blocks above
+hold the first key and the location of each block below. A key is looked up in the
+newest run first. Only recently used blocks are cached in memory.
+
+Updates are buffered in memory and written as a new run once there are
+`max_buffered_keys` of them. A run is merged with the run before it while that one is
+not much larger, so that there are few runs and every key is merged only a few
+times. Every run records the position in the log up to which it is up to date, so
+that opening the store only replays the log after the newest run.
+ */
+pub(super) struct DiskIndex {
+    storage: Arc<dyn Storage>,
+    dir_path: PathBuf,
+    // `index` or `index_<namespace id>`
+    stem: String,
+    max_buffered_keys: usize,
+    state: RwLock<DiskState>,
+    // number of a run and offset of a block in it -> block
+    cache: Mutex<LruCache<(u64, u64), Arc<Block>>>,
+}
+
+struct DiskState {
+    // oldest first
+    runs: Vec<IndexFile>,
+    // None for a removed key
+    buffered: BTreeMap<String, Option<CommandPosition>>,
+    count: u64,
+}
+
+impl DiskIndex {
+    pub(super) fn open(
+        storage: &Arc<dyn Storage>,
+        dir_path: &Path,
+        namespace_id: u64,
+        cache_pages: usize,
+        max_buffered_keys: usize,
+    ) -> Result<DiskIndex> {
+        let stem = match namespace_id {
+            0 => INDEX_STEM.to_owned(),
+            id => format!("{}_{}", INDEX_STEM, id),
+        };
+        let runs = match open_runs(storage, dir_path, &stem) {
+            Ok(runs) => match runs.last() {
+                Some(newest) if !reaches(storage.as_ref(), dir_path, &newest.checkpoint)? => {
+                    // the data files lost the unsynced commands the index was saved with in a crash
+                    warn!(
+                        "Rebuilding index because {:?} is ahead of the data files",
+                        newest.path
+                    );
+                    remove_runs(storage.as_ref(), dir_path, &stem)?;
+                    Vec::new()
+                }
+                _ => runs,
+            },
+            Err(KVStoreError::CorruptIndex(reason)) => {
+                // the data files are complete, so the index can be rebuilt from them
+                warn!(
+                    "Rebuilding index {} because it is corrupt: {}",
+                    stem, reason
+                );
+                remove_runs(storage.as_ref(), dir_path, &stem)?;
+                Vec::new()
+            }
+            Err(err) => return Err(err),
+        };
+        Ok(DiskIndex {
+            storage: Arc::clone(storage),
+            dir_path: dir_path.to_owned(),
+            stem,
+            max_buffered_keys,
+            state: RwLock::new(DiskState {
+                count: runs.last().map_or(0, |file| file.count),
+                runs,
+                buffered: BTreeMap::new(),
+            }),
+            cache: Mutex::new(LruCache::new(cache_pages as u64)),
+        })
+    }
+
+    fn run_path(&self, number: u64) -> PathBuf {
+        run_path(&self.dir_path, &self.stem, number)
+    }
+
+    /// Delete the index files of a store which is opened with the in-memory index,
+    /// since they will not be kept up to date.
+    pub(super) fn remove_stale(storage: &dyn Storage, dir_path: &Path) -> Result<()> {
+        for path in storage.read_dir(dir_path)? {
+            let is_index = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) => name.starts_with(INDEX_STEM) && name.ends_with(".dat"),
+                None => false,
+            };
+            if is_index {
+                remove_index_file(storage, &path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Delete the index files of a dropped namespace.
+    pub(super) fn remove(storage: &dyn Storage, dir_path: &Path, namespace_id: u64) -> Result<()> {
+        remove_runs(
+            storage,
+            dir_path,
+            &format!("{}_{}", INDEX_STEM, namespace_id),
+        )
+    }
+
+    fn lookup(&self, state: &DiskState, key: &str) -> Result<Option<CommandPosition>> {
+        if let Some(position) = state.buffered.get(key) {
+            return Ok(*position);
+        }
+        for file in state.runs.iter().rev() {
+            if let Some(values) = self.find(file, key)? {
+                return Ok(position_of(&values));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Find the entry of a key in one run.
+    fn find(&self, file: &IndexFile, key: &str) -> Result<Option<[u64; 3]>> {
+        let (mut offset, mut length) = match file.root {
+            Some(root) => root,
+            None => return Ok(None),
+        };
+        loop {
+            let block = self.block(file, offset, length)?;
+            if block.leaf {
+                return Ok(block.find(key).copied());
+            }
+            match block.find_child(key) {
+                Some(child) => {
+                    offset = child[0];
+                    length = child[1];
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+
+    fn block(&self, file: &IndexFile, offset: u64, length: u64) -> Result<Arc<Block>> {
+        if let Some(block) = self.cache.lock().unwrap().get(&(file.number, offset)) {
+            return Ok(Arc::clone(block));
+        }
+        let block = Arc::new(file.read_block(offset, length)?);
+        self.cache
+            .lock()
+            .unwrap()
+            .insert((file.number, offset), Arc::clone(&block), 1);
+        Ok(block)
+    }
+
+    /// Write the merged entries of the runs and the buffer, as changed by `f`, into a
+    /// single new run.
+    fn write(
+        &self,
+        f: &mut dyn FnMut(&str, &CommandPosition) -> Result<CommandPosition>,
+        finish: &mut dyn FnMut() -> Result<Checkpoint>,
+    ) -> Result<()> {
+        let path = self.run_path(0);
+        let temp_path = path.with_extension("dat.tmp");
+        {
+            let state = self.state.read().unwrap();
+            let mut builder = IndexBuilder::new(self.storage.as_ref(), &temp_path)?;
+            let mut count = 0;
+            merged_for_each(&state, &mut |key, position| {
+                count += 1;
+                builder.add(key, Some(&f(key, position)?))
+            })?;
+            builder.finish(count, finish()?)?;
+        }
+
+        // the writer is the only one which changes the index, so nothing changed in the meantime
+        let mut state = self.state.write().unwrap();
+        // the newest runs go first, so that a crash leaves an older state of the index
+        for file in state.runs.iter().rev().filter(|file| file.number > 0) {
+            self.storage.remove_file(&file.path)?;
+        }
+        self.storage.rename(&temp_path, &path)?;
+        state.runs = IndexFile::open(&self.storage, &path, 0)?
+            .into_iter()
+            .collect();
+        state.count = state.runs.last().map_or(0, |file| file.count);
+        state.buffered.clear();
+        self.cache.lock().unwrap().clear();
+        Ok(())
+    }
+
+    /// Write the buffered updates as a new run, then merge the newest runs.
+    fn write_run(&self, checkpoint: Checkpoint) -> Result<()> {
+        let (path, number) = {
+            let state = self.state.read().unwrap();
+            let number = state.runs.last().map_or(0, |file| file.number + 1);
+            let path = self.run_path(number);
+            let temp_path = path.with_extension("dat.tmp");
+            let mut builder = IndexBuilder::new(self.storage.as_ref(), &temp_path)?;
+            for (key, position) in &state.buffered {
+                // the first run has nothing to hide a removed key in
+                if position.is_some() || !state.runs.is_empty() {
+                    builder.add(key, position.as_ref())?;
+                }
+            }
+            builder.finish(state.count, checkpoint)?;
+            self.storage.rename(&temp_path, &path)?;
+            (path, number)
+        };
+
+        let file = IndexFile::open(&self.storage, &path, number)?;
+        let mut state = self.state.write().unwrap();
+        state.runs.extend(file);
+        state.buffered.clear();
+        drop(state);
+        self.merge_runs()
+    }
+
+    /** Merge the two newest runs into the older one while it is not much larger. A crash
+    before the newer one is deleted leaves it on top of the merged run, which holds the
+    same entries.
+     */
+    fn merge_runs(&self) -> Result<()> {
+        loop {
+            let (temp_path, path, number) = {
+                let state = self.state.read().unwrap();
+                let runs = match state.runs.len() {
+                    len if len >= 2 => &state.runs[len - 2..],
+                    _ => return Ok(()),
+                };
+                let (older, newer) = (&runs[0], &runs[1]);
+                if older.blocks_end > newer.blocks_end * MERGE_RATIO {
+                    return Ok(());
+                }
+                let temp_path = older.path.with_extension("dat.tmp");
+                let mut builder = IndexBuilder::new(self.storage.as_ref(), &temp_path)?;
+                // the oldest run has nothing to hide a removed key in
+                let keep_removed = state.runs.len() > 2;
+                merge_entries(
+                    &BTreeMap::new(),
+                    runs,
+                    &mut |key, position| match position {
+                        Some(_) => builder.add(key, position),
+                        None if keep_removed => builder.add(key, None),
+                        None => Ok(()),
+                    },
+                )?;
+                builder.finish(newer.count, newer.checkpoint)?;
+                (temp_path, older.path.clone(), older.number)
+            };
+
+            let mut state = self.state.write().unwrap();
+            self.storage.rename(&temp_path, &path)?;
+            let file = IndexFile::open(&self.storage, &path, number)?;
+            if let Some(newer) = state.runs.pop() {
+                self.storage.remove_file(&newer.path)?;
+            }
+            state.runs.pop();
+            state.runs.extend(file);
+            self.cache.lock().unwrap().clear();
+        }
+    }
+}
+
+/// Return the path of a run of an index.
+fn run_path(dir_path: &Path, stem: &str, number: u64) -> PathBuf {
+    match number {
+        0 => dir_path.join(format!("{}.dat", stem)),
+        number => dir_path.join(format!("{}-{}.dat", stem, number)),
+    }
+}
+
+/// Return the numbers of the runs of an index in the store directory, oldest first.
+fn run_numbers(storage: &dyn Storage, dir_path: &Path, stem: &str) -> Result<Vec<u64>> {
+    let base = format!("{}.dat", stem);
+    let prefix = format!("{}-", stem);
+    let mut numbers: Vec<u64> = storage
+        .read_dir(dir_path)?
+        .iter()
+        .filter_map(|path| path.file_name()?.to_str())
+        .filter_map(|name| match name {
+            _ if name == base => Some(0),
+            _ => name
+                .strip_prefix(&prefix)?
+                .strip_suffix(".dat")?
+                .parse()
+                .ok(),
+        })
+        .collect();
+    numbers.sort_unstable();
+    Ok(numbers)
+}
+
+fn open_runs(storage: &Arc<dyn Storage>, dir_path: &Path, stem: &str) -> Result<Vec<IndexFile>> {
+    let mut runs = Vec::new();
+    for number in run_numbers(storage.as_ref(), dir_path, stem)? {
+        runs.extend(IndexFile::open(
+            storage,
+            &run_path(dir_path, stem, number),
+            number,
+        )?);
+    }
+    Ok(runs)
+}
+
+fn remove_runs(storage: &dyn Storage, dir_path: &Path, stem: &str) -> Result<()> {
+    // the newest runs go first, so that a crash leaves an older state of the index
+    for number in run_numbers(storage, dir_path, stem)?.into_iter().rev() {
+        remove_index_file(storage, &run_path(dir_path, stem, number))?;
+    }
+    Ok(())
+}
+
+/// Return true if the data files hold every command up to the checkpoint.
+fn reaches(storage: &dyn Storage, dir_path: &Path, checkpoint: &Checkpoint) -> Result<bool> {
+    if checkpoint.offset == 0 {
+        return Ok(true);
+    }
+    match storage.open(&data_file_path(dir_path, checkpoint.file_number)) {
+        Ok(file) => Ok(file.size()? >= checkpoint.offset),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn remove_index_file(storage: &dyn Storage, path: &Path) -> Result<()> {
+    match storage.remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+impl Index for DiskIndex {
+    fn get(&self, key: &str) -> Result<Option<CommandPosition>> {
+        self.lookup(&self.state.read().unwrap(), key)
+    }
+
+    fn insert(&self, key: String, position: CommandPosition) -> Result<Option<CommandPosition>> {
+        let old = self.get(&key)?;
+        let mut state = self.state.write().unwrap();
+        if old.is_none() {
+            state.count += 1;
+        }
+        state.buffered.insert(key, Some(position));
+        Ok(old)
+    }
+
+    fn remove(&self, key: &str) -> Result<Option<CommandPosition>> {
+        let old = self.get(key)?;
+        if old.is_some() {
+            let mut state = self.state.write().unwrap();
+            state.count -= 1;
+            state.buffered.insert(key.to_owned(), None);
+        }
+        Ok(old)
+    }
+
+    fn len(&self) -> u64 {
+        self.state.read().unwrap().count
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(&str, &CommandPosition) -> Result<()>) -> Result<()> {
+        merged_for_each(&self.state.read().unwrap(), f)
+    }
+
+    fn rewrite(
+        &self,
+        f: &mut dyn FnMut(&str, &CommandPosition) -> Result<CommandPosition>,
+        finish: &mut dyn FnMut() -> Result<Checkpoint>,
+    ) -> Result<()> {
+        self.write(f, finish)
+    }
+
+    fn applied(&self, checkpoint: Checkpoint, sync: &mut dyn FnMut() -> Result<()>) -> Result<()> {
+        if self.state.read().unwrap().buffered.len() < self.max_buffered_keys {
+            return Ok(());
+        }
+        // a run which points at commands a crash loses would hide that they are lost
+        sync()?;
+        self.write_run(checkpoint)
+    }
+
+    fn recovery_start(&self) -> Option<Checkpoint> {
+        let state = self.state.read().unwrap();
+        state.runs.last().map(|file| file.checkpoint)
+    }
+}
+
+/// Call `f` with every live entry of the runs and the buffer in key order.
+fn merged_for_each(
+    state: &DiskState,
+    f: &mut dyn FnMut(&str, &CommandPosition) -> Result<()>,
+) -> Result<()> {
+    merge_entries(
+        &state.buffered,
+        &state.runs,
+        &mut |key, position| match position {
+            Some(position) => f(key, position),
+            None => Ok(()),
+        },
+    )
+}
+
+/// Call `f` with the newest entry of every key of the runs and the buffer in key order,
+/// None for a removed key.
+fn merge_entries(
+    buffered: &BTreeMap<String, Option<CommandPosition>>,
+    runs: &[IndexFile],
+    f: &mut dyn FnMut(&str, Option<&CommandPosition>) -> Result<()>,
+) -> Result<()> {
+    let mut buffered = buffered
+        .iter()
+        .map(|(key, position)| (key.clone(), *position));
+    let mut leaves = runs
+        .iter()
+        .rev()
+        .map(LeafReader::open)
+        .collect::<Result<Vec<_>>>()?;
+    // the next entry of every source, the buffer first and then the runs newest first
+    let mut heads = vec![buffered.next()];
+    for leaves in &mut leaves {
+        heads.push(leaves.next_entry()?);
+    }
+    loop {
+        let key = match heads.iter().flatten().map(|(key, _)| key).min() {
+            Some(key) => key.clone(),
+            None => return Ok(()),
+        };
+        let mut newest = None;
+        for (i, head) in heads.iter_mut().enumerate() {
+            if matches!(head, Some((head_key, _)) if *head_key == key) {
+                let position = head.take().unwrap().1;
+                newest.get_or_insert(position);
+                *head = match i {
+                    0 => buffered.next(),
+                    i => leaves[i - 1].next_entry()?,
+                };
+            }
+        }
+        f(&key, newest.flatten().as_ref())?;
+    }
+}
+
+/// Return the command position of a leaf entry, None for a removed key.
+fn position_of(values: &[u64; 3]) -> Option<CommandPosition> {
+    // no command is empty
+    if values[1] == 0 {
+        return None;
+    }
+    Some(CommandPosition {
+        offset: values[0],
+        length: values[1],
+        file_number: values[2],
+    })
+}
+
+/// A block of entries, each a key and three numbers: a command position in a leaf,
+/// the offset and length of the block below in the other blocks.
+struct Block {
+    leaf: bool,
+    entries: Vec<(String, [u64; 3])>,
+}
+
+impl Block {
+    /// Read a block. Return it and the number of bytes read.
+    fn read_from(reader: &mut impl Read) -> Result<(Block, u64)> {
+        let mut header = [0; 5];
+        reader.read_exact(&mut header)?;
+        let leaf = match header[0] {
+            0 => true,
+            1 => false,
+            kind => {
+                return Err(KVStoreError::CorruptIndex(format!(
+                    "unknown block kind {}",
+                    kind
+                )))
+            }
+        };
+        let count = u32::from_le_bytes([header[1], header[2], header[3], header[4]]);
+        let mut size = header.len() as u64;
+        let mut entries = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let key_length = read_u32(reader)?;
+            let mut key = vec![0; key_length as usize];
+            reader.read_exact(&mut key)?;
+            let key = String::from_utf8(key)?;
+            let values = [read_u64(reader)?, read_u64(reader)?, read_u64(reader)?];
+            size += 4 + key_length as u64 + 24;
+            entries.push((key, values));
+        }
+        Ok((Block { leaf, entries }, size))
+    }
+
+    /// Find the entry of `key` in a leaf block.
+    fn find(&self, key: &str) -> Option<&[u64; 3]> {
+        self.entries
+            .binary_search_by(|(entry_key, _)| entry_key.as_str().cmp(key))
+            .ok()
+            .map(|i| &self.entries[i].1)
+    }
+
+    /// Find the entry of the block below which may hold `key`.
+    fn find_child(&self, key: &str) -> Option<&[u64; 3]> {
+        match self
+            .entries
+            .binary_search_by(|(entry_key, _)| entry_key.as_str().cmp(key))
+        {
+            Ok(i) => Some(&self.entries[i].1),
+            Err(0) => None,
+            Err(i) => Some(&self.entries[i - 1].1),
+        }
+    }
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// An immutable index file, one run of an index.
+struct IndexFile {
+    storage: Arc<dyn Storage>,
+    path: PathBuf,
+    number: u64,
+    file: Mutex<Box<dyn StorageFile>>,
+    // offset and length of the root block, None for an empty run
+    root: Option<(u64, u64)>,
+    // number of keys of the index up to this run
+    count: u64,
+    checkpoint: Checkpoint,
+    // offset of the footer
+    blocks_end: u64,
+}
+
+impl IndexFile {
+    /// Open an index file, None if there is none.
+    fn open(storage: &Arc<dyn Storage>, path: &Path, number: u64) -> Result<Option<IndexFile>> {
+        let mut file = match storage.open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let length = file.size()?;
+        if length < MAGIC.len() as u64 + FOOTER_SIZE {
+            return Err(KVStoreError::CorruptIndex("file is too short".to_owned()));
+        }
+        let mut magic = [0; 8];
+        file.read_exact(&mut magic)?;
+        file.seek(SeekFrom::Start(length - FOOTER_SIZE))?;
+        let mut footer = [0; 6];
+        for value in footer.iter_mut() {
+            *value = read_u64(&mut file)?;
+        }
+        let mut end_magic = [0; 8];
+        file.read_exact(&mut end_magic)?;
+        if &magic != MAGIC || &end_magic != MAGIC {
+            return Err(KVStoreError::CorruptIndex("bad magic bytes".to_owned()));
+        }
+
+        Ok(Some(IndexFile {
+            storage: Arc::clone(storage),
+            path: path.to_owned(),
+            number,
+            file: Mutex::new(file),
+            root: if footer[1] == 0 {
+                None
+            } else {
+                Some((footer[0], footer[1]))
+            },
+            count: footer[2],
+            checkpoint: Checkpoint {
+                file_number: footer[3],
+                offset: footer[4],
+                useless_size: footer[5],
+            },
+            blocks_end: length - FOOTER_SIZE,
+        }))
+    }
+
+    fn read_block(&self, offset: u64, length: u64) -> Result<Block> {
+        let mut bytes = vec![0; length as usize];
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut bytes)?;
+        }
+        Ok(Block::read_from(&mut bytes.as_slice())?.0)
+    }
+}
+
+/// Reads the entries of the leaf blocks of an index file in key order.
+struct LeafReader {
+    reader: BufReader<Box<dyn StorageFile>>,
+    remaining: u64,
+    entries: std::vec::IntoIter<(String, [u64; 3])>,
+}
+
+impl LeafReader {
+    fn open(file: &IndexFile) -> Result<LeafReader> {
+        let mut reader = BufReader::new(file.storage.open(&file.path)?);
+        reader.seek(SeekFrom::Start(MAGIC.len() as u64))?;
+        Ok(LeafReader {
+            reader,
+            remaining: file.blocks_end - MAGIC.len() as u64,
+            entries: Vec::new().into_iter(),
+        })
+    }
+
+    /// Return the next entry, with None for a removed key.
+    fn next_entry(&mut self) -> Result<Option<(String, Option<CommandPosition>)>> {
+        loop {
+            if let Some((key, values)) = self.entries.next() {
+                return Ok(Some((key, position_of(&values))));
+            }
+            if self.remaining == 0 {
+                return Ok(None);
+            }
+            let (block, size) = Block::read_from(&mut self.reader)?;
+            self.remaining -= size;
+            if block.leaf {
+                self.entries = block.entries.into_iter();
+            }
+        }
+    }
+}
+
+/// Writes the entries of an index file, which must be added in key order.
+struct IndexBuilder {
+    writer: BufWriter<Box<dyn StorageFile>>,
+    offset: u64,
+    // the unwritten block of every level of the tree, leaves first
+    levels: Vec<PendingBlock>,
+}
+
+#[derive(Default)]
+struct PendingBlock {
+    first_key: String,
+    count: u32,
+    bytes: Vec<u8>,
+    written: u64,
+}
+
+impl IndexBuilder {
+    fn new(storage: &dyn Storage, path: &Path) -> Result<IndexBuilder> {
+        let mut writer = BufWriter::new(storage.create(path)?);
+        writer.write_all(MAGIC)?;
+        Ok(IndexBuilder {
+            writer,
+            offset: MAGIC.len() as u64,
+            levels: Vec::new(),
+        })
+    }
+
+    /// Add the position of a key, None for a removed key.
+    fn add(&mut self, key: &str, position: Option<&CommandPosition>) -> Result<()> {
+        let values = match position {
+            Some(position) => [position.offset, position.length, position.file_number],
+            None => [0; 3],
+        };
+        self.add_to_level(0, key, values)
+    }
+
+    fn add_to_level(&mut self, level: usize, key: &str, values: [u64; 3]) -> Result<()> {
+        if level == self.levels.len() {
+            self.levels.push(PendingBlock::default());
+        }
+        let block = &mut self.levels[level];
+        if block.count == 0 {
+            block.first_key = key.to_owned();
+        }
+        block.count += 1;
+        block
+            .bytes
+            .extend_from_slice(&(key.len() as u32).to_le_bytes());
+        block.bytes.extend_from_slice(key.as_bytes());
+        for value in &values {
+            block.bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        if block.bytes.len() >= BLOCK_SIZE {
+            self.flush_level(level)?;
+        }
+        Ok(())
+    }
+
+    /// Write the pending block of a level and add it to the level above.
+    fn flush_level(&mut self, level: usize) -> Result<()> {
+        let (first_key, offset, length) = self.write_block(level)?;
+        self.add_to_level(level + 1, &first_key, [offset, length, 0])
+    }
+
+    fn write_block(&mut self, level: usize) -> Result<(String, u64, u64)> {
+        let block = &mut self.levels[level];
+        let kind: u8 = if level == 0 { 0 } else { 1 };
+        self.writer.write_all(&[kind])?;
+        self.writer.write_all(&block.count.to_le_bytes())?;
+        self.writer.write_all(&block.bytes)?;
+
+        let offset = self.offset;
+        let length = 5 + block.bytes.len() as u64;
+        self.offset += length;
+        block.count = 0;
+        block.bytes.clear();
+        block.written += 1;
+        Ok((std::mem::take(&mut block.first_key), offset, length))
+    }
+
+    /// Write the file, with the number of keys of the index.
+    fn finish(mut self, count: u64, checkpoint: Checkpoint) -> Result<()> {
+        let mut root = (0, 0);
+        let mut level = 0;
+        while level < self.levels.len() {
+            let block = &self.levels[level];
+            if level + 1 == self.levels.len() && block.written == 0 {
+                // the only block of the top level is the root
+                if block.count > 0 {
+                    let (_, offset, length) = self.write_block(level)?;
+                    root = (offset, length);
+                }
+                break;
+            }
+            if block.count > 0 {
+                self.flush_level(level)?;
+            }
+            level += 1;
+        }
+
+        for value in &[
+            root.0,
+            root.1,
+            count,
+            checkpoint.file_number,
+            checkpoint.offset,
+            checkpoint.useless_size,
+        ] {
+            self.writer.write_all(&value.to_le_bytes())?;
+        }
+        self.writer.write_all(MAGIC)?;
+        self.writer
+            .into_inner()
+            .map_err(|err| err.into_error())?
+            .sync()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,141 @@
+use super::encryption::Encryption;
+use super::namespace::{NamespaceFile, NamespaceIndexes};
+use super::record::{sorted_file_numbers, CommandIter};
+use super::storage::DiskStorage;
+use super::CommandPosition;
+use crate::{BlobPointer, Command, KVStoreError, Result};
+use serde::Serialize;
+use std::path::Path;
+
+/// One command of a data file, as shown by `dump`.
+#[derive(Serialize, Debug)]
+pub struct RecordInfo {
+    /// number of the `data_N.txt` file
+    pub file_number: u64,
+    /// offset of the command in the file
+    pub offset: u64,
+    /// length of the command in bytes
+    pub length: u64,
+    /// `SET`, `BLOBSET` or `RM`
+    pub command: String,
+    /// id of the namespace of the command, 0 for the default namespace
+    pub namespace_id: u64,
+    /// the key of the command
+    pub key: String,
+    /// length of the value in bytes, None for `RM` and `BLOBSET`
+    pub value_size: Option<u64>,
+    /// where the value of a `BLOBSET` is kept, None for the other commands
+    pub blob: Option<BlobPointer>,
+    /// whether the index of the store points at this command
+    pub live: bool,
+}
+
+/** List the commands of a KvStore directory without opening the store,
+optionally only those of one key, in any namespace, or of one data file.
+Commands which can not be read are skipped, use `verify` to find them.
+ */
+pub fn dump(
+    path: impl AsRef<Path>,
+    key: Option<&str>,
+    file_number: Option<u64>,
+) -> Result<Vec<RecordInfo>> {
+    dump_files(path.as_ref(), key, file_number, None)
+}
+
+/// List the commands of a KvStore directory whose records are encrypted.
+pub fn dump_encrypted(
+    path: impl AsRef<Path>,
+    key: Option<&str>,
+    file_number: Option<u64>,
+    encryption: &Encryption,
+) -> Result<Vec<RecordInfo>> {
+    dump_files(path.as_ref(), key, file_number, Some(encryption))
+}
+
+fn dump_files(
+    dir_path: &Path,
+    key: Option<&str>,
+    file_number: Option<u64>,
+    encryption: Option<&Encryption>,
+) -> Result<Vec<RecordInfo>> {
+    if !dir_path.is_dir() {
+        return Err(KVStoreError::CommonStringError(format!(
+            "{:?} is not a directory",
+            dir_path
+        )));
+    }
+    let file_numbers = sorted_file_numbers(&DiskStorage, dir_path)?;
+
+    let index = NamespaceIndexes::new(&NamespaceFile::load(&DiskStorage, dir_path, encryption)?);
+    for number in &file_numbers {
+        for_each_command(dir_path, *number, encryption, |position, command| {
+            index.apply(position, command)?;
+            Ok(())
+        })?;
+    }
+
+    let mut records = Vec::new();
+    for number in &file_numbers {
+        if file_number.map_or(false, |file_number| file_number != *number) {
+            continue;
+        }
+        for_each_command(dir_path, *number, encryption, |position, command| {
+            let namespace_id = command.namespace_id();
+            let (command, command_key, value_size, blob) = match command {
+                Command::SET(command_key, value) | Command::NSSET(_, command_key, value) => {
+                    ("SET", command_key, Some(value.len() as u64), None)
+                }
+                Command::BLOBSET(command_key, pointer)
+                | Command::NSBLOBSET(_, command_key, pointer) => {
+                    ("BLOBSET", command_key, None, Some(pointer))
+                }
+                Command::RM(command_key) | Command::NSRM(_, command_key) => {
+                    ("RM", command_key, None, None)
+                }
+            };
+            if key.map_or(false, |key| key != command_key) {
+                return Ok(());
+            }
+            let live = index
+                .get(namespace_id, &command_key)
+                .map_or(false, |entry| {
+                    entry.file_number == position.file_number && entry.offset == position.offset
+                });
+            records.push(RecordInfo {
+                file_number: position.file_number,
+                offset: position.offset,
+                length: position.length,
+                command: command.to_owned(),
+                namespace_id,
+                key: command_key,
+                value_size,
+                blob,
+                live,
+            });
+            Ok(())
+        })?;
+    }
+    Ok(records)
+}
+
+fn for_each_command<F>(
+    dir_path: &Path,
+    file_number: u64,
+    encryption: Option<&Encryption>,
+    mut f: F,
+) -> Result<()>
+where
+    F: FnMut(CommandPosition, Command) -> Result<()>,
+{
+    let mut iter = CommandIter::open(&DiskStorage, dir_path, file_number, encryption)?;
+    while let Some(record) = iter.next() {
+        match record {
+            Ok((position, command)) => f(position, command)?,
+            Err(err @ KVStoreError::EncryptionKey(_)) => return Err(err),
+            Err(_) => {
+                iter.resync()?;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,165 @@
+use crate::{KVStoreError, Result};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+const NONCE_SIZE: usize = 12;
+// key id and nonce in front of the ciphertext
+const PREFIX_SIZE: usize = 4 + NONCE_SIZE;
+
+/** The keys a KvStore encrypts its records with.
+
+Records are written with the current key and read with whichever key they were
+written with. To rotate keys, open the store with a new current key and the old
+keys: compaction rewrites every live record with the current key, after which the
+old keys are no longer needed.
+
+Keys are 32 bytes written as 64 hex digits. A key file or environment variable holds
+the current key first and the old keys after it, separated by whitespace or commas.
+ */
+#[derive(Clone)]
+pub struct Encryption {
+    key: EncryptionKey,
+    old_keys: Vec<EncryptionKey>,
+}
+
+#[derive(Clone)]
+struct EncryptionKey {
+    id: u32,
+    cipher: ChaCha20Poly1305,
+}
+
+impl Encryption {
+    /// Encrypt with the given key.
+    pub fn new(key: [u8; 32]) -> Encryption {
+        Encryption {
+            key: EncryptionKey::new(key),
+            old_keys: Vec::new(),
+        }
+    }
+
+    /// Also decrypt the records written with an old key.
+    pub fn with_old_key(mut self, key: [u8; 32]) -> Encryption {
+        self.old_keys.push(EncryptionKey::new(key));
+        self
+    }
+
+    /// Parse hex keys, the current key first.
+    pub fn from_hex(keys: &str) -> Result<Encryption> {
+        let mut keys = keys
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|key| !key.is_empty())
+            .map(parse_key);
+        let mut encryption = match keys.next() {
+            Some(key) => Encryption::new(key?),
+            None => return Err(KVStoreError::EncryptionKey("no key given".to_owned())),
+        };
+        for key in keys {
+            encryption = encryption.with_old_key(key?);
+        }
+        Ok(encryption)
+    }
+
+    /// Read the keys from a file.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Encryption> {
+        Self::from_hex(&fs::read_to_string(path)?)
+    }
+
+    /// Read the keys from an environment variable.
+    pub fn from_env(name: &str) -> Result<Encryption> {
+        match env::var(name) {
+            Ok(keys) => Self::from_hex(&keys),
+            Err(_) => Err(KVStoreError::EncryptionKey(format!("{} is not set", name))),
+        }
+    }
+
+    /// Encrypt a payload, authenticating `aad` too.
+    pub(super) fn encrypt(&self, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .key
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .map_err(|_| KVStoreError::EncryptionKey("can not encrypt record".to_owned()))?;
+        let mut payload = Vec::with_capacity(PREFIX_SIZE + ciphertext.len());
+        payload.extend_from_slice(&self.key.id.to_le_bytes());
+        payload.extend_from_slice(&nonce);
+        payload.extend_from_slice(&ciphertext);
+        Ok(payload)
+    }
+
+    /// Decrypt a payload with the key it was encrypted with, checking that it was
+    /// encrypted with the same `aad`.
+    pub(super) fn decrypt(&self, aad: &[u8], payload: &[u8]) -> Result<Vec<u8>> {
+        if payload.len() < PREFIX_SIZE {
+            return Err(KVStoreError::CorruptRecord(
+                "truncated encrypted payload".to_owned(),
+            ));
+        }
+        let id = u32::from_le_bytes([payload[0], payload[1], payload[2], payload[3]]);
+        let key = std::iter::once(&self.key)
+            .chain(&self.old_keys)
+            .find(|key| key.id == id)
+            .ok_or_else(|| unknown_key(id))?;
+        key.cipher
+            .decrypt(
+                Nonce::from_slice(&payload[4..PREFIX_SIZE]),
+                Payload {
+                    msg: &payload[PREFIX_SIZE..],
+                    aad,
+                },
+            )
+            .map_err(|_| {
+                KVStoreError::CorruptRecord("encrypted record failed authentication".to_owned())
+            })
+    }
+}
+
+/// The error for a record encrypted with a key which was not supplied.
+fn unknown_key(id: u32) -> KVStoreError {
+    KVStoreError::EncryptionKey(format!(
+        "a record is encrypted with key {:08x}, which was not supplied",
+        id
+    ))
+}
+
+impl EncryptionKey {
+    fn new(key: [u8; 32]) -> EncryptionKey {
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+        // the tag of an empty message identifies the key without revealing it
+        let tag = cipher
+            .encrypt(Nonce::from_slice(&[0; NONCE_SIZE]), &[][..])
+            .expect("encrypting an empty message can not fail");
+        EncryptionKey {
+            id: u32::from_le_bytes([tag[0], tag[1], tag[2], tag[3]]),
+            cipher,
+        }
+    }
+}
+
+fn parse_key(hex_key: &str) -> Result<[u8; 32]> {
+    let mut key = [0; 32];
+    hex::decode_to_slice(hex_key, &mut key).map_err(|err| {
+        KVStoreError::EncryptionKey(format!("a key must be 64 hex digits: {}", err))
+    })?;
+    Ok(key)
+}
+
+impl fmt::Debug for Encryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // only the ids, never the keys
+        f.debug_struct("Encryption")
+            .field("key", &format_args!("{:08x}", self.key.id))
+            .field("old_keys", &self.old_keys.len())
+            .finish()
+    }
+}
@@ -0,0 +1,314 @@
+use super::encryption::Encryption;
+use super::record::{data_file_path, CommandIter};
+use super::storage::Storage;
+use crate::engine::bloom::BloomFilter;
+use crate::{Command, KVStoreError, Result};
+use log::warn;
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+const MAGIC: &[u8; 8] = b"KVSBLOOM";
+// keys of the first filter of a data file, every further one takes twice as many
+const FIRST_CAPACITY: usize = 1024;
+
+/** Bloom filters of the keys set in every data file, so that a read of a key which is
+not in the store rarely has to look at the index.
+
+A data file gets a chain of filters which grows as keys are written to it: once a
+filter holds as many keys as it was sized for, a new one twice as large with a bit
+and a half more per key is added, so that the false-positive rate of the chain stays
+below twice the configured one. The filters of a data file are saved in
+`bloom_<file number>.dat` once it is sealed, and built again from the data file when
+that file is missing or damaged. Removed keys stay in the filters until compaction.
+ */
+pub(super) struct KeyFilters {
+    storage: Arc<dyn Storage>,
+    dir_path: Arc<PathBuf>,
+    bits_per_key: u32,
+    // data file number -> filters of the keys set in it
+    files: RwLock<BTreeMap<u64, FileFilter>>,
+    // reads of missing keys which the filters answered
+    skips: AtomicU64,
+    // reads which the filters let through to find no key
+    false_positives: AtomicU64,
+}
+
+struct FileFilter {
+    // filters with the number of keys each was sized for and holds
+    layers: Vec<(BloomFilter, usize, usize)>,
+    bits_per_key: u32,
+}
+
+impl KeyFilters {
+    /// Create the filters with the bits per key which give `false_positive_rate`.
+    pub(super) fn new(
+        storage: Arc<dyn Storage>,
+        dir_path: Arc<PathBuf>,
+        false_positive_rate: f64,
+    ) -> Result<KeyFilters> {
+        if !(false_positive_rate > 0.0 && false_positive_rate < 1.0) {
+            return Err(KVStoreError::CommonStringError(format!(
+                "the bloom filter false-positive rate {} is not between 0 and 1",
+                false_positive_rate
+            )));
+        }
+        // -ln(p) / ln(2)^2 bits per key give a false-positive rate of p
+        let bits_per_key = (-false_positive_rate.ln() / std::f64::consts::LN_2.powi(2)).ceil();
+        Ok(KeyFilters {
+            storage,
+            dir_path,
+            bits_per_key: (bits_per_key as u32).clamp(1, 64),
+            files: RwLock::new(BTreeMap::new()),
+            skips: AtomicU64::new(0),
+            false_positives: AtomicU64::new(0),
+        })
+    }
+
+    /// Delete the filter files of a store which is opened without filters, since they
+    /// will not be kept up to date, and those whose data file is gone.
+    pub(super) fn remove_stale(storage: &dyn Storage, dir_path: &Path, keep: bool) -> Result<()> {
+        for path in storage.read_dir(dir_path)? {
+            let file_number = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix("bloom_"))
+                .and_then(|name| name.strip_suffix(".dat"))
+                .and_then(|number| number.parse::<u64>().ok());
+            if let Some(file_number) = file_number {
+                if !keep || !exists(storage, &data_file_path(dir_path, file_number))? {
+                    remove_filter_file(storage, &path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Load the saved filters of a sealed data file. Return false if there are none.
+    pub(super) fn load(&self, file_number: u64) -> Result<bool> {
+        let path = self.file_path(file_number);
+        let mut bytes = Vec::new();
+        match self.storage.open(&path) {
+            Ok(mut file) => file.read_to_end(&mut bytes)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err.into()),
+        };
+        match decode(&bytes) {
+            Some(filter) => {
+                self.files.write().unwrap().insert(file_number, filter);
+                Ok(true)
+            }
+            None => {
+                warn!("Rebuilding bloom filter because {:?} is corrupt", path);
+                remove_filter_file(self.storage.as_ref(), &path)?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Build the filters of a data file from the commands in it.
+    pub(super) fn build(&self, file_number: u64, encryption: Option<&Encryption>) -> Result<()> {
+        let iter = CommandIter::open(
+            self.storage.as_ref(),
+            &self.dir_path,
+            file_number,
+            encryption,
+        )?;
+        // the commands after one which can not be read are not in the store either
+        for (_, command) in iter.map_while(|record| record.ok()) {
+            self.insert_command(file_number, &command);
+        }
+        Ok(())
+    }
+
+    /// Add the key of a command which sets it.
+    pub(super) fn insert_command(&self, file_number: u64, command: &Command) {
+        if command.is_set() {
+            self.insert(file_number, command.namespace_id(), command.key());
+        }
+    }
+
+    pub(super) fn insert(&self, file_number: u64, namespace_id: u64, key: &str) {
+        self.files
+            .write()
+            .unwrap()
+            .entry(file_number)
+            .or_insert_with(|| FileFilter::new(self.bits_per_key))
+            .insert(&filter_key(namespace_id, key));
+    }
+
+    /// Return false if no data file holds the key. Count the reads it answers.
+    pub(super) fn may_contain(&self, namespace_id: u64, key: &str) -> bool {
+        let key = filter_key(namespace_id, key);
+        let found = self
+            .files
+            .read()
+            .unwrap()
+            .values()
+            .any(|filter| filter.may_contain(&key));
+        if !found {
+            self.skips.fetch_add(1, Ordering::SeqCst);
+        }
+        found
+    }
+
+    /// Count a read which the filters let through but which found no key.
+    pub(super) fn false_positive(&self) {
+        self.false_positives.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Save the filters of a data file which is sealed.
+    pub(super) fn save(&self, file_number: u64) -> Result<()> {
+        let bytes = encode(
+            self.files
+                .write()
+                .unwrap()
+                .entry(file_number)
+                .or_insert_with(|| FileFilter::new(self.bits_per_key)),
+        );
+        let mut file = self.storage.create(&self.file_path(file_number))?;
+        file.write_all(&bytes)?;
+        file.sync()?;
+        Ok(())
+    }
+
+    /// Drop the filters of the data files before `file_number` and delete their files.
+    pub(super) fn remove_before(&self, file_number: u64) -> Result<()> {
+        let removed: Vec<u64> = {
+            let mut files = self.files.write().unwrap();
+            let kept = files.split_off(&file_number);
+            std::mem::replace(&mut *files, kept).into_keys().collect()
+        };
+        for number in removed {
+            remove_filter_file(self.storage.as_ref(), &self.file_path(number))?;
+        }
+        Ok(())
+    }
+
+    /// Return the reads which the filters answered and those they let through in vain.
+    pub(super) fn stats(&self) -> (u64, u64) {
+        (
+            self.skips.load(Ordering::SeqCst),
+            self.false_positives.load(Ordering::SeqCst),
+        )
+    }
+
+    fn file_path(&self, file_number: u64) -> PathBuf {
+        self.dir_path.join(format!("bloom_{}.dat", file_number))
+    }
+}
+
+impl FileFilter {
+    fn new(bits_per_key: u32) -> FileFilter {
+        FileFilter {
+            layers: Vec::new(),
+            bits_per_key,
+        }
+    }
+
+    fn insert(&mut self, key: &[u8]) {
+        let full = self
+            .layers
+            .last()
+            .map_or(true, |(_, capacity, keys)| keys >= capacity);
+        if full {
+            let level = self.layers.len();
+            let capacity = FIRST_CAPACITY << level.min(20);
+            let bits_per_key = self.bits_per_key + (level as u32 * 3 + 1) / 2;
+            self.layers
+                .push((BloomFilter::new(capacity, bits_per_key), capacity, 0));
+        }
+        let (filter, _, keys) = self.layers.last_mut().unwrap();
+        filter.insert(key);
+        *keys += 1;
+    }
+
+    fn may_contain(&self, key: &[u8]) -> bool {
+        self.layers
+            .iter()
+            .any(|(filter, _, _)| filter.may_contain(key))
+    }
+}
+
+/// The namespace id as 8 big-endian bytes, then the key.
+fn filter_key(namespace_id: u64, key: &str) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(8 + key.len());
+    bytes.extend_from_slice(&namespace_id.to_be_bytes());
+    bytes.extend_from_slice(key.as_bytes());
+    bytes
+}
+
+/** The magic bytes, the bits per key and the number of filters as u32, then for every
+filter the keys it was sized for and holds and the length of its bytes as u64 and its
+bytes, then the crc32 of everything before it.
+ */
+fn encode(filter: &FileFilter) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&filter.bits_per_key.to_le_bytes());
+    bytes.extend_from_slice(&(filter.layers.len() as u32).to_le_bytes());
+    for (layer, capacity, keys) in &filter.layers {
+        let layer = layer.to_bytes();
+        for value in [*capacity as u64, *keys as u64, layer.len() as u64] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(&layer);
+    }
+    let crc = crc32fast::hash(&bytes);
+    bytes.extend_from_slice(&crc.to_le_bytes());
+    bytes
+}
+
+/// Return None for a damaged file.
+fn decode(bytes: &[u8]) -> Option<FileFilter> {
+    let (body, crc) = bytes.split_at(bytes.len().checked_sub(4)?);
+    if !body.starts_with(MAGIC) || crc32fast::hash(body).to_le_bytes() != crc {
+        return None;
+    }
+    let mut rest = &body[MAGIC.len()..];
+    let bits_per_key = read_u32(&mut rest)?;
+    let count = read_u32(&mut rest)?;
+    let mut layers = Vec::with_capacity(count.min(64) as usize);
+    for _ in 0..count {
+        let capacity = read_u64(&mut rest)? as usize;
+        let keys = read_u64(&mut rest)? as usize;
+        let length = read_u64(&mut rest)? as usize;
+        let layer = rest.get(..length)?;
+        rest = &rest[length..];
+        layers.push((BloomFilter::from_bytes(layer).ok()?, capacity, keys));
+    }
+    Some(FileFilter {
+        layers,
+        bits_per_key,
+    })
+}
+
+fn read_u32(bytes: &mut &[u8]) -> Option<u32> {
+    let value = bytes.get(..4)?;
+    let value = u32::from_le_bytes([value[0], value[1], value[2], value[3]]);
+    *bytes = &bytes[4..];
+    Some(value)
+}
+
+fn read_u64(bytes: &mut &[u8]) -> Option<u64> {
+    let mut value = [0; 8];
+    value.copy_from_slice(bytes.get(..8)?);
+    *bytes = &bytes[8..];
+    Some(u64::from_le_bytes(value))
+}
+
+fn exists(storage: &dyn Storage, path: &Path) -> Result<bool> {
+    match storage.open(path) {
+        Ok(_) => Ok(true),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn remove_filter_file(storage: &dyn Storage, path: &Path) -> Result<()> {
+    match storage.remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
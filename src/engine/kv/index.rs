@@ -0,0 +1,95 @@
+use super::CommandPosition;
+use crate::Result;
+use dashmap::DashMap;
+
+/// The position in the log up to which the commands have been applied to an index.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(super) struct Checkpoint {
+    pub(super) file_number: u64,
+    pub(super) offset: u64,
+    /// useless bytes of the data files at this position
+    pub(super) useless_size: u64,
+}
+
+/// Maps every key to the position of its live command.
+/// Only the writer and the recovery of a KvStore change an index.
+pub(super) trait Index: Send + Sync {
+    fn get(&self, key: &str) -> Result<Option<CommandPosition>>;
+
+    /// Return the replaced position.
+    fn insert(&self, key: String, position: CommandPosition) -> Result<Option<CommandPosition>>;
+
+    /// Return the removed position.
+    fn remove(&self, key: &str) -> Result<Option<CommandPosition>>;
+
+    fn len(&self) -> u64;
+
+    /// Call `f` with every key and position.
+    fn for_each(&self, f: &mut dyn FnMut(&str, &CommandPosition) -> Result<()>) -> Result<()>;
+
+    /// Replace the position of every key by the one returned by `f`. Then `finish` is called
+    /// before the new positions are used, and returns up to where the commands are applied.
+    fn rewrite(
+        &self,
+        f: &mut dyn FnMut(&str, &CommandPosition) -> Result<CommandPosition>,
+        finish: &mut dyn FnMut() -> Result<Checkpoint>,
+    ) -> Result<()>;
+
+    /// Called after the commands up to `checkpoint` are applied. An index which is
+    /// persisted may save itself, after `sync` made the commands up to it durable.
+    fn applied(&self, checkpoint: Checkpoint, sync: &mut dyn FnMut() -> Result<()>) -> Result<()>;
+
+    /// Where to start replaying the log, None to replay every data file.
+    fn recovery_start(&self) -> Option<Checkpoint>;
+}
+
+/// The in-memory index, which is rebuilt from every data file on open.
+impl Index for DashMap<String, CommandPosition> {
+    fn get(&self, key: &str) -> Result<Option<CommandPosition>> {
+        Ok(DashMap::get(self, key).map(|entry| *entry.value()))
+    }
+
+    fn insert(&self, key: String, position: CommandPosition) -> Result<Option<CommandPosition>> {
+        Ok(DashMap::insert(self, key, position))
+    }
+
+    fn remove(&self, key: &str) -> Result<Option<CommandPosition>> {
+        Ok(DashMap::remove(self, key).map(|(_, position)| position))
+    }
+
+    fn len(&self) -> u64 {
+        DashMap::len(self) as u64
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(&str, &CommandPosition) -> Result<()>) -> Result<()> {
+        for entry in self.iter() {
+            f(entry.key(), entry.value())?;
+        }
+        Ok(())
+    }
+
+    fn rewrite(
+        &self,
+        f: &mut dyn FnMut(&str, &CommandPosition) -> Result<CommandPosition>,
+        finish: &mut dyn FnMut() -> Result<Checkpoint>,
+    ) -> Result<()> {
+        for mut entry in self.iter_mut() {
+            let (key, position) = entry.pair_mut();
+            *position = f(key, position)?;
+        }
+        finish()?;
+        Ok(())
+    }
+
+    fn applied(
+        &self,
+        _checkpoint: Checkpoint,
+        _sync: &mut dyn FnMut() -> Result<()>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn recovery_start(&self) -> Option<Checkpoint> {
+        None
+    }
+}
@@ -0,0 +1,1528 @@
+use self::blob::BlobCollection;
+use self::blob::{blob_file_path, blob_files_size, read_blob, sorted_blob_numbers};
+use self::cache::ValueCache;
+use self::compaction::{compaction_file_path, remove_compaction_files, Compaction, RateLimiter};
+use self::disk_index::DiskIndex;
+use self::filter::KeyFilters;
+use self::index::{Checkpoint, Index};
+use self::namespace::{Keyspace, NamespaceFile};
+use self::record::{
+    data_file_path, decode_command, encode_command, is_garbage, is_torn, sorted_file_numbers,
+    write_file_header, CommandIter, RecordPlace, FILE_HEADER_SIZE,
+};
+use self::sealed::SealedFiles;
+use self::storage::DISK_FULL_ERRORS;
+use super::check_namespace_name;
+use crate::{
+    BlobPointer, Command, DataFileStats, EngineStats, KVStoreError, KvsEngine, Result, SizeLimits,
+};
+use dashmap::DashMap;
+use log::{info, warn};
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+mod blob;
+mod cache;
+mod compaction;
+mod disk_index;
+mod dump;
+mod encryption;
+mod filter;
+mod index;
+mod namespace;
+mod options;
+mod record;
+mod repair;
+mod sealed;
+mod sharded;
+mod storage;
+mod upgrade;
+mod verify;
+
+pub use self::compaction::{CompactionOptions, CompactionWindow};
+pub use self::dump::{dump, dump_encrypted, RecordInfo};
+pub use self::encryption::Encryption;
+pub use self::options::{Compression, IndexMode, KvStoreOptions};
+pub use self::record::FORMAT_VERSION;
+pub use self::repair::{repair, repair_encrypted, FileRepair, RepairReport};
+pub use self::sharded::ShardedKvStore;
+pub use self::storage::{DiskStorage, Faults, FileMap, MemoryStorage, Storage, StorageFile};
+pub use self::upgrade::{upgrade, upgrade_encrypted, upgrade_with_storage, UpgradeReport};
+pub use self::verify::{verify, verify_encrypted, CorruptRange, FileReport, VerifyReport};
+
+const MAX_USELESS_SIZE: u64 = 1024 * 1024;
+// free disk space kept besides the live records a compaction copies
+const MIN_FREE_SPACE: u64 = 1024 * 1024;
+
+/** A KvStore stores key/value pairs using BitCask.
+# Example
+```
+use std::env;
+use kvs::{KvStore, Result};
+use crate::kvs::KvsEngine;
+# fn try_main() -> Result<()> {
+
+let mut store = KvStore::open(env::current_dir()?)?;
+
+store.set("1".to_owned(),"1".to_owned())?;
+assert_eq!(store.get("1".to_owned())?, Some("1".to_owned()));
+
+store.remove("1".to_owned())?;
+assert_eq!(store.get("1".to_owned())?, None);
+# Ok(())
+# }
+```
+ */
+#[derive(Clone)]
+pub struct KvStore {
+    // the namespace of this handle
+    keyspace: Arc<Keyspace>,
+    writer: Arc<Mutex<Writer>>,
+    readers: Reader,
+    cache: Option<Arc<ValueCache>>,
+    filters: Option<Arc<KeyFilters>>,
+    compaction_paused: Arc<AtomicBool>,
+    size_limits: SizeLimits,
+}
+
+impl KvStore {
+    /// Open the KvStore at a given path. Return the KvStore.
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        Self::open_with_options(path, KvStoreOptions::default())
+    }
+
+    /// Open the KvStore at a given path with the given options. Return the KvStore.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        let dir_path = Arc::new(path.into());
+        if let (IndexMode::Disk { .. }, Some(_)) = (&options.index, &options.encryption) {
+            return Err(KVStoreError::CommonStringError(
+                "the disk index keeps the keys in plaintext, it can not be used with encryption"
+                    .to_owned(),
+            ));
+        }
+        let storage = options.storage();
+        storage.create_dir_all(dir_path.as_path())?;
+        remove_compaction_files(storage.as_ref(), &dir_path)?;
+
+        if let IndexMode::Memory = options.index {
+            DiskIndex::remove_stale(storage.as_ref(), &dir_path)?;
+        }
+        KeyFilters::remove_stale(
+            storage.as_ref(),
+            &dir_path,
+            options.bloom_false_positive_rate.is_some(),
+        )?;
+        let filters = match options.bloom_false_positive_rate {
+            Some(rate) => Some(Arc::new(KeyFilters::new(
+                Arc::clone(&storage),
+                Arc::clone(&dir_path),
+                rate,
+            )?)),
+            None => None,
+        };
+        let encryption = options.encryption.map(Arc::new);
+        let namespaces = NamespaceFile::load(storage.as_ref(), &dir_path, encryption.as_deref())?;
+        // the names of a store which is encrypted from now on, or with a new key
+        if encryption.is_some() && namespaces.iter().next().is_some() {
+            namespaces.save(storage.as_ref(), &dir_path, encryption.as_deref())?;
+        }
+        let mut keyspaces = BTreeMap::new();
+        keyspaces.insert(
+            0,
+            Arc::new(Keyspace::new(
+                0,
+                None,
+                open_index(&storage, &dir_path, &options.index, 0)?,
+            )),
+        );
+        for (name, id) in namespaces.iter() {
+            let index = open_index(&storage, &dir_path, &options.index, id)?;
+            keyspaces.insert(id, Arc::new(Keyspace::new(id, Some(name), index)));
+        }
+        let cache = match options.value_cache_size {
+            0 => None,
+            size => Some(Arc::new(ValueCache::new(size))),
+        };
+        let (current_file_number, useless_size) = Self::recover(
+            storage.as_ref(),
+            &dir_path,
+            &keyspaces,
+            filters.as_deref(),
+            encryption.as_deref(),
+        )?;
+
+        let current_file_path = data_file_path(&dir_path, current_file_number);
+
+        let current_writer = open_data_file(storage.as_ref(), &current_file_path)?;
+        let data_size = data_files_size(storage.as_ref(), &dir_path)?;
+
+        // the sealed files are read through memory maps
+        let mut readers = HashMap::new();
+        readers.insert(
+            current_file_number,
+            BufReader::new(storage.open(&current_file_path)?),
+        );
+
+        let readers = Reader {
+            storage: Arc::clone(&storage),
+            dir_path: Arc::clone(&dir_path),
+            compaction_number: Arc::new(AtomicU64::new(0)),
+            open_readers: Arc::new(AtomicU64::new(readers.len() as u64)),
+            readers: RefCell::new(readers),
+            encryption,
+            sealed: Arc::new(SealedFiles::new(
+                storage,
+                Arc::clone(&dir_path),
+                current_file_number,
+            )),
+        };
+
+        let blob_numbers = sorted_blob_numbers(readers.storage.as_ref(), &dir_path)?;
+        let blob_size = blob_files_size(readers.storage.as_ref(), &dir_path)?;
+        // the blobs no command points at are garbage, counted again since nothing keeps it
+        let blob_garbage = match blob_numbers.len() as u64 {
+            0 => 0,
+            files => blob_size
+                .saturating_sub(live_blob_size(&readers, &keyspaces)? + files * FILE_HEADER_SIZE),
+        };
+
+        let keyspace = Arc::clone(&keyspaces[&0]);
+        let compaction_paused = Arc::new(AtomicBool::new(false));
+        let writer = Arc::new(Mutex::new(Writer {
+            current_writer,
+            current_file_number,
+            useless_size,
+            dir_path,
+            namespaces,
+            keyspaces,
+            index_mode: options.index,
+            cache: cache.clone(),
+            filters: filters.clone(),
+            compression: options.compression,
+            sync_writes: options.sync_writes,
+            compaction: options.compaction,
+            compaction_paused: Arc::clone(&compaction_paused),
+            compaction_refused: false,
+            max_size: options.max_size,
+            data_size,
+            disk_full: false,
+            read_only: false,
+            compacting: false,
+            blob_threshold: options.blob_threshold,
+            blob_writer: None,
+            blob_file_number: blob_numbers.last().copied().unwrap_or(1),
+            blob_size,
+            blob_garbage,
+            blob_collections: 0,
+            reader: readers.clone(),
+            compactions: 0,
+            compaction_time: Duration::default(),
+        }));
+
+        Ok(KvStore {
+            keyspace,
+            readers,
+            writer,
+            cache,
+            filters,
+            compaction_paused,
+            size_limits: options.size_limits,
+        })
+    }
+
+    fn recover(
+        storage: &dyn Storage,
+        dir_path: &Arc<PathBuf>,
+        keyspaces: &BTreeMap<u64, Arc<Keyspace>>,
+        filters: Option<&KeyFilters>,
+        encryption: Option<&Encryption>,
+    ) -> Result<(u64, u64)> {
+        let versions = sorted_file_numbers(storage, dir_path)?;
+        // a persisted index is up to date until its checkpoint, so the log is replayed from
+        // the oldest checkpoint and every index skips the commands before its own
+        let starts: HashMap<u64, Checkpoint> = keyspaces
+            .iter()
+            .map(|(id, keyspace)| (*id, keyspace.index.recovery_start().unwrap_or_default()))
+            .collect();
+        let start = starts
+            .values()
+            .min_by_key(|start| (start.file_number, start.offset))
+            .copied()
+            .unwrap_or_default();
+
+        let mut useless_size = start.useless_size;
+        for version in &versions {
+            let is_last = Some(version) == versions.last();
+            // the filters of the newest file are built again, since it is still appended to
+            let filters = match filters {
+                Some(filters) if is_last || !filters.load(*version)? => Some(filters),
+                _ => None,
+            };
+            if *version < start.file_number {
+                if let Some(filters) = filters {
+                    filters.build(*version, encryption)?;
+                    filters.save(*version)?;
+                }
+                continue;
+            }
+            let offset = if *version == start.file_number {
+                start.offset
+            } else {
+                0
+            };
+            // the commands before the offset are not replayed
+            let replayed_filters = match filters {
+                Some(filters) if offset > 0 => {
+                    filters.build(*version, encryption)?;
+                    None
+                }
+                filters => filters,
+            };
+            let mut iter =
+                match CommandIter::open_at(storage, dir_path, *version, offset, encryption) {
+                    Ok(iter) => iter,
+                    Err(err) => {
+                        let path = data_file_path(dir_path, *version);
+                        // a crash while the header of the newest file was written
+                        if is_last && storage.open(&path)?.size()? < FILE_HEADER_SIZE {
+                            truncate_torn_write(storage, &path, 0)?;
+                            continue;
+                        }
+                        return Err(err);
+                    }
+                };
+            while let Some(record) = iter.next() {
+                let (position, command) = match record {
+                    Ok(record) => record,
+                    Err(err) => {
+                        let end = iter.offset();
+                        // a crash while the newest file was appended to leaves a torn write
+                        // behind every command, which the writer must not append after, or
+                        // garbage where the end of the write did not reach the disk
+                        if is_last
+                            && (is_torn(&err) || is_garbage(&err))
+                            && iter.resync()?.is_none()
+                        {
+                            truncate_torn_write(storage, &data_file_path(dir_path, *version), end)?;
+                            break;
+                        }
+                        return Err(err);
+                    }
+                };
+                let end = position.offset + position.length;
+                if let Some(filters) = replayed_filters {
+                    filters.insert_command(*version, &command);
+                }
+                let keyspace = match keyspaces.get(&command.namespace_id()) {
+                    Some(keyspace) => keyspace,
+                    // the commands of a dropped namespace
+                    None => {
+                        useless_size += position.length;
+                        continue;
+                    }
+                };
+                let keyspace_start = &starts[&keyspace.id];
+                if (*version, position.offset) < (keyspace_start.file_number, keyspace_start.offset)
+                {
+                    continue;
+                }
+                useless_size += keyspace.apply(position, command)?;
+                // the replayed commands are those which reached the disk
+                keyspace.index.applied(
+                    Checkpoint {
+                        file_number: *version,
+                        offset: end,
+                        useless_size,
+                    },
+                    &mut || Ok(()),
+                )?;
+            }
+            if let (Some(filters), false) = (filters, is_last) {
+                filters.save(*version)?;
+            }
+        }
+
+        Ok((*versions.last().unwrap_or(&0), useless_size))
+    }
+
+    /** Compact or collect the blob garbage once it is due. The writer is only locked to
+    start and to finish them, so that the other writes go on while the live data is
+    copied, however slow the rate limit makes that.
+     */
+    fn compact_if_due(&self) -> Result<()> {
+        let (compaction_due, collection_due) = self.writer.lock().unwrap().compactions_due();
+        let mut result = Ok(());
+        if compaction_due {
+            result = self.run_compaction();
+        }
+        if collection_due && result.is_ok() {
+            result = self.run_blob_collection();
+        }
+        match result {
+            Err(KVStoreError::InsufficientSpace(needed, available)) => {
+                let mut writer = self.writer.lock().unwrap();
+                if !writer.compaction_refused {
+                    warn!(
+                        "Compaction waits for {} bytes of free disk space, {} are available",
+                        needed, available
+                    );
+                }
+                writer.compaction_refused = true;
+                Ok(())
+            }
+            // another write started one first
+            Err(KVStoreError::CompactionRunning) => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Compact at the rate limit, with the writer unlocked while the live commands are copied.
+    fn run_compaction(&self) -> Result<()> {
+        let mut compaction = {
+            let mut writer = self.writer.lock().unwrap();
+            let rate_limit = writer.compaction.rate_limit;
+            writer.start_compaction(rate_limit)?
+        };
+        let copied = compaction.copy();
+        self.writer
+            .lock()
+            .unwrap()
+            .finish_compaction(compaction, copied)
+    }
+
+    /// Collect the blob garbage at the rate limit, with the writer unlocked while the live
+    /// blobs are copied.
+    fn run_blob_collection(&self) -> Result<()> {
+        let mut collection = {
+            let mut writer = self.writer.lock().unwrap();
+            let rate_limit = writer.compaction.rate_limit;
+            writer.start_blob_collection(rate_limit)?
+        };
+        let copied = collection.copy();
+        self.writer
+            .lock()
+            .unwrap()
+            .finish_blob_collection(collection, copied)
+    }
+
+    /// Make the writes which returned durable.
+    pub fn sync(&self) -> Result<()> {
+        self.writer.lock().unwrap().sync()
+    }
+}
+
+/// Return the share of the reads let through by the bloom filters which found no key
+/// among the reads of missing keys, None before any.
+fn observed_false_positive_rate(skips: u64, false_positives: u64) -> Option<f64> {
+    match skips + false_positives {
+        0 => None,
+        misses => Some(false_positives as f64 / misses as f64),
+    }
+}
+
+fn truncate_torn_write(storage: &dyn Storage, path: &Path, end: u64) -> Result<()> {
+    warn!("Truncating the torn write at offset {} of {:?}", end, path);
+    let mut file = storage.append(path)?;
+    file.set_len(end)?;
+    file.sync()?;
+    Ok(())
+}
+
+/// Open the index of a namespace.
+fn open_index(
+    storage: &Arc<dyn Storage>,
+    dir_path: &Path,
+    mode: &IndexMode,
+    namespace_id: u64,
+) -> Result<Arc<dyn Index>> {
+    Ok(match *mode {
+        IndexMode::Memory => Arc::new(DashMap::new()),
+        IndexMode::Disk {
+            cache_pages,
+            max_buffered_keys,
+        } => Arc::new(DiskIndex::open(
+            storage,
+            dir_path,
+            namespace_id,
+            cache_pages,
+            max_buffered_keys,
+        )?),
+    })
+}
+
+impl KvsEngine for KvStore {
+    /// Set the value of a string key to a string. Return an error if the value is not written successfully.
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.size_limits.check(&key, &value)?;
+        self.writer
+            .lock()
+            .unwrap()
+            .set(&self.keyspace, key, value)?;
+        self.compact_if_due()
+    }
+
+    /// Get the string value of a string key. If the key does not exist, return None. Return an error if the value is not read successfully.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.keyspace.check()?;
+        let namespace_id = self.keyspace.id;
+        if let Some(filters) = &self.filters {
+            if !filters.may_contain(namespace_id, &key) {
+                return Ok(None);
+            }
+        }
+        loop {
+            let position = match self.keyspace.index.get(&key)? {
+                Some(position) => position,
+                None => {
+                    if let Some(filters) = &self.filters {
+                        filters.false_positive();
+                    }
+                    return Ok(None);
+                }
+            };
+            if let Some(value) = self
+                .cache
+                .as_ref()
+                .and_then(|c| c.get(namespace_id, &key, &position))
+            {
+                return Ok(Some(value));
+            }
+            match self.readers.read_command(&position) {
+                Ok(Some(value)) => {
+                    if let Some(cache) = &self.cache {
+                        cache.insert(namespace_id, key, position, value.clone());
+                    }
+                    return Ok(Some(value));
+                }
+                // a compaction removed the file after the position was read, or a blob
+                // garbage collection moved the blob
+                Err(KVStoreError::Io(ref err))
+                    if err.kind() == io::ErrorKind::NotFound
+                        && (position.file_number
+                            < self.readers.compaction_number.load(Ordering::SeqCst)
+                            || self.keyspace.index.get(&key)? != Some(position)) => {}
+                result => return result,
+            }
+        }
+    }
+
+    /// Remove a given key. Return an error if the key does not exist or is not removed successfully.
+    fn remove(&self, key: String) -> Result<()> {
+        self.writer.lock().unwrap().remove(&self.keyspace, key)?;
+        self.compact_if_due()
+    }
+
+    /// Call `f` with every key/value pair in the store.
+    fn scan<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        self.keyspace.check()?;
+        self.keyspace.index.for_each(&mut |key, position| {
+            if let Some(value) = self.readers.read_command(position)? {
+                f(key.to_owned(), value)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Return the statistics of the store.
+    fn stats(&self) -> Result<EngineStats> {
+        // hold the writer so that no compaction deletes files in the meantime
+        let writer = self.writer.lock().unwrap();
+        let mut data_files = Vec::new();
+        let storage = self.readers.storage.as_ref();
+        for file_number in sorted_file_numbers(storage, &writer.dir_path)? {
+            data_files.push(DataFileStats {
+                file_number,
+                size: storage
+                    .open(&data_file_path(&writer.dir_path, file_number))?
+                    .size()?,
+            });
+        }
+        let bloom_stats = self.filters.as_ref().map(|filters| filters.stats());
+        Ok(EngineStats {
+            engine: "kvs".to_owned(),
+            keys: self.keyspace.index.len(),
+            disk_size: data_files.iter().map(|file| file.size).sum::<u64>() + writer.blob_size,
+            data_files,
+            garbage_bytes: Some(writer.useless_size),
+            compactions: Some(writer.compactions),
+            compaction_millis: Some(writer.compaction_time.as_millis() as u64),
+            compaction_paused: Some(self.compaction_paused.load(Ordering::SeqCst)),
+            read_only: Some(writer.read_only),
+            open_readers: Some(self.readers.open_readers.load(Ordering::SeqCst)),
+            mapped_files: Some(self.readers.sealed.mapped_files()),
+            cache_hits: self.cache.as_ref().map(|cache| cache.hits()),
+            cache_misses: self.cache.as_ref().map(|cache| cache.misses()),
+            bloom_skips: bloom_stats.map(|(skips, _)| skips),
+            bloom_false_positives: bloom_stats.map(|(_, false_positives)| false_positives),
+            bloom_false_positive_rate: bloom_stats.and_then(|(skips, false_positives)| {
+                observed_false_positive_rate(skips, false_positives)
+            }),
+            blob_bytes: Some(writer.blob_size),
+            blob_garbage_bytes: Some(writer.blob_garbage),
+            blob_collections: Some(writer.blob_collections),
+        })
+    }
+
+    /// Return a handle to the index of the namespace.
+    fn namespace(&self, name: &str) -> Result<KvStore> {
+        let writer = self.writer.lock().unwrap();
+        let keyspace = writer
+            .namespaces
+            .id(name)
+            .and_then(|id| writer.keyspaces.get(&id))
+            .ok_or_else(|| KVStoreError::NamespaceNotFound(name.to_owned()))?;
+        Ok(KvStore {
+            keyspace: Arc::clone(keyspace),
+            writer: Arc::clone(&self.writer),
+            readers: self.readers.clone(),
+            cache: self.cache.clone(),
+            filters: self.filters.clone(),
+            compaction_paused: Arc::clone(&self.compaction_paused),
+            size_limits: self.size_limits,
+        })
+    }
+
+    /// Create a namespace with an empty index.
+    fn create_namespace(&self, name: &str) -> Result<()> {
+        check_namespace_name(name)?;
+        self.writer.lock().unwrap().create_namespace(name)
+    }
+
+    /// Drop a namespace. Its commands stay in the data files until the next compaction.
+    fn drop_namespace(&self, name: &str) -> Result<()> {
+        self.writer.lock().unwrap().drop_namespace(name)?;
+        self.compact_if_due()
+    }
+
+    /// Return the names of the namespaces.
+    fn list_namespaces(&self) -> Result<Vec<String>> {
+        let writer = self.writer.lock().unwrap();
+        Ok(writer
+            .namespaces
+            .iter()
+            .map(|(name, _)| name.to_owned())
+            .collect())
+    }
+
+    /// Compact the data files and collect the blob garbage now, whether compaction is
+    /// paused or outside its windows. Reads and writes go on while the live data is copied.
+    fn compact(&self) -> Result<()> {
+        self.run_compaction()?;
+        if self.writer.lock().unwrap().blob_garbage > 0 {
+            self.run_blob_collection()?;
+        }
+        Ok(())
+    }
+
+    /// Pause or resume the compactions which start by themselves. A running compaction
+    /// finishes first.
+    fn set_compaction_paused(&self, paused: bool) -> Result<()> {
+        self.compaction_paused.store(paused, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+struct Reader {
+    storage: Arc<dyn Storage>,
+    dir_path: Arc<PathBuf>,
+    compaction_number: Arc<AtomicU64>,
+    // number of open readers of all clones
+    open_readers: Arc<AtomicU64>,
+    readers: RefCell<HashMap<u64, BufReader<Box<dyn StorageFile>>>>,
+    sealed: Arc<SealedFiles>,
+    encryption: Option<Arc<Encryption>>,
+}
+
+impl Clone for Reader {
+    fn clone(&self) -> Self {
+        Reader {
+            storage: Arc::clone(&self.storage),
+            dir_path: Arc::clone(&self.dir_path),
+            compaction_number: Arc::clone(&self.compaction_number),
+            open_readers: Arc::clone(&self.open_readers),
+            readers: RefCell::new(HashMap::new()),
+            sealed: Arc::clone(&self.sealed),
+            encryption: self.encryption.clone(),
+        }
+    }
+}
+
+impl Drop for Reader {
+    fn drop(&mut self) {
+        self.open_readers
+            .fetch_sub(self.readers.borrow().len() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Reader {
+    fn try_to_remove_stale_readers(&self) {
+        let compaction_number = self.compaction_number.load(Ordering::SeqCst);
+        let mut readers = self.readers.borrow_mut();
+        while !readers.is_empty() {
+            let reader_number = *readers.keys().next().unwrap();
+            if compaction_number <= reader_number {
+                break;
+            }
+            readers.remove(&reader_number);
+            self.open_readers.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    fn read_add<F, R>(&self, position: &CommandPosition, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut dyn Read) -> Result<R>,
+    {
+        if self.sealed.is_sealed(position.file_number) {
+            let map = self
+                .sealed
+                .map(position.file_number, &self.compaction_number)?;
+            return f(&mut SealedFiles::read(&map, position)?);
+        }
+        self.try_to_remove_stale_readers();
+
+        let mut readers = self.readers.borrow_mut();
+
+        if let Entry::Vacant(entry) = readers.entry(position.file_number) {
+            let new_reader = BufReader::new(
+                self.storage
+                    .open(&data_file_path(&self.dir_path, position.file_number))?,
+            );
+            entry.insert(new_reader);
+            self.open_readers.fetch_add(1, Ordering::SeqCst);
+        }
+
+        let source_reader = readers
+            .get_mut(&position.file_number)
+            .expect("Can not find key in files but it is in memory");
+        source_reader.seek(SeekFrom::Start(position.offset))?;
+        f(&mut source_reader.take(position.length))
+    }
+
+    fn read_command(&self, position: &CommandPosition) -> Result<Option<String>> {
+        let place = RecordPlace::Data(position.file_number, position.offset);
+        match decode_command(
+            &self.read_record(position)?,
+            self.encryption.as_deref(),
+            place,
+        )? {
+            Command::SET(_, value) | Command::NSSET(_, _, value) => Ok(Some(value)),
+            Command::BLOBSET(_, pointer) | Command::NSBLOBSET(_, _, pointer) => {
+                Ok(Some(read_blob(
+                    self.storage.as_ref(),
+                    &self.dir_path,
+                    &pointer,
+                    self.encryption.as_deref(),
+                )?))
+            }
+            _ => Err(KVStoreError::UnknownCommandType),
+        }
+    }
+
+    /// Return the blob the command at `position` points at, None for a command which
+    /// keeps its value or for none.
+    fn blob_pointer(&self, position: Option<CommandPosition>) -> Result<Option<BlobPointer>> {
+        let position = match position {
+            Some(position) => position,
+            None => return Ok(None),
+        };
+        let place = RecordPlace::Data(position.file_number, position.offset);
+        match decode_command(
+            &self.read_record(&position)?,
+            self.encryption.as_deref(),
+            place,
+        )? {
+            Command::BLOBSET(_, pointer) | Command::NSBLOBSET(_, _, pointer) => Ok(Some(pointer)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Return the bytes of the blobs the commands of a namespace point at.
+    fn blob_size(&self, keyspace: &Keyspace) -> Result<u64> {
+        let mut size = 0;
+        keyspace.index.for_each(&mut |_, position| {
+            if let Some(pointer) = self.blob_pointer(Some(*position))? {
+                size += pointer.length;
+            }
+            Ok(())
+        })?;
+        Ok(size)
+    }
+
+    fn read_record(&self, position: &CommandPosition) -> Result<Vec<u8>> {
+        self.read_add(position, |data_reader| {
+            let mut record = Vec::with_capacity(position.length as usize);
+            data_reader.read_to_end(&mut record)?;
+            Ok(record)
+        })
+    }
+
+    /** Delete the data files before `file_number`, the oldest first.
+
+    A remove command stays on disk as long as a data file older than its own may still
+    hold the key, since replaying that file without it would bring the key back. So when
+    a file can not be deleted, it is kept with every newer one, and the next compaction
+    tries again.
+     */
+    fn remove_useless_reader(&mut self, file_number: u64) -> Result<()> {
+        let mut readers = self.readers.borrow_mut();
+        let open = readers.len();
+        readers.retain(|number, _| *number >= file_number);
+        self.open_readers
+            .fetch_sub((open - readers.len()) as u64, Ordering::SeqCst);
+        self.sealed.remove_before(file_number);
+
+        for number in sorted_file_numbers(self.storage.as_ref(), &self.dir_path)?
+            .into_iter()
+            .filter(|number| *number < file_number)
+        {
+            let file_path = data_file_path(&self.dir_path, number);
+            match self.storage.remove_file(&file_path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                    warn!(
+                        "can not delete file {:?} because {}, keeping the newer files",
+                        file_path, err
+                    );
+                    break;
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+struct Writer {
+    dir_path: Arc<PathBuf>,
+    reader: Reader,
+    current_writer: BufWriterWithPosition<Box<dyn StorageFile>>,
+    current_file_number: u64,
+    useless_size: u64,
+    namespaces: NamespaceFile,
+    // namespace id -> index of the namespace
+    keyspaces: BTreeMap<u64, Arc<Keyspace>>,
+    index_mode: IndexMode,
+    cache: Option<Arc<ValueCache>>,
+    filters: Option<Arc<KeyFilters>>,
+    compression: Compression,
+    sync_writes: bool,
+    compaction: CompactionOptions,
+    // automatic compactions wait while it is set
+    compaction_paused: Arc<AtomicBool>,
+    // the last automatic compaction did not start for lack of disk space
+    compaction_refused: bool,
+    max_size: Option<u64>,
+    // bytes of all data files
+    data_size: u64,
+    // a write failed on a full disk, and there has not been room since
+    disk_full: bool,
+    // the last write was refused for lack of room
+    read_only: bool,
+    // a compaction or blob garbage collection copies the live data
+    compacting: bool,
+    // values of at least this many bytes go to blob files
+    blob_threshold: Option<usize>,
+    // the newest blob file, opened by the first blob written to it
+    blob_writer: Option<BufWriterWithPosition<Box<dyn StorageFile>>>,
+    blob_file_number: u64,
+    // bytes of all blob files
+    blob_size: u64,
+    // bytes of the blobs no command points at
+    blob_garbage: u64,
+    blob_collections: u64,
+    compactions: u64,
+    compaction_time: Duration,
+}
+
+impl Writer {
+    fn set(&mut self, keyspace: &Keyspace, key: String, value: String) -> Result<()> {
+        keyspace.check()?;
+        // only a store with blob files can replace a blob
+        let old_position = match self.blob_size {
+            0 => None,
+            _ => keyspace.index.get(&key)?,
+        };
+        if let Some(cache) = &self.cache {
+            cache.invalidate(keyspace.id, &key);
+        }
+        let command = match self.blob_threshold {
+            Some(threshold) if value.len() >= threshold => {
+                let pointer = self.write_blob(&keyspace.set_command(key.clone(), value))?;
+                keyspace.blob_command(key, pointer)
+            }
+            _ => keyspace.set_command(key, value),
+        };
+        let position = self.write_command(&command)?;
+        self.useless_size += keyspace.apply(position, command)?;
+        self.applied(keyspace)?;
+        self.add_blob_garbage(old_position)
+    }
+
+    fn remove(&mut self, keyspace: &Keyspace, key: String) -> Result<()> {
+        keyspace.check()?;
+        let old_position = keyspace.index.get(&key)?;
+        if old_position.is_none() {
+            return Err(KVStoreError::KeyNotFound);
+        }
+        if let Some(cache) = &self.cache {
+            cache.invalidate(keyspace.id, &key);
+        }
+        let command = keyspace.remove_command(key);
+        let position = self.write_command(&command)?;
+        self.useless_size += keyspace.apply(position, command)?;
+        self.applied(keyspace)?;
+        if self.blob_size > 0 {
+            self.add_blob_garbage(old_position)?;
+        }
+        Ok(())
+    }
+
+    /// Append a command to the current file and add its key to the filter of the file.
+    /// Return its position.
+    fn write_command(&mut self, command: &Command) -> Result<CommandPosition> {
+        let place = |writer: &Writer| {
+            RecordPlace::Data(
+                writer.current_file_number,
+                writer.current_writer.get_position(),
+            )
+        };
+        let encryption = self.reader.encryption.clone();
+        let mut data_place = place(self);
+        let mut data =
+            encode_command(command, self.compression, encryption.as_deref(), data_place)?;
+        self.make_room(data.len() as u64)?;
+        // an encrypted record is bound to its place, which a compaction making room moves
+        if encryption.is_some() && place(self) != data_place {
+            data_place = place(self);
+            data = encode_command(command, self.compression, encryption.as_deref(), data_place)?;
+        }
+
+        let offset = self.current_writer.get_position();
+        if let Err(err) = append(&mut self.current_writer, &data, self.sync_writes) {
+            // the next command must not follow a torn one
+            let path = data_file_path(&self.dir_path, self.current_file_number);
+            match reopen_at(self.reader.storage.as_ref(), &path, offset) {
+                Ok(writer) => std::mem::replace(&mut self.current_writer, writer).discard(),
+                Err(truncate_err) => {
+                    warn!("can not cut off the failed write because {}", truncate_err)
+                }
+            }
+            return Err(self.write_failed(err));
+        }
+        self.data_size += self.current_writer.get_position() - offset;
+        // the key goes to the filter of the file the command is in, which is only known
+        // once a compaction making room for it is done
+        if let Some(filters) = &self.filters {
+            filters.insert_command(self.current_file_number, command);
+        }
+        Ok(CommandPosition {
+            offset,
+            length: self.current_writer.get_position() - offset,
+            file_number: self.current_file_number,
+        })
+    }
+
+    /// Append the set command of a blob to the newest blob file. Return where it is.
+    fn write_blob(&mut self, command: &Command) -> Result<BlobPointer> {
+        let encryption = self.reader.encryption.clone();
+        // a new blob file starts with its header
+        let place = |writer: &Writer| {
+            RecordPlace::Blob(
+                writer.blob_file_number,
+                writer
+                    .blob_writer
+                    .as_ref()
+                    .map_or(FILE_HEADER_SIZE, |writer| writer.get_position()),
+            )
+        };
+        let mut record_place = place(self);
+        let mut record = encode_command(
+            command,
+            self.compression,
+            encryption.as_deref(),
+            record_place,
+        )?;
+        self.make_room(record.len() as u64)?;
+        let path = blob_file_path(&self.dir_path, self.blob_file_number);
+        if self.blob_writer.is_none() {
+            self.blob_writer = Some(open_data_file(self.reader.storage.as_ref(), &path)?);
+        }
+        if encryption.is_some() && place(self) != record_place {
+            record_place = place(self);
+            record = encode_command(
+                command,
+                self.compression,
+                encryption.as_deref(),
+                record_place,
+            )?;
+        }
+        let writer = self
+            .blob_writer
+            .as_mut()
+            .expect("the blob writer was just opened");
+
+        let offset = writer.get_position();
+        if let Err(err) = append(writer, &record, self.sync_writes) {
+            // what a failed write left in the file is never pointed at, but the buffer
+            // must not be written after the next blob
+            if let Some(writer) = self.blob_writer.take() {
+                writer.discard();
+            }
+            if let Ok(writer) = reopen_at(self.reader.storage.as_ref(), &path, offset) {
+                self.blob_writer = Some(writer);
+            }
+            return Err(self.write_failed(err));
+        }
+        let length = record.len() as u64;
+        self.data_size += length;
+        self.blob_size += length;
+        Ok(BlobPointer {
+            file_number: self.blob_file_number,
+            offset,
+            length,
+        })
+    }
+
+    /// Return the error of a failed write, which leaves the store read-only on a full disk.
+    fn write_failed(&mut self, err: KVStoreError) -> KVStoreError {
+        if is_disk_full(&err) {
+            warn!("The disk is full, refusing writes until there is room");
+            self.disk_full = true;
+            self.read_only = true;
+            return KVStoreError::ReadOnly("the disk is full".to_owned());
+        }
+        err
+    }
+
+    /// Count the blob the replaced command at `old_position` pointed at as garbage.
+    fn add_blob_garbage(&mut self, old_position: Option<CommandPosition>) -> Result<()> {
+        if let Some(pointer) = self.reader.blob_pointer(old_position)? {
+            self.blob_garbage += pointer.length;
+        }
+        Ok(())
+    }
+
+    /// Make the blobs written so far durable.
+    fn sync_blobs(&mut self) -> Result<()> {
+        match &mut self.blob_writer {
+            Some(writer) => writer.sync(),
+            None => Ok(()),
+        }
+    }
+
+    /** Return an error if writing `length` more bytes would pass the size limit, or if
+    the disk was full and still has no room, after compacting if that may help. The
+    store stays read-only until there is room again.
+     */
+    fn make_room(&mut self, length: u64) -> Result<()> {
+        let over_limit = |writer: &Writer| {
+            writer
+                .max_size
+                .map_or(false, |max| writer.data_size + length > max)
+        };
+        if over_limit(self)
+            && self.useless_size > 0
+            && !self.compaction_paused.load(Ordering::SeqCst)
+        {
+            match self.compact() {
+                // a running compaction makes room once it is finished
+                Ok(())
+                | Err(KVStoreError::InsufficientSpace(..) | KVStoreError::CompactionRunning) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        if self.disk_full {
+            let available = self.reader.storage.available_space(&self.dir_path)?;
+            self.disk_full =
+                available.map_or(true, |available| available < length + MIN_FREE_SPACE);
+        }
+        let reason = match self.max_size {
+            Some(max) if over_limit(self) => {
+                format!("the data files reached the size limit of {} bytes", max)
+            }
+            _ if self.disk_full => "the disk is full".to_owned(),
+            _ => {
+                self.read_only = false;
+                return Ok(());
+            }
+        };
+        self.read_only = true;
+        Err(KVStoreError::ReadOnly(reason))
+    }
+
+    fn create_namespace(&mut self, name: &str) -> Result<()> {
+        let storage = &self.reader.storage;
+        let id = self.namespaces.create(
+            storage.as_ref(),
+            &self.dir_path,
+            self.reader.encryption.as_deref(),
+            name,
+        )?;
+        let index = open_index(storage, &self.dir_path, &self.index_mode, id)?;
+        self.keyspaces
+            .insert(id, Arc::new(Keyspace::new(id, Some(name), index)));
+        Ok(())
+    }
+
+    fn drop_namespace(&mut self, name: &str) -> Result<()> {
+        let id = self
+            .namespaces
+            .id(name)
+            .ok_or_else(|| KVStoreError::NamespaceNotFound(name.to_owned()))?;
+        // an index file without namespace is never used again, while a namespace without
+        // index file just replays the log, so the index file goes first
+        let storage = self.reader.storage.as_ref();
+        DiskIndex::remove(storage, &self.dir_path, id)?;
+        self.namespaces.drop(
+            storage,
+            &self.dir_path,
+            self.reader.encryption.as_deref(),
+            name,
+        )?;
+        if let Some(keyspace) = self.keyspaces.remove(&id) {
+            if self.blob_size > 0 {
+                self.blob_garbage += self.reader.blob_size(&keyspace)?;
+            }
+            self.useless_size += keyspace.set_dropped();
+        }
+        Ok(())
+    }
+
+    /** Return whether a compaction and a blob garbage collection are due: once there is
+    enough garbage in the data files, and once the blob garbage is half of the blob files,
+    unless compaction is paused, running or not allowed now.
+     */
+    fn compactions_due(&self) -> (bool, bool) {
+        if self.compacting
+            || self.compaction_paused.load(Ordering::SeqCst)
+            || !self.compaction.allows_now()
+        {
+            return (false, false);
+        }
+        (
+            self.useless_size > MAX_USELESS_SIZE,
+            self.blob_garbage > MAX_USELESS_SIZE && self.blob_garbage * 2 > self.blob_size,
+        )
+    }
+
+    /// Compact with the lock of the writer held, which a write that waits for the room
+    /// compaction makes does. Such a compaction is not slowed down by the rate limit.
+    fn compact(&mut self) -> Result<()> {
+        let mut compaction = self.start_compaction(None)?;
+        let result = compaction.copy();
+        self.finish_compaction(compaction, result)
+    }
+
+    /** Seal the current file and start a compaction of the sealed files. The compaction
+    writes the next data file, while the writer appends to the one after it, so the live
+    commands it copies come before every command written meanwhile.
+     */
+    fn start_compaction(&mut self, rate_limit: Option<u64>) -> Result<Compaction> {
+        if self.compacting {
+            return Err(KVStoreError::CompactionRunning);
+        }
+        // the live records are written before the old files are deleted, so a compaction
+        // which could run out of space halfway does not start
+        let needed = self
+            .data_size
+            .saturating_sub(self.blob_size + self.useless_size)
+            + MIN_FREE_SPACE;
+        if let Some(available) = self.reader.storage.available_space(&self.dir_path)? {
+            if available < needed {
+                return Err(KVStoreError::InsufficientSpace(needed, available));
+            }
+        }
+
+        info!("Compaction starts");
+        let file_number = self.current_file_number + 1;
+        self.create_file(file_number + 1)?;
+        // the file only gets its name once it is complete, so that a crash does not leave
+        // a torn file before the newest one
+        let writer = open_data_file(
+            self.reader.storage.as_ref(),
+            &compaction_file_path(&self.dir_path, file_number),
+        )?;
+        self.compacting = true;
+        Ok(Compaction {
+            file_number,
+            writer,
+            storage: Arc::clone(&self.reader.storage),
+            dir_path: Arc::clone(&self.dir_path),
+            keyspaces: self.keyspaces.clone(),
+            compression: self.compression,
+            encryption: self.reader.encryption.clone(),
+            filters: self.filters.clone(),
+            limiter: RateLimiter::new(rate_limit),
+            moved: HashMap::new(),
+            started: Instant::now(),
+        })
+    }
+
+    /// Point the indexes at the copies of a compaction and delete the compacted files, or
+    /// drop the copies if copying failed.
+    fn finish_compaction(&mut self, compaction: Compaction, copied: Result<()>) -> Result<()> {
+        self.compacting = false;
+        let storage = Arc::clone(&self.reader.storage);
+        let file_number = compaction.file_number;
+        let temp_path = compaction_file_path(&self.dir_path, file_number);
+        let result = copied.and_then(|()| {
+            storage.rename(&temp_path, &data_file_path(&self.dir_path, file_number))?;
+            Ok(())
+        });
+        if let Err(err) = result {
+            compaction.writer.discard();
+            if let Err(remove_err) = storage.remove_file(&temp_path) {
+                warn!("can not delete {:?} because {}", temp_path, remove_err);
+            }
+            return Err(err);
+        }
+        if let Some(filters) = &self.filters {
+            if let Err(err) = filters.save(file_number) {
+                warn!(
+                    "can not save the bloom filter of file {} because {}",
+                    file_number, err
+                );
+            }
+        }
+
+        // what is not live in the compacted file and the newest one is garbage
+        let mut live_size = 0;
+        for keyspace in self.keyspaces.values() {
+            let mut keyspace_size = 0;
+            keyspace.index.for_each(&mut |key, position| {
+                keyspace_size += compaction.position(keyspace.id, key, position).length;
+                Ok(())
+            })?;
+            keyspace.set_live_size(keyspace_size);
+            live_size += keyspace_size;
+        }
+        let useless_size = (compaction.writer.get_position() + self.current_writer.get_position())
+            .saturating_sub(2 * FILE_HEADER_SIZE + live_size);
+        // the old files are deleted once the commands the indexes point at are durable
+        self.current_writer.sync()?;
+        let checkpoint = Checkpoint {
+            file_number: self.current_file_number,
+            offset: self.current_writer.get_position(),
+            useless_size,
+        };
+        for keyspace in self.keyspaces.values() {
+            keyspace.index.rewrite(
+                &mut |key, position| Ok(compaction.position(keyspace.id, key, position)),
+                &mut || Ok(checkpoint),
+            )?;
+        }
+
+        self.reader
+            .compaction_number
+            .store(file_number, Ordering::SeqCst);
+        if let Some(cache) = &self.cache {
+            cache.clear();
+        }
+
+        self.reader.remove_useless_reader(file_number)?;
+        if let Some(filters) = &self.filters {
+            filters.remove_before(file_number)?;
+        }
+
+        self.useless_size = useless_size;
+        self.data_size = data_files_size(storage.as_ref(), &self.dir_path)?;
+        self.compaction_refused = false;
+        self.disk_full = false;
+
+        self.compactions += 1;
+        self.compaction_time += compaction.started.elapsed();
+        info!(
+            "Compaction finished, cost {:?}",
+            compaction.started.elapsed()
+        );
+        Ok(())
+    }
+
+    /** Start a blob garbage collection, which copies the live blobs into a new blob file.
+    The blobs written meanwhile go to the blob file after it.
+     */
+    fn start_blob_collection(&mut self, rate_limit: Option<u64>) -> Result<BlobCollection> {
+        if self.compacting {
+            return Err(KVStoreError::CompactionRunning);
+        }
+        let needed = self.blob_size.saturating_sub(self.blob_garbage) + MIN_FREE_SPACE;
+        if let Some(available) = self.reader.storage.available_space(&self.dir_path)? {
+            if available < needed {
+                return Err(KVStoreError::InsufficientSpace(needed, available));
+            }
+        }
+
+        info!("Blob collection starts");
+        self.sync_blobs()?;
+        self.blob_writer = None;
+        let storage = self.reader.storage.as_ref();
+        let mut old_files = Vec::new();
+        for number in sorted_blob_numbers(storage, &self.dir_path)? {
+            let size = storage
+                .open(&blob_file_path(&self.dir_path, number))?
+                .size()?;
+            old_files.push((number, size));
+        }
+        let file_number = self.blob_file_number + 1;
+        let writer = open_data_file(storage, &blob_file_path(&self.dir_path, file_number))?;
+        self.blob_file_number = file_number + 1;
+        self.compacting = true;
+        Ok(BlobCollection {
+            file_number,
+            writer,
+            old_files,
+            reader: self.reader.clone(),
+            keyspaces: self.keyspaces.clone(),
+            compression: self.compression,
+            limiter: RateLimiter::new(rate_limit),
+            moved: Vec::new(),
+            started: Instant::now(),
+        })
+    }
+
+    /** Point the keys whose blobs were copied at the copies and delete the old blob files,
+    or delete the copies if copying failed. The commands which pointed at the old blobs
+    become garbage of the data files.
+     */
+    fn finish_blob_collection(
+        &mut self,
+        collection: BlobCollection,
+        copied: Result<()>,
+    ) -> Result<()> {
+        self.compacting = false;
+        let storage = Arc::clone(&self.reader.storage);
+        if let Err(err) = copied {
+            collection.writer.discard();
+            let path = blob_file_path(&self.dir_path, collection.file_number);
+            if let Err(remove_err) = storage.remove_file(&path) {
+                warn!("can not delete blob file {:?} because {}", path, remove_err);
+            }
+            self.blob_size = blob_files_size(storage.as_ref(), &self.dir_path)?;
+            return Err(err);
+        }
+
+        // old blob file -> bytes of its blobs which are still live, and the bytes of the
+        // copies which are not
+        let mut live_sizes: HashMap<u64, u64> = HashMap::new();
+        let mut copied_garbage = 0;
+        for (keyspace, key, position, blob, copy) in collection.moved {
+            if keyspace.check().is_err() || keyspace.index.get(&key)? != Some(position) {
+                copied_garbage += copy.length;
+                continue;
+            }
+            let command = keyspace.blob_command(key, copy);
+            let position = self.write_command(&command)?;
+            self.useless_size += keyspace.apply(position, command)?;
+            self.applied(&keyspace)?;
+            *live_sizes.entry(blob.file_number).or_default() += blob.length;
+        }
+        if let Some(cache) = &self.cache {
+            cache.clear();
+        }
+
+        // the old files are deleted once the commands which point at the copies are durable
+        self.sync_blobs()?;
+        self.current_writer.sync()?;
+        let mut removed_garbage = 0;
+        for (number, size) in &collection.old_files {
+            let path = blob_file_path(&self.dir_path, *number);
+            let live_size = live_sizes.get(number).copied().unwrap_or(0);
+            match storage.remove_file(&path) {
+                // every byte of the file which was not copied was garbage
+                Ok(()) => removed_garbage += size.saturating_sub(live_size + FILE_HEADER_SIZE),
+                // the file holds nothing but garbage now
+                Err(err) => {
+                    warn!("can not delete blob file {:?} because {}", path, err);
+                    copied_garbage += live_size;
+                }
+            }
+        }
+        self.blob_garbage = self.blob_garbage.saturating_sub(removed_garbage) + copied_garbage;
+        self.blob_size = blob_files_size(storage.as_ref(), &self.dir_path)?;
+        self.data_size = data_files_size(storage.as_ref(), &self.dir_path)?;
+        self.blob_collections += 1;
+        info!(
+            "Blob collection finished, cost {:?}",
+            collection.started.elapsed()
+        );
+        Ok(())
+    }
+
+    /// Tell the index of a namespace that the commands written so far are applied.
+    fn applied(&mut self, keyspace: &Keyspace) -> Result<()> {
+        let checkpoint = self.checkpoint();
+        keyspace.index.applied(checkpoint, &mut || self.sync())
+    }
+
+    /// Make the writes so far durable.
+    fn sync(&mut self) -> Result<()> {
+        // a command must not be durable before the blob it points at
+        self.sync_blobs()?;
+        self.current_writer.sync()
+    }
+
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            file_number: self.current_file_number,
+            offset: self.current_writer.get_position(),
+            useless_size: self.useless_size,
+        }
+    }
+
+    /// Seal the current file and go on writing the data file with the given number.
+    fn create_file(&mut self, file_number: u64) -> Result<()> {
+        // only the newest file can end in a torn write after a crash, and a command must
+        // not be durable before the blob it points at
+        self.sync_blobs()?;
+        self.current_writer.sync()?;
+        // a filter which is not saved is built again from the sealed file on the next open
+        if let Some(filters) = &self.filters {
+            if let Err(err) = filters.save(self.current_file_number) {
+                warn!(
+                    "can not save the bloom filter of file {} because {}",
+                    self.current_file_number, err
+                );
+            }
+        }
+        self.current_file_number = file_number;
+        self.current_writer = open_data_file(
+            self.reader.storage.as_ref(),
+            &data_file_path(&self.dir_path, self.current_file_number),
+        )?;
+        self.reader.sealed.set_active(self.current_file_number);
+        Ok(())
+    }
+}
+
+/// Write a record and flush it, or sync it if every write is to be durable.
+fn append(
+    writer: &mut BufWriterWithPosition<Box<dyn StorageFile>>,
+    data: &[u8],
+    sync: bool,
+) -> Result<()> {
+    writer.write_all(data)?;
+    if sync {
+        writer.sync()
+    } else {
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Open a file for appending at `offset`, cutting off what a failed write left after it.
+fn reopen_at(
+    storage: &dyn Storage,
+    path: &Path,
+    offset: u64,
+) -> Result<BufWriterWithPosition<Box<dyn StorageFile>>> {
+    let mut file = storage.append(path)?;
+    // a file which lost its unsynced tail is not padded back out
+    if file.size()? > offset {
+        file.set_len(offset)?;
+    }
+    BufWriterWithPosition::new(file)
+}
+
+/// Return the bytes of all data and blob files.
+fn data_files_size(storage: &dyn Storage, dir_path: &Path) -> Result<u64> {
+    let mut size = blob_files_size(storage, dir_path)?;
+    for file_number in sorted_file_numbers(storage, dir_path)? {
+        size += storage
+            .open(&data_file_path(dir_path, file_number))?
+            .size()?;
+    }
+    Ok(size)
+}
+
+/// Return the bytes of the blobs the commands of every namespace point at.
+fn live_blob_size(reader: &Reader, keyspaces: &BTreeMap<u64, Arc<Keyspace>>) -> Result<u64> {
+    let mut size = 0;
+    for keyspace in keyspaces.values() {
+        size += reader.blob_size(keyspace)?;
+    }
+    Ok(size)
+}
+
+/// Return true for the error of a write to a full disk or past the disk quota of the user.
+fn is_disk_full(err: &KVStoreError) -> bool {
+    matches!(err, KVStoreError::Io(err)
+        if err.raw_os_error().map_or(false, |code| DISK_FULL_ERRORS.contains(&code)))
+}
+
+/// Open a data file for appending. A new file starts with the header of the current format,
+/// a legacy file without header goes on without one.
+fn open_data_file(
+    storage: &dyn Storage,
+    path: &Path,
+) -> Result<BufWriterWithPosition<Box<dyn StorageFile>>> {
+    let mut writer = BufWriterWithPosition::new(storage.append(path)?)?;
+    if writer.get_position() == 0 {
+        write_file_header(&mut writer, SystemTime::now())?;
+        writer.sync()?;
+    }
+    Ok(writer)
+}
+
+/// a struct which records writer's current position
+struct BufWriterWithPosition<T: Write + Seek> {
+    position: u64,
+    writer: BufWriter<T>,
+}
+
+impl<T: Write + Seek> BufWriterWithPosition<T> {
+    fn new(mut inner: T) -> Result<Self> {
+        let position = inner.seek(SeekFrom::End(0))?;
+        Ok(BufWriterWithPosition {
+            position,
+            writer: BufWriter::new(inner),
+        })
+    }
+
+    fn get_position(&self) -> u64 {
+        self.position
+    }
+
+    /// Drop the writer without writing its buffer.
+    fn discard(self) {
+        let _ = self.writer.into_parts();
+    }
+}
+
+impl BufWriterWithPosition<Box<dyn StorageFile>> {
+    /// Write the buffer and make the file durable.
+    fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_mut().sync()?;
+        Ok(())
+    }
+}
+
+impl<T: Write + Seek> Write for BufWriterWithPosition<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.writer.write(buf)?;
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// a struct which records command's metadata
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct CommandPosition {
+    offset: u64,
+    length: u64,
+    file_number: u64,
+}
@@ -0,0 +1,266 @@
+use super::encryption::Encryption;
+use super::index::Index;
+use super::record::apply_command;
+use super::storage::Storage;
+use super::CommandPosition;
+use crate::{BlobPointer, Command, KVStoreError, Result};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+const NAMESPACE_FILE: &str = "namespaces.json";
+/// First bytes of an encrypted namespace file, which the rest of the file is bound to.
+const ENCRYPTED_MAGIC: &[u8; 8] = b"KVS-NSPC";
+
+/** The named namespaces of a store and their ids, saved in `namespaces.json`.
+
+The commands of a namespace refer to it by id, and an id is never used again once
+its namespace is dropped. Dropping a namespace therefore only removes it from this
+file: its commands are no longer applied to any index, and compaction discards them.
+The file of a store whose records are encrypted is encrypted too, so that it does not
+give away the names.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(super) struct NamespaceFile {
+    next_id: u64,
+    namespaces: BTreeMap<String, u64>,
+}
+
+impl NamespaceFile {
+    /// Load the namespaces of a store directory, none if the file does not exist.
+    pub(super) fn load(
+        storage: &dyn Storage,
+        dir_path: &Path,
+        encryption: Option<&Encryption>,
+    ) -> Result<NamespaceFile> {
+        let mut contents = Vec::new();
+        match storage.open(&dir_path.join(NAMESPACE_FILE)) {
+            Ok(mut file) => file.read_to_end(&mut contents)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(NamespaceFile {
+                    // 0 is the default namespace
+                    next_id: 1,
+                    namespaces: BTreeMap::new(),
+                });
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let payload = match contents.strip_prefix(ENCRYPTED_MAGIC) {
+            Some(payload) => payload,
+            None => return Ok(serde_json::from_slice(&contents)?),
+        };
+        let encryption = encryption.ok_or_else(|| {
+            KVStoreError::EncryptionKey(
+                "the namespace file is encrypted but no key was supplied".to_owned(),
+            )
+        })?;
+        Ok(serde_json::from_slice(
+            &encryption.decrypt(ENCRYPTED_MAGIC, payload)?,
+        )?)
+    }
+
+    pub(super) fn id(&self, name: &str) -> Option<u64> {
+        self.namespaces.get(name).copied()
+    }
+
+    /// Return the name and id of every named namespace.
+    pub(super) fn iter(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.namespaces
+            .iter()
+            .map(|(name, id)| (name.as_str(), *id))
+    }
+
+    /// Add a namespace and save the file. Return the id of the namespace.
+    pub(super) fn create(
+        &mut self,
+        storage: &dyn Storage,
+        dir_path: &Path,
+        encryption: Option<&Encryption>,
+        name: &str,
+    ) -> Result<u64> {
+        if self.namespaces.contains_key(name) {
+            return Err(KVStoreError::NamespaceExists(name.to_owned()));
+        }
+        let mut file = self.clone();
+        let id = file.next_id;
+        file.next_id += 1;
+        file.namespaces.insert(name.to_owned(), id);
+        file.save(storage, dir_path, encryption)?;
+        *self = file;
+        Ok(id)
+    }
+
+    /// Remove a namespace and save the file. Return the id of the namespace.
+    pub(super) fn drop(
+        &mut self,
+        storage: &dyn Storage,
+        dir_path: &Path,
+        encryption: Option<&Encryption>,
+        name: &str,
+    ) -> Result<u64> {
+        let mut file = self.clone();
+        let id = file
+            .namespaces
+            .remove(name)
+            .ok_or_else(|| KVStoreError::NamespaceNotFound(name.to_owned()))?;
+        file.save(storage, dir_path, encryption)?;
+        *self = file;
+        Ok(id)
+    }
+
+    /// Write the file, encrypted with the current key if there is one.
+    pub(super) fn save(
+        &self,
+        storage: &dyn Storage,
+        dir_path: &Path,
+        encryption: Option<&Encryption>,
+    ) -> Result<()> {
+        let path = dir_path.join(NAMESPACE_FILE);
+        let temp_path = path.with_extension("json.tmp");
+        let mut writer = BufWriter::new(storage.create(&temp_path)?);
+        let json = serde_json::to_vec(self)?;
+        match encryption {
+            Some(encryption) => {
+                writer.write_all(ENCRYPTED_MAGIC)?;
+                writer.write_all(&encryption.encrypt(ENCRYPTED_MAGIC, &json)?)?;
+            }
+            None => writer.write_all(&json)?,
+        }
+        writer
+            .into_inner()
+            .map_err(|err| err.into_error())?
+            .sync()?;
+        storage.rename(&temp_path, &path)?;
+        Ok(())
+    }
+}
+
+/// The index of one namespace of an open KvStore, shared by all handles of the namespace.
+pub(super) struct Keyspace {
+    pub(super) id: u64,
+    // None for the default namespace
+    name: Option<String>,
+    pub(super) index: Arc<dyn Index>,
+    // bytes of the live commands, which become useless when the namespace is dropped
+    live_size: AtomicU64,
+    dropped: AtomicBool,
+}
+
+impl Keyspace {
+    pub(super) fn new(id: u64, name: Option<&str>, index: Arc<dyn Index>) -> Keyspace {
+        Keyspace {
+            id,
+            name: name.map(str::to_owned),
+            index,
+            live_size: AtomicU64::new(0),
+            dropped: AtomicBool::new(false),
+        }
+    }
+
+    /// Return an error if the namespace was dropped.
+    pub(super) fn check(&self) -> Result<()> {
+        if self.dropped.load(Ordering::SeqCst) {
+            return Err(KVStoreError::NamespaceNotFound(
+                self.name.clone().unwrap_or_default(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub(super) fn set_command(&self, key: String, value: String) -> Command {
+        match self.id {
+            0 => Command::SET(key, value),
+            id => Command::NSSET(id, key, value),
+        }
+    }
+
+    pub(super) fn blob_command(&self, key: String, pointer: BlobPointer) -> Command {
+        match self.id {
+            0 => Command::BLOBSET(key, pointer),
+            id => Command::NSBLOBSET(id, key, pointer),
+        }
+    }
+
+    pub(super) fn remove_command(&self, key: String) -> Command {
+        match self.id {
+            0 => Command::RM(key),
+            id => Command::NSRM(id, key),
+        }
+    }
+
+    /// Apply a command of this namespace to its index. Return the number of bytes it made useless.
+    pub(super) fn apply(&self, position: CommandPosition, command: Command) -> Result<u64> {
+        let is_set = command.is_set();
+        let useless = apply_command(self.index.as_ref(), position, command)?;
+        // a set makes the replaced command useless, a remove also makes itself useless
+        let (added, removed) = if is_set {
+            (position.length, useless)
+        } else {
+            (0, useless.saturating_sub(position.length))
+        };
+        let live_size = self.live_size.load(Ordering::SeqCst);
+        self.live_size.store(
+            (live_size + added).saturating_sub(removed),
+            Ordering::SeqCst,
+        );
+        Ok(useless)
+    }
+
+    /// Called by compaction after rewriting every command of the namespace.
+    pub(super) fn set_live_size(&self, live_size: u64) {
+        self.live_size.store(live_size, Ordering::SeqCst);
+    }
+
+    /// Mark the namespace as dropped. Return the bytes of its commands, which are now useless.
+    pub(super) fn set_dropped(&self) -> u64 {
+        self.dropped.store(true, Ordering::SeqCst);
+        self.live_size.load(Ordering::SeqCst)
+    }
+}
+
+/// In-memory indexes of every namespace, for the tools which replay the log of a closed store.
+pub(super) struct NamespaceIndexes {
+    indexes: BTreeMap<u64, DashMap<String, CommandPosition>>,
+}
+
+impl NamespaceIndexes {
+    pub(super) fn new(namespaces: &NamespaceFile) -> NamespaceIndexes {
+        let mut indexes = BTreeMap::new();
+        indexes.insert(0, DashMap::new());
+        for (_, id) in namespaces.iter() {
+            indexes.insert(id, DashMap::new());
+        }
+        NamespaceIndexes { indexes }
+    }
+
+    /// Apply a command to the index of its namespace. Return the number of bytes it made
+    /// useless, all of them for a command of a dropped namespace.
+    pub(super) fn apply(&self, position: CommandPosition, command: Command) -> Result<u64> {
+        match self.indexes.get(&command.namespace_id()) {
+            Some(index) => apply_command(index, position, command),
+            None => Ok(position.length),
+        }
+    }
+
+    pub(super) fn get(&self, namespace_id: u64, key: &str) -> Option<CommandPosition> {
+        self.indexes
+            .get(&namespace_id)
+            .and_then(|index| index.get(key).map(|entry| *entry.value()))
+    }
+
+    /// Return the positions of the live commands of every namespace.
+    pub(super) fn positions(&self) -> impl Iterator<Item = CommandPosition> + '_ {
+        self.indexes
+            .values()
+            .flat_map(|index| index.iter().map(|entry| *entry.value()))
+    }
+
+    /// Return the number of live keys of every namespace.
+    pub(super) fn len(&self) -> u64 {
+        self.indexes.values().map(|index| index.len() as u64).sum()
+    }
+}
@@ -0,0 +1,92 @@
+use super::compaction::CompactionOptions;
+use super::encryption::Encryption;
+use super::storage::{DiskStorage, Storage};
+use crate::SizeLimits;
+use std::sync::Arc;
+
+/// Options of a KvStore.
+#[derive(Clone, Debug, Default)]
+pub struct KvStoreOptions {
+    /// how the index of keys is kept
+    pub index: IndexMode,
+    /// bytes of keys and values cached for reads, 0 to disable the cache
+    pub value_cache_size: u64,
+    /// how records are compressed, also applied to the records compaction rewrites
+    pub compression: Compression,
+    /// the keys records are encrypted with, None to write plaintext
+    pub encryption: Option<Encryption>,
+    /// false-positive rate of the bloom filters of the keys of every data file, which
+    /// answer most reads of missing keys without the index, None to go without filters
+    pub bloom_false_positive_rate: Option<f64>,
+    /// bytes the data files may hold, beyond which writes are refused, None for no
+    /// limit. Compaction may pass it for a while, since it copies the live records
+    /// before it deletes the old files.
+    pub max_size: Option<u64>,
+    /// values of at least this many bytes are kept in blob files, with only a pointer in
+    /// the data files, so that compaction does not copy them. None keeps every value in
+    /// the data files. A store with blob files reads every live command on open.
+    pub blob_threshold: Option<usize>,
+    /// largest keys and values a set takes
+    pub size_limits: SizeLimits,
+    /// when and how fast the data files are compacted
+    pub compaction: CompactionOptions,
+    /// sync the data file after every write, so that a write is durable once it returns
+    pub sync_writes: bool,
+    /// the file system the files are kept in, None for the local disk
+    pub storage: Option<Arc<dyn Storage>>,
+}
+
+impl KvStoreOptions {
+    pub(super) fn storage(&self) -> Arc<dyn Storage> {
+        self.storage
+            .clone()
+            .unwrap_or_else(|| Arc::new(DiskStorage))
+    }
+}
+
+/// How a KvStore keeps the index of its keys.
+#[derive(Clone, Debug, Default)]
+pub enum IndexMode {
+    /// Keep every key in memory and rebuild the index from the data files on open.
+    #[default]
+    Memory,
+    /// Keep the keys in a sorted, paged index file, so that memory use does not grow with
+    /// the number of keys. Reads of keys which are not cached cost extra disk reads.
+    Disk {
+        /// number of index pages cached in memory
+        cache_pages: usize,
+        /// number of updates buffered in memory before they are written to the index files
+        max_buffered_keys: usize,
+    },
+}
+
+impl IndexMode {
+    /// The on-disk index with a cache of 1024 pages and 64k buffered updates.
+    pub fn disk() -> Self {
+        IndexMode::Disk {
+            cache_pages: 1024,
+            max_buffered_keys: 64 * 1024,
+        }
+    }
+}
+
+/// How a KvStore compresses the records it writes. Records shorter than `min_size`
+/// bytes, or which do not get smaller, are written uncompressed.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Compression {
+    /// Write every record uncompressed.
+    #[default]
+    None,
+    /// Compress with LZ4, which is fast but compresses less.
+    Lz4 {
+        /// size of the smallest record to compress
+        min_size: usize,
+    },
+    /// Compress with zstd at the given level.
+    Zstd {
+        /// zstd compression level, 1 to 22
+        level: i32,
+        /// size of the smallest record to compress
+        min_size: usize,
+    },
+}
@@ -0,0 +1,635 @@
+use super::encryption::Encryption;
+use super::index::Index;
+use super::options::Compression;
+use super::storage::{Storage, StorageFile};
+use super::CommandPosition;
+use crate::{Command, KVStoreError, Result};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::Deserializer;
+use std::borrow::Cow;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/** A data file starts with a header: these magic bytes, the format version as 8 hex
+digits and the creation time in seconds since the Unix epoch as 16 hex digits, so that
+the files stay readable text. Legacy files have no header and are format version 0,
+they hold the same records as version 1. Version 2 adds the commands which point at
+values in blob files.
+ */
+const FILE_MAGIC: &[u8; 8] = b"KVS-DATA";
+pub(super) const FILE_HEADER_SIZE: u64 = 32;
+/// The format version of the data files this version writes.
+pub const FORMAT_VERSION: u32 = 2;
+
+/** First byte of a framed record. Records are written as frames: this byte, a flags byte,
+the payload length as a u32 and the crc32 of the flags, the length and the payload, which
+is a JSON command. Earlier versions wrote plain JSON commands, which start with `{` and
+are still read, but have no checksum. The low bits of the flags name the codec the JSON
+command in the payload is compressed with, 0 for none, and another bit marks a payload
+which is encrypted after the compression. An encrypted payload authenticates the flags
+and the file and offset of its record, so that it can not be moved.
+ */
+const FRAME_MAGIC: u8 = 0xfb;
+const FRAME_HEADER_SIZE: usize = 10;
+const CODEC_MASK: u8 = 0b11;
+const CODEC_LZ4: u8 = 1;
+const CODEC_ZSTD: u8 = 2;
+const FLAG_ENCRYPTED: u8 = 0b100;
+
+/// Where a record is kept, which an encrypted record is bound to.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(super) enum RecordPlace {
+    /// a file number and an offset in a data file
+    Data(u64, u64),
+    /// a file number and an offset in a blob file
+    Blob(u64, u64),
+}
+
+impl RecordPlace {
+    /// The data an encrypted record with `flags` authenticates besides its payload.
+    fn aad(self, flags: u8) -> Vec<u8> {
+        let (kind, file_number, offset) = match self {
+            RecordPlace::Data(file_number, offset) => (b'D', file_number, offset),
+            RecordPlace::Blob(file_number, offset) => (b'B', file_number, offset),
+        };
+        let mut aad = Vec::with_capacity(18);
+        aad.push(flags);
+        aad.push(kind);
+        aad.extend_from_slice(&file_number.to_le_bytes());
+        aad.extend_from_slice(&offset.to_le_bytes());
+        aad
+    }
+}
+
+/// Return the path of the data file with the given number.
+pub(super) fn data_file_path(dir_path: &Path, file_number: u64) -> PathBuf {
+    dir_path.join(format!("data_{}.txt", file_number))
+}
+
+/// Return the numbers of all data files in `dir_path`, in ascending order.
+pub(super) fn sorted_file_numbers(storage: &dyn Storage, dir_path: &Path) -> Result<Vec<u64>> {
+    let mut versions: Vec<u64> = storage
+        .read_dir(dir_path)?
+        .into_iter()
+        .filter(|path| path.extension() == Some("txt".as_ref()))
+        .flat_map(|path| {
+            path.file_name()
+                .and_then(|filename| filename.to_str())
+                .map(|filename| {
+                    filename
+                        .trim_start_matches("data_")
+                        .trim_end_matches(".txt")
+                })
+                .map(str::parse::<u64>)
+        })
+        .flatten()
+        .collect();
+    versions.sort_unstable();
+    Ok(versions)
+}
+
+/// Return true if a command could not be read because the file ends within it, as it
+/// does after a crash tore the write of the command.
+pub(super) fn is_torn(err: &KVStoreError) -> bool {
+    match err {
+        KVStoreError::Io(err) => err.kind() == io::ErrorKind::UnexpectedEof,
+        KVStoreError::Serde(err) => err.is_eof(),
+        _ => false,
+    }
+}
+
+/// Return true if a command could not be read because its bytes are no command, as the
+/// bytes which a crash left in place of a torn write may be.
+pub(super) fn is_garbage(err: &KVStoreError) -> bool {
+    match err {
+        KVStoreError::CorruptRecord(_) => true,
+        KVStoreError::Serde(err) => err.is_syntax() || err.is_data(),
+        _ => false,
+    }
+}
+
+/// The header of a data file.
+#[derive(Clone, Copy, Debug)]
+pub(super) struct FileHeader {
+    pub(super) version: u32,
+    pub(super) created: SystemTime,
+}
+
+/// Write the header of a new data file.
+pub(super) fn write_file_header(writer: &mut impl Write, created: SystemTime) -> Result<()> {
+    let created = created
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    writer.write_all(FILE_MAGIC)?;
+    write!(writer, "{:08x}{:016x}", FORMAT_VERSION, created)?;
+    Ok(())
+}
+
+/// Read the header of a data file, None for a legacy file without header.
+pub(super) fn read_file_header(file: &mut (impl Read + Seek)) -> Result<Option<FileHeader>> {
+    let mut header = Vec::with_capacity(FILE_HEADER_SIZE as usize);
+    file.seek(SeekFrom::Start(0))?;
+    file.take(FILE_HEADER_SIZE).read_to_end(&mut header)?;
+    if !header.starts_with(FILE_MAGIC) {
+        return Ok(None);
+    }
+    if header.len() < FILE_HEADER_SIZE as usize {
+        return Err(KVStoreError::CorruptRecord(
+            "truncated data file header".to_owned(),
+        ));
+    }
+    let bad_header = || KVStoreError::CorruptRecord("bad data file header".to_owned());
+    if !header.is_ascii() {
+        return Err(bad_header());
+    }
+    let fields = std::str::from_utf8(&header[FILE_MAGIC.len()..]).map_err(|_| bad_header())?;
+    let version = u32::from_str_radix(&fields[..8], 16).map_err(|_| bad_header())?;
+    if version > FORMAT_VERSION {
+        return Err(KVStoreError::UnsupportedFormatVersion(version));
+    }
+    let created = u64::from_str_radix(&fields[8..], 16).map_err(|_| bad_header())?;
+    Ok(Some(FileHeader {
+        version,
+        created: UNIX_EPOCH + Duration::from_secs(created),
+    }))
+}
+
+/// Apply a command read from the log to the index of its namespace.
+/// Return the number of bytes it made useless.
+pub(super) fn apply_command(
+    index: &dyn Index,
+    position: CommandPosition,
+    command: Command,
+) -> Result<u64> {
+    Ok(match command {
+        Command::SET(key, _)
+        | Command::NSSET(_, key, _)
+        | Command::BLOBSET(key, _)
+        | Command::NSBLOBSET(_, key, _) => index.insert(key, position)?.map_or(0, |cp| cp.length),
+        Command::RM(key) | Command::NSRM(_, key) => {
+            index.remove(&key)?.map_or(0, |cp| cp.length) + position.length
+        }
+    })
+}
+
+/** Encode a command as a framed record, compressed if `compression` asks for it and it
+pays off, and encrypted if there is a key. An encrypted record can only be read at `place`.
+ */
+pub(super) fn encode_command(
+    command: &Command,
+    compression: Compression,
+    encryption: Option<&Encryption>,
+    place: RecordPlace,
+) -> Result<Vec<u8>> {
+    let json = serde_json::to_vec(command)?;
+    let compressed = match compression {
+        Compression::Lz4 { min_size } if json.len() >= min_size => {
+            Some((CODEC_LZ4, lz4_flex::compress_prepend_size(&json)))
+        }
+        Compression::Zstd { level, min_size } if json.len() >= min_size => {
+            Some((CODEC_ZSTD, zstd::bulk::compress(&json, level)?))
+        }
+        _ => None,
+    };
+    let (codec, payload) = match compressed {
+        Some((codec, payload)) if payload.len() < json.len() => (codec, payload),
+        _ => (0, json),
+    };
+    let (codec, payload) = match encryption {
+        Some(encryption) => {
+            let flags = codec | FLAG_ENCRYPTED;
+            (flags, encryption.encrypt(&place.aad(flags), &payload)?)
+        }
+        None => (codec, payload),
+    };
+    Ok(frame(codec, &payload))
+}
+
+fn frame(flags: u8, payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+    record.push(FRAME_MAGIC);
+    record.push(flags);
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&frame_crc(flags, payload).to_le_bytes());
+    record.extend_from_slice(payload);
+    record
+}
+
+/** Return a record read at `from` as it must be written at `to`. An encrypted record is
+encrypted again for the new place, any other record is copied as it is.
+ */
+pub(super) fn move_record(
+    record: &[u8],
+    encryption: Option<&Encryption>,
+    from: RecordPlace,
+    to: RecordPlace,
+) -> Result<Vec<u8>> {
+    let (flags, payload) = match split_frame(record)? {
+        Some((flags, payload)) if flags & FLAG_ENCRYPTED != 0 && from != to => (flags, payload),
+        _ => return Ok(record.to_vec()),
+    };
+    let encryption = encryption.ok_or_else(missing_key)?;
+    let plaintext = encryption.decrypt(&from.aad(flags), payload)?;
+    Ok(frame(
+        flags,
+        &encryption.encrypt(&to.aad(flags), &plaintext)?,
+    ))
+}
+
+/// Decode a whole record read at `place`.
+pub(super) fn decode_command(
+    record: &[u8],
+    encryption: Option<&Encryption>,
+    place: RecordPlace,
+) -> Result<Command> {
+    match split_frame(record)? {
+        Some((flags, payload)) => {
+            let crc = parse_frame_header(record).2;
+            decode_frame(flags, crc, payload, encryption, place)
+        }
+        None => Ok(serde_json::from_slice(record)?),
+    }
+}
+
+/// Return the flags and the payload of a framed record, None for a plain JSON command.
+fn split_frame(record: &[u8]) -> Result<Option<(u8, &[u8])>> {
+    if record.first() != Some(&FRAME_MAGIC) {
+        return Ok(None);
+    }
+    if record.len() < FRAME_HEADER_SIZE {
+        return Err(KVStoreError::CorruptRecord(
+            "truncated frame header".to_owned(),
+        ));
+    }
+    let (header, payload) = record.split_at(FRAME_HEADER_SIZE);
+    let (flags, length, _) = parse_frame_header(header);
+    if length as usize != payload.len() {
+        return Err(KVStoreError::CorruptRecord(format!(
+            "frame payload is {} bytes instead of {}",
+            payload.len(),
+            length
+        )));
+    }
+    Ok(Some((flags, payload)))
+}
+
+/** Return the namespace id and the key at the start of a JSON command, the rest of which
+may be damaged.
+ */
+fn command_key(json: &[u8]) -> Option<(u64, String)> {
+    let mut rest = json;
+    let tag: String = next_value(&mut rest, b"{")?;
+    let namespace_id = match tag.as_str() {
+        "SET" | "BLOBSET" => {
+            rest = skip_separators(rest, b":[")?;
+            0
+        }
+        "RM" => {
+            rest = skip_separators(rest, b":")?;
+            0
+        }
+        "NSSET" | "NSBLOBSET" | "NSRM" => {
+            let namespace_id = next_value(&mut rest, b":[")?;
+            rest = skip_separators(rest, b",")?;
+            namespace_id
+        }
+        _ => return None,
+    };
+    Some((namespace_id, next_value(&mut rest, b"")?))
+}
+
+/// Read the JSON value after `separators` at the start of `json` and skip past it.
+fn next_value<T: DeserializeOwned>(json: &mut &[u8], separators: &[u8]) -> Option<T> {
+    let rest = skip_separators(json, separators)?;
+    let mut values = Deserializer::from_slice(rest).into_iter();
+    let value = values.next()?.ok()?;
+    *json = &rest[values.byte_offset()..];
+    Some(value)
+}
+
+/// Skip `separators` at the start of `json`, and the whitespace around them.
+fn skip_separators<'a>(mut json: &'a [u8], separators: &[u8]) -> Option<&'a [u8]> {
+    for separator in separators {
+        while let Some((b' ' | b'\n' | b'\t' | b'\r', rest)) = json.split_first() {
+            json = rest;
+        }
+        json = json.strip_prefix(&[*separator])?;
+    }
+    Some(json)
+}
+
+fn parse_frame_header(header: &[u8]) -> (u8, u32, u32) {
+    let flags = header[1];
+    let length = u32::from_le_bytes([header[2], header[3], header[4], header[5]]);
+    let crc = u32::from_le_bytes([header[6], header[7], header[8], header[9]]);
+    (flags, length, crc)
+}
+
+fn frame_crc(flags: u8, payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&[flags]);
+    hasher.update(&(payload.len() as u32).to_le_bytes());
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+fn decode_frame(
+    flags: u8,
+    crc: u32,
+    payload: &[u8],
+    encryption: Option<&Encryption>,
+    place: RecordPlace,
+) -> Result<Command> {
+    if frame_crc(flags, payload) != crc {
+        return Err(KVStoreError::CorruptRecord("checksum mismatch".to_owned()));
+    }
+    Ok(serde_json::from_slice(&frame_json(
+        flags, payload, encryption, place,
+    )?)?)
+}
+
+/// Return the JSON command in the payload of a frame, decrypted and decompressed.
+fn frame_json<'a>(
+    flags: u8,
+    payload: &'a [u8],
+    encryption: Option<&Encryption>,
+    place: RecordPlace,
+) -> Result<Cow<'a, [u8]>> {
+    if flags & !(CODEC_MASK | FLAG_ENCRYPTED) != 0 {
+        return Err(KVStoreError::CorruptRecord(format!(
+            "unknown flags {:#x}",
+            flags
+        )));
+    }
+    let payload = if flags & FLAG_ENCRYPTED == 0 {
+        Cow::Borrowed(payload)
+    } else {
+        Cow::Owned(
+            encryption
+                .ok_or_else(missing_key)?
+                .decrypt(&place.aad(flags), payload)?,
+        )
+    };
+    Ok(Cow::Owned(match flags & CODEC_MASK {
+        0 => return Ok(payload),
+        CODEC_LZ4 => lz4_flex::decompress_size_prepended(&payload)
+            .map_err(|err| KVStoreError::CorruptRecord(err.to_string()))?,
+        CODEC_ZSTD => zstd::stream::decode_all(&payload[..])
+            .map_err(|err| KVStoreError::CorruptRecord(err.to_string()))?,
+        codec => {
+            return Err(KVStoreError::CorruptRecord(format!(
+                "unknown codec {}",
+                codec
+            )))
+        }
+    }))
+}
+
+fn missing_key() -> KVStoreError {
+    KVStoreError::EncryptionKey("a record is encrypted but no key was supplied".to_owned())
+}
+
+/// An iterator over the commands of one data file and their positions.
+/// It stops after the first command which can not be read, until `resync` is called.
+pub(super) struct CommandIter<'a> {
+    storage: &'a dyn Storage,
+    reader: CountingReader,
+    file_path: PathBuf,
+    file_number: u64,
+    file_size: u64,
+    header: Option<FileHeader>,
+    encryption: Option<Encryption>,
+    // the kind of file, which encrypted records are bound to
+    place: fn(u64, u64) -> RecordPlace,
+    failed: bool,
+}
+
+impl<'a> CommandIter<'a> {
+    pub(super) fn open(
+        storage: &'a dyn Storage,
+        dir_path: &Path,
+        file_number: u64,
+        encryption: Option<&Encryption>,
+    ) -> Result<CommandIter<'a>> {
+        Self::open_at(storage, dir_path, file_number, 0, encryption)
+    }
+
+    /// Iterate over the commands from `offset` on, which must be the start of a command
+    /// or the start of the file.
+    pub(super) fn open_at(
+        storage: &'a dyn Storage,
+        dir_path: &Path,
+        file_number: u64,
+        offset: u64,
+        encryption: Option<&Encryption>,
+    ) -> Result<CommandIter<'a>> {
+        Self::open_path(
+            storage,
+            data_file_path(dir_path, file_number),
+            file_number,
+            offset,
+            encryption.cloned(),
+            RecordPlace::Data,
+        )
+    }
+
+    /** Iterate over the commands of a file with the format of a data file, like a blob file.
+    `place` gives the place of a record from the file number and its offset.
+     */
+    pub(super) fn open_path(
+        storage: &'a dyn Storage,
+        file_path: PathBuf,
+        file_number: u64,
+        offset: u64,
+        encryption: Option<Encryption>,
+        place: fn(u64, u64) -> RecordPlace,
+    ) -> Result<CommandIter<'a>> {
+        let mut file = storage.open(&file_path)?;
+        let file_size = file.size()?;
+        let header = read_file_header(&mut file)?;
+        let offset = match header {
+            Some(_) => offset.max(FILE_HEADER_SIZE),
+            None => offset,
+        };
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(CommandIter {
+            storage,
+            reader: CountingReader {
+                inner: BufReader::new(file),
+                offset,
+            },
+            file_path,
+            file_number,
+            file_size,
+            header,
+            encryption,
+            place,
+            failed: false,
+        })
+    }
+
+    /// The format version of the file.
+    pub(super) fn format_version(&self) -> u32 {
+        self.header.map_or(0, |header| header.version)
+    }
+
+    /// The creation time of the file, None for a legacy file.
+    pub(super) fn created(&self) -> Option<SystemTime> {
+        self.header.map(|header| header.created)
+    }
+
+    /// The offset where the next command starts, or where the failed command started.
+    pub(super) fn offset(&self) -> u64 {
+        self.reader.offset
+    }
+
+    /** Skip forward from a command which can not be read to the next readable command.
+    Return the offset of that command, or None if there is none until the end of the file.
+     */
+    pub(super) fn resync(&mut self) -> Result<Option<u64>> {
+        let mut file = self.storage.open(&self.file_path)?;
+        let mut chunk = vec![0; 64 * 1024];
+        let mut chunk_offset = self.reader.offset + 1;
+        loop {
+            file.seek(SeekFrom::Start(chunk_offset))?;
+            let n = file.read(&mut chunk)?;
+            if n == 0 {
+                return Ok(None);
+            }
+            for (i, byte) in chunk[..n].iter().enumerate() {
+                let candidate = chunk_offset + i as u64;
+                if (*byte == b'{' || *byte == FRAME_MAGIC) && self.is_command_at(candidate)? {
+                    *self = Self::open_path(
+                        self.storage,
+                        self.file_path.clone(),
+                        self.file_number,
+                        candidate,
+                        self.encryption.take(),
+                        self.place,
+                    )?;
+                    return Ok(Some(candidate));
+                }
+            }
+            chunk_offset += n as u64;
+        }
+    }
+
+    /** Return the namespace id and the key of the command which can not be read, if they
+    still can be. The payload of a frame whose checksum fails is decoded all the same.
+     */
+    pub(super) fn damaged_key(&self) -> Result<Option<(u64, String)>> {
+        let mut file = self.storage.open(&self.file_path)?;
+        let offset = self.reader.offset;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut header = Vec::with_capacity(FRAME_HEADER_SIZE);
+        (&mut file)
+            .take(FRAME_HEADER_SIZE as u64)
+            .read_to_end(&mut header)?;
+        if header.first() != Some(&FRAME_MAGIC) {
+            // a plain JSON command names its key near its start
+            file.seek(SeekFrom::Start(offset))?;
+            let mut head = Vec::new();
+            file.take(4096).read_to_end(&mut head)?;
+            return Ok(command_key(&head));
+        }
+        if header.len() < FRAME_HEADER_SIZE {
+            return Ok(None);
+        }
+        let (flags, length, _) = parse_frame_header(&header);
+        // a damaged length must not make us read more than the file holds
+        let length = (length as u64).min(self.file_size.saturating_sub(offset));
+        let mut payload = Vec::new();
+        file.take(length).read_to_end(&mut payload)?;
+        let place = (self.place)(self.file_number, offset);
+        Ok(frame_json(flags, &payload, self.encryption.as_ref(), place)
+            .ok()
+            .and_then(|json| command_key(&json)))
+    }
+
+    fn is_command_at(&self, offset: u64) -> Result<bool> {
+        let mut iter = Self::open_path(
+            self.storage,
+            self.file_path.clone(),
+            self.file_number,
+            offset,
+            self.encryption.clone(),
+            self.place,
+        )?;
+        Ok(matches!(iter.next(), Some(Ok(_))))
+    }
+
+    fn read_command(&mut self) -> Result<Command> {
+        let place = (self.place)(self.file_number, self.reader.offset);
+        if self.reader.inner.fill_buf()?.first() != Some(&FRAME_MAGIC) {
+            return Ok(Command::deserialize(&mut Deserializer::from_reader(
+                &mut self.reader,
+            ))?);
+        }
+
+        let mut header = [0; FRAME_HEADER_SIZE];
+        self.reader.read_exact(&mut header)?;
+        let (flags, length, crc) = parse_frame_header(&header);
+        // a damaged length must not make us allocate more than the file holds
+        if self.reader.offset + length as u64 > self.file_size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "frame payload is beyond the end of the data file",
+            )
+            .into());
+        }
+        let mut payload = vec![0; length as usize];
+        self.reader.read_exact(&mut payload)?;
+        decode_frame(flags, crc, &payload, self.encryption.as_ref(), place)
+    }
+}
+
+impl Iterator for CommandIter<'_> {
+    type Item = Result<(CommandPosition, Command)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let offset = self.reader.offset;
+        match self.reader.inner.fill_buf() {
+            Ok([]) => return None,
+            Ok(_) => {}
+            Err(err) => {
+                self.failed = true;
+                return Some(Err(err.into()));
+            }
+        }
+        let command = match self.read_command() {
+            Ok(command) => command,
+            Err(err) => {
+                self.failed = true;
+                // the failed command is reported at its start
+                self.reader.offset = offset;
+                return Some(Err(err));
+            }
+        };
+        let position = CommandPosition {
+            offset,
+            length: self.reader.offset - offset,
+            file_number: self.file_number,
+        };
+        Some(Ok((position, command)))
+    }
+}
+
+/// A reader which counts the offset in the file it reads.
+struct CountingReader {
+    inner: BufReader<Box<dyn StorageFile>>,
+    offset: u64,
+}
+
+impl Read for CountingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
@@ -0,0 +1,222 @@
+use super::disk_index::DiskIndex;
+use super::encryption::Encryption;
+use super::namespace::{NamespaceFile, NamespaceIndexes};
+use super::record::{
+    data_file_path, move_record, sorted_file_numbers, write_file_header, CommandIter, RecordPlace,
+    FILE_HEADER_SIZE,
+};
+use super::storage::DiskStorage;
+use super::CorruptRange;
+use crate::{KVStoreError, Result};
+use log::info;
+use serde::Serialize;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{create_dir_all, metadata, remove_file, rename, File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Name of the directory which damaged data files are moved into.
+const QUARANTINE_DIR: &str = "quarantine";
+
+/// The result of repairing a KvStore directory.
+#[derive(Serialize, Debug)]
+pub struct RepairReport {
+    /// one report per data file, in file number order
+    pub files: Vec<FileRepair>,
+    /// number of the data file holding the salvaged records, None if nothing was corrupt
+    pub new_file_number: Option<u64>,
+    /// number of keys written to the new data file
+    pub salvaged_keys: u64,
+    /// keys which may have lost updates in the corrupt ranges,
+    /// as `<namespace>/<key>` outside the default namespace
+    pub suspect_keys: Vec<String>,
+}
+
+/// What was salvaged from one data file.
+#[derive(Serialize, Debug)]
+pub struct FileRepair {
+    /// number of the `data_N.txt` file
+    pub file_number: u64,
+    /// number of commands which could be read
+    pub salvaged_records: u64,
+    /// byte ranges which could not be read
+    pub corrupt: Vec<CorruptRange>,
+    /// whether the file was moved into the quarantine directory
+    pub quarantined: bool,
+}
+
+/** Salvage every readable command of a KvStore directory whose data files are corrupt.
+The live values are written into a fresh data file, damaged files are moved into the
+`quarantine` directory and the other old data files are deleted.
+Nothing is changed when no data file is corrupt.
+ */
+pub fn repair(path: impl AsRef<Path>) -> Result<RepairReport> {
+    repair_files(path.as_ref(), None)
+}
+
+/// Repair a KvStore directory whose records are encrypted. Salvaged records stay encrypted.
+pub fn repair_encrypted(path: impl AsRef<Path>, encryption: &Encryption) -> Result<RepairReport> {
+    repair_files(path.as_ref(), Some(encryption))
+}
+
+fn repair_files(dir_path: &Path, encryption: Option<&Encryption>) -> Result<RepairReport> {
+    if !dir_path.is_dir() {
+        return Err(KVStoreError::CommonStringError(format!(
+            "{:?} is not a directory",
+            dir_path
+        )));
+    }
+
+    let namespaces = NamespaceFile::load(&DiskStorage, dir_path, encryption)?;
+    let names: HashMap<u64, &str> = namespaces.iter().map(|(name, id)| (id, name)).collect();
+    let index = NamespaceIndexes::new(&namespaces);
+    // no key of a dropped namespace is suspect
+    let key_name = |namespace_id, key: String| match namespace_id {
+        0 => Some(key),
+        id => names.get(&id).map(|name| format!("{}/{}", name, key)),
+    };
+    // (file number, offset) of the last command of every key
+    let mut last_commands = HashMap::new();
+    // (file number, offset) of every corrupt range and the key of its command, if readable
+    let mut corruptions = Vec::new();
+    let mut files = Vec::new();
+    let file_numbers = sorted_file_numbers(&DiskStorage, dir_path)?;
+    for file_number in &file_numbers {
+        let size = metadata(data_file_path(dir_path, *file_number))?.len();
+        let mut file = FileRepair {
+            file_number: *file_number,
+            salvaged_records: 0,
+            corrupt: Vec::new(),
+            quarantined: false,
+        };
+
+        let mut iter = CommandIter::open(&DiskStorage, dir_path, *file_number, encryption)?;
+        while let Some(record) = iter.next() {
+            match record {
+                Ok((position, command)) => {
+                    file.salvaged_records += 1;
+                    if let Some(key) = key_name(command.namespace_id(), command.key().to_owned()) {
+                        last_commands.insert(key, (position.file_number, position.offset));
+                    }
+                    index.apply(position, command)?;
+                }
+                Err(err @ KVStoreError::EncryptionKey(_)) => return Err(err),
+                Err(err) => {
+                    let start = iter.offset();
+                    let key = iter.damaged_key()?;
+                    let end = iter.resync()?.unwrap_or(size);
+                    corruptions.push(((*file_number, start), key));
+                    file.corrupt.push(CorruptRange {
+                        start,
+                        end,
+                        reason: format!("{}", err),
+                    });
+                }
+            }
+        }
+        files.push(file);
+    }
+
+    if corruptions.is_empty() {
+        return Ok(RepairReport {
+            files,
+            new_file_number: None,
+            salvaged_keys: 0,
+            suspect_keys: Vec::new(),
+        });
+    }
+    // a corrupt range may have held the latest command of its key, when nothing after it
+    // updates the key, and of any key before it when its key can not be read
+    let mut suspect_keys = BTreeSet::new();
+    let mut unknown_corruption = None;
+    for (position, key) in corruptions {
+        match key {
+            Some((namespace_id, key)) => {
+                let key = match key_name(namespace_id, key) {
+                    Some(key) => key,
+                    None => continue,
+                };
+                if !matches!(last_commands.get(&key), Some(last) if *last > position) {
+                    suspect_keys.insert(key);
+                }
+            }
+            None => unknown_corruption = Some(position),
+        }
+    }
+    if let Some(unknown_corruption) = unknown_corruption {
+        suspect_keys.extend(
+            last_commands
+                .into_iter()
+                .filter(|(_, position)| *position < unknown_corruption)
+                .map(|(key, _)| key),
+        );
+    }
+
+    let new_file_number = file_numbers.last().unwrap_or(&0) + 1;
+    let new_file_path = data_file_path(dir_path, new_file_number);
+    let temp_file_path = new_file_path.with_extension("txt.tmp");
+    let mut writer = BufWriter::new(
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&temp_file_path)?,
+    );
+    write_file_header(&mut writer, SystemTime::now())?;
+    let mut sources = HashMap::new();
+    let mut offset = FILE_HEADER_SIZE;
+    for position in index.positions() {
+        if let Entry::Vacant(vacant) = sources.entry(position.file_number) {
+            vacant.insert(File::open(data_file_path(dir_path, position.file_number))?);
+        }
+        let source = sources.get_mut(&position.file_number).unwrap();
+        source.seek(SeekFrom::Start(position.offset))?;
+        let mut record = Vec::with_capacity(position.length as usize);
+        source.take(position.length).read_to_end(&mut record)?;
+        // an encrypted record is bound to the place it is read from
+        let record = move_record(
+            &record,
+            encryption,
+            RecordPlace::Data(position.file_number, position.offset),
+            RecordPlace::Data(new_file_number, offset),
+        )?;
+        writer.write_all(&record)?;
+        offset += record.len() as u64;
+    }
+    writer
+        .into_inner()
+        .map_err(|err| err.into_error())?
+        .sync_all()?;
+    rename(&temp_file_path, &new_file_path)?;
+
+    let quarantine_path = dir_path.join(QUARANTINE_DIR);
+    for file in &mut files {
+        let file_path = data_file_path(dir_path, file.file_number);
+        if file.corrupt.is_empty() {
+            remove_file(&file_path)?;
+        } else {
+            create_dir_all(&quarantine_path)?;
+            rename(
+                &file_path,
+                quarantine_path.join(format!("data_{}.txt", file.file_number)),
+            )?;
+            file.quarantined = true;
+        }
+    }
+    // an index file refers to the removed data files
+    DiskIndex::remove_stale(&DiskStorage, dir_path)?;
+    info!(
+        "Salvaged {} keys into {:?}",
+        index.len(),
+        new_file_path.file_name().unwrap_or_default()
+    );
+
+    Ok(RepairReport {
+        files,
+        new_file_number: Some(new_file_number),
+        salvaged_keys: index.len(),
+        suspect_keys: suspect_keys.into_iter().collect(),
+    })
+}
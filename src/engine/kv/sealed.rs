@@ -0,0 +1,90 @@
+use super::record::data_file_path;
+use super::storage::{FileMap, Storage};
+use super::CommandPosition;
+use crate::Result;
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+/** Read-only memory maps of the sealed data files, shared by all clones of a KvStore.
+
+Every data file before the one the writer appends to is sealed: it is never changed
+again until compaction deletes it. A sealed file is mapped on its first read.
+ */
+pub(super) struct SealedFiles {
+    storage: Arc<dyn Storage>,
+    dir_path: Arc<PathBuf>,
+    // number of the file the writer appends to
+    active_file_number: AtomicU64,
+    maps: RwLock<HashMap<u64, FileMap>>,
+}
+
+impl SealedFiles {
+    pub(super) fn new(
+        storage: Arc<dyn Storage>,
+        dir_path: Arc<PathBuf>,
+        active_file_number: u64,
+    ) -> SealedFiles {
+        SealedFiles {
+            storage,
+            dir_path,
+            active_file_number: AtomicU64::new(active_file_number),
+            maps: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Called by the writer once every command of the previous files is flushed.
+    pub(super) fn set_active(&self, file_number: u64) {
+        self.active_file_number.store(file_number, Ordering::SeqCst);
+    }
+
+    pub(super) fn is_sealed(&self, file_number: u64) -> bool {
+        file_number < self.active_file_number.load(Ordering::SeqCst)
+    }
+
+    /// Return the map of a sealed file. The map is not kept if the file is older
+    /// than `compaction_number`, since compaction deletes it.
+    pub(super) fn map(&self, file_number: u64, compaction_number: &AtomicU64) -> Result<FileMap> {
+        if let Some(map) = self.maps.read().unwrap().get(&file_number) {
+            return Ok(Arc::clone(map));
+        }
+
+        let map = self
+            .storage
+            .map(&data_file_path(&self.dir_path, file_number))?;
+        let mut maps = self.maps.write().unwrap();
+        if file_number >= compaction_number.load(Ordering::SeqCst) {
+            maps.insert(file_number, Arc::clone(&map));
+        }
+        Ok(map)
+    }
+
+    /// Return the bytes of the command at `position` of a sealed file.
+    pub(super) fn read<'a>(map: &'a FileMap, position: &CommandPosition) -> Result<&'a [u8]> {
+        let map = (**map).as_ref();
+        let start = position.offset as usize;
+        let end = start + position.length as usize;
+        if end > map.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "command is beyond the end of the data file",
+            )
+            .into());
+        }
+        Ok(&map[start..end])
+    }
+
+    /// Drop the maps of the files before `file_number`.
+    pub(super) fn remove_before(&self, file_number: u64) {
+        self.maps
+            .write()
+            .unwrap()
+            .retain(|number, _| *number >= file_number);
+    }
+
+    pub(super) fn mapped_files(&self) -> u64 {
+        self.maps.read().unwrap().len() as u64
+    }
+}
@@ -0,0 +1,320 @@
+use super::record::sorted_file_numbers;
+use super::storage::{DiskStorage, Storage};
+use super::{KvStore, KvStoreOptions};
+use crate::{EngineStats, KVStoreError, KvsEngine, Result};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// The file holding the number of shards, written before the first shard is created.
+const SHARDS_FILE: &str = "shards";
+
+/** A KvStore whose keys are split by hash into independent shards.
+
+Every shard is a complete KvStore in the `shard_<i>` directory, with its own data
+files, writer and compaction, so writes to different shards do not wait for each
+other. The shard of a key is the crc32 of the key modulo the number of shards, so a
+store must always be opened with the number of shards it was created with, which is
+recorded in the `shards` file.
+Namespaces exist in every shard.
+# Example
+```
+use std::env;
+use kvs::{ShardedKvStore, Result};
+use crate::kvs::KvsEngine;
+# fn try_main() -> Result<()> {
+
+let store = ShardedKvStore::open(env::current_dir()?, 4)?;
+
+store.set("1".to_owned(),"1".to_owned())?;
+assert_eq!(store.get("1".to_owned())?, Some("1".to_owned()));
+# Ok(())
+# }
+```
+ */
+#[derive(Clone)]
+pub struct ShardedKvStore {
+    shards: Vec<KvStore>,
+}
+
+impl ShardedKvStore {
+    /// Open the ShardedKvStore with `shards` shards at a given path. Return the ShardedKvStore.
+    pub fn open(path: impl Into<PathBuf>, shards: usize) -> Result<ShardedKvStore> {
+        Self::open_with_options(path, shards, KvStoreOptions::default())
+    }
+
+    /// Open the ShardedKvStore with `shards` shards at a given path, opening every shard
+    /// with the given options. The value cache and the size limit are split between the
+    /// shards.
+    pub fn open_with_options(
+        path: impl Into<PathBuf>,
+        shards: usize,
+        options: KvStoreOptions,
+    ) -> Result<ShardedKvStore> {
+        let dir_path = path.into();
+        if shards == 0 {
+            return Err(KVStoreError::CommonStringError(
+                "a sharded store needs at least one shard".to_owned(),
+            ));
+        }
+        let storage = options.storage();
+        storage.create_dir_all(&dir_path)?;
+        if !sorted_file_numbers(storage.as_ref(), &dir_path)?.is_empty() {
+            return Err(KVStoreError::CommonStringError(format!(
+                "{:?} holds a store which is not sharded",
+                dir_path
+            )));
+        }
+        // the marker is written before any shard, so an interrupted first open is
+        // completed by the next one; a store created before the marker existed is
+        // counted by its shard directories
+        let marker = read_shard_count(storage.as_ref(), &dir_path)?;
+        let existing = match marker {
+            Some(existing) => existing,
+            None => count_shards(storage.as_ref(), &dir_path)?,
+        };
+        match existing {
+            existing if existing == 0 || existing == shards => {
+                if marker.is_none() {
+                    write_shard_count(storage.as_ref(), &dir_path, shards)?;
+                }
+            }
+            existing => {
+                return Err(KVStoreError::CommonStringError(format!(
+                    "{:?} holds {} shards, not {}",
+                    dir_path, existing, shards
+                )))
+            }
+        }
+
+        let options = KvStoreOptions {
+            value_cache_size: options.value_cache_size / shards as u64,
+            max_size: options.max_size.map(|max_size| max_size / shards as u64),
+            ..options
+        };
+        let shards = (0..shards)
+            .map(|i| KvStore::open_with_options(shard_path(&dir_path, i), options.clone()))
+            .collect::<Result<_>>()?;
+        Ok(ShardedKvStore { shards })
+    }
+
+    /// Return the number of shards of the store at a given path, 0 if there is none.
+    pub fn existing_shards(path: impl AsRef<Path>) -> Result<usize> {
+        match read_shard_count(&DiskStorage, path.as_ref())? {
+            Some(shards) => Ok(shards),
+            None => count_shards(&DiskStorage, path.as_ref()),
+        }
+    }
+
+    fn shard(&self, key: &str) -> &KvStore {
+        &self.shards[crc32fast::hash(key.as_bytes()) as usize % self.shards.len()]
+    }
+}
+
+/// Read the number of shards from the marker file, None if there is no marker.
+fn read_shard_count(storage: &dyn Storage, dir_path: &Path) -> Result<Option<usize>> {
+    let mut file = match storage.open(&dir_path.join(SHARDS_FILE)) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let mut content = String::new();
+    file.read_to_string(&mut content)?;
+    match content.trim().parse::<usize>() {
+        Ok(shards) if shards > 0 => Ok(Some(shards)),
+        _ => Err(KVStoreError::CommonStringError(format!(
+            "{:?} holds a corrupt shard count",
+            dir_path.join(SHARDS_FILE)
+        ))),
+    }
+}
+
+/// Write the number of shards to a temporary file and rename it over the marker, so an
+/// interrupted open leaves either no marker or a complete one.
+fn write_shard_count(storage: &dyn Storage, dir_path: &Path, shards: usize) -> Result<()> {
+    let path = dir_path.join(SHARDS_FILE);
+    let temp_path = path.with_extension("tmp");
+    let mut file = storage.create(&temp_path)?;
+    file.write_all(format!("{}\n", shards).as_bytes())?;
+    file.sync()?;
+    storage.rename(&temp_path, &path)?;
+    Ok(())
+}
+
+fn count_shards(storage: &dyn Storage, dir_path: &Path) -> Result<usize> {
+    let entries = match storage.read_dir(dir_path) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err.into()),
+    };
+    Ok(entries
+        .iter()
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix("shard_"))
+                .map_or(false, |number| number.parse::<usize>().is_ok())
+        })
+        .count())
+}
+
+fn shard_path(dir_path: &Path, shard: usize) -> PathBuf {
+    dir_path.join(format!("shard_{}", shard))
+}
+
+impl KvsEngine for ShardedKvStore {
+    /// Set the value of a string key in its shard.
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.shard(&key).set(key, value)
+    }
+
+    /// Get the string value of a string key from its shard.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.shard(&key).get(key)
+    }
+
+    /// Remove a given key from its shard.
+    fn remove(&self, key: String) -> Result<()> {
+        self.shard(&key).remove(key)
+    }
+
+    /// Call `f` with every key/value pair of every shard.
+    fn scan<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        for shard in &self.shards {
+            shard.scan(&mut f)?;
+        }
+        Ok(())
+    }
+
+    /// Return the statistics of all shards added up.
+    fn stats(&self) -> Result<EngineStats> {
+        let mut stats = EngineStats {
+            engine: "kvs-sharded".to_owned(),
+            keys: 0,
+            disk_size: 0,
+            data_files: Vec::new(),
+            garbage_bytes: Some(0),
+            compactions: Some(0),
+            compaction_millis: Some(0),
+            compaction_paused: None,
+            read_only: None,
+            open_readers: Some(0),
+            mapped_files: Some(0),
+            cache_hits: None,
+            cache_misses: None,
+            bloom_skips: None,
+            bloom_false_positives: None,
+            bloom_false_positive_rate: None,
+            blob_bytes: Some(0),
+            blob_garbage_bytes: Some(0),
+            blob_collections: Some(0),
+        };
+        let add = |total: &mut Option<u64>, value: Option<u64>| {
+            if let Some(value) = value {
+                *total = Some(total.unwrap_or(0) + value);
+            }
+        };
+        for shard in &self.shards {
+            let shard_stats = shard.stats()?;
+            stats.keys += shard_stats.keys;
+            stats.disk_size += shard_stats.disk_size;
+            stats.data_files.extend(shard_stats.data_files);
+            add(&mut stats.garbage_bytes, shard_stats.garbage_bytes);
+            add(&mut stats.compactions, shard_stats.compactions);
+            add(&mut stats.compaction_millis, shard_stats.compaction_millis);
+            stats.compaction_paused = shard_stats.compaction_paused;
+            stats.read_only =
+                Some(stats.read_only.unwrap_or(false) || shard_stats.read_only.unwrap_or(false));
+            add(&mut stats.open_readers, shard_stats.open_readers);
+            add(&mut stats.mapped_files, shard_stats.mapped_files);
+            add(&mut stats.cache_hits, shard_stats.cache_hits);
+            add(&mut stats.cache_misses, shard_stats.cache_misses);
+            add(&mut stats.bloom_skips, shard_stats.bloom_skips);
+            add(
+                &mut stats.bloom_false_positives,
+                shard_stats.bloom_false_positives,
+            );
+            add(&mut stats.blob_bytes, shard_stats.blob_bytes);
+            add(
+                &mut stats.blob_garbage_bytes,
+                shard_stats.blob_garbage_bytes,
+            );
+            add(&mut stats.blob_collections, shard_stats.blob_collections);
+        }
+        if let (Some(skips), Some(false_positives)) =
+            (stats.bloom_skips, stats.bloom_false_positives)
+        {
+            if skips + false_positives > 0 {
+                stats.bloom_false_positive_rate =
+                    Some(false_positives as f64 / (skips + false_positives) as f64);
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Return a handle to the namespace in every shard.
+    fn namespace(&self, name: &str) -> Result<ShardedKvStore> {
+        let shards = self
+            .shards
+            .iter()
+            .map(|shard| shard.namespace(name))
+            .collect::<Result<_>>()?;
+        Ok(ShardedKvStore { shards })
+    }
+
+    /// Create the namespace in every shard.
+    fn create_namespace(&self, name: &str) -> Result<()> {
+        // a namespace which an interrupted create left in some shards is completed
+        let mut created = false;
+        for shard in &self.shards {
+            match shard.create_namespace(name) {
+                Ok(()) => created = true,
+                Err(KVStoreError::NamespaceExists(_)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        if !created {
+            return Err(KVStoreError::NamespaceExists(name.to_owned()));
+        }
+        Ok(())
+    }
+
+    /// Drop the namespace in every shard.
+    fn drop_namespace(&self, name: &str) -> Result<()> {
+        let mut dropped = false;
+        for shard in &self.shards {
+            match shard.drop_namespace(name) {
+                Ok(()) => dropped = true,
+                Err(KVStoreError::NamespaceNotFound(_)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        if !dropped {
+            return Err(KVStoreError::NamespaceNotFound(name.to_owned()));
+        }
+        Ok(())
+    }
+
+    /// Return the names of the namespaces.
+    fn list_namespaces(&self) -> Result<Vec<String>> {
+        self.shards[0].list_namespaces()
+    }
+
+    /// Compact every shard, one after the other.
+    fn compact(&self) -> Result<()> {
+        for shard in &self.shards {
+            shard.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Pause or resume the compactions of every shard.
+    fn set_compaction_paused(&self, paused: bool) -> Result<()> {
+        for shard in &self.shards {
+            shard.set_compaction_paused(paused)?;
+        }
+        Ok(())
+    }
+}
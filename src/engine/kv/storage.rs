@@ -0,0 +1,598 @@
+use memmap2::Mmap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// The bytes of a file mapped into memory.
+pub type FileMap = Arc<dyn AsRef<[u8]> + Send + Sync>;
+
+/** The file system a KvStore keeps its files in.
+
+A KvStore does all of its I/O through this trait, so that it can run over something
+other than the local disk, such as a `MemoryStorage` which injects faults.
+ */
+pub trait Storage: fmt::Debug + Send + Sync {
+    /// Open an existing file for reading.
+    fn open(&self, path: &Path) -> io::Result<Box<dyn StorageFile>>;
+
+    /// Open a file for appending, creating it if it does not exist.
+    fn append(&self, path: &Path) -> io::Result<Box<dyn StorageFile>>;
+
+    /// Create an empty file for writing, truncating it if it exists.
+    fn create(&self, path: &Path) -> io::Result<Box<dyn StorageFile>>;
+
+    /// Map a file which is never written again into memory.
+    fn map(&self, path: &Path) -> io::Result<FileMap>;
+
+    /// Return the paths of the entries of a directory.
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// Create a directory and all of its missing parents.
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Delete a file.
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Rename a file, replacing the file at `to` if there is one.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Return the bytes which can still be written to the file system holding `path`,
+    /// None if that is not known.
+    fn available_space(&self, _path: &Path) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
+}
+
+/// A file opened through a `Storage`.
+pub trait StorageFile: Read + Write + Seek + Send {
+    /// Make the data written to the file durable.
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// Return the size of the file in bytes.
+    fn size(&self) -> io::Result<u64>;
+
+    /// Truncate or extend the file to `size` bytes.
+    fn set_len(&mut self, size: u64) -> io::Result<()>;
+
+    /// Return when the file was last modified, None if that is not known.
+    fn modified(&self) -> io::Result<Option<SystemTime>> {
+        Ok(None)
+    }
+}
+
+/// The local file system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DiskStorage;
+
+impl Storage for DiskStorage {
+    fn open(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn append(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(
+            OpenOptions::new().create(true).append(true).open(path)?,
+        ))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(
+            OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(path)?,
+        ))
+    }
+
+    fn map(&self, path: &Path) -> io::Result<FileMap> {
+        let file = File::open(path)?;
+        // SAFETY: the file is never written again and is only deleted, which keeps the
+        // mapped pages valid.
+        Ok(Arc::new(unsafe { Mmap::map(&file)? }))
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect()
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    #[cfg(unix)]
+    fn available_space(&self, path: &Path) -> io::Result<Option<u64>> {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = CString::new(path.as_os_str().as_bytes())?;
+        let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+        // SAFETY: the path is a valid C string and `stat` is written by a successful call
+        if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: statvfs succeeded
+        let stat = unsafe { stat.assume_init() };
+        // the field types differ between platforms
+        #[allow(clippy::unnecessary_cast)]
+        Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+    }
+}
+
+impl StorageFile for File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_all()
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_len(&mut self, size: u64) -> io::Result<()> {
+        File::set_len(self, size)
+    }
+
+    fn modified(&self) -> io::Result<Option<SystemTime>> {
+        Ok(self.metadata()?.modified().ok())
+    }
+}
+
+/// The faults a `MemoryStorage` injects.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Faults {
+    /// number of writes which succeed before every further write and sync fails,
+    /// None to never fail
+    pub fail_after_writes: Option<u64>,
+    /// keep the first half of the data of the first failing write, as a torn write
+    pub tear_failing_write: bool,
+    /// largest number of bytes a single write takes, 0 for no limit
+    pub max_write_size: usize,
+    /// fail every read
+    pub fail_reads: bool,
+    /// number of file removals which fail before removals succeed again
+    pub failing_removes: u64,
+    /// bytes all files may hold together, writes beyond fail as on a full disk,
+    /// None for no limit
+    pub capacity: Option<u64>,
+}
+
+/** A file system in memory for tests, which injects I/O errors and simulates crashes.
+
+Clones share the same files. The data written to a file is durable once the file is
+synced, while creating, renaming and removing files is durable at once. `crash`
+drops the data which was not synced, as a power loss would, and makes every file
+opened before it fail.
+# Example
+```
+use kvs::{KvStore, KvStoreOptions, KvsEngine, MemoryStorage, Result};
+use std::sync::Arc;
+# fn try_main() -> Result<()> {
+
+let storage = MemoryStorage::new();
+let options = KvStoreOptions {
+    storage: Some(Arc::new(storage.clone())),
+    ..KvStoreOptions::default()
+};
+let store = KvStore::open_with_options("/store", options)?;
+store.set("1".to_owned(), "1".to_owned())?;
+# Ok(())
+# }
+```
+ */
+#[derive(Clone, Default)]
+pub struct MemoryStorage {
+    state: Arc<Mutex<MemoryState>>,
+}
+
+#[derive(Default)]
+struct MemoryState {
+    dirs: BTreeSet<PathBuf>,
+    files: BTreeMap<PathBuf, Arc<Mutex<MemoryFile>>>,
+    faults: Faults,
+    // writes since the faults were set
+    writes: u64,
+    torn: bool,
+    // number of crashes, the files opened before the last one fail
+    generation: u64,
+}
+
+#[derive(Default)]
+struct MemoryFile {
+    data: Vec<u8>,
+    // the synced data is the first `synced_len` bytes of `data`, unless a write changed
+    // them, which keeps a copy of them here first
+    synced_len: usize,
+    synced_copy: Option<Vec<u8>>,
+}
+
+impl MemoryStorage {
+    /// Create a storage without files.
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+
+    /// Inject the given faults from now on, replacing the previous ones.
+    pub fn set_faults(&self, faults: Faults) {
+        let mut state = self.state.lock().unwrap();
+        state.faults = faults;
+        state.writes = 0;
+        state.torn = false;
+    }
+
+    /// Stop injecting faults.
+    pub fn clear_faults(&self) {
+        self.set_faults(Faults::default());
+    }
+
+    /// Simulate a power loss: drop the data which was not synced and the faults, and
+    /// make the files which are open fail.
+    pub fn crash(&self) {
+        self.crash_tearing(|_, _| 0, None);
+    }
+
+    /** Simulate a power loss which tears the last writes: like `crash`, but keep the
+    first `keep(path, unsynced)` of the `unsynced` bytes appended to a file since its
+    last sync, as if they partly reached the disk. Unsynced changes of synced bytes are
+    always dropped. With a `garbage_seed`, the rest of the unsynced bytes is not dropped
+    but overwritten with random bytes from the seed, as if the file grew but the end of
+    the writes did not reach the disk.
+     */
+    pub fn crash_tearing(
+        &self,
+        mut keep: impl FnMut(&Path, u64) -> u64,
+        garbage_seed: Option<u64>,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        state.generation += 1;
+        state.faults = Faults::default();
+        // the state of the random bytes, which must not be 0
+        let mut garbage = garbage_seed.map(|seed| seed | 1);
+        for (path, file) in &state.files {
+            let mut file = file.lock().unwrap();
+            match file.synced_copy.take() {
+                Some(copy) => file.data = copy,
+                None => {
+                    let unsynced = (file.data.len() - file.synced_len) as u64;
+                    let kept = keep(path, unsynced).min(unsynced) as usize;
+                    let len = file.synced_len + kept;
+                    match &mut garbage {
+                        Some(state) => {
+                            for byte in &mut file.data[len..] {
+                                *byte = xorshift(state) as u8;
+                            }
+                        }
+                        None => file.data.truncate(len),
+                    }
+                }
+            }
+            file.synced_len = file.data.len();
+        }
+    }
+
+    /// Return the paths of all files.
+    pub fn files(&self) -> Vec<PathBuf> {
+        self.state.lock().unwrap().files.keys().cloned().collect()
+    }
+
+    /// Return the bytes the capacity leaves besides the files other than `except`,
+    /// which the caller may have locked.
+    fn free_space(state: &MemoryState, except: Option<&Arc<Mutex<MemoryFile>>>) -> Option<u64> {
+        let capacity = state.faults.capacity?;
+        let used: usize = state
+            .files
+            .values()
+            .filter(|file| !except.map_or(false, |except| Arc::ptr_eq(file, except)))
+            .map(|file| file.lock().unwrap().data.len())
+            .sum();
+        Some(capacity.saturating_sub(used as u64))
+    }
+
+    fn open_file(
+        &self,
+        path: &Path,
+        append: bool,
+        truncate: bool,
+    ) -> io::Result<Box<dyn StorageFile>> {
+        let mut state = self.state.lock().unwrap();
+        let file = match state.files.get(path) {
+            Some(file) => Arc::clone(file),
+            None if append || truncate => {
+                let parent = path.parent().unwrap_or_else(|| Path::new(""));
+                if !parent.as_os_str().is_empty() && !state.dirs.contains(parent) {
+                    return Err(not_found(path));
+                }
+                let file = Arc::new(Mutex::new(MemoryFile::default()));
+                state.files.insert(path.to_owned(), Arc::clone(&file));
+                file
+            }
+            None => return Err(not_found(path)),
+        };
+        if truncate {
+            file.lock().unwrap().truncate();
+        }
+        Ok(Box::new(MemoryHandle {
+            storage: self.clone(),
+            file,
+            position: 0,
+            append,
+            generation: state.generation,
+        }))
+    }
+}
+
+impl fmt::Debug for MemoryStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+        f.debug_struct("MemoryStorage")
+            .field("files", &state.files.len())
+            .field("faults", &state.faults)
+            .finish()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn open(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        self.open_file(path, false, false)
+    }
+
+    fn append(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        self.open_file(path, true, false)
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        self.open_file(path, false, true)
+    }
+
+    fn map(&self, path: &Path) -> io::Result<FileMap> {
+        let state = self.state.lock().unwrap();
+        let file = state.files.get(path).ok_or_else(|| not_found(path))?;
+        let data = file.lock().unwrap().data.clone();
+        Ok(Arc::new(data))
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let state = self.state.lock().unwrap();
+        if !state.dirs.contains(path) {
+            return Err(not_found(path));
+        }
+        Ok(state
+            .dirs
+            .iter()
+            .chain(state.files.keys())
+            .filter(|entry| entry.parent() == Some(path))
+            .cloned()
+            .collect())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        for dir in path.ancestors().filter(|dir| !dir.as_os_str().is_empty()) {
+            state.dirs.insert(dir.to_owned());
+        }
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.faults.failing_removes > 0 {
+            state.faults.failing_removes -= 1;
+            return Err(injected());
+        }
+        state
+            .files
+            .remove(path)
+            .map(|_| ())
+            .ok_or_else(|| not_found(path))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let file = state.files.remove(from).ok_or_else(|| not_found(from))?;
+        state.files.insert(to.to_owned(), file);
+        Ok(())
+    }
+
+    fn available_space(&self, _path: &Path) -> io::Result<Option<u64>> {
+        Ok(MemoryStorage::free_space(&self.state.lock().unwrap(), None))
+    }
+}
+
+impl MemoryFile {
+    /// Keep a copy of the synced data before it is changed.
+    fn keep_synced(&mut self, position: usize) {
+        if position < self.synced_len && self.synced_copy.is_none() {
+            self.synced_copy = Some(self.data[..self.synced_len].to_vec());
+        }
+    }
+
+    fn write_at(&mut self, position: usize, buf: &[u8]) {
+        self.keep_synced(position);
+        let end = position + buf.len();
+        if self.data.len() < end {
+            self.data.resize(end, 0);
+        }
+        self.data[position..end].copy_from_slice(buf);
+    }
+
+    fn truncate(&mut self) {
+        self.keep_synced(0);
+        self.data.clear();
+    }
+
+    fn set_len(&mut self, size: usize) {
+        self.keep_synced(size);
+        self.data.resize(size, 0);
+    }
+}
+
+/// A file of a `MemoryStorage` opened for reading or writing.
+struct MemoryHandle {
+    storage: MemoryStorage,
+    file: Arc<Mutex<MemoryFile>>,
+    position: u64,
+    append: bool,
+    generation: u64,
+}
+
+impl MemoryHandle {
+    /// Fail if the storage crashed since the file was opened.
+    fn check(&self, state: &MemoryState) -> io::Result<()> {
+        if state.generation != self.generation {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "the file was opened before the storage crashed",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Read for MemoryHandle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let state = self.storage.state.lock().unwrap();
+        self.check(&state)?;
+        if state.faults.fail_reads {
+            return Err(injected());
+        }
+        let file = self.file.lock().unwrap();
+        let start = (self.position as usize).min(file.data.len());
+        let n = buf.len().min(file.data.len() - start);
+        buf[..n].copy_from_slice(&file.data[start..start + n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for MemoryHandle {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.storage.state.lock().unwrap();
+        self.check(&state)?;
+        let mut file = self.file.lock().unwrap();
+        if self.append {
+            self.position = file.data.len() as u64;
+        }
+        if state
+            .faults
+            .fail_after_writes
+            .map_or(false, |n| state.writes >= n)
+        {
+            if state.faults.tear_failing_write && !state.torn {
+                state.torn = true;
+                file.write_at(self.position as usize, &buf[..buf.len() / 2]);
+            }
+            return Err(injected());
+        }
+        state.writes += 1;
+        let mut n = match state.faults.max_write_size {
+            0 => buf.len(),
+            max => buf.len().min(max),
+        };
+        // a full disk takes what fits, then fails
+        let position = self.position as usize;
+        if let Some(free) = MemoryStorage::free_space(&state, Some(&self.file)) {
+            let fits = (free as usize)
+                .max(file.data.len())
+                .saturating_sub(position);
+            if fits == 0 {
+                return Err(io::Error::from_raw_os_error(DISK_FULL_ERRORS[0]));
+            }
+            n = n.min(fits);
+        }
+        file.write_at(self.position as usize, &buf[..n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryHandle {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let size = self.file.lock().unwrap().data.len() as i64;
+        let position = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => size + offset,
+            SeekFrom::Current(offset) => self.position as i64 + offset,
+        };
+        if position < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the start of the file",
+            ));
+        }
+        self.position = position as u64;
+        Ok(self.position)
+    }
+}
+
+impl StorageFile for MemoryHandle {
+    fn sync(&mut self) -> io::Result<()> {
+        let state = self.storage.state.lock().unwrap();
+        self.check(&state)?;
+        if state
+            .faults
+            .fail_after_writes
+            .map_or(false, |n| state.writes >= n)
+        {
+            return Err(injected());
+        }
+        let mut file = self.file.lock().unwrap();
+        file.synced_len = file.data.len();
+        file.synced_copy = None;
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.file.lock().unwrap().data.len() as u64)
+    }
+
+    fn set_len(&mut self, size: u64) -> io::Result<()> {
+        let state = self.storage.state.lock().unwrap();
+        self.check(&state)?;
+        self.file.lock().unwrap().set_len(size as usize);
+        Ok(())
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{:?} does not exist", path),
+    )
+}
+
+fn injected() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "injected I/O error")
+}
+
+/** The raw OS errors of a write to a full disk and of a write past the disk quota of the
+user: ENOSPC and EDQUOT, or ERROR_DISK_FULL and ERROR_HANDLE_DISK_FULL on Windows.
+ */
+#[cfg(unix)]
+pub(super) const DISK_FULL_ERRORS: [i32; 2] = [libc::ENOSPC, libc::EDQUOT];
+#[cfg(not(unix))]
+pub(super) const DISK_FULL_ERRORS: [i32; 2] = [112, 39];
+
+/// Return the next number of a xorshift generator, whose state must not be 0.
+fn xorshift(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
@@ -0,0 +1,121 @@
+use super::disk_index::DiskIndex;
+use super::encryption::Encryption;
+use super::record::{
+    data_file_path, move_record, read_file_header, sorted_file_numbers, write_file_header,
+    CommandIter, RecordPlace, FILE_HEADER_SIZE,
+};
+use super::storage::{DiskStorage, Storage};
+use crate::{KVStoreError, Result};
+use log::info;
+use serde::Serialize;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+/// The result of upgrading a KvStore directory.
+#[derive(Serialize, Debug)]
+pub struct UpgradeReport {
+    /// numbers of the legacy data files which were rewritten in the current format
+    pub upgraded: Vec<u64>,
+    /// numbers of the data files which were already in the current format
+    pub current: Vec<u64>,
+}
+
+/** Rewrite the legacy data files of a KvStore directory, which have no header,
+into the current format. The store must not be open.
+
+The records of a legacy file are kept as they are behind the new header, except
+the encrypted ones, which are bound to their offset and need `upgrade_encrypted`.
+Damaged bytes are kept as well, for `repair`. A file is replaced only once its new
+version is synced, so an interrupted upgrade can simply be run again.
+ */
+pub fn upgrade(path: impl AsRef<Path>) -> Result<UpgradeReport> {
+    upgrade_with_storage(path, &DiskStorage)
+}
+
+/// Upgrade a KvStore directory whose records are encrypted.
+pub fn upgrade_encrypted(path: impl AsRef<Path>, encryption: &Encryption) -> Result<UpgradeReport> {
+    upgrade_files(path.as_ref(), &DiskStorage, Some(encryption))
+}
+
+/// Upgrade the legacy data files of a KvStore directory kept in the given storage.
+pub fn upgrade_with_storage(
+    path: impl AsRef<Path>,
+    storage: &dyn Storage,
+) -> Result<UpgradeReport> {
+    upgrade_files(path.as_ref(), storage, None)
+}
+
+fn upgrade_files(
+    dir_path: &Path,
+    storage: &dyn Storage,
+    encryption: Option<&Encryption>,
+) -> Result<UpgradeReport> {
+    if storage.read_dir(dir_path).is_err() {
+        return Err(KVStoreError::CommonStringError(format!(
+            "{:?} is not a directory",
+            dir_path
+        )));
+    }
+
+    let mut report = UpgradeReport {
+        upgraded: Vec::new(),
+        current: Vec::new(),
+    };
+    for file_number in sorted_file_numbers(storage, dir_path)? {
+        let file_path = data_file_path(dir_path, file_number);
+        let mut file = storage.open(&file_path)?;
+        if read_file_header(&mut file)?.is_some() {
+            report.current.push(file_number);
+            continue;
+        }
+
+        let temp_file_path = file_path.with_extension("txt.tmp");
+        let mut writer = BufWriter::new(storage.create(&temp_file_path)?);
+        // the last modification is the best guess of when a legacy file was written
+        let created = file.modified()?.unwrap_or_else(SystemTime::now);
+        write_file_header(&mut writer, created)?;
+        let mut source = BufReader::new(storage.open(&file_path)?);
+        let mut copied = 0;
+        let mut iter = CommandIter::open(storage, dir_path, file_number, encryption)?;
+        while let Some(record) = iter.next() {
+            let position = match record {
+                Ok((position, _)) => position,
+                Err(err @ KVStoreError::EncryptionKey(_)) => return Err(err),
+                Err(_) => {
+                    iter.resync()?;
+                    continue;
+                }
+            };
+            io::copy(
+                &mut (&mut source).take(position.offset - copied),
+                &mut writer,
+            )?;
+            let mut record = Vec::with_capacity(position.length as usize);
+            (&mut source)
+                .take(position.length)
+                .read_to_end(&mut record)?;
+            writer.write_all(&move_record(
+                &record,
+                encryption,
+                RecordPlace::Data(file_number, position.offset),
+                RecordPlace::Data(file_number, position.offset + FILE_HEADER_SIZE),
+            )?)?;
+            copied = position.offset + position.length;
+        }
+        io::copy(&mut source, &mut writer)?;
+        writer
+            .into_inner()
+            .map_err(|err| err.into_error())?
+            .sync()?;
+        storage.rename(&temp_file_path, &file_path)?;
+        info!("Upgraded {:?}", file_path.file_name().unwrap_or_default());
+        report.upgraded.push(file_number);
+    }
+
+    if !report.upgraded.is_empty() {
+        // the offsets of every command of the upgraded files moved
+        DiskIndex::remove_stale(storage, dir_path)?;
+    }
+    Ok(report)
+}
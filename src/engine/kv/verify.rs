@@ -0,0 +1,154 @@
+use super::encryption::Encryption;
+use super::namespace::{NamespaceFile, NamespaceIndexes};
+use super::record::{data_file_path, sorted_file_numbers, CommandIter, FILE_HEADER_SIZE};
+use super::storage::DiskStorage;
+use crate::{Command, KVStoreError, Result};
+use serde::Serialize;
+use std::fs::metadata;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+/// The result of checking every data file of a KvStore directory.
+#[derive(Serialize, Debug)]
+pub struct VerifyReport {
+    /// one report per data file, in file number order
+    pub files: Vec<FileReport>,
+}
+
+/// What was found in one data file.
+#[derive(Serialize, Debug)]
+pub struct FileReport {
+    /// number of the `data_N.txt` file
+    pub file_number: u64,
+    /// size of the file in bytes
+    pub size: u64,
+    /// format version of the file, 0 for a legacy file without header
+    pub format_version: u32,
+    /// creation time in seconds since the Unix epoch, None for a legacy file
+    pub created: Option<u64>,
+    /// number of commands which could be read
+    pub records: u64,
+    /// number of keys whose live value is in this file
+    pub live_keys: u64,
+    /// number of remove commands
+    pub tombstones: u64,
+    /// bytes of readable commands which are no longer live, without the file header
+    pub garbage_bytes: u64,
+    /// byte ranges which could not be read
+    pub corrupt: Vec<CorruptRange>,
+}
+
+/// A byte range of a data file which does not hold readable commands.
+#[derive(Serialize, Debug)]
+pub struct CorruptRange {
+    /// offset of the first corrupt byte
+    pub start: u64,
+    /// offset after the last corrupt byte
+    pub end: u64,
+    /// why the range could not be read
+    pub reason: String,
+}
+
+impl VerifyReport {
+    /// Return true if any data file has a corrupt range.
+    pub fn is_corrupt(&self) -> bool {
+        self.files.iter().any(|file| !file.corrupt.is_empty())
+    }
+
+    /// Return the number of live keys in the whole directory.
+    pub fn live_keys(&self) -> u64 {
+        self.files.iter().map(|file| file.live_keys).sum()
+    }
+}
+
+/** Check every data file in a KvStore directory without opening the store.
+Commands are replayed the same way `KvStore::open` recovers the index, but reading
+goes on with the next readable command when a command can not be read.
+ */
+pub fn verify(path: impl AsRef<Path>) -> Result<VerifyReport> {
+    verify_files(path.as_ref(), None)
+}
+
+/// Check the data files of a KvStore directory whose records are encrypted.
+pub fn verify_encrypted(path: impl AsRef<Path>, encryption: &Encryption) -> Result<VerifyReport> {
+    verify_files(path.as_ref(), Some(encryption))
+}
+
+fn verify_files(dir_path: &Path, encryption: Option<&Encryption>) -> Result<VerifyReport> {
+    if !dir_path.is_dir() {
+        return Err(KVStoreError::CommonStringError(format!(
+            "{:?} is not a directory",
+            dir_path
+        )));
+    }
+
+    let index = NamespaceIndexes::new(&NamespaceFile::load(&DiskStorage, dir_path, encryption)?);
+    let mut files = Vec::new();
+    for file_number in sorted_file_numbers(&DiskStorage, dir_path)? {
+        let size = metadata(data_file_path(dir_path, file_number))?.len();
+        let mut iter = CommandIter::open(&DiskStorage, dir_path, file_number, encryption)?;
+        let mut report = FileReport {
+            file_number,
+            size,
+            format_version: iter.format_version(),
+            created: iter.created().map(|created| {
+                created
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs()
+            }),
+            records: 0,
+            live_keys: 0,
+            tombstones: 0,
+            garbage_bytes: 0,
+            corrupt: Vec::new(),
+        };
+
+        while let Some(record) = iter.next() {
+            match record {
+                Ok((position, command)) => {
+                    report.records += 1;
+                    if let Command::RM(_) | Command::NSRM(..) = command {
+                        report.tombstones += 1;
+                    }
+                    index.apply(position, command)?;
+                }
+                // a missing key is not corruption
+                Err(err @ KVStoreError::EncryptionKey(_)) => return Err(err),
+                Err(err) => {
+                    let start = iter.offset();
+                    let end = iter.resync()?.unwrap_or(size);
+                    report.corrupt.push(CorruptRange {
+                        start,
+                        end,
+                        reason: format!("{}", err),
+                    });
+                }
+            }
+        }
+        files.push(report);
+    }
+
+    let mut live_bytes = vec![0; files.len()];
+    for position in index.positions() {
+        if let Ok(i) = files.binary_search_by_key(&position.file_number, |file| file.file_number) {
+            files[i].live_keys += 1;
+            live_bytes[i] += position.length;
+        }
+    }
+    for (file, live_bytes) in files.iter_mut().zip(live_bytes) {
+        let corrupt_bytes: u64 = file
+            .corrupt
+            .iter()
+            .map(|range| range.end - range.start)
+            .sum();
+        // a legacy file has no header
+        let header_size = match file.format_version {
+            0 => 0,
+            _ => FILE_HEADER_SIZE,
+        };
+        file.garbage_bytes = (file.size - corrupt_bytes - live_bytes).saturating_sub(header_size);
+    }
+
+    Ok(VerifyReport { files })
+}
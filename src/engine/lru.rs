@@ -0,0 +1,77 @@
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// A cache which evicts the least recently used entries once the total weight
+/// of its entries exceeds its capacity.
+pub(crate) struct LruCache<K, V> {
+    capacity: u64,
+    weight: u64,
+    tick: u64,
+    entries: HashMap<K, (V, u64, u64)>,
+    // tick of the last use -> key
+    order: BTreeMap<u64, K>,
+}
+
+impl<K: Hash + Eq + Clone, V> LruCache<K, V> {
+    pub(crate) fn new(capacity: u64) -> Self {
+        LruCache {
+            capacity,
+            weight: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn get<Q>(&mut self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.tick += 1;
+        let tick = self.tick;
+        let (key, (_, _, last_used)) = self.entries.get_key_value(key)?;
+        let key = key.clone();
+        self.order.remove(last_used);
+        self.order.insert(tick, key.clone());
+        let entry = self.entries.get_mut::<K>(&key).unwrap();
+        entry.2 = tick;
+        Some(&entry.0)
+    }
+
+    /// Insert an entry, or replace the entry of the same key. Entries heavier than
+    /// the whole capacity are not cached.
+    pub(crate) fn insert(&mut self, key: K, value: V, weight: u64) {
+        self.remove(&key);
+        if weight > self.capacity {
+            return;
+        }
+        self.tick += 1;
+        self.weight += weight;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, weight, self.tick));
+        while self.weight > self.capacity {
+            let (_, oldest) = self.order.pop_first().unwrap();
+            let (_, weight, _) = self.entries.remove(&oldest).unwrap();
+            self.weight -= weight;
+        }
+    }
+
+    pub(crate) fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let (value, weight, last_used) = self.entries.remove(key)?;
+        self.order.remove(&last_used);
+        self.weight -= weight;
+        Some(value)
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+        self.weight = 0;
+    }
+}
@@ -0,0 +1,101 @@
+use crate::{KVStoreError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{rename, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter};
+use std::path::Path;
+
+const MANIFEST_FILE: &str = "MANIFEST";
+
+/** The state of an LsmKvsEngine which is not in its tables, saved in `MANIFEST`.
+
+It lists the tables of every level and the write-ahead log of the memtable. Tables and
+logs which the manifest does not list were left behind by an interrupted flush or
+compaction and are deleted when the engine is opened. The manifest also holds the named
+namespaces and their ids, which prefix the keys of the namespace in the tables.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(super) struct Manifest {
+    /// number of the next table or log file
+    pub(super) next_file: u64,
+    /// number of the log of the memtable
+    pub(super) wal: u64,
+    /// table ids of every level, oldest first in level 0 and by smallest key below
+    pub(super) levels: Vec<Vec<u64>>,
+    next_namespace: u64,
+    namespaces: BTreeMap<String, u64>,
+}
+
+impl Manifest {
+    /// Load the manifest of a directory, an empty one if the file does not exist.
+    pub(super) fn load(dir_path: &Path) -> Result<Manifest> {
+        match File::open(dir_path.join(MANIFEST_FILE)) {
+            Ok(file) => Ok(serde_json::from_reader(BufReader::new(file))?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Manifest {
+                next_file: 1,
+                wal: 0,
+                levels: vec![Vec::new()],
+                // 0 is the default namespace
+                next_namespace: 1,
+                namespaces: BTreeMap::new(),
+            }),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Return a new file number.
+    pub(super) fn new_file(&mut self) -> u64 {
+        self.next_file += 1;
+        self.next_file - 1
+    }
+
+    pub(super) fn namespace_id(&self, name: &str) -> Option<u64> {
+        self.namespaces.get(name).copied()
+    }
+
+    pub(super) fn namespace_names(&self) -> Vec<String> {
+        self.namespaces.keys().cloned().collect()
+    }
+
+    /// Return true if the namespace with the given id exists.
+    pub(super) fn is_live(&self, namespace_id: u64) -> bool {
+        namespace_id == 0 || self.namespaces.values().any(|id| *id == namespace_id)
+    }
+
+    /// Add a namespace. Return the id of the namespace.
+    pub(super) fn create_namespace(&mut self, name: &str) -> Result<u64> {
+        if self.namespaces.contains_key(name) {
+            return Err(KVStoreError::NamespaceExists(name.to_owned()));
+        }
+        let id = self.next_namespace;
+        self.next_namespace += 1;
+        self.namespaces.insert(name.to_owned(), id);
+        Ok(id)
+    }
+
+    /// Remove a namespace. Return the id of the namespace.
+    pub(super) fn drop_namespace(&mut self, name: &str) -> Result<u64> {
+        self.namespaces
+            .remove(name)
+            .ok_or_else(|| KVStoreError::NamespaceNotFound(name.to_owned()))
+    }
+
+    pub(super) fn save(&self, dir_path: &Path) -> Result<()> {
+        let path = dir_path.join(MANIFEST_FILE);
+        let temp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(
+            OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(&temp_path)?,
+        );
+        serde_json::to_writer(&mut writer, self)?;
+        writer
+            .into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()?;
+        rename(&temp_path, &path)?;
+        Ok(())
+    }
+}
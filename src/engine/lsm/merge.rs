@@ -0,0 +1,68 @@
+use super::table::Entry;
+use crate::Result;
+
+type Source<'a> = Box<dyn Iterator<Item = Result<Entry>> + 'a>;
+
+/** Merges sorted sources of entries into one sorted sequence.
+
+The sources are given newest first. When several sources hold a key, the entry of the
+newest one is returned and the others are skipped, so a tombstone hides the values
+of older sources. Tombstones are returned too.
+ */
+pub(super) struct MergeIter<'a> {
+    sources: Vec<Source<'a>>,
+    // the next entry of every source, None once the source is exhausted
+    heads: Vec<Option<Entry>>,
+}
+
+impl<'a> MergeIter<'a> {
+    pub(super) fn new(sources: Vec<Source<'a>>) -> Result<MergeIter<'a>> {
+        let heads = (0..sources.len()).map(|_| None).collect();
+        let mut iter = MergeIter { sources, heads };
+        for i in 0..iter.sources.len() {
+            iter.advance(i)?;
+        }
+        Ok(iter)
+    }
+
+    fn advance(&mut self, i: usize) -> Result<()> {
+        self.heads[i] = self.sources[i].next().transpose()?;
+        Ok(())
+    }
+}
+
+impl Iterator for MergeIter<'_> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // the newest source with the smallest key
+        let mut newest: Option<usize> = None;
+        for (i, head) in self.heads.iter().enumerate() {
+            if let Some((key, _)) = head {
+                if newest.map_or(true, |n| key < &self.heads[n].as_ref().unwrap().0) {
+                    newest = Some(i);
+                }
+            }
+        }
+        let newest = newest?;
+        let entry = self.heads[newest].take().unwrap();
+        let result = (0..self.sources.len()).try_for_each(|i| {
+            let is_same = i == newest
+                || self.heads[i]
+                    .as_ref()
+                    .map_or(false, |(key, _)| *key == entry.0);
+            if is_same {
+                self.advance(i)?;
+            }
+            Ok(())
+        });
+        match result {
+            Ok(()) => Some(Ok(entry)),
+            Err(err) => {
+                self.sources.clear();
+                self.heads.clear();
+                Some(Err(err))
+            }
+        }
+    }
+}
@@ -0,0 +1,660 @@
+use self::manifest::Manifest;
+use self::merge::MergeIter;
+use self::table::{table_path, Entry, Table, TableBuilder};
+use self::wal::{wal_path, Wal};
+use super::check_namespace_name;
+use crate::{DataFileStats, EngineStats, KVStoreError, KvsEngine, Result, SizeLimits};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{create_dir_all, metadata, read_dir, remove_file};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+mod manifest;
+mod merge;
+mod table;
+mod wal;
+
+// level 1 holds this many tables of `table_size`, every deeper level ten times more
+const LEVEL1_TABLES: u64 = 10;
+const LEVEL_SIZE_MULTIPLIER: u64 = 10;
+
+type Source = Box<dyn Iterator<Item = Result<Entry>>>;
+
+/// Options of an LsmKvsEngine.
+#[derive(Clone, Debug)]
+pub struct LsmOptions {
+    /// bytes written to the memtable before it is flushed into a level 0 table
+    pub memtable_size: u64,
+    /// bytes of a table written by compaction
+    pub table_size: u64,
+    /// number of level 0 tables which starts a compaction into level 1
+    pub level0_tables: usize,
+    /// bits per key of the bloom filter of a table, 10 give about 1% false positives
+    pub bloom_bits_per_key: u32,
+    /// largest keys and values a set takes
+    pub size_limits: SizeLimits,
+}
+
+impl Default for LsmOptions {
+    /// A 4MB memtable, 2MB tables, compaction at 4 level 0 tables, 10 bloom bits per key
+    /// and the default size limits.
+    fn default() -> Self {
+        LsmOptions {
+            memtable_size: 4 * 1024 * 1024,
+            table_size: 2 * 1024 * 1024,
+            level0_tables: 4,
+            bloom_bits_per_key: 10,
+            size_limits: SizeLimits::default(),
+        }
+    }
+}
+
+/** An LsmKvsEngine stores key/value pairs in a log-structured merge tree.
+
+Writes go to a write-ahead log and a sorted in-memory memtable. A full memtable is
+flushed into a sorted table file in level 0, and leveled compaction merges the tables
+of a level into the next one, whose size limit is ten times larger. Every table has a
+block index and a bloom filter, so a read of a missing key rarely touches the disk.
+Removed keys are kept as tombstones until compaction reaches the deepest level.
+# Example
+```
+use std::env;
+use kvs::{LsmKvsEngine, Result};
+use crate::kvs::KvsEngine;
+# fn try_main() -> Result<()> {
+
+let store = LsmKvsEngine::open(env::current_dir()?)?;
+
+store.set("1".to_owned(),"1".to_owned())?;
+assert_eq!(store.get("1".to_owned())?, Some("1".to_owned()));
+
+store.remove("1".to_owned())?;
+assert_eq!(store.get("1".to_owned())?, None);
+# Ok(())
+# }
+```
+ */
+#[derive(Clone)]
+pub struct LsmKvsEngine {
+    inner: Arc<Inner>,
+    // the namespace of this handle
+    namespace: Arc<Namespace>,
+}
+
+struct Namespace {
+    id: u64,
+    // None for the default namespace
+    name: Option<String>,
+    dropped: AtomicBool,
+}
+
+impl Namespace {
+    fn new(id: u64, name: Option<&str>) -> Arc<Namespace> {
+        Arc::new(Namespace {
+            id,
+            name: name.map(str::to_owned),
+            dropped: AtomicBool::new(false),
+        })
+    }
+
+    /// Return an error if the namespace was dropped.
+    fn check(&self) -> Result<()> {
+        if self.dropped.load(Ordering::SeqCst) {
+            return Err(KVStoreError::NamespaceNotFound(
+                self.name.clone().unwrap_or_default(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Return the key in the tables: the namespace id in big-endian, then the key.
+    fn internal_key(&self, key: &str) -> Vec<u8> {
+        let mut internal_key = self.id.to_be_bytes().to_vec();
+        internal_key.extend_from_slice(key.as_bytes());
+        internal_key
+    }
+}
+
+/// Return the namespace id of a key in the tables.
+fn namespace_id(internal_key: &[u8]) -> u64 {
+    let mut id = [0; 8];
+    id.copy_from_slice(&internal_key[..8]);
+    u64::from_be_bytes(id)
+}
+
+/// State shared by all handles.
+struct Inner {
+    dir_path: PathBuf,
+    options: LsmOptions,
+    // what reads look at, replaced by flushes and compactions
+    state: RwLock<State>,
+    // serializes writes, flushes and compactions
+    writer: Mutex<Writer>,
+}
+
+struct State {
+    memtable: BTreeMap<Vec<u8>, Option<String>>,
+    // bytes written to the memtable
+    memtable_size: u64,
+    // the tables of every level, oldest first in level 0 and by smallest key below
+    levels: Vec<Vec<Arc<Table>>>,
+}
+
+struct Writer {
+    wal: Wal,
+    manifest: Manifest,
+    // name -> namespace of the handles of every named namespace
+    namespaces: HashMap<String, Arc<Namespace>>,
+    compactions: u64,
+    compaction_time: Duration,
+}
+
+impl LsmKvsEngine {
+    /// Open the LsmKvsEngine at a given path. Return the LsmKvsEngine.
+    pub fn open(path: impl Into<PathBuf>) -> Result<LsmKvsEngine> {
+        Self::open_with_options(path, LsmOptions::default())
+    }
+
+    /// Open the LsmKvsEngine at a given path with the given options.
+    /// The memtable is rebuilt from its write-ahead log.
+    pub fn open_with_options(
+        path: impl Into<PathBuf>,
+        options: LsmOptions,
+    ) -> Result<LsmKvsEngine> {
+        let dir_path = path.into();
+        create_dir_all(&dir_path)?;
+        let manifest = Manifest::load(&dir_path)?;
+        remove_unused_files(&dir_path, &manifest)?;
+
+        let levels = manifest
+            .levels
+            .iter()
+            .map(|ids| {
+                ids.iter()
+                    .map(|id| Table::open(&dir_path, *id).map(Arc::new))
+                    .collect::<Result<Vec<_>>>()
+            })
+            .collect::<Result<_>>()?;
+        let (wal, entries) = Wal::open(&dir_path, manifest.wal)?;
+        let mut memtable = BTreeMap::new();
+        let mut memtable_size = 0;
+        for (key, value) in entries {
+            memtable_size += (key.len() + value.as_ref().map_or(0, String::len)) as u64;
+            memtable.insert(key, value);
+        }
+        let namespaces = manifest
+            .namespace_names()
+            .into_iter()
+            .filter_map(|name| {
+                let id = manifest.namespace_id(&name)?;
+                Some((name.clone(), Namespace::new(id, Some(&name))))
+            })
+            .collect();
+
+        Ok(LsmKvsEngine {
+            inner: Arc::new(Inner {
+                dir_path,
+                options,
+                state: RwLock::new(State {
+                    memtable,
+                    memtable_size,
+                    levels,
+                }),
+                writer: Mutex::new(Writer {
+                    wal,
+                    manifest,
+                    namespaces,
+                    compactions: 0,
+                    compaction_time: Duration::default(),
+                }),
+            }),
+            namespace: Namespace::new(0, None),
+        })
+    }
+
+    /// Write a value, or a tombstone for None. Flush the memtable once it is full.
+    fn write(&self, key: String, value: Option<String>) -> Result<()> {
+        let mut writer = self.inner.writer.lock().unwrap();
+        self.namespace.check()?;
+        let key = self.namespace.internal_key(&key);
+        if value.is_none() && self.inner.get(&key)?.is_none() {
+            return Err(KVStoreError::KeyNotFound);
+        }
+        writer.wal.append(&key, value.as_deref())?;
+        let is_full = {
+            let mut state = self.inner.state.write().unwrap();
+            state.memtable_size += (key.len() + value.as_ref().map_or(0, String::len)) as u64;
+            state.memtable.insert(key, value);
+            state.memtable_size >= self.inner.options.memtable_size
+        };
+        if is_full {
+            self.inner.flush(&mut writer)?;
+            self.inner.compact(&mut writer)?;
+        }
+        Ok(())
+    }
+
+    /// Return the live key/value pairs of the namespace of this handle in key order.
+    fn pairs(&self) -> Result<impl Iterator<Item = Result<(String, String)>>> {
+        self.namespace.check()?;
+        let prefix = self.namespace.id.to_be_bytes();
+        let (memtable, levels): (Vec<Entry>, _) = {
+            let state = self.inner.state.read().unwrap();
+            let memtable = state
+                .memtable
+                .range(prefix.to_vec()..)
+                .take_while(|(key, _)| key.starts_with(&prefix))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect();
+            (memtable, state.levels.clone())
+        };
+
+        let mut sources: Vec<Source> = vec![Box::new(memtable.into_iter().map(Ok))];
+        for table in levels[0].iter().rev() {
+            sources.push(Box::new(table.iter_from(&prefix)));
+        }
+        for tables in levels.into_iter().skip(1) {
+            let first = tables.partition_point(|table| table.largest.as_slice() < &prefix[..]);
+            sources.push(Box::new(
+                tables
+                    .into_iter()
+                    .skip(first)
+                    .flat_map(move |table| table.iter_from(&prefix)),
+            ));
+        }
+        Ok(MergeIter::new(sources)?
+            .take_while(move |entry| {
+                entry
+                    .as_ref()
+                    .map_or(true, |(key, _)| key.starts_with(&prefix))
+            })
+            .filter_map(|entry| match entry {
+                Ok((key, Some(value))) => Some(
+                    String::from_utf8(key[8..].to_vec())
+                        .map(|key| (key, value))
+                        .map_err(KVStoreError::from),
+                ),
+                Ok((_, None)) => None,
+                Err(err) => Some(Err(err)),
+            }))
+    }
+}
+
+/// Delete the tables and logs which the manifest does not list.
+fn remove_unused_files(dir_path: &Path, manifest: &Manifest) -> Result<()> {
+    let tables: HashSet<u64> = manifest.levels.iter().flatten().copied().collect();
+    for entry in read_dir(dir_path)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let file_name = match file_name.to_str() {
+            Some(file_name) => file_name,
+            None => continue,
+        };
+        let number = |prefix: &str, suffix: &str| {
+            file_name
+                .strip_prefix(prefix)
+                .and_then(|name| name.strip_suffix(suffix))
+                .and_then(|number| number.parse::<u64>().ok())
+        };
+        let is_unused = match (number("sst_", ".sst"), number("wal_", ".log")) {
+            (Some(id), _) => !tables.contains(&id),
+            (_, Some(number)) => number != manifest.wal,
+            _ => false,
+        };
+        if is_unused {
+            remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+impl Inner {
+    /// Return the value of a key in the tables, looking at newer data first.
+    fn get(&self, key: &[u8]) -> Result<Option<String>> {
+        let state = self.state.read().unwrap();
+        if let Some(value) = state.memtable.get(key) {
+            return Ok(value.clone());
+        }
+        // level 0 tables may overlap, the newest is the last one
+        for table in state.levels[0].iter().rev() {
+            if let Some(value) = table.get(key)? {
+                return Ok(value);
+            }
+        }
+        for tables in &state.levels[1..] {
+            let i = tables.partition_point(|table| table.largest.as_slice() < key);
+            if let Some(table) = tables.get(i) {
+                if let Some(value) = table.get(key)? {
+                    return Ok(value);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Write sorted entries into new tables of about `table_size` bytes.
+    fn write_tables(
+        &self,
+        manifest: &mut Manifest,
+        entries: impl Iterator<Item = Result<Entry>>,
+    ) -> Result<Vec<Arc<Table>>> {
+        let mut tables = Vec::new();
+        let mut builder: Option<(u64, TableBuilder)> = None;
+        for entry in entries {
+            let (key, value) = entry?;
+            let (_, table) = match builder.as_mut() {
+                Some(builder) => builder,
+                None => {
+                    let id = manifest.new_file();
+                    let table =
+                        TableBuilder::new(&self.dir_path, id, self.options.bloom_bits_per_key)?;
+                    builder.insert((id, table))
+                }
+            };
+            table.add(&key, value.as_deref())?;
+            if table.size() >= self.options.table_size {
+                let (id, table) = builder.take().unwrap();
+                table.finish()?;
+                tables.push(Arc::new(Table::open(&self.dir_path, id)?));
+            }
+        }
+        if let Some((id, table)) = builder {
+            table.finish()?;
+            tables.push(Arc::new(Table::open(&self.dir_path, id)?));
+        }
+        Ok(tables)
+    }
+
+    /// Write the memtable into level 0 and start a new write-ahead log.
+    fn flush(&self, writer: &mut Writer) -> Result<()> {
+        let entries: Vec<Entry> = {
+            let state = self.state.read().unwrap();
+            state
+                .memtable
+                .iter()
+                .filter(|(key, _)| writer.manifest.is_live(namespace_id(key)))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect()
+        };
+        let mut manifest = writer.manifest.clone();
+        let tables = self.write_tables(&mut manifest, entries.into_iter().map(Ok))?;
+        manifest.levels[0].extend(tables.iter().map(|table| table.id));
+        let wal_number = manifest.new_file();
+        let wal = Wal::create(&self.dir_path, wal_number)?;
+        let old_wal = std::mem::replace(&mut manifest.wal, wal_number);
+        manifest.save(&self.dir_path)?;
+        writer.manifest = manifest;
+        writer.wal = wal;
+
+        {
+            let mut state = self.state.write().unwrap();
+            state.levels[0].extend(tables);
+            state.memtable.clear();
+            state.memtable_size = 0;
+        }
+        remove_file(wal_path(&self.dir_path, old_wal))?;
+        Ok(())
+    }
+
+    /// Compact levels until every level is within its limit.
+    fn compact(&self, writer: &mut Writer) -> Result<()> {
+        while let Some((level, inputs)) = self.pick_compaction() {
+            let start = Instant::now();
+            self.compact_level(writer, level, inputs)?;
+            writer.compactions += 1;
+            writer.compaction_time += start.elapsed();
+        }
+        Ok(())
+    }
+
+    /// Return a level which is over its limit and the tables of it to merge into the next level.
+    fn pick_compaction(&self) -> Option<(usize, Vec<Arc<Table>>)> {
+        let state = self.state.read().unwrap();
+        if state.levels[0].len() >= self.options.level0_tables {
+            return Some((0, state.levels[0].clone()));
+        }
+        let mut max_size = self.options.table_size * LEVEL1_TABLES;
+        for (level, tables) in state.levels.iter().enumerate().skip(1) {
+            if tables.iter().map(|table| table.size()).sum::<u64>() > max_size {
+                return Some((level, vec![Arc::clone(&tables[0])]));
+            }
+            max_size *= LEVEL_SIZE_MULTIPLIER;
+        }
+        None
+    }
+
+    /** Merge tables of a level with the overlapping tables of the next level into new
+    tables of the next level.
+
+    Entries of dropped namespaces are discarded, and so are tombstones when no deeper
+    level may hold an older value of their key.
+     */
+    fn compact_level(
+        &self,
+        writer: &mut Writer,
+        level: usize,
+        inputs: Vec<Arc<Table>>,
+    ) -> Result<()> {
+        let smallest = inputs
+            .iter()
+            .map(|table| &table.smallest)
+            .min()
+            .unwrap()
+            .clone();
+        let largest = inputs
+            .iter()
+            .map(|table| &table.largest)
+            .max()
+            .unwrap()
+            .clone();
+        let (overlapping, is_bottom) = {
+            let state = self.state.read().unwrap();
+            let overlapping: Vec<Arc<Table>> = state
+                .levels
+                .get(level + 1)
+                .into_iter()
+                .flatten()
+                .filter(|table| table.overlaps(&smallest, &largest))
+                .cloned()
+                .collect();
+            let is_bottom = state
+                .levels
+                .iter()
+                .skip(level + 2)
+                .flatten()
+                .all(|table| !table.overlaps(&smallest, &largest));
+            (overlapping, is_bottom)
+        };
+
+        // newest first: the inputs are newer than the next level
+        let mut sources: Vec<Source> = Vec::new();
+        for table in inputs.iter().rev() {
+            sources.push(Box::new(table.iter_from(&[])));
+        }
+        let next_level = overlapping.clone();
+        sources.push(Box::new(
+            next_level
+                .into_iter()
+                .flat_map(|table| table.iter_from(&[])),
+        ));
+        let live = &writer.manifest;
+        let entries = MergeIter::new(sources)?.filter(|entry| match entry {
+            Ok((key, value)) => (value.is_some() || !is_bottom) && live.is_live(namespace_id(key)),
+            Err(_) => true,
+        });
+        let mut manifest = writer.manifest.clone();
+        let tables = self.write_tables(&mut manifest, entries)?;
+
+        let removed: HashSet<u64> = inputs
+            .iter()
+            .chain(&overlapping)
+            .map(|table| table.id)
+            .collect();
+        {
+            let mut state = self.state.write().unwrap();
+            let mut levels = state.levels.clone();
+            if levels.len() == level + 1 {
+                levels.push(Vec::new());
+            }
+            for tables in levels.iter_mut() {
+                tables.retain(|table| !removed.contains(&table.id));
+            }
+            levels[level + 1].extend(tables);
+            levels[level + 1].sort_by(|a, b| a.smallest.cmp(&b.smallest));
+            manifest.levels = levels
+                .iter()
+                .map(|tables| tables.iter().map(|table| table.id).collect())
+                .collect();
+            manifest.save(&self.dir_path)?;
+            state.levels = levels;
+        }
+        writer.manifest = manifest;
+        for id in removed {
+            remove_file(table_path(&self.dir_path, id))?;
+        }
+        Ok(())
+    }
+}
+
+impl KvsEngine for LsmKvsEngine {
+    /// Set the value of a string key to a string.
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.inner.options.size_limits.check(&key, &value)?;
+        self.write(key, Some(value))
+    }
+
+    /// Get the string value of a string key. If the key does not exist, return None.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.namespace.check()?;
+        self.inner.get(&self.namespace.internal_key(&key))
+    }
+
+    /// Remove a given key by writing a tombstone. Return an error if the key does not exist.
+    fn remove(&self, key: String) -> Result<()> {
+        self.write(key, None)
+    }
+
+    /// Call `f` with every key/value pair in the store, in key order.
+    fn scan<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        for pair in self.pairs()? {
+            let (key, value) = pair?;
+            f(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Return the statistics of the store. Every table is listed as a data file.
+    fn stats(&self) -> Result<EngineStats> {
+        let keys = self
+            .pairs()?
+            .try_fold(0, |keys, pair| pair.map(|_| keys + 1))?;
+        // hold the writer so that no compaction deletes files in the meantime
+        let writer = self.inner.writer.lock().unwrap();
+        let state = self.inner.state.read().unwrap();
+        let mut data_files: Vec<DataFileStats> = state
+            .levels
+            .iter()
+            .flatten()
+            .map(|table| DataFileStats {
+                file_number: table.id,
+                size: table.size(),
+            })
+            .collect();
+        data_files.sort_by_key(|file| file.file_number);
+        let wal_size = metadata(wal_path(&self.inner.dir_path, writer.manifest.wal))?.len();
+        Ok(EngineStats {
+            engine: "lsm".to_owned(),
+            keys,
+            disk_size: data_files.iter().map(|file| file.size).sum::<u64>() + wal_size,
+            data_files,
+            garbage_bytes: None,
+            compactions: Some(writer.compactions),
+            compaction_millis: Some(writer.compaction_time.as_millis() as u64),
+            compaction_paused: None,
+            read_only: None,
+            open_readers: None,
+            mapped_files: Some(state.levels.iter().map(|tables| tables.len() as u64).sum()),
+            cache_hits: None,
+            cache_misses: None,
+            bloom_skips: None,
+            bloom_false_positives: None,
+            bloom_false_positive_rate: None,
+            blob_bytes: None,
+            blob_garbage_bytes: None,
+            blob_collections: None,
+        })
+    }
+
+    /// Return a handle to the keys of the namespace.
+    fn namespace(&self, name: &str) -> Result<LsmKvsEngine> {
+        let namespace = self
+            .inner
+            .writer
+            .lock()
+            .unwrap()
+            .namespaces
+            .get(name)
+            .cloned()
+            .ok_or_else(|| KVStoreError::NamespaceNotFound(name.to_owned()))?;
+        Ok(LsmKvsEngine {
+            inner: Arc::clone(&self.inner),
+            namespace,
+        })
+    }
+
+    /// Create an empty namespace, which gets a new id.
+    fn create_namespace(&self, name: &str) -> Result<()> {
+        check_namespace_name(name)?;
+        let mut writer = self.inner.writer.lock().unwrap();
+        let mut manifest = writer.manifest.clone();
+        let id = manifest.create_namespace(name)?;
+        manifest.save(&self.inner.dir_path)?;
+        writer.manifest = manifest;
+        writer
+            .namespaces
+            .insert(name.to_owned(), Namespace::new(id, Some(name)));
+        Ok(())
+    }
+
+    /// Drop a namespace. Compaction discards its keys from the tables it merges.
+    fn drop_namespace(&self, name: &str) -> Result<()> {
+        let mut writer = self.inner.writer.lock().unwrap();
+        let mut manifest = writer.manifest.clone();
+        manifest.drop_namespace(name)?;
+        manifest.save(&self.inner.dir_path)?;
+        writer.manifest = manifest;
+        if let Some(namespace) = writer.namespaces.remove(name) {
+            namespace.dropped.store(true, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    /// Return the names of the namespaces.
+    fn list_namespaces(&self) -> Result<Vec<String>> {
+        Ok(self.inner.writer.lock().unwrap().manifest.namespace_names())
+    }
+
+    /// Write the memtable into level 0, then merge the levels which are over their limits.
+    fn compact(&self) -> Result<()> {
+        let mut writer = self.inner.writer.lock().unwrap();
+        if !self.inner.state.read().unwrap().memtable.is_empty() {
+            self.inner.flush(&mut writer)?;
+        }
+        self.inner.compact(&mut writer)
+    }
+
+    /// Return an error for a pause, since level compactions are part of the writes.
+    fn set_compaction_paused(&self, paused: bool) -> Result<()> {
+        if paused {
+            return Err(KVStoreError::CommonStringError(
+                "the lsm engine does not pause compaction".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
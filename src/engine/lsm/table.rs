@@ -0,0 +1,356 @@
+use crate::engine::bloom::BloomFilter;
+use crate::{KVStoreError, Result};
+use memmap2::Mmap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const MAGIC: &[u8; 8] = b"KVSLSMT1";
+// index offset and length, bloom offset and length, number of entries, magic
+const FOOTER_SIZE: usize = 5 * 8 + 8;
+// a block is written once its entries take at least this many bytes
+const BLOCK_SIZE: usize = 4096;
+
+const VALUE: u8 = 0;
+const TOMBSTONE: u8 = 1;
+
+/// A key with its value, None for a removed key.
+pub(super) type Entry = (Vec<u8>, Option<String>);
+
+/// Return the path of the table with the given id.
+pub(super) fn table_path(dir_path: &Path, id: u64) -> PathBuf {
+    dir_path.join(format!("sst_{}.sst", id))
+}
+
+/// Append an entry: the key length as a u32 and the key, a kind byte, then for a value
+/// its length as a u32 and the value.
+pub(super) fn encode_entry(buf: &mut Vec<u8>, key: &[u8], value: Option<&str>) {
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(key);
+    match value {
+        Some(value) => {
+            buf.push(VALUE);
+            buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            buf.extend_from_slice(value.as_bytes());
+        }
+        None => buf.push(TOMBSTONE),
+    }
+}
+
+/// Decode the entry at `*pos` and move `*pos` after it.
+pub(super) fn decode_entry(buf: &[u8], pos: &mut usize) -> Result<Entry> {
+    let key_len = read_u32(buf, pos)? as usize;
+    let key = take(buf, pos, key_len)?.to_vec();
+    let value = match take(buf, pos, 1)?[0] {
+        VALUE => {
+            let value_len = read_u32(buf, pos)? as usize;
+            Some(String::from_utf8(take(buf, pos, value_len)?.to_vec())?)
+        }
+        TOMBSTONE => None,
+        kind => return Err(corrupt(format!("unknown entry kind {}", kind))),
+    };
+    Ok((key, value))
+}
+
+fn take<'a>(buf: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8]> {
+    let bytes = buf
+        .get(*pos..*pos + len)
+        .ok_or_else(|| corrupt("truncated entry".to_owned()))?;
+    *pos += len;
+    Ok(bytes)
+}
+
+fn read_u32(buf: &[u8], pos: &mut usize) -> Result<u32> {
+    let bytes = take(buf, pos, 4)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn read_u64(buf: &[u8], pos: &mut usize) -> Result<u64> {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(take(buf, pos, 8)?);
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn corrupt(reason: String) -> KVStoreError {
+    KVStoreError::CorruptRecord(reason)
+}
+
+/// Where a data block is and the last key in it.
+struct BlockHandle {
+    last_key: Vec<u8>,
+    offset: usize,
+    length: usize,
+}
+
+/** A sorted string table: an immutable file of sorted entries, read through a memory map.
+
+The file holds data blocks of entries, then an index block with the last key and the
+location of every data block, then a bloom filter of the keys and a fixed-size footer.
+Every block ends with the crc32 of its bytes.
+ */
+pub(super) struct Table {
+    pub(super) id: u64,
+    map: Mmap,
+    index: Vec<BlockHandle>,
+    bloom: BloomFilter,
+    pub(super) smallest: Vec<u8>,
+    pub(super) largest: Vec<u8>,
+}
+
+impl Table {
+    pub(super) fn open(dir_path: &Path, id: u64) -> Result<Table> {
+        let file = File::open(table_path(dir_path, id))?;
+        // SAFETY: a table is never written again once it is complete, only deleted
+        let map = unsafe { Mmap::map(&file)? };
+        if map.len() < FOOTER_SIZE || &map[map.len() - MAGIC.len()..] != MAGIC {
+            return Err(corrupt(format!("table {} has no footer", id)));
+        }
+        let mut pos = map.len() - FOOTER_SIZE;
+        let index_offset = read_u64(&map, &mut pos)? as usize;
+        let index_length = read_u64(&map, &mut pos)? as usize;
+        let bloom_offset = read_u64(&map, &mut pos)? as usize;
+        let bloom_length = read_u64(&map, &mut pos)? as usize;
+
+        let index_block = checked_block(&map, index_offset, index_length)?;
+        let mut index = Vec::new();
+        let mut pos = 0;
+        while pos < index_block.len() {
+            let key_len = read_u32(index_block, &mut pos)? as usize;
+            let last_key = take(index_block, &mut pos, key_len)?.to_vec();
+            let offset = read_u64(index_block, &mut pos)? as usize;
+            let length = read_u64(index_block, &mut pos)? as usize;
+            index.push(BlockHandle {
+                last_key,
+                offset,
+                length,
+            });
+        }
+        let bloom = BloomFilter::from_bytes(checked_block(&map, bloom_offset, bloom_length)?)?;
+
+        let (first, last) = match (index.first(), index.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return Err(corrupt(format!("table {} is empty", id))),
+        };
+        let smallest = decode_entry(checked_block(&map, first.offset, first.length)?, &mut 0)?.0;
+        let largest = last.last_key.clone();
+        Ok(Table {
+            id,
+            map,
+            index,
+            bloom,
+            smallest,
+            largest,
+        })
+    }
+
+    pub(super) fn size(&self) -> u64 {
+        self.map.len() as u64
+    }
+
+    /// Return the entry of a key, None if the table does not hold the key.
+    pub(super) fn get(&self, key: &[u8]) -> Result<Option<Option<String>>> {
+        if key < self.smallest.as_slice()
+            || key > self.largest.as_slice()
+            || !self.bloom.may_contain(key)
+        {
+            return Ok(None);
+        }
+        let i = self
+            .index
+            .partition_point(|handle| handle.last_key.as_slice() < key);
+        let handle = match self.index.get(i) {
+            Some(handle) => handle,
+            None => return Ok(None),
+        };
+        let block = checked_block(&self.map, handle.offset, handle.length)?;
+        let mut pos = 0;
+        while pos < block.len() {
+            let (entry_key, value) = decode_entry(block, &mut pos)?;
+            if entry_key == key {
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Return true if the table may hold keys between `smallest` and `largest`.
+    pub(super) fn overlaps(&self, smallest: &[u8], largest: &[u8]) -> bool {
+        self.smallest.as_slice() <= largest && self.largest.as_slice() >= smallest
+    }
+
+    /// Iterate over the entries from the first key which is not less than `start`.
+    pub(super) fn iter_from(self: &Arc<Self>, start: &[u8]) -> TableIter {
+        let block = self
+            .index
+            .partition_point(|handle| handle.last_key.as_slice() < start);
+        TableIter {
+            table: Arc::clone(self),
+            start: start.to_vec(),
+            block,
+            entries: Vec::new().into_iter(),
+        }
+    }
+}
+
+/// Return the bytes of a block after checking its crc.
+fn checked_block(map: &[u8], offset: usize, length: usize) -> Result<&[u8]> {
+    let block = map
+        .get(offset..offset + length)
+        .filter(|block| block.len() >= 4)
+        .ok_or_else(|| corrupt("block is beyond the end of the table".to_owned()))?;
+    let (data, crc) = block.split_at(block.len() - 4);
+    if crc32fast::hash(data).to_le_bytes() != crc {
+        return Err(corrupt("block checksum mismatch".to_owned()));
+    }
+    Ok(data)
+}
+
+/// The entries of a table in key order, decoded one block at a time.
+pub(super) struct TableIter {
+    table: Arc<Table>,
+    start: Vec<u8>,
+    // the next block to decode
+    block: usize,
+    entries: std::vec::IntoIter<Entry>,
+}
+
+impl TableIter {
+    fn next_block(&mut self) -> Result<bool> {
+        let handle = match self.table.index.get(self.block) {
+            Some(handle) => handle,
+            None => return Ok(false),
+        };
+        self.block += 1;
+        let block = checked_block(&self.table.map, handle.offset, handle.length)?;
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        while pos < block.len() {
+            let entry = decode_entry(block, &mut pos)?;
+            if entry.0 >= self.start {
+                entries.push(entry);
+            }
+        }
+        self.entries = entries.into_iter();
+        Ok(true)
+    }
+}
+
+impl Iterator for TableIter {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                return Some(Ok(entry));
+            }
+            match self.next_block() {
+                Ok(true) => {}
+                Ok(false) => return None,
+                Err(err) => {
+                    // stop after an error
+                    self.block = self.table.index.len();
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
+
+/// Writes the entries of a new table, which must be added in key order.
+pub(super) struct TableBuilder {
+    writer: BufWriter<File>,
+    offset: usize,
+    block: Vec<u8>,
+    last_key: Vec<u8>,
+    index: Vec<u8>,
+    keys: Vec<Vec<u8>>,
+    bloom_bits_per_key: u32,
+}
+
+impl TableBuilder {
+    pub(super) fn new(dir_path: &Path, id: u64, bloom_bits_per_key: u32) -> Result<TableBuilder> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(table_path(dir_path, id))?;
+        Ok(TableBuilder {
+            writer: BufWriter::new(file),
+            offset: 0,
+            block: Vec::new(),
+            last_key: Vec::new(),
+            index: Vec::new(),
+            keys: Vec::new(),
+            bloom_bits_per_key,
+        })
+    }
+
+    pub(super) fn add(&mut self, key: &[u8], value: Option<&str>) -> Result<()> {
+        encode_entry(&mut self.block, key, value);
+        self.last_key = key.to_vec();
+        self.keys.push(key.to_vec());
+        if self.block.len() >= BLOCK_SIZE {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    /// Return the bytes written so far.
+    pub(super) fn size(&self) -> u64 {
+        (self.offset + self.block.len()) as u64
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let block = std::mem::take(&mut self.block);
+        let (offset, length) = self.write_block(&block)?;
+        self.index
+            .extend_from_slice(&(self.last_key.len() as u32).to_le_bytes());
+        self.index.extend_from_slice(&self.last_key);
+        self.index.extend_from_slice(&(offset as u64).to_le_bytes());
+        self.index.extend_from_slice(&(length as u64).to_le_bytes());
+        Ok(())
+    }
+
+    /// Write a block with its crc. Return its offset and length.
+    fn write_block(&mut self, block: &[u8]) -> Result<(usize, usize)> {
+        let offset = self.offset;
+        self.writer.write_all(block)?;
+        self.writer
+            .write_all(&crc32fast::hash(block).to_le_bytes())?;
+        self.offset += block.len() + 4;
+        Ok((offset, block.len() + 4))
+    }
+
+    /// Write the index, the bloom filter and the footer, then sync the file.
+    pub(super) fn finish(mut self) -> Result<()> {
+        self.finish_block()?;
+        let index = std::mem::take(&mut self.index);
+        let (index_offset, index_length) = self.write_block(&index)?;
+
+        let mut bloom = BloomFilter::new(self.keys.len(), self.bloom_bits_per_key);
+        for key in &self.keys {
+            bloom.insert(key);
+        }
+        let (bloom_offset, bloom_length) = self.write_block(&bloom.to_bytes())?;
+
+        for field in [
+            index_offset,
+            index_length,
+            bloom_offset,
+            bloom_length,
+            self.keys.len(),
+        ] {
+            self.writer.write_all(&(field as u64).to_le_bytes())?;
+        }
+        self.writer.write_all(MAGIC)?;
+        self.writer
+            .into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,87 @@
+use super::table::{decode_entry, encode_entry, Entry};
+use crate::Result;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Return the path of the write-ahead log with the given number.
+pub(super) fn wal_path(dir_path: &Path, number: u64) -> PathBuf {
+    dir_path.join(format!("wal_{}.log", number))
+}
+
+/** The write-ahead log of the memtable.
+
+Every write is appended as the length and crc32 of the entry followed by the entry,
+before it goes into the memtable. A memtable which was not flushed into a table is
+rebuilt from its log when the engine is opened.
+ */
+pub(super) struct Wal {
+    file: File,
+}
+
+impl Wal {
+    /// Create an empty log.
+    pub(super) fn create(dir_path: &Path, number: u64) -> Result<Wal> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(wal_path(dir_path, number))?;
+        Ok(Wal { file })
+    }
+
+    /** Read the entries of a log and open it for appending.
+
+    A write which was torn by a crash leaves a short or mismatched record at the end
+    of the log. Reading stops there and the log is truncated after the last whole record.
+     */
+    pub(super) fn open(dir_path: &Path, number: u64) -> Result<(Wal, Vec<Entry>)> {
+        let path = wal_path(dir_path, number);
+        let mut file = match OpenOptions::new().read(true).append(true).open(&path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok((Wal::create(dir_path, number)?, Vec::new()))
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        while let Some((entry, next)) = read_record(&buf, pos) {
+            entries.push(entry);
+            pos = next;
+        }
+        if pos < buf.len() {
+            file.set_len(pos as u64)?;
+            file.sync_all()?;
+        }
+        Ok((Wal { file }, entries))
+    }
+
+    /// Append an entry. It survives a crash of the process once this returns.
+    pub(super) fn append(&mut self, key: &[u8], value: Option<&str>) -> Result<()> {
+        let mut entry = Vec::new();
+        encode_entry(&mut entry, key, value);
+        let mut record = Vec::with_capacity(entry.len() + 8);
+        record.extend_from_slice(&(entry.len() as u32).to_le_bytes());
+        record.extend_from_slice(&crc32fast::hash(&entry).to_le_bytes());
+        record.extend_from_slice(&entry);
+        self.file.write_all(&record)?;
+        Ok(())
+    }
+}
+
+/// Return the whole record at `pos` and the position after it.
+fn read_record(buf: &[u8], pos: usize) -> Option<(Entry, usize)> {
+    let header = buf.get(pos..pos + 8)?;
+    let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let crc = [header[4], header[5], header[6], header[7]];
+    let entry = buf.get(pos + 8..pos + 8 + len)?;
+    if crc32fast::hash(entry).to_le_bytes() != crc {
+        return None;
+    }
+    let entry = decode_entry(entry, &mut 0).ok()?;
+    Some((entry, pos + 8 + len))
+}
@@ -0,0 +1,191 @@
+use super::check_namespace_name;
+use crate::{EngineStats, KVStoreError, KvsEngine, Result, SizeLimits};
+use dashmap::DashMap;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/** A MemoryKvsEngine stores key/value pairs in memory only, without any file.
+Everything is lost once the last handle is dropped, which suits tests and caches.
+# Example
+```
+use kvs::{MemoryKvsEngine, Result};
+use crate::kvs::KvsEngine;
+# fn try_main() -> Result<()> {
+
+let store = MemoryKvsEngine::new();
+
+store.set("1".to_owned(),"1".to_owned())?;
+assert_eq!(store.get("1".to_owned())?, Some("1".to_owned()));
+
+store.remove("1".to_owned())?;
+assert_eq!(store.get("1".to_owned())?, None);
+# Ok(())
+# }
+```
+ */
+#[derive(Clone, Default)]
+pub struct MemoryKvsEngine {
+    // the keys of the namespace of this handle
+    keys: Arc<Keys>,
+    // name -> keys of every namespace, shared by all handles
+    namespaces: Arc<Mutex<BTreeMap<String, Arc<Keys>>>>,
+    size_limits: SizeLimits,
+}
+
+#[derive(Default)]
+struct Keys {
+    // None for the default namespace
+    name: Option<String>,
+    map: DashMap<String, String>,
+    dropped: AtomicBool,
+}
+
+impl MemoryKvsEngine {
+    /// Create an empty MemoryKvsEngine.
+    pub fn new() -> MemoryKvsEngine {
+        MemoryKvsEngine::default()
+    }
+
+    /// Create an empty MemoryKvsEngine whose sets take keys and values up to the limits.
+    pub fn with_size_limits(size_limits: SizeLimits) -> MemoryKvsEngine {
+        MemoryKvsEngine {
+            size_limits,
+            ..MemoryKvsEngine::default()
+        }
+    }
+
+    /// Return the keys of this handle, or an error if its namespace was dropped.
+    fn keys(&self) -> Result<&DashMap<String, String>> {
+        if self.keys.dropped.load(Ordering::SeqCst) {
+            return Err(KVStoreError::NamespaceNotFound(
+                self.keys.name.clone().unwrap_or_default(),
+            ));
+        }
+        Ok(&self.keys.map)
+    }
+}
+
+impl KvsEngine for MemoryKvsEngine {
+    /// Set the value of a string key to a string.
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.size_limits.check(&key, &value)?;
+        self.keys()?.insert(key, value);
+        Ok(())
+    }
+
+    /// Get the string value of a string key. If the key does not exist, return None.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.keys()?.get(&key).map(|value| value.clone()))
+    }
+
+    /// Remove a given key. Return an error if the key does not exist.
+    fn remove(&self, key: String) -> Result<()> {
+        self.keys()?
+            .remove(&key)
+            .map(|_| ())
+            .ok_or(KVStoreError::KeyNotFound)
+    }
+
+    /// Call `f` with every key/value pair in the store.
+    fn scan<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        // copy the pairs first, so that `f` may change the store
+        let pairs: Vec<(String, String)> = self
+            .keys()?
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+        for (key, value) in pairs {
+            f(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Return the statistics of the store.
+    fn stats(&self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            engine: "memory".to_owned(),
+            keys: self.keys()?.len() as u64,
+            disk_size: 0,
+            data_files: Vec::new(),
+            garbage_bytes: None,
+            compactions: None,
+            compaction_millis: None,
+            compaction_paused: None,
+            read_only: None,
+            open_readers: None,
+            mapped_files: None,
+            cache_hits: None,
+            cache_misses: None,
+            bloom_skips: None,
+            bloom_false_positives: None,
+            bloom_false_positive_rate: None,
+            blob_bytes: None,
+            blob_garbage_bytes: None,
+            blob_collections: None,
+        })
+    }
+
+    /// Return a handle to the keys of the namespace.
+    fn namespace(&self, name: &str) -> Result<MemoryKvsEngine> {
+        let keys = self
+            .namespaces
+            .lock()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| KVStoreError::NamespaceNotFound(name.to_owned()))?;
+        Ok(MemoryKvsEngine {
+            keys,
+            namespaces: Arc::clone(&self.namespaces),
+            size_limits: self.size_limits,
+        })
+    }
+
+    /// Create an empty namespace.
+    fn create_namespace(&self, name: &str) -> Result<()> {
+        check_namespace_name(name)?;
+        let mut namespaces = self.namespaces.lock().unwrap();
+        if namespaces.contains_key(name) {
+            return Err(KVStoreError::NamespaceExists(name.to_owned()));
+        }
+        namespaces.insert(
+            name.to_owned(),
+            Arc::new(Keys {
+                name: Some(name.to_owned()),
+                ..Keys::default()
+            }),
+        );
+        Ok(())
+    }
+
+    /// Drop a namespace. Its keys are freed with the last handle of the namespace.
+    fn drop_namespace(&self, name: &str) -> Result<()> {
+        let keys = self
+            .namespaces
+            .lock()
+            .unwrap()
+            .remove(name)
+            .ok_or_else(|| KVStoreError::NamespaceNotFound(name.to_owned()))?;
+        keys.dropped.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Return the names of the namespaces.
+    fn list_namespaces(&self) -> Result<Vec<String>> {
+        Ok(self.namespaces.lock().unwrap().keys().cloned().collect())
+    }
+
+    /// Do nothing, since the engine keeps no garbage.
+    fn compact(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Do nothing, since the engine never compacts.
+    fn set_compaction_paused(&self, _paused: bool) -> Result<()> {
+        Ok(())
+    }
+}
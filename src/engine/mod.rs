@@ -1,16 +1,34 @@
-use crate::Result;
+use crate::{KVStoreError, Result};
+use log::info;
 use serde::{Deserialize, Serialize};
 
+mod bloom;
+mod btree;
 mod kv;
+mod lru;
+mod lsm;
+mod memory;
 mod sled;
 
-pub use self::kv::KvStore;
+pub use self::btree::{BTreeKvsEngine, BTreeOptions};
+pub use self::kv::{dump, dump_encrypted, RecordInfo};
+pub use self::kv::{repair, repair_encrypted, FileRepair, RepairReport};
+pub use self::kv::{
+    upgrade, upgrade_encrypted, upgrade_with_storage, UpgradeReport, FORMAT_VERSION,
+};
+pub use self::kv::{verify, verify_encrypted, CorruptRange, FileReport, KvStore, VerifyReport};
+pub use self::kv::{CompactionOptions, CompactionWindow};
+pub use self::kv::{Compression, Encryption, IndexMode, KvStoreOptions, ShardedKvStore};
+pub use self::kv::{DiskStorage, Faults, FileMap, MemoryStorage, Storage, StorageFile};
+pub use self::lsm::{LsmKvsEngine, LsmOptions};
+pub use self::memory::MemoryKvsEngine;
 pub use self::sled::SledKvsEngine;
 
 /// A trait which supports pluggable storage engines
 pub trait KvsEngine: Clone + Send + 'static {
     /// Set the value of a string key to a string.
-    /// Return an error if the value is not written successfully.
+    /// Return an error if the key or value is larger than the size limits of the engine,
+    /// or if the value is not written successfully.
     fn set(&self, key: String, value: String) -> Result<()>;
     /// Get the string value of a string key. If the key does not exist, return None.
     /// Return an error if the value is not read successfully.
@@ -18,6 +36,164 @@ pub trait KvsEngine: Clone + Send + 'static {
     /// Remove a given string key.
     /// Return an error if the key does not exit or value is not read successfully.
     fn remove(&self, key: String) -> Result<()>;
+    /// Call `f` with every key/value pair in the engine, in no particular order.
+    /// Return an error if a pair is not read successfully or `f` returns an error.
+    fn scan<F>(&self, f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>;
+    /// Return the statistics of the engine.
+    /// Return an error if the statistics are not read successfully.
+    fn stats(&self) -> Result<EngineStats>;
+    /// Return a handle whose keys are those of the namespace `name`.
+    /// Handles returned by `open` work on the default namespace, which has no name.
+    /// Return an error if the namespace does not exist.
+    fn namespace(&self, name: &str) -> Result<Self>;
+    /// Create an empty namespace. Return an error if it already exists.
+    fn create_namespace(&self, name: &str) -> Result<()>;
+    /// Drop a namespace with all of its keys. Handles of the namespace fail afterwards.
+    /// Return an error if the namespace does not exist.
+    fn drop_namespace(&self, name: &str) -> Result<()>;
+    /// Return the names of all namespaces in ascending order.
+    fn list_namespaces(&self) -> Result<Vec<String>>;
+    /// Compact the data of the engine now, whatever its schedule says.
+    /// Return an error if the compaction fails.
+    fn compact(&self) -> Result<()>;
+    /// Pause or resume the compactions the engine starts by itself.
+    /// Return an error for an engine whose compactions can not be paused.
+    fn set_compaction_paused(&self, paused: bool) -> Result<()>;
+}
+
+/// Return an error for a name which can not name a namespace.
+fn check_namespace_name(name: &str) -> Result<()> {
+    if name.is_empty() {
+        return Err(KVStoreError::CommonStringError(
+            "a namespace name must not be empty".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+/// Largest keys and values an engine stores, in bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SizeLimits {
+    /// bytes of a key
+    pub max_key_size: usize,
+    /// bytes of a value
+    pub max_value_size: usize,
+}
+
+impl Default for SizeLimits {
+    /// Keys of 64KB and values of 64MB.
+    fn default() -> Self {
+        SizeLimits {
+            max_key_size: 64 * 1024,
+            max_value_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+impl SizeLimits {
+    /// Return an error if the key or the value is larger than the limits.
+    pub fn check(&self, key: &str, value: &str) -> Result<()> {
+        if key.len() > self.max_key_size {
+            return Err(KVStoreError::KeyTooLarge(key.len(), self.max_key_size));
+        }
+        if value.len() > self.max_value_size {
+            return Err(KVStoreError::ValueTooLarge(
+                value.len(),
+                self.max_value_size,
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Statistics of a storage engine. Fields which do not apply to an engine are None.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EngineStats {
+    /// name of the engine
+    pub engine: String,
+    /// number of keys
+    pub keys: u64,
+    /// bytes used on disk
+    pub disk_size: u64,
+    /// data files of a KvStore
+    pub data_files: Vec<DataFileStats>,
+    /// bytes of the data files which are no longer live
+    pub garbage_bytes: Option<u64>,
+    /// number of compactions since the engine was opened
+    pub compactions: Option<u64>,
+    /// total duration of those compactions in milliseconds
+    pub compaction_millis: Option<u64>,
+    /// whether the compactions which start by themselves are paused
+    pub compaction_paused: Option<bool>,
+    /// whether writes are refused because the store is full or the disk is
+    pub read_only: Option<bool>,
+    /// number of open data file readers of all handles
+    pub open_readers: Option<u64>,
+    /// number of sealed data files mapped into memory
+    pub mapped_files: Option<u64>,
+    /// number of reads served by the value cache
+    pub cache_hits: Option<u64>,
+    /// number of reads which missed the value cache
+    pub cache_misses: Option<u64>,
+    /// number of reads of missing keys which the bloom filters answered
+    pub bloom_skips: Option<u64>,
+    /// number of reads which the bloom filters let through but which found no key
+    pub bloom_false_positives: Option<u64>,
+    /// observed false-positive rate of the bloom filters among the reads of missing keys
+    pub bloom_false_positive_rate: Option<f64>,
+    /// bytes of the blob files which keep the large values of a KvStore
+    pub blob_bytes: Option<u64>,
+    /// bytes of the blob files which no command points at any more
+    pub blob_garbage_bytes: Option<u64>,
+    /// number of blob garbage collections since the engine was opened
+    pub blob_collections: Option<u64>,
+}
+
+/// Size of one data file.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DataFileStats {
+    /// number of the `data_N.txt` file
+    pub file_number: u64,
+    /// size in bytes
+    pub size: u64,
+}
+
+/// Copy every key/value pair of the default namespace and of every other namespace from
+/// `source` into `target`, then check that each namespace of `target` holds as many keys
+/// as were read from `source`. Return the number of migrated keys.
+pub fn migrate<S: KvsEngine, T: KvsEngine>(source: &S, target: &T) -> Result<u64> {
+    let mut migrated = migrate_keys(source, target)?;
+    for name in source.list_namespaces()? {
+        target.create_namespace(&name)?;
+        migrated += migrate_keys(&source.namespace(&name)?, &target.namespace(&name)?)?;
+    }
+    Ok(migrated)
+}
+
+/// Copy the keys of one namespace. Return the number of keys copied.
+fn migrate_keys<S: KvsEngine, T: KvsEngine>(source: &S, target: &T) -> Result<u64> {
+    let mut migrated = 0;
+    source.scan(|key, value| {
+        target.set(key, value)?;
+        migrated += 1;
+        if migrated % 100_000 == 0 {
+            info!("Migrated {} keys", migrated);
+        }
+        Ok(())
+    })?;
+
+    let mut stored = 0;
+    target.scan(|_, _| {
+        stored += 1;
+        Ok(())
+    })?;
+
+    if stored != migrated {
+        return Err(KVStoreError::MigrationMismatch(migrated, stored));
+    }
+    Ok(migrated)
 }
 
 /// a struct which supports serialization and deserialization
@@ -27,4 +203,50 @@ pub enum Command {
     SET(String, String),
     /// for rm command
     RM(String),
+    /// for set command in a namespace, given by its id
+    NSSET(u64, String, String),
+    /// for rm command in a namespace, given by its id
+    NSRM(u64, String),
+    /// for set command whose value is kept in a blob file
+    BLOBSET(String, BlobPointer),
+    /// for set command in a namespace whose value is kept in a blob file
+    NSBLOBSET(u64, String, BlobPointer),
+}
+
+/// Where the value of a `BLOBSET` command is kept.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlobPointer {
+    /// number of the `blob_N.blob` file
+    pub file_number: u64,
+    /// offset of the record of the value in the file
+    pub offset: u64,
+    /// length of the record in bytes
+    pub length: u64,
+}
+
+impl Command {
+    /// Return the id of the namespace of the command, 0 for the default namespace.
+    pub fn namespace_id(&self) -> u64 {
+        match self {
+            Command::SET(..) | Command::RM(_) | Command::BLOBSET(..) => 0,
+            Command::NSSET(id, ..) | Command::NSRM(id, _) | Command::NSBLOBSET(id, ..) => *id,
+        }
+    }
+
+    /// Return the key of the command.
+    pub fn key(&self) -> &str {
+        match self {
+            Command::SET(key, _)
+            | Command::RM(key)
+            | Command::NSSET(_, key, _)
+            | Command::NSRM(_, key)
+            | Command::BLOBSET(key, _)
+            | Command::NSBLOBSET(_, key, _) => key,
+        }
+    }
+
+    /// Return true for a command which sets its key.
+    pub fn is_set(&self) -> bool {
+        !matches!(self, Command::RM(_) | Command::NSRM(..))
+    }
 }
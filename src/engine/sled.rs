@@ -1,6 +1,14 @@
-use crate::{KVStoreError, KvsEngine, Result};
-use sled::Db;
+use super::check_namespace_name;
+use crate::{EngineStats, KVStoreError, KvsEngine, Result, SizeLimits};
+use sled::{Db, Tree};
+use std::collections::HashMap;
+use std::ops::Deref;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Prefix of the trees which hold namespaces, which keeps them apart from sled's own trees.
+const NAMESPACE_PREFIX: &str = "namespace/";
 
 /** A KvStore stores key/value pairs using sled.
 # Example
@@ -22,21 +30,71 @@ assert_eq!(store.get("1".to_owned())?, None);
 */
 #[derive(Clone)]
 pub struct SledKvsEngine {
-    inner: Db,
+    db: Db,
+    // the tree of the namespace of this handle
+    inner: Tree,
+    // set once the namespace of this handle is dropped, None for the default namespace
+    dropped: Option<Arc<AtomicBool>>,
+    // namespace name -> dropped flag of its handles, shared by all handles
+    handles: Arc<Mutex<HashMap<String, Arc<AtomicBool>>>>,
+    size_limits: SizeLimits,
 }
 
 impl SledKvsEngine {
     /// Open the SledKvsEngine at a given path. Return the SledKvsEngine.
     pub fn open(path: impl Into<PathBuf>) -> Result<SledKvsEngine> {
+        Self::open_with_size_limits(path, SizeLimits::default())
+    }
+
+    /// Open the SledKvsEngine at a given path, whose sets take keys and values up to
+    /// the limits. Return the SledKvsEngine.
+    pub fn open_with_size_limits(
+        path: impl Into<PathBuf>,
+        size_limits: SizeLimits,
+    ) -> Result<SledKvsEngine> {
+        let db = sled::open(path.into())?;
         Ok(SledKvsEngine {
-            inner: sled::open(path.into())?,
+            inner: db.deref().clone(),
+            db,
+            dropped: None,
+            handles: Arc::new(Mutex::new(HashMap::new())),
+            size_limits,
         })
     }
+
+    /// Return an error if the namespace of this handle was dropped, since sled
+    /// keeps serving the trees it dropped.
+    fn check(&self) -> Result<()> {
+        if self
+            .dropped
+            .as_ref()
+            .map_or(false, |dropped| dropped.load(Ordering::SeqCst))
+        {
+            let name = self.inner.name();
+            return Err(KVStoreError::NamespaceNotFound(
+                String::from_utf8_lossy(&name[NAMESPACE_PREFIX.len()..]).into_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn has_namespace(&self, name: &str) -> bool {
+        self.db
+            .tree_names()
+            .iter()
+            .any(|tree_name| tree_name == namespace_tree(name).as_bytes())
+    }
+}
+
+fn namespace_tree(name: &str) -> String {
+    format!("{}{}", NAMESPACE_PREFIX, name)
 }
 
 impl KvsEngine for SledKvsEngine {
     /// Set the value of a string key to a string. Return an error if the value is not written successfully.
     fn set(&self, key: String, value: String) -> Result<()> {
+        self.size_limits.check(&key, &value)?;
+        self.check()?;
         self.inner.insert(key, value.into_bytes())?;
         // self.inner.flush()?;
         Ok(())
@@ -44,6 +102,7 @@ impl KvsEngine for SledKvsEngine {
 
     /// Get the string value of a string key. If the key does not exist, return None. Return an error if the value is not read successfully.
     fn get(&self, key: String) -> Result<Option<String>> {
+        self.check()?;
         Ok(self
             .inner
             .get(key)?
@@ -54,8 +113,125 @@ impl KvsEngine for SledKvsEngine {
 
     /// Remove a given key. Return an error if the key does not exist or is not removed successfully.
     fn remove(&self, key: String) -> Result<()> {
+        self.check()?;
         self.inner.remove(key)?.ok_or(KVStoreError::KeyNotFound)?;
-        self.inner.flush()?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Call `f` with every key/value pair in the store.
+    fn scan<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        self.check()?;
+        for pair in self.inner.iter() {
+            let (key, value) = pair?;
+            f(
+                String::from_utf8(key.to_vec())?,
+                String::from_utf8(value.to_vec())?,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Return the statistics of the store.
+    fn stats(&self) -> Result<EngineStats> {
+        self.check()?;
+        // sled writes to disk in the background, so flush for an up to date size
+        self.db.flush()?;
+        Ok(EngineStats {
+            engine: "sled".to_owned(),
+            keys: self.inner.len() as u64,
+            disk_size: self.db.size_on_disk()?,
+            data_files: Vec::new(),
+            garbage_bytes: None,
+            compactions: None,
+            compaction_millis: None,
+            compaction_paused: None,
+            read_only: None,
+            open_readers: None,
+            mapped_files: None,
+            cache_hits: None,
+            cache_misses: None,
+            bloom_skips: None,
+            bloom_false_positives: None,
+            bloom_false_positive_rate: None,
+            blob_bytes: None,
+            blob_garbage_bytes: None,
+            blob_collections: None,
+        })
+    }
+
+    /// Return a handle to the tree of the namespace.
+    fn namespace(&self, name: &str) -> Result<SledKvsEngine> {
+        if !self.has_namespace(name) {
+            return Err(KVStoreError::NamespaceNotFound(name.to_owned()));
+        }
+        let dropped = Arc::clone(
+            self.handles
+                .lock()
+                .unwrap()
+                .entry(name.to_owned())
+                .or_default(),
+        );
+        Ok(SledKvsEngine {
+            db: self.db.clone(),
+            inner: self.db.open_tree(namespace_tree(name))?,
+            dropped: Some(dropped),
+            handles: Arc::clone(&self.handles),
+            size_limits: self.size_limits,
+        })
+    }
+
+    /// Create the tree of a namespace.
+    fn create_namespace(&self, name: &str) -> Result<()> {
+        check_namespace_name(name)?;
+        if self.has_namespace(name) {
+            return Err(KVStoreError::NamespaceExists(name.to_owned()));
+        }
+        self.db.open_tree(namespace_tree(name))?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Drop the tree of a namespace.
+    fn drop_namespace(&self, name: &str) -> Result<()> {
+        if !self.db.drop_tree(namespace_tree(name))? {
+            return Err(KVStoreError::NamespaceNotFound(name.to_owned()));
+        }
+        if let Some(dropped) = self.handles.lock().unwrap().remove(name) {
+            dropped.store(true, Ordering::SeqCst);
+        }
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Return the names of the namespace trees.
+    fn list_namespaces(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for tree_name in self.db.tree_names() {
+            if let Some(name) = tree_name.strip_prefix(NAMESPACE_PREFIX.as_bytes()) {
+                names.push(String::from_utf8(name.to_vec())?);
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Flush the buffers of sled, which compacts its files by itself.
+    fn compact(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Return an error for a pause, since sled compacts its files by itself.
+    fn set_compaction_paused(&self, paused: bool) -> Result<()> {
+        if paused {
+            return Err(KVStoreError::CommonStringError(
+                "the sled engine does not pause compaction".to_owned(),
+            ));
+        }
         Ok(())
     }
 }
@@ -1,3 +1,5 @@
+#![allow(non_local_definitions)]
+use crate::ErrorCode;
 use failure::Fail;
 use std::{io, string};
 
@@ -43,6 +45,73 @@ pub enum KVStoreError {
     #[fail(display = "Change engine after initialization")]
     ChangeEngineError,
 
+    /// A record of a data file can not be decoded
+    #[fail(display = "Corrupt record: {}", _0)]
+    CorruptRecord(String),
+    /// The on-disk index file of a KvStore can not be read
+    #[fail(display = "Corrupt index file: {}", _0)]
+    CorruptIndex(String),
+
+    /// An encryption key is missing, invalid or does not match the data
+    #[fail(display = "Encryption key error: {}", _0)]
+    EncryptionKey(String),
+
+    /// A data file was written by a newer version
+    #[fail(display = "Unsupported data file format version {}", _0)]
+    UnsupportedFormatVersion(u32),
+
+    /// A namespace which does not exist was used or dropped
+    #[fail(display = "Namespace {} not found", _0)]
+    NamespaceNotFound(String),
+
+    /// A namespace which already exists was created
+    #[fail(display = "Namespace {} already exists", _0)]
+    NamespaceExists(String),
+
+    /// Engine migration read and wrote a different number of keys
+    #[fail(
+        display = "Migration mismatch: read {} keys but target holds {} keys",
+        _0, _1
+    )]
+    MigrationMismatch(u64, u64),
+
+    /// A write was refused because the store reached its size limit or the disk is full
+    #[fail(display = "The store is read-only because {}", _0)]
+    ReadOnly(String),
+
+    /// Compaction did not start because it needs more free disk space than there is
+    #[fail(
+        display = "Compaction needs {} bytes of free disk space but only {} are available",
+        _0, _1
+    )]
+    InsufficientSpace(u64, u64),
+
+    /// Compaction did not start because a compaction or blob garbage collection is running
+    #[fail(display = "A compaction is already running")]
+    CompactionRunning,
+
+    /// A key is larger than the engine or server accepts
+    #[fail(
+        display = "Key of {} bytes is larger than the limit of {} bytes",
+        _0, _1
+    )]
+    KeyTooLarge(usize, usize),
+
+    /// A value is larger than the engine or server accepts
+    #[fail(
+        display = "Value of {} bytes is larger than the limit of {} bytes",
+        _0, _1
+    )]
+    ValueTooLarge(usize, usize),
+
+    /// A request is larger than the server reads
+    #[fail(display = "Request is larger than the limit of {} bytes", _0)]
+    RequestTooLarge(u64),
+
+    /// A server refused a request for a reason it gave an error code to
+    #[fail(display = "{}", _1)]
+    Refused(ErrorCode, String),
+
     /// common string error
     #[fail(display = "{}", _0)]
     CommonStringError(String),
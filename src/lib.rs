@@ -11,8 +11,16 @@ mod server;
 pub mod thread_pool;
 
 pub use client::Client;
-pub use engine::Command;
-pub use engine::{KvStore, KvsEngine, SledKvsEngine};
+pub use engine::{dump, dump_encrypted, RecordInfo};
+pub use engine::{migrate, KvStore, KvsEngine, MemoryKvsEngine, ShardedKvStore, SledKvsEngine};
+pub use engine::{repair, repair_encrypted, FileRepair, RepairReport};
+pub use engine::{upgrade, upgrade_encrypted, upgrade_with_storage, UpgradeReport, FORMAT_VERSION};
+pub use engine::{verify, verify_encrypted, CorruptRange, FileReport, VerifyReport};
+pub use engine::{BTreeKvsEngine, BTreeOptions, LsmKvsEngine, LsmOptions};
+pub use engine::{BlobPointer, Command};
+pub use engine::{CompactionOptions, CompactionWindow, SizeLimits};
+pub use engine::{Compression, DataFileStats, Encryption, EngineStats, IndexMode, KvStoreOptions};
+pub use engine::{DiskStorage, Faults, FileMap, MemoryStorage, Storage, StorageFile};
 pub use errors::{KVStoreError, Result};
-pub use proto::{Request, Response};
+pub use proto::{ErrorCode, Operation, Request, Response};
 pub use server::{EngineType, KvServer};
@@ -2,13 +2,55 @@ use serde::{Deserialize, Serialize};
 
 /// a request struct which supports serialization and deserialization
 #[derive(Serialize, Deserialize, Debug)]
-pub enum Request {
+pub struct Request {
+    /// the namespace of the keys, None for the default namespace
+    #[serde(default)]
+    pub namespace: Option<String>,
+    /// what to do
+    pub operation: Operation,
+}
+
+impl Request {
+    /// a request in the default namespace
+    pub fn new(operation: Operation) -> Request {
+        Request {
+            namespace: None,
+            operation,
+        }
+    }
+
+    /// a request in the given namespace, None for the default namespace
+    pub fn in_namespace(namespace: Option<String>, operation: Operation) -> Request {
+        Request {
+            namespace,
+            operation,
+        }
+    }
+}
+
+/// an operation of a request
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Operation {
     /// for set command
     SET(String, String),
     /// for rm command
     RM(String),
     /// for get command
     GET(String),
+    /// for stats command
+    STATS,
+    /// for creating a namespace
+    NSCREATE(String),
+    /// for dropping a namespace
+    NSDROP(String),
+    /// for listing the namespaces
+    NSLIST,
+    /// for resuming the compactions and starting one now
+    COMPACTSTART,
+    /// for pausing the compactions which start by themselves
+    COMPACTPAUSE,
+    /// for a piece of a value sent in chunks, the value is set with the last one
+    SETCHUNK(String, String, bool),
 }
 
 /// a response struct which supports serialization and deserialization
@@ -18,4 +60,17 @@ pub enum Response {
     Ok(Option<String>),
     /// for failed request
     Err(String),
+    /// for request refused for a reason which has an error code
+    Refused(ErrorCode, String),
+}
+
+/// the reason of a refused request, which clients can act upon
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// the key is larger than the server takes
+    KeyTooLarge,
+    /// the value is larger than the server takes
+    ValueTooLarge,
+    /// the request is larger than the server reads, a large value may be sent in chunks
+    RequestTooLarge,
 }
@@ -1,21 +1,42 @@
 use crate::thread_pool::ThreadPool;
-use crate::Result;
-use crate::{KvsEngine, Request, Response};
-use log::{debug, error};
+use crate::{ErrorCode, KVStoreError, KvsEngine, Operation, Request, Response};
+use crate::{Result, SizeLimits};
+use log::{debug, error, warn};
 use serde::Deserialize;
 use serde_json::Deserializer;
 use std::fmt;
-use std::io::BufReader;
-use std::net::{TcpListener, TcpStream};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::io::{self, BufReader, Read};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
+
+// bytes of a request besides its key and value, like the namespace and the JSON syntax
+const REQUEST_OVERHEAD: u64 = 64 * 1024;
+// JSON escapes a byte of a string into at most six bytes
+const MAX_ESCAPED_BYTE: u64 = 6;
+// how long the rest of a refused request is read before its connection is closed
+const LINGER_TIMEOUT: Duration = Duration::from_secs(1);
+// namespace which holds the chunks of the values whose last chunk did not come yet
+const CHUNKS_NAMESPACE: &str = "kvs-server.chunks";
+
+// id of the next value taken in chunks
+static NEXT_UPLOAD: AtomicU64 = AtomicU64::new(0);
 
 /// a generic KvServer which supports pluggable storage engines
 pub struct KvServer<E: KvsEngine, P: ThreadPool> {
     engine: E,
     pool: P,
     is_stop: Arc<AtomicBool>,
+    limits: Limits,
+}
+
+/// What a connection reads.
+#[derive(Clone, Copy, Debug)]
+struct Limits {
+    sizes: SizeLimits,
+    // bytes of a chunk of a value, None if values are not taken in chunks
+    chunk_size: Option<usize>,
 }
 
 impl<E: KvsEngine, P: ThreadPool> KvServer<E, P> {
@@ -25,20 +46,49 @@ impl<E: KvsEngine, P: ThreadPool> KvServer<E, P> {
             engine,
             pool,
             is_stop,
+            limits: Limits {
+                sizes: SizeLimits::default(),
+                chunk_size: None,
+            },
         }
     }
 
+    /// Refuse keys and values larger than the limits, without reading much more of
+    /// a request than the limits allow.
+    pub fn with_size_limits(mut self, sizes: SizeLimits) -> Self {
+        self.limits.sizes = sizes;
+        self
+    }
+
+    /** Take values in chunks of at most `chunk_size` bytes, as `Client::set_chunked`
+    sends them, and read no request which holds more than a chunk. The chunks are written
+    to the `kvs-server.chunks` namespace of the engine as they come, and the value is set
+    once its last chunk came. It is still limited by the size limits.
+     */
+    pub fn with_value_chunks(mut self, chunk_size: usize) -> Self {
+        self.limits.chunk_size = Some(chunk_size);
+        self
+    }
+
     /// serve at addr to handle requests
     pub fn serve(&mut self, addr: &String) -> Result<()> {
+        if self.limits.chunk_size.is_some() {
+            // the chunks of the values whose connection ended with the last server
+            match self.engine.drop_namespace(CHUNKS_NAMESPACE) {
+                Ok(()) | Err(KVStoreError::NamespaceNotFound(_)) => {}
+                Err(err) => return Err(err),
+            }
+        }
         let listener = TcpListener::bind(addr)?;
         for stream in listener.incoming() {
             if self.is_stop.load(Ordering::SeqCst) {
                 break;
             }
             let engine = self.engine.clone();
+            let limits = self.limits;
             self.pool.spawn(move || match stream {
                 Ok(stream) => {
-                    if let Err(err) = handle_connection(engine, stream) {
+                    if let Err(err) = handle_connection(engine, stream, limits) {
                         error!("Unexpected error occurs when serving request: {:?}", err)
                     }
                 }
@@ -52,34 +102,82 @@ impl<E: KvsEngine, P: ThreadPool> KvServer<E, P> {
     }
 }
 
-fn handle_connection<E: KvsEngine>(engine: E, mut stream: TcpStream) -> Result<()> {
-    let request =
-        Request::deserialize(&mut Deserializer::from_reader(BufReader::new(&mut stream)))?;
-
-    let now = SystemTime::now();
-    debug!("Request: {:?}", &request);
+/// Serve a request, or the chunks of a value up to its last one.
+fn handle_connection<E: KvsEngine>(engine: E, stream: TcpStream, limits: Limits) -> Result<()> {
+    // the value whose chunks came so far
+    let mut pending = None;
+    let result = serve_requests(&engine, stream, limits, &mut pending);
+    // a value whose last chunk did not come is not set
+    if let Some(upload) = pending {
+        upload.discard(&engine);
+    }
+    result
+}
 
-    let response;
-    match request {
-        Request::SET(key, value) => {
-            match engine.set(key, value) {
-                Ok(_) => response = Response::Ok(None),
-                Err(err) => response = Response::Err(format!("{}", err)),
-            };
-        }
-        Request::RM(key) => {
-            match engine.remove(key) {
-                Ok(_) => response = Response::Ok(None),
-                Err(err) => response = Response::Err(format!("{}", err)),
-            };
-        }
-        Request::GET(key) => {
-            match engine.get(key) {
-                Ok(value) => response = Response::Ok(value),
-                Err(err) => response = Response::Err(format!("{}", err)),
+fn serve_requests<E: KvsEngine>(
+    engine: &E,
+    mut stream: TcpStream,
+    limits: Limits,
+    pending: &mut Option<Upload>,
+) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    loop {
+        let max_request_size = limits.request_size();
+        let mut request_reader =
+            StringSizeReader::new((&mut reader).take(max_request_size), limits.string_size());
+        let request =
+            match Request::deserialize(&mut Deserializer::from_reader(&mut request_reader)) {
+                Ok(request) => request,
+                // the request was cut off at a limit rather than read in full
+                Err(_) if request_reader.exceeded() || request_reader.inner.limit() == 0 => {
+                    let limit = if request_reader.exceeded() {
+                        limits.string_size()
+                    } else {
+                        max_request_size
+                    };
+                    let err = KVStoreError::RequestTooLarge(limit);
+                    respond(&mut stream, Err(err), SystemTime::now())?;
+                    // closing with unread bytes resets the connection, which may drop the
+                    // response before the client reads it
+                    stream.shutdown(Shutdown::Write)?;
+                    stream.set_read_timeout(Some(LINGER_TIMEOUT))?;
+                    let _ = io::copy(&mut reader.take(max_request_size), &mut io::sink());
+                    return Ok(());
+                }
+                Err(err) => return Err(err.into()),
             };
+
+        let now = SystemTime::now();
+        debug!("Request: {:?}", &request);
+
+        let more_chunks = matches!(request.operation, Operation::SETCHUNK(_, _, false));
+        let result = match request.operation {
+            Operation::SETCHUNK(key, chunk, last) => limits
+                .add_chunk(engine, pending, request.namespace, key, chunk, last)
+                .and_then(|request| match request {
+                    Some(request) => execute(engine.clone(), request),
+                    None => Ok(None),
+                }),
+            _ => limits
+                .check(&request)
+                .and_then(|_| execute(engine.clone(), request)),
+        };
+        let failed = result.is_err();
+        respond(&mut stream, result, now)?;
+        if !more_chunks || failed {
+            return Ok(());
         }
     }
+}
+
+fn respond(stream: &mut TcpStream, result: Result<Option<String>>, now: SystemTime) -> Result<()> {
+    let response = match result {
+        Ok(value) => Response::Ok(value),
+        Err(err) => match error_code(&err) {
+            Some(code) => Response::Refused(code, format!("{}", err)),
+            None => Response::Err(format!("{}", err)),
+        },
+    };
 
     debug!("Response: {:?}, {:?}", &response, now.elapsed());
 
@@ -88,6 +186,277 @@ fn handle_connection<E: KvsEngine>(engine: E, mut stream: TcpStream) -> Result<(
     Ok(())
 }
 
+/// Return the error code of the errors which clients can tell apart.
+fn error_code(err: &KVStoreError) -> Option<ErrorCode> {
+    match err {
+        KVStoreError::KeyTooLarge(..) => Some(ErrorCode::KeyTooLarge),
+        KVStoreError::ValueTooLarge(..) => Some(ErrorCode::ValueTooLarge),
+        KVStoreError::RequestTooLarge(_) => Some(ErrorCode::RequestTooLarge),
+        KVStoreError::Refused(code, _) => Some(*code),
+        _ => None,
+    }
+}
+
+impl Limits {
+    /// Return the bytes of the largest request, whose strings may all be escaped.
+    fn request_size(&self) -> u64 {
+        self.string_size() * MAX_ESCAPED_BYTE
+    }
+
+    /// Return the bytes the strings of the largest request hold once they are unescaped,
+    /// which is what reading the request buffers.
+    fn string_size(&self) -> u64 {
+        let value_size = self.chunk_size.unwrap_or(self.sizes.max_value_size);
+        (self.sizes.max_key_size + value_size) as u64 + REQUEST_OVERHEAD
+    }
+
+    /// Return an error if a set is larger than the limits.
+    fn check(&self, request: &Request) -> Result<()> {
+        match &request.operation {
+            Operation::SET(key, value) => self.sizes.check(key, value),
+            _ => Ok(()),
+        }
+    }
+
+    /** Write a chunk to the engine next to the chunks of the value which came before it.
+    Return the request which sets the whole value once the last chunk came, which is
+    only then read back from the engine. A value which fails is discarded.
+     */
+    fn add_chunk<E: KvsEngine>(
+        &self,
+        engine: &E,
+        pending: &mut Option<Upload>,
+        namespace: Option<String>,
+        key: String,
+        chunk: String,
+        last: bool,
+    ) -> Result<Option<Request>> {
+        if self.chunk_size.is_none() {
+            return Err(KVStoreError::CommonStringError(
+                "the server does not take values in chunks".to_owned(),
+            ));
+        }
+        let mut upload = match pending.take() {
+            None => Upload {
+                namespace,
+                key,
+                id: NEXT_UPLOAD.fetch_add(1, Ordering::SeqCst),
+                chunks: 0,
+                size: 0,
+            },
+            Some(upload) if upload.namespace == namespace && upload.key == key => upload,
+            Some(upload) => {
+                upload.discard(engine);
+                return Err(KVStoreError::CommonStringError(
+                    "a chunk of another key came before the last chunk".to_owned(),
+                ));
+            }
+        };
+        upload.size += chunk.len();
+        let result = self.check_upload(&upload).and_then(|_| {
+            if last {
+                upload.finish(engine, chunk).map(Some)
+            } else {
+                upload.stage(engine, chunk).map(|_| None)
+            }
+        });
+        match result {
+            Ok(None) => *pending = Some(upload),
+            Ok(Some(_)) | Err(_) => upload.discard(engine),
+        }
+        result
+    }
+
+    /// Return an error if the key or the chunks of a value so far are larger than the limits.
+    fn check_upload(&self, upload: &Upload) -> Result<()> {
+        self.sizes.check(&upload.key, "")?;
+        if upload.size > self.sizes.max_value_size {
+            return Err(KVStoreError::ValueTooLarge(
+                upload.size,
+                self.sizes.max_value_size,
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A value taken in chunks, whose chunks are staged in the engine until the last one came.
+struct Upload {
+    namespace: Option<String>,
+    key: String,
+    // the chunks are the keys `<id>/<n>` of the chunks namespace
+    id: u64,
+    chunks: u64,
+    // bytes of the chunks which came so far
+    size: usize,
+}
+
+impl Upload {
+    fn chunk_key(&self, n: u64) -> String {
+        format!("{}/{}", self.id, n)
+    }
+
+    /// Write a chunk which is not the last one to the engine.
+    fn stage<E: KvsEngine>(&mut self, engine: &E, chunk: String) -> Result<()> {
+        let staged = match engine.namespace(CHUNKS_NAMESPACE) {
+            Err(KVStoreError::NamespaceNotFound(_)) => {
+                match engine.create_namespace(CHUNKS_NAMESPACE) {
+                    // another connection created it meanwhile
+                    Ok(()) | Err(KVStoreError::NamespaceExists(_)) => {}
+                    Err(err) => return Err(err),
+                }
+                engine.namespace(CHUNKS_NAMESPACE)?
+            }
+            result => result?,
+        };
+        staged.set(self.chunk_key(self.chunks), chunk)?;
+        self.chunks += 1;
+        Ok(())
+    }
+
+    /// Return the request which sets the staged chunks followed by the last chunk.
+    fn finish<E: KvsEngine>(&self, engine: &E, last_chunk: String) -> Result<Request> {
+        let mut value = String::with_capacity(self.size);
+        if self.chunks > 0 {
+            let staged = engine.namespace(CHUNKS_NAMESPACE)?;
+            for n in 0..self.chunks {
+                let chunk = staged.get(self.chunk_key(n))?.ok_or_else(|| {
+                    KVStoreError::CommonStringError("a staged chunk is missing".to_owned())
+                })?;
+                value.push_str(&chunk);
+            }
+        }
+        value.push_str(&last_chunk);
+        Ok(Request::in_namespace(
+            self.namespace.clone(),
+            Operation::SET(self.key.clone(), value),
+        ))
+    }
+
+    /// Remove the staged chunks from the engine.
+    fn discard<E: KvsEngine>(&self, engine: &E) {
+        if self.chunks == 0 {
+            return;
+        }
+        let result = engine.namespace(CHUNKS_NAMESPACE).and_then(|staged| {
+            for n in 0..self.chunks {
+                staged.remove(self.chunk_key(n))?;
+            }
+            Ok(())
+        });
+        if let Err(err) = result {
+            warn!(
+                "can not remove the staged chunks of {:?} because {}",
+                self.key, err
+            );
+        }
+    }
+}
+
+/** A reader of a JSON request which counts the bytes its strings hold once unescaped,
+and fails as soon as they pass the limit, rather than once they are buffered.
+ */
+struct StringSizeReader<R> {
+    inner: R,
+    max_size: u64,
+    size: u64,
+    in_string: bool,
+    // bytes of the escape sequence read so far, 0 outside of one
+    escape: u8,
+    // the code point of a `\u` escape read so far
+    code_point: u32,
+}
+
+impl<R: Read> StringSizeReader<R> {
+    fn new(inner: R, max_size: u64) -> Self {
+        StringSizeReader {
+            inner,
+            max_size,
+            size: 0,
+            in_string: false,
+            escape: 0,
+            code_point: 0,
+        }
+    }
+
+    fn exceeded(&self) -> bool {
+        self.size > self.max_size
+    }
+
+    fn count(&mut self, byte: u8) {
+        match (self.in_string, self.escape, byte) {
+            (false, _, b'"') => self.in_string = true,
+            (false, _, _) => {}
+            (true, 0, b'\\') => self.escape = 1,
+            (true, 0, b'"') => self.in_string = false,
+            (true, 0, _) => self.size += 1,
+            (true, 1, b'u') => {
+                self.escape = 2;
+                self.code_point = 0;
+            }
+            (true, 1, _) => {
+                self.escape = 0;
+                self.size += 1;
+            }
+            (true, _, _) => {
+                let digit = (byte as char).to_digit(16).unwrap_or(0);
+                self.code_point = self.code_point * 16 + digit;
+                self.escape += 1;
+                if self.escape == 6 {
+                    self.escape = 0;
+                    // each half of a surrogate pair stands for two of its four bytes
+                    self.size += match self.code_point {
+                        0..=0x7f => 1,
+                        0x80..=0x7ff | 0xd800..=0xdfff => 2,
+                        _ => 3,
+                    };
+                }
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for StringSizeReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        for byte in &buf[..n] {
+            self.count(*byte);
+        }
+        if self.exceeded() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the strings of the request are larger than the limit",
+            ));
+        }
+        Ok(n)
+    }
+}
+
+/// Run the operation of a request on the namespace it names.
+fn execute<E: KvsEngine>(engine: E, request: Request) -> Result<Option<String>> {
+    let engine = match &request.namespace {
+        Some(name) => engine.namespace(name)?,
+        None => engine,
+    };
+    match request.operation {
+        Operation::SET(key, value) => engine.set(key, value).map(|_| None),
+        Operation::RM(key) => engine.remove(key).map(|_| None),
+        Operation::GET(key) => engine.get(key),
+        Operation::STATS => Ok(Some(serde_json::to_string(&engine.stats()?)?)),
+        Operation::NSCREATE(name) => engine.create_namespace(&name).map(|_| None),
+        Operation::NSDROP(name) => engine.drop_namespace(&name).map(|_| None),
+        Operation::NSLIST => Ok(Some(serde_json::to_string(&engine.list_namespaces()?)?)),
+        Operation::COMPACTSTART => {
+            engine.set_compaction_paused(false)?;
+            engine.compact().map(|_| None)
+        }
+        Operation::COMPACTPAUSE => engine.set_compaction_paused(true).map(|_| None),
+        Operation::SETCHUNK(..) => Err(KVStoreError::CommonStringError(
+            "a chunk is not a request of its own".to_owned(),
+        )),
+    }
+}
+
 /// Indicates the type of engine
 #[derive(Debug)]
 pub enum EngineType {
@@ -95,6 +464,12 @@ pub enum EngineType {
     KvStore,
     /// for SledKvsEngine
     SledKvsEngine,
+    /// for MemoryKvsEngine
+    MemoryKvsEngine,
+    /// for LsmKvsEngine
+    LsmKvsEngine,
+    /// for BTreeKvsEngine
+    BTreeKvsEngine,
 }
 
 impl fmt::Display for EngineType {
@@ -102,6 +477,9 @@ impl fmt::Display for EngineType {
         match self {
             EngineType::KvStore => write!(f, "kvs"),
             EngineType::SledKvsEngine => write!(f, "sled"),
+            EngineType::MemoryKvsEngine => write!(f, "memory"),
+            EngineType::LsmKvsEngine => write!(f, "lsm"),
+            EngineType::BTreeKvsEngine => write!(f, "btree"),
         }
     }
 }
@@ -1,4 +1,5 @@
 use assert_cmd::prelude::*;
+use kvs::{Client, KvStore, KvsEngine, Operation, Request};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
 use std::process::Command;
@@ -20,28 +21,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -52,35 +53,35 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -91,28 +92,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -123,7 +124,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -134,7 +135,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -145,7 +146,7 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -157,13 +158,14 @@ fn cli_log_configuration() {
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().expect("server exited before waited");
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -178,15 +180,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("server exited before waited");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -197,15 +200,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("server exited before waited");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -217,19 +221,20 @@ fn cli_access_server(engine: &str, addr: &str) {
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("server exited before waited");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -237,7 +242,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -245,7 +250,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value2", "--addr", addr])
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -253,7 +258,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -261,7 +266,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -269,7 +274,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key2", "--addr", addr])
+        .args(["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -277,7 +282,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key2", "value3", "--addr", addr])
+        .args(["set", "key2", "value3", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -285,12 +290,21 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key1", "--addr", addr])
+        .args(["rm", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(is_empty());
 
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["stats", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(format!("\"engine\": \"{}\"", engine)))
+        .stdout(contains("\"keys\": 1,"));
+
     sender.send(()).unwrap();
     handle.join().unwrap();
 
@@ -298,26 +312,27 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("server exited before waited");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -335,3 +350,793 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_migrate_engine() {
+    let addr = "127.0.0.1:4006";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server exited before waited");
+
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args([
+            "--engine",
+            "sled",
+            "--migrate",
+            "--keep-old",
+            "--addr",
+            addr,
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server exited before waited");
+
+    assert!(!temp_dir.path().join("kvs").exists());
+    assert!(temp_dir.path().join("kvs.bak").exists());
+    assert!(temp_dir.path().join("sled").exists());
+}
+
+#[test]
+fn cli_migrate_engine_recovers() {
+    let addr = "127.0.0.1:4019";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server exited before waited");
+
+    // a migration which crashed before it copied every key is rolled back
+    fs::create_dir(temp_dir.path().join("sled.migrating")).unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server exited before waited");
+    assert!(!temp_dir.path().join("sled.migrating").exists());
+
+    // a migration which crashed after the migrated data was moved into place is finished
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "sled", "--migrate", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server exited before waited");
+    fs::create_dir(temp_dir.path().join("kvs")).unwrap();
+    fs::write(temp_dir.path().join("migrated"), "sled remove\n").unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server exited before waited");
+    assert!(!temp_dir.path().join("kvs").exists());
+    assert!(!temp_dir.path().join("migrated").exists());
+}
+
+#[test]
+fn cli_tool_verify() {
+    let addr = "127.0.0.1:4007";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server exited before waited");
+
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(["verify", "kvs"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("1 live keys, ok"));
+
+    fs::write(temp_dir.path().join("kvs").join("data_1.txt"), "garbage").unwrap();
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(["verify", "kvs"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("corrupt bytes 0..7"));
+}
+
+#[test]
+fn cli_encrypted_server() {
+    let addr = "127.0.0.1:4008";
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("keys"), "11".repeat(32)).unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--key-file", "keys"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "secret-key", "secret-value", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server exited before waited");
+
+    let data = fs::read(temp_dir.path().join("kvs").join("data_0.txt")).unwrap();
+    assert!(!String::from_utf8_lossy(&data).contains("secret"));
+
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(["verify", "kvs"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("no key was supplied"));
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(["verify", "kvs", "--key-file", "keys"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("1 live keys, ok"));
+}
+
+fn cli_namespaces(engine: &str, addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs-client").unwrap();
+        command
+            .args(args)
+            .args(["--addr", addr])
+            .current_dir(&temp_dir);
+        command
+    };
+    client(&["ns", "create", "users"]).assert().success();
+    client(&["ns", "create", "orders"]).assert().success();
+    client(&["ns", "create", "users"])
+        .assert()
+        .failure()
+        .stderr(contains("already exists"));
+    client(&["ns", "list"])
+        .assert()
+        .success()
+        .stdout("orders\nusers\n");
+
+    client(&["set", "key", "default"]).assert().success();
+    client(&["set", "key", "user", "--namespace", "users"])
+        .assert()
+        .success();
+    client(&["get", "key", "--namespace", "users"])
+        .assert()
+        .success()
+        .stdout("user\n");
+    client(&["get", "key", "--namespace", "orders"])
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+    client(&["get", "key", "--namespace", "missing"])
+        .assert()
+        .failure()
+        .stderr(contains("Namespace missing not found"));
+
+    client(&["ns", "drop", "users"]).assert().success();
+    client(&["get", "key", "--namespace", "users"])
+        .assert()
+        .failure()
+        .stderr(contains("not found"));
+    client(&["ns", "list"])
+        .assert()
+        .success()
+        .stdout("orders\n");
+    client(&["get", "key"])
+        .assert()
+        .success()
+        .stdout("default\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server exited before waited");
+}
+
+#[test]
+fn cli_namespaces_kvs_engine() {
+    cli_namespaces("kvs", "127.0.0.1:4009");
+}
+
+#[test]
+fn cli_namespaces_sled_engine() {
+    cli_namespaces("sled", "127.0.0.1:4010");
+}
+
+#[test]
+fn cli_sharded_server() {
+    let addr = "127.0.0.1:4011";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--shards", "4", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    for i in 0..20 {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", &format!("key{}", i), "value", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server exited before waited");
+    assert!(temp_dir.path().join("kvs").join("shard_3").is_dir());
+
+    // the store stays sharded without --shards
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key7", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["stats", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("\"keys\": 20"));
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server exited before waited");
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--shards", "2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("holds 4 shards"));
+}
+
+#[test]
+fn cli_memory_server() {
+    let addr = "127.0.0.1:4012";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "memory", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["stats", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("\"engine\": \"memory\""));
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server exited before waited");
+    assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "memory", "--shards", "2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("only the kvs engine is sharded"));
+}
+
+// an engine which keeps its data on disk is found from its directory when restarted
+fn cli_restarted_server(engine: &str, addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server exited before waited");
+    assert!(temp_dir.path().join(engine).is_dir());
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["stats", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(format!("\"engine\": \"{}\"", engine)));
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server exited before waited");
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+#[test]
+fn cli_lsm_server() {
+    cli_restarted_server("lsm", "127.0.0.1:4013");
+}
+
+#[test]
+fn cli_btree_server() {
+    cli_restarted_server("btree", "127.0.0.1:4014");
+}
+
+#[test]
+fn cli_compaction_control() {
+    let addr = "127.0.0.1:4015";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--engine",
+            "kvs",
+            "--compaction-rate",
+            "1048576",
+            "--compaction-window",
+            "00:00-12:00",
+            "--compaction-window",
+            "12:00-00:00",
+            "--addr",
+            addr,
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["compaction", "pause", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["stats", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("\"compaction_paused\": true"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["compaction", "start", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["stats", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("\"compaction_paused\": false"))
+        .stdout(contains("\"compactions\": 1"));
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server exited before waited");
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--compaction-window", "22:30", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("is not a compaction window"));
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--engine",
+            "memory",
+            "--compaction-rate",
+            "1000",
+            "--addr",
+            addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("only the kvs engine schedules its compactions"));
+}
+
+#[test]
+fn cli_size_limit() {
+    let addr = "127.0.0.1:4016";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--max-size", "1024", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key", &"v".repeat(2000), "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("read-only"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["stats", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("\"read_only\": true"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key", "value", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server exited before waited");
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "memory", "--max-size", "1024", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("only the kvs engine has a size limit"));
+}
+
+#[test]
+fn cli_key_value_size_limits() {
+    let addr = "127.0.0.1:4017";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--engine",
+            "kvs",
+            "--max-key-size",
+            "16",
+            "--max-value-size",
+            "50000",
+            "--value-chunk-size",
+            "1000",
+            "--addr",
+            addr,
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", &"k".repeat(17), "value", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("KeyTooLarge"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key", &"v".repeat(60000), "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("ValueTooLarge"));
+    // the request is cut off before it is read in full
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key", &"v".repeat(100000), "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("RequestTooLarge"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "set",
+            "key",
+            &"v".repeat(60000),
+            "--chunk-size",
+            "1000",
+            "--addr",
+            addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("ValueTooLarge"));
+    let value = "é".repeat(20000);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key", &value, "--chunk-size", "1000", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(format!("{}\n", value));
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server exited before waited");
+
+    // a server which does not take chunks refuses them
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--engine",
+            "kvs",
+            "--max-key-size",
+            "16",
+            "--max-value-size",
+            "50000",
+            "--addr",
+            addr,
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    // and stops reading a value once it passes the limit, though it may be escaped
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key", &"v".repeat(120000), "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("RequestTooLarge"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key", "value", "--chunk-size", "2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("does not take values in chunks"));
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server exited before waited");
+}
+
+// the server keeps no more than a chunk of a value in memory while the value comes
+#[test]
+fn cli_staged_value_chunks() {
+    let addr = "127.0.0.1:4020";
+    let temp_dir = TempDir::new().unwrap();
+    let start_server = || {
+        let child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args([
+                "--engine",
+                "kvs",
+                "--value-chunk-size",
+                "1000",
+                "--addr",
+                addr,
+            ])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        child
+    };
+    let request = |client: &mut Client, operation: Operation| {
+        client
+            .request(&Request::in_namespace(None, operation))
+            .unwrap()
+    };
+    let chunk = |n: usize, last: bool| {
+        Operation::SETCHUNK("key".to_owned(), n.to_string().repeat(1000), last)
+    };
+
+    // every chunk which is not the last one is in the engine once it was answered
+    let mut child = start_server();
+    let mut client = Client::new(addr).unwrap();
+    for n in 0..5 {
+        request(&mut client, chunk(n, false));
+    }
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server exited before waited");
+    drop(client);
+    let store = KvStore::open(temp_dir.path().join("kvs")).unwrap();
+    assert_eq!(
+        store
+            .namespace("kvs-server.chunks")
+            .unwrap()
+            .stats()
+            .unwrap()
+            .keys,
+        5
+    );
+    assert_eq!(store.get("key".to_owned()).unwrap(), None);
+    drop(store);
+
+    // the chunks the last server left are removed, and so are those of a connection
+    // which ends before its last chunk
+    let mut child = start_server();
+    let mut client = Client::new(addr).unwrap();
+    request(&mut client, chunk(0, false));
+    drop(client);
+    let mut client = Client::new(addr).unwrap();
+    for n in 0..5 {
+        request(&mut client, chunk(n, false));
+    }
+    request(&mut client, chunk(5, true));
+    drop(client);
+    let expected: String = (0..6).map(|n: usize| n.to_string().repeat(1000)).collect();
+    let mut client = Client::new(addr).unwrap();
+    assert_eq!(
+        request(&mut client, Operation::GET("key".to_owned())),
+        Some(expected)
+    );
+    drop(client);
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server exited before waited");
+    let store = KvStore::open(temp_dir.path().join("kvs")).unwrap();
+    assert_eq!(
+        store
+            .namespace("kvs-server.chunks")
+            .unwrap()
+            .stats()
+            .unwrap()
+            .keys,
+        0
+    );
+}
+
+#[test]
+fn cli_blob_threshold() {
+    let addr = "127.0.0.1:4018";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--blob-threshold", "100", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let value = "v".repeat(2000);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key", &value, "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(format!("{}\n", value));
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server exited before waited");
+    assert!(temp_dir.path().join("kvs").join("blob_1.blob").exists());
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--engine",
+            "memory",
+            "--blob-threshold",
+            "100",
+            "--addr",
+            addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("only the kvs engine keeps values in blob files"));
+}
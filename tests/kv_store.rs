@@ -1,6 +1,20 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    dump, dump_encrypted, migrate, repair, repair_encrypted, upgrade, upgrade_with_storage, verify,
+    verify_encrypted, BTreeKvsEngine, BTreeOptions, CompactionOptions, CompactionWindow,
+    Compression, Encryption, Faults, IndexMode, KVStoreError, KvStore, KvStoreOptions, KvsEngine,
+    LsmKvsEngine, LsmOptions, MemoryKvsEngine, MemoryStorage, Result, ShardedKvStore, SizeLimits,
+    SledKvsEngine, Storage, FORMAT_VERSION,
+};
+use rand::distributions::Alphanumeric;
+use rand::rngs::StdRng;
+use rand::{thread_rng, Rng, SeedableRng};
+use std::collections::{btree_map, BTreeMap, HashMap};
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -209,3 +223,2936 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn migrate_to_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path().join("kvs"))?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    store.create_namespace("customers")?;
+    let customers = store.namespace("customers")?;
+    for i in 0..10 {
+        customers.set(format!("key{}", i), format!("customer{}", i))?;
+    }
+    store.create_namespace("empty")?;
+
+    let sled = SledKvsEngine::open(temp_dir.path().join("sled"))?;
+    assert_eq!(migrate(&store, &sled)?, 109);
+    assert_eq!(sled.get("key0".to_owned())?, None);
+    for i in 1..100 {
+        assert_eq!(sled.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(sled.list_namespaces()?, vec!["customers", "empty"]);
+    let customers = sled.namespace("customers")?;
+    for i in 0..10 {
+        assert_eq!(
+            customers.get(format!("key{}", i))?,
+            Some(format!("customer{}", i))
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn verify_data_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value1".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+
+    let report = verify(temp_dir.path())?;
+    assert!(!report.is_corrupt());
+    assert_eq!(report.live_keys(), 1);
+    assert_eq!(report.files.len(), 1);
+    assert_eq!(report.files[0].records, 4);
+    assert_eq!(report.files[0].tombstones, 1);
+    // the frames of the first set of key1 (35 bytes), the set of key2 (35) and its remove (23)
+    assert_eq!(report.files[0].garbage_bytes, 93);
+
+    // a torn write at the end of the file
+    let size = report.files[0].size;
+    OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("data_0.txt"))?
+        .write_all(b"{\"SET\":[\"key3\",\"val")?;
+
+    let report = verify(temp_dir.path())?;
+    assert!(report.is_corrupt());
+    assert_eq!(report.live_keys(), 1);
+    assert_eq!(report.files[0].corrupt.len(), 1);
+    assert_eq!(report.files[0].corrupt[0].start, size);
+    assert_eq!(report.files[0].corrupt[0].end, report.files[0].size);
+
+    Ok(())
+}
+
+#[test]
+fn verify_record_checksum() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // a damaged value which is still valid JSON
+    let data_path = temp_dir.path().join("data_0.txt");
+    let mut content = fs::read(&data_path)?;
+    let damaged = content
+        .windows(6)
+        .position(|window| window == b"value2")
+        .expect("the value of key2 is in the file");
+    content[damaged] = b'V';
+    fs::write(&data_path, content)?;
+
+    let report = verify(temp_dir.path())?;
+    assert!(report.is_corrupt());
+    assert_eq!(
+        report.files[0].corrupt[0].reason,
+        "Corrupt record: checksum mismatch"
+    );
+    Ok(())
+}
+
+#[test]
+fn repair_corrupt_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    // damage the record of key2 in the middle of the file
+    let data_path = temp_dir.path().join("data_0.txt");
+    let mut content = fs::read(&data_path)?;
+    let damaged = content
+        .windows(7)
+        .position(|window| window == b"[\"key2\"")
+        .expect("the record of key2 is in the file");
+    content[damaged + 1] = b'#';
+    fs::write(&data_path, content)?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    assert_eq!(verify(temp_dir.path())?.files[0].corrupt.len(), 1);
+
+    let report = repair(temp_dir.path())?;
+    assert_eq!(report.new_file_number, Some(1));
+    assert_eq!(report.salvaged_keys, 2);
+    assert_eq!(report.suspect_keys, vec!["key1".to_owned()]);
+    assert!(report.files[0].quarantined);
+    assert!(temp_dir
+        .path()
+        .join("quarantine")
+        .join("data_0.txt")
+        .exists());
+    assert!(!verify(temp_dir.path())?.is_corrupt());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn repair_suspect_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.create_namespace("users")?;
+    let users = store.namespace("users")?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    users.set("user1".to_owned(), "damaged1".to_owned())?;
+    users.set("user1".to_owned(), "value2".to_owned())?;
+    users.set("user2".to_owned(), "damaged2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(users);
+    drop(store);
+
+    // damage the values so that they are no JSON, the keys stay readable
+    let data_path = temp_dir.path().join("data_0.txt");
+    let mut content = fs::read(&data_path)?;
+    for value in [&b"damaged1"[..], b"damaged2"] {
+        let damaged = content
+            .windows(value.len())
+            .position(|window| window == value)
+            .expect("the value is in the file");
+        content[damaged] = b'"';
+    }
+    fs::write(&data_path, content)?;
+
+    // only the latest command of user2 is lost
+    let report = repair(temp_dir.path())?;
+    assert_eq!(report.files[0].corrupt.len(), 2);
+    assert_eq!(report.suspect_keys, vec!["users/user2".to_owned()]);
+    Ok(())
+}
+
+#[test]
+fn dump_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value11".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+
+    let records = dump(temp_dir.path(), None, None)?;
+    assert_eq!(records.len(), 4);
+    // the data file header comes first
+    assert_eq!(records[0].offset, 32);
+    assert_eq!(records[1].offset, 32 + records[0].length);
+    let summary: Vec<(&str, &str, Option<u64>, bool)> = records
+        .iter()
+        .map(|r| (r.command.as_str(), r.key.as_str(), r.value_size, r.live))
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            ("SET", "key1", Some(6), false),
+            ("SET", "key2", Some(6), false),
+            ("SET", "key1", Some(7), true),
+            ("RM", "key2", None, false),
+        ]
+    );
+
+    let records = dump(temp_dir.path(), Some("key1"), None)?;
+    assert_eq!(records.len(), 2);
+    assert!(dump(temp_dir.path(), None, Some(1))?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn engine_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value1".to_owned())?;
+
+    let stats = store.stats()?;
+    assert_eq!(stats.engine, "kvs");
+    assert_eq!(stats.keys, 2);
+    assert_eq!(stats.data_files.len(), 1);
+    assert_eq!(stats.disk_size, stats.data_files[0].size);
+    assert!(stats.garbage_bytes.unwrap() > 0);
+    assert_eq!(stats.compactions, Some(0));
+
+    let value = "v".repeat(10 * 1024);
+    for _ in 0..1000 {
+        store.set("key1".to_owned(), value.clone())?;
+        if store.stats()?.compactions == Some(1) {
+            let stats = store.stats()?;
+            assert_eq!(stats.keys, 2);
+            assert_eq!(stats.garbage_bytes, Some(0));
+            return Ok(());
+        }
+    }
+    panic!("No compaction detected");
+}
+
+#[test]
+fn sled_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value1".to_owned())?;
+
+    let stats = store.stats()?;
+    assert_eq!(stats.engine, "sled");
+    assert_eq!(stats.keys, 2);
+    assert!(stats.disk_size > 0);
+    assert_eq!(stats.garbage_bytes, None);
+    Ok(())
+}
+
+fn disk_index_options() -> KvStoreOptions {
+    KvStoreOptions {
+        index: IndexMode::Disk {
+            cache_pages: 4,
+            max_buffered_keys: 100,
+        },
+        ..KvStoreOptions::default()
+    }
+}
+
+#[test]
+fn disk_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), disk_index_options())?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in (0..1000).step_by(2) {
+        store.set(format!("key{}", i), format!("new{}", i))?;
+    }
+    for i in (0..1000).step_by(5) {
+        store.remove(format!("key{}", i))?;
+    }
+    assert!(store.remove("key0".to_owned()).is_err());
+    assert!(temp_dir.path().join("index.dat").exists());
+
+    let check = |store: &KvStore| -> Result<()> {
+        for i in 0..1000 {
+            let expected = if i % 5 == 0 {
+                None
+            } else if i % 2 == 0 {
+                Some(format!("new{}", i))
+            } else {
+                Some(format!("value{}", i))
+            };
+            assert_eq!(store.get(format!("key{}", i))?, expected);
+        }
+        assert_eq!(store.stats()?.keys, 800);
+        let mut scanned = 0;
+        store.scan(|_, _| {
+            scanned += 1;
+            Ok(())
+        })?;
+        assert_eq!(scanned, 800);
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+
+    // reopen from the index file and the log after its checkpoint
+    let store = KvStore::open_with_options(temp_dir.path(), disk_index_options())?;
+    check(&store)?;
+    drop(store);
+
+    // the in-memory index rebuilds from the data files and drops the stale index file
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+    drop(store);
+    assert!(!temp_dir.path().join("index.dat").exists());
+
+    Ok(())
+}
+
+// Buffered updates are written as runs which are merged, not into a new copy of the index
+#[test]
+fn disk_index_runs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions {
+        index: IndexMode::Disk {
+            cache_pages: 4,
+            max_buffered_keys: 10,
+        },
+        ..KvStoreOptions::default()
+    };
+    let index_files = || -> Result<usize> {
+        let mut count = 0;
+        for entry in fs::read_dir(temp_dir.path())? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if name.starts_with("index") && name.ends_with(".dat") {
+                count += 1;
+            }
+        }
+        Ok(count)
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for i in 0..2000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        assert!(index_files()? <= 12);
+    }
+    for i in (0..2000).step_by(3) {
+        store.remove(format!("key{}", i))?;
+    }
+    assert!(index_files()? > 1);
+    drop(store);
+
+    // a key removed in a newer run stays removed
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for i in 0..2000 {
+        let expected = if i % 3 != 0 {
+            Some(format!("value{}", i))
+        } else {
+            None
+        };
+        assert_eq!(store.get(format!("key{}", i))?, expected);
+    }
+    assert_eq!(store.stats()?.keys, 1333);
+    Ok(())
+}
+
+#[test]
+fn disk_index_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), disk_index_options())?;
+    let value = "v".repeat(10000);
+    for iter in 0..3 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}{}", iter, value))?;
+        }
+    }
+    assert!(store.stats()?.compactions.unwrap_or(0) > 0);
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), disk_index_options())?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("2{}", value))
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn disk_index_rebuilds_corrupt_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), disk_index_options())?;
+    for i in 0..200 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let index_path = temp_dir.path().join("index.dat");
+    let size = fs::metadata(&index_path)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&index_path)?
+        .set_len(size - 3)?;
+
+    let store = KvStore::open_with_options(temp_dir.path(), disk_index_options())?;
+    for i in 0..200 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
+
+fn bloom_options() -> KvStoreOptions {
+    KvStoreOptions {
+        bloom_false_positive_rate: Some(0.01),
+        ..disk_index_options()
+    }
+}
+
+fn bloom_files(dir: &Path) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if name.starts_with("bloom_") {
+            names.push(name);
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+#[test]
+fn bloom_filters() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), bloom_options())?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        assert_eq!(store.get(format!("missing{}", i))?, None);
+    }
+
+    let stats = store.stats()?;
+    let skips = stats.bloom_skips.unwrap();
+    let false_positives = stats.bloom_false_positives.unwrap();
+    assert_eq!(skips + false_positives, 1000);
+    assert!(false_positives < 50);
+    assert_eq!(
+        stats.bloom_false_positive_rate,
+        Some(false_positives as f64 / 1000.0)
+    );
+
+    // a removed key passes the filters until compaction
+    store.remove("key0".to_owned())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(
+        store.stats()?.bloom_false_positives,
+        Some(false_positives + 1)
+    );
+
+    // the stores without filters report no filter statistics
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let stats = KvStore::open(temp_dir.path())?.stats()?;
+    assert_eq!(stats.bloom_skips, None);
+    assert_eq!(stats.bloom_false_positive_rate, None);
+
+    assert!(KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions {
+            bloom_false_positive_rate: Some(1.0),
+            ..KvStoreOptions::default()
+        }
+    )
+    .is_err());
+
+    Ok(())
+}
+
+#[test]
+fn bloom_filter_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), bloom_options())?;
+    // overwritten values make compaction seal the files
+    let value = "v".repeat(10000);
+    for _ in 0..2 {
+        for i in 0..200 {
+            store.set(format!("key{}", i), value.clone())?;
+        }
+    }
+    assert!(store.stats()?.compactions.unwrap_or(0) > 0);
+    drop(store);
+
+    // the filters of the sealed files are saved, those of the newest file are built on open
+    let check = |store: &KvStore| -> Result<()> {
+        for i in 0..200 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(value.clone()));
+            assert_eq!(store.get(format!("missing{}", i))?, None);
+        }
+        assert!(store.stats()?.bloom_skips.unwrap() > 150);
+        Ok(())
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), bloom_options())?;
+    check(&store)?;
+    store.set("new".to_owned(), "1".to_owned())?;
+    drop(store);
+    let saved = bloom_files(temp_dir.path())?;
+    assert!(!saved.is_empty());
+
+    let store = KvStore::open_with_options(temp_dir.path(), bloom_options())?;
+    check(&store)?;
+    assert_eq!(store.get("new".to_owned())?, Some("1".to_owned()));
+    drop(store);
+
+    // a damaged or missing filter file is built again from its data file
+    let damaged = temp_dir.path().join(&saved[0]);
+    let size = fs::metadata(&damaged)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&damaged)?
+        .set_len(size - 3)?;
+    if let Some(name) = saved.get(1) {
+        fs::remove_file(temp_dir.path().join(name))?;
+    }
+    let store = KvStore::open_with_options(temp_dir.path(), bloom_options())?;
+    check(&store)?;
+    drop(store);
+    assert_eq!(bloom_files(temp_dir.path())?, saved);
+
+    // filters which a store without them would not keep up to date are deleted
+    let store = KvStore::open_with_options(temp_dir.path(), disk_index_options())?;
+    store.set("key0".to_owned(), "2".to_owned())?;
+    drop(store);
+    assert!(bloom_files(temp_dir.path())?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn bloom_filters_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), bloom_options())?;
+    let ns = {
+        store.create_namespace("ns")?;
+        store.namespace("ns")?
+    };
+    ns.set("only_in_ns".to_owned(), "1".to_owned())?;
+    let value = "v".repeat(10000);
+    for iter in 0..3 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}{}", iter, value))?;
+        }
+    }
+    assert!(store.stats()?.compactions.unwrap_or(0) > 0);
+
+    let check = |store: &KvStore, ns: &KvStore| -> Result<()> {
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("2{}", value))
+            );
+            assert_eq!(ns.get(format!("key{}", key_id))?, None);
+        }
+        assert_eq!(ns.get("only_in_ns".to_owned())?, Some("1".to_owned()));
+        assert_eq!(store.get("only_in_ns".to_owned())?, None);
+        Ok(())
+    };
+    check(&store, &ns)?;
+    drop(ns);
+    drop(store);
+
+    // the filters of the files removed by compaction are gone with them
+    for name in bloom_files(temp_dir.path())? {
+        let number = name["bloom_".len()..name.len() - ".dat".len()].to_owned();
+        assert!(temp_dir
+            .path()
+            .join(format!("data_{}.txt", number))
+            .exists());
+    }
+
+    let store = KvStore::open_with_options(temp_dir.path(), bloom_options())?;
+    let ns = store.namespace("ns")?;
+    check(&store, &ns)?;
+
+    Ok(())
+}
+
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        value_cache_size: 1024 * 1024,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // clones share the cache
+    let clone = store.clone();
+    assert_eq!(clone.get("key1".to_owned())?, Some("value1".to_owned()));
+    let stats = store.stats()?;
+    assert_eq!(stats.cache_hits, Some(1));
+    assert_eq!(stats.cache_misses, Some(1));
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(clone.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.remove("key1".to_owned())?;
+    assert_eq!(clone.get("key1".to_owned())?, None);
+
+    // compaction moves every command
+    let value = "v".repeat(10000);
+    for iter in 0..3 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}{}", iter, value))?;
+            assert_eq!(
+                clone.get(format!("key{}", key_id))?,
+                Some(format!("{}{}", iter, value))
+            );
+        }
+    }
+    let stats = store.stats()?;
+    assert!(stats.compactions.unwrap_or(0) > 0);
+    for key_id in 0..100 {
+        for _ in 0..2 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("2{}", value))
+            );
+        }
+    }
+    assert!(store.stats()?.cache_hits.unwrap() >= stats.cache_hits.unwrap() + 100);
+
+    assert_eq!(
+        KvStore::open(TempDir::new().unwrap().path())?
+            .stats()?
+            .cache_hits,
+        None
+    );
+    Ok(())
+}
+
+#[test]
+fn mapped_sealed_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = "v".repeat(10000);
+    for iter in 0..3 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}{}", iter, value))?;
+        }
+    }
+    store.set("last".to_owned(), "value".to_owned())?;
+    let stats = store.stats()?;
+    assert!(stats.compactions.unwrap() > 0);
+    // only the compacted file and the active file are left
+    assert_eq!(stats.data_files.len(), 2);
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for key_id in 0..100 {
+                    assert_eq!(
+                        store.get(format!("key{}", key_id))?.map(|v| v.len()),
+                        Some(10001)
+                    );
+                }
+                assert_eq!(store.get("last".to_owned())?, Some("value".to_owned()));
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    let stats = store.stats()?;
+    assert_eq!(stats.mapped_files, Some(1));
+    drop(store);
+
+    // every file before the active one is sealed after reopening
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(format!("2{}", value)));
+    assert_eq!(store.get("last".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.stats()?.mapped_files, Some(1));
+
+    Ok(())
+}
+
+fn compression_options(compression: Compression) -> KvStoreOptions {
+    KvStoreOptions {
+        compression,
+        ..KvStoreOptions::default()
+    }
+}
+
+fn verbose_value(i: u32) -> String {
+    format!(
+        "{{\"id\": {}, \"tags\": [{}]}}",
+        i,
+        "\"verbose\", ".repeat(50)
+    )
+}
+
+#[test]
+fn compressed_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("plain".to_owned(), verbose_value(0))?;
+    drop(store);
+    let plain_size = fs::metadata(temp_dir.path().join("data_0.txt"))?.len();
+
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        compression_options(Compression::Lz4 { min_size: 64 }),
+    )?;
+    store.set("lz4".to_owned(), verbose_value(1))?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("plain".to_owned())?, Some(verbose_value(0)));
+    assert_eq!(store.get("lz4".to_owned())?, Some(verbose_value(1)));
+    drop(store);
+    let size = fs::metadata(temp_dir.path().join("data_0.txt"))?.len();
+    assert!(size - plain_size < plain_size / 2);
+
+    // mixed files are read whatever the options are
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("plain".to_owned())?, Some(verbose_value(0)));
+    assert_eq!(store.get("lz4".to_owned())?, Some(verbose_value(1)));
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    drop(store);
+
+    let records = dump(temp_dir.path(), None, None)?;
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[1].value_size, Some(verbose_value(1).len() as u64));
+    assert!(!verify(temp_dir.path())?.is_corrupt());
+
+    Ok(())
+}
+
+#[test]
+fn compaction_recompresses_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), verbose_value(i))?;
+    }
+    drop(store);
+    let plain_size: u64 = verify(temp_dir.path())?.files.iter().map(|f| f.size).sum();
+
+    let options = compression_options(Compression::Zstd {
+        level: 3,
+        min_size: 64,
+    });
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    // random values which do not compress much, so that compaction starts
+    let mut rng = thread_rng();
+    for _ in 0..3 {
+        for key_id in 0..100 {
+            let value: String = (&mut rng)
+                .sample_iter(&Alphanumeric)
+                .take(10000)
+                .map(char::from)
+                .collect();
+            store.set(format!("big{}", key_id), value)?;
+        }
+    }
+    let stats = store.stats()?;
+    assert!(stats.compactions.unwrap() > 0);
+    // the plain records were compressed by the compaction
+    let compacted_size: u64 = dump(temp_dir.path(), None, Some(stats.data_files[0].file_number))?
+        .iter()
+        .filter(|record| record.key.starts_with("key"))
+        .map(|record| record.length)
+        .sum();
+    assert!(compacted_size < plain_size / 2);
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(verbose_value(i)));
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(verbose_value(i)));
+    }
+    Ok(())
+}
+
+#[test]
+fn corrupt_compressed_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = compression_options(Compression::Lz4 { min_size: 64 });
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), verbose_value(1))?;
+    store.set("key2".to_owned(), verbose_value(2))?;
+    store.set("key3".to_owned(), verbose_value(3))?;
+    drop(store);
+
+    // flip a byte in the payload of the second record
+    let records = dump(temp_dir.path(), None, None)?;
+    let data_path = temp_dir.path().join("data_0.txt");
+    let mut content = fs::read(&data_path)?;
+    let damaged = (records[1].offset + records[1].length / 2) as usize;
+    content[damaged] ^= 0xff;
+    fs::write(&data_path, content)?;
+
+    let report = verify(temp_dir.path())?;
+    assert!(report.is_corrupt());
+    assert_eq!(report.files[0].records, 2);
+    assert_eq!(report.files[0].corrupt[0].start, records[1].offset);
+    assert_eq!(report.files[0].corrupt[0].end, records[2].offset);
+
+    repair(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(verbose_value(1)));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some(verbose_value(3)));
+    Ok(())
+}
+
+fn encryption_options(encryption: Encryption) -> KvStoreOptions {
+    KvStoreOptions {
+        encryption: Some(encryption),
+        ..KvStoreOptions::default()
+    }
+}
+
+#[test]
+fn encrypted_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = Encryption::from_hex(&"ab".repeat(32))?;
+    let options = KvStoreOptions {
+        compression: Compression::Lz4 { min_size: 64 },
+        ..encryption_options(key.clone())
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("secret1".to_owned(), "plaintext".to_owned())?;
+    store.set("secret2".to_owned(), verbose_value(2))?;
+    store.remove("secret1".to_owned())?;
+    drop(store);
+
+    let data = fs::read(temp_dir.path().join("data_0.txt"))?;
+    let data = String::from_utf8_lossy(&data);
+    assert!(!data.contains("secret") && !data.contains("plaintext") && !data.contains("verbose"));
+
+    let store = KvStore::open_with_options(temp_dir.path(), encryption_options(key.clone()))?;
+    assert_eq!(store.get("secret1".to_owned())?, None);
+    assert_eq!(store.get("secret2".to_owned())?, Some(verbose_value(2)));
+    drop(store);
+
+    // a missing or wrong key is an error, not corruption
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KVStoreError::EncryptionKey(_))
+    ));
+    let wrong_key = Encryption::from_hex(&"cd".repeat(32))?;
+    assert!(matches!(
+        KvStore::open_with_options(temp_dir.path(), encryption_options(wrong_key)),
+        Err(KVStoreError::EncryptionKey(_))
+    ));
+    assert!(matches!(
+        verify(temp_dir.path()),
+        Err(KVStoreError::EncryptionKey(_))
+    ));
+    let report = verify_encrypted(temp_dir.path(), &key)?;
+    assert!(!report.is_corrupt());
+    assert_eq!(report.live_keys(), 1);
+
+    assert!(Encryption::from_hex("not a key").is_err());
+    assert!(Encryption::from_hex(&"ab".repeat(31)).is_err());
+    Ok(())
+}
+
+#[test]
+fn encryption_key_rotation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let old_key = [1; 32];
+    let new_key = [2; 32];
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("plain".to_owned(), "value0".to_owned())?;
+    drop(store);
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        encryption_options(Encryption::new(old_key)),
+    )?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let rotating = Encryption::new(new_key).with_old_key(old_key);
+    let store = KvStore::open_with_options(temp_dir.path(), encryption_options(rotating))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let value = "v".repeat(10000);
+    for iter in 0..3 {
+        for key_id in 0..100 {
+            store.set(format!("big{}", key_id), format!("{}{}", iter, value))?;
+        }
+    }
+    assert!(store.stats()?.compactions.unwrap() > 0);
+    drop(store);
+
+    // compaction rewrote every record with the new key
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        encryption_options(Encryption::new(new_key)),
+    )?;
+    assert_eq!(store.get("plain".to_owned())?, Some("value0".to_owned()));
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        assert_eq!(store.get(format!("big{}", i))?, Some(format!("2{}", value)));
+    }
+    Ok(())
+}
+
+#[test]
+fn encrypted_records_are_bound_to_their_place() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = Encryption::new([3; 32]);
+    let store = KvStore::open_with_options(temp_dir.path(), encryption_options(key.clone()))?;
+    for i in 1..=3 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    // swapping two records of the same length must not go unnoticed
+    let records = dump_encrypted(temp_dir.path(), None, None, &key)?;
+    assert_eq!(records[1].length, records[2].length);
+    let path = temp_dir.path().join("data_0.txt");
+    let mut data = fs::read(&path)?;
+    let (first, second) = (records[1].offset as usize, records[2].offset as usize);
+    let length = records[1].length as usize;
+    let record = data[first..first + length].to_vec();
+    data.copy_within(second..second + length, first);
+    data[second..second + length].copy_from_slice(&record);
+    fs::write(&path, &data)?;
+    let report = verify_encrypted(temp_dir.path(), &key)?;
+    assert_eq!(report.files[0].corrupt.len(), 1);
+    assert!(report.files[0].corrupt[0].reason.contains("authentication"));
+
+    // repair binds the salvaged records to their new place
+    repair_encrypted(temp_dir.path(), &key)?;
+    let store = KvStore::open_with_options(temp_dir.path(), encryption_options(key.clone()))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    drop(store);
+
+    // the disk index would keep the keys in plaintext
+    let options = KvStoreOptions {
+        index: IndexMode::disk(),
+        ..encryption_options(key)
+    };
+    assert!(KvStore::open_with_options(temp_dir.path(), options).is_err());
+    Ok(())
+}
+
+#[test]
+fn upgrade_legacy_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // a directory written before data files had a header
+    fs::write(
+        temp_dir.path().join("data_0.txt"),
+        r#"{"SET":["key1","value1"]}{"SET":["key2","value2"]}{"RM":"key1"}"#,
+    )?;
+    fs::write(
+        temp_dir.path().join("data_1.txt"),
+        r#"{"SET":["key3","value3"]}"#,
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    drop(store);
+    let report = verify(temp_dir.path())?;
+    assert_eq!(report.files[0].format_version, 0);
+    assert_eq!(report.files[0].created, None);
+
+    let report = upgrade(temp_dir.path())?;
+    assert_eq!(report.upgraded, vec![0, 1]);
+    assert!(report.current.is_empty());
+    let report = verify(temp_dir.path())?;
+    assert!(!report.is_corrupt());
+    assert!(report
+        .files
+        .iter()
+        .all(|file| file.format_version == FORMAT_VERSION && file.created.is_some()));
+    assert!(fs::read(temp_dir.path().join("data_1.txt"))?.starts_with(b"KVS-DATA"));
+    assert_eq!(upgrade(temp_dir.path())?.upgraded, Vec::<u64>::new());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    drop(store);
+
+    // a file written by a newer version is refused
+    fs::write(
+        temp_dir.path().join("data_2.txt"),
+        "KVS-DATA000000090000000000000000",
+    )?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KVStoreError::UnsupportedFormatVersion(9))
+    ));
+    Ok(())
+}
+
+// An upgrade runs over any storage and can be run again after a crash
+#[test]
+fn upgrade_memory_storage() -> Result<()> {
+    let storage = MemoryStorage::new();
+    storage.create_dir_all(Path::new("/store"))?;
+    let mut file = storage.create(Path::new("/store/data_0.txt"))?;
+    file.write_all(br#"{"SET":["key1","value1"]}{"SET":["key2","value2"]}{"RM":"key1"}"#)?;
+    file.sync()?;
+    drop(file);
+    assert!(upgrade_with_storage("/missing", &storage).is_err());
+
+    storage.set_faults(Faults {
+        fail_after_writes: Some(0),
+        ..Faults::default()
+    });
+    assert!(upgrade_with_storage("/store", &storage).is_err());
+    storage.crash();
+    let report = upgrade_with_storage("/store", &storage)?;
+    assert_eq!(report.upgraded, vec![0]);
+    assert_eq!(upgrade_with_storage("/store", &storage)?.current, vec![0]);
+
+    let mut data = Vec::new();
+    storage
+        .open(Path::new("/store/data_0.txt"))?
+        .read_to_end(&mut data)?;
+    assert!(data.starts_with(b"KVS-DATA"));
+    let store = KvStore::open_with_options(
+        "/store",
+        memory_storage_options(&storage, IndexMode::Memory),
+    )?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+    assert!(!storage
+        .files()
+        .iter()
+        .any(|path| path.extension().map_or(false, |ext| ext == "tmp")));
+    Ok(())
+}
+
+// Namespaces keep their keys apart, and a dropped namespace leaves nothing behind
+fn check_namespaces<E: KvsEngine>(open: impl Fn() -> Result<E>) -> Result<()> {
+    let store = open()?;
+    store.create_namespace("users")?;
+    store.create_namespace("orders")?;
+    assert!(matches!(
+        store.create_namespace("users"),
+        Err(KVStoreError::NamespaceExists(_))
+    ));
+    assert!(store.create_namespace("").is_err());
+    assert!(matches!(
+        store.namespace("missing"),
+        Err(KVStoreError::NamespaceNotFound(_))
+    ));
+    assert_eq!(store.list_namespaces()?, vec!["orders", "users"]);
+
+    let users = store.namespace("users")?;
+    let orders = store.namespace("orders")?;
+    store.set("key".to_owned(), "default".to_owned())?;
+    users.set("key".to_owned(), "user".to_owned())?;
+    orders.set("key".to_owned(), "order".to_owned())?;
+    for i in 0..100 {
+        users.set(format!("user{}", i), format!("value{}", i))?;
+    }
+    orders.remove("key".to_owned())?;
+    assert!(orders.remove("key".to_owned()).is_err());
+
+    assert_eq!(store.get("key".to_owned())?, Some("default".to_owned()));
+    assert_eq!(users.get("key".to_owned())?, Some("user".to_owned()));
+    assert_eq!(orders.get("key".to_owned())?, None);
+    assert_eq!(store.get("user0".to_owned())?, None);
+    assert_eq!(users.stats()?.keys, 101);
+    let mut scanned = 0;
+    users.scan(|_, _| {
+        scanned += 1;
+        Ok(())
+    })?;
+    assert_eq!(scanned, 101);
+
+    store.drop_namespace("users")?;
+    assert!(matches!(
+        users.get("key".to_owned()),
+        Err(KVStoreError::NamespaceNotFound(_))
+    ));
+    assert!(store.drop_namespace("users").is_err());
+    assert_eq!(store.list_namespaces()?, vec!["orders"]);
+    drop(users);
+    drop(orders);
+    drop(store);
+
+    let store = open()?;
+    assert_eq!(store.list_namespaces()?, vec!["orders"]);
+    assert_eq!(store.get("key".to_owned())?, Some("default".to_owned()));
+    assert_eq!(store.namespace("orders")?.stats()?.keys, 0);
+    // a namespace created again with the same name starts empty
+    store.create_namespace("users")?;
+    let users = store.namespace("users")?;
+    assert_eq!(users.get("key".to_owned())?, None);
+    assert_eq!(users.stats()?.keys, 0);
+    Ok(())
+}
+
+#[test]
+fn namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_namespaces(|| KvStore::open(temp_dir.path()))
+}
+
+#[test]
+fn namespaces_disk_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_namespaces(|| KvStore::open_with_options(temp_dir.path(), disk_index_options()))?;
+
+    // every namespace has an index file of its own
+    let store = KvStore::open_with_options(temp_dir.path(), disk_index_options())?;
+    let users = store.namespace("users")?;
+    for i in 0..1000 {
+        users.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(users);
+    drop(store);
+    let mut index_stems = Vec::new();
+    for entry in fs::read_dir(temp_dir.path())? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if name.starts_with("index_") && name.ends_with(".dat") {
+            index_stems.push(name.split(['-', '.']).next().unwrap_or_default().to_owned());
+        }
+    }
+    index_stems.sort();
+    index_stems.dedup();
+    assert_eq!(index_stems.len(), 1);
+    let store = KvStore::open_with_options(temp_dir.path(), disk_index_options())?;
+    let users = store.namespace("users")?;
+    assert_eq!(users.stats()?.keys, 1000);
+    assert_eq!(users.get("key999".to_owned())?, Some("value999".to_owned()));
+    assert_eq!(store.get("key".to_owned())?, Some("default".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn sled_namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_namespaces(|| SledKvsEngine::open(temp_dir.path()))
+}
+
+// Dropping a namespace turns its commands into garbage which compaction removes
+#[test]
+fn drop_namespace_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.create_namespace("temp")?;
+    let temp = store.namespace("temp")?;
+    let value = "v".repeat(1000);
+    for i in 0..600 {
+        temp.set(format!("key{}", i), value.clone())?;
+    }
+    store.set("kept".to_owned(), "value".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.compactions, Some(0));
+    assert!(stats.garbage_bytes < Some(1000));
+
+    store.drop_namespace("temp")?;
+    let stats = store.stats()?;
+    assert_eq!(stats.compactions, Some(0));
+    assert!(stats.garbage_bytes > Some(600 * 1000));
+
+    // the commands of the dropped namespace count towards the next compaction
+    store.create_namespace("temp")?;
+    let temp = store.namespace("temp")?;
+    for i in 0..600 {
+        temp.set(format!("key{}", i), value.clone())?;
+    }
+    store.drop_namespace("temp")?;
+    let stats = store.stats()?;
+    assert_eq!(stats.compactions, Some(1));
+    assert!(stats.disk_size < 1000);
+    assert_eq!(verify(temp_dir.path())?.live_keys(), 1);
+    assert!(dump(temp_dir.path(), Some("key0"), None)?.is_empty());
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("kept".to_owned())?, Some("value".to_owned()));
+    assert!(store.list_namespaces()?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn encrypted_namespace_names() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.create_namespace("customers")?;
+    store
+        .namespace("customers")?
+        .set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    // the names are encrypted once the store is opened with a key
+    let key = Encryption::new([4; 32]);
+    let store = KvStore::open_with_options(temp_dir.path(), encryption_options(key.clone()))?;
+    store.create_namespace("suppliers")?;
+    drop(store);
+    let names = fs::read(temp_dir.path().join("namespaces.json"))?;
+    let names = String::from_utf8_lossy(&names);
+    assert!(!names.contains("customers") && !names.contains("suppliers"));
+
+    let store = KvStore::open_with_options(temp_dir.path(), encryption_options(key))?;
+    assert_eq!(
+        store.namespace("customers")?.get("key".to_owned())?,
+        Some("value".to_owned())
+    );
+    drop(store);
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KVStoreError::EncryptionKey(_))
+    ));
+    Ok(())
+}
+
+// Keys are split between the shards, which are written in parallel
+#[test]
+fn sharded_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = ShardedKvStore::open(temp_dir.path(), 4)?;
+    let handles: Vec<_> = (0..4)
+        .map(|thread| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..250 {
+                    store.set(format!("key{}-{}", thread, i), format!("value{}", i))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    store.remove("key0-0".to_owned())?;
+    assert!(store.remove("key0-0".to_owned()).is_err());
+    assert_eq!(store.stats()?.keys, 999);
+    drop(store);
+
+    // every shard holds a part of the keys
+    for shard in 0..4 {
+        let shard = KvStore::open(temp_dir.path().join(format!("shard_{}", shard)))?;
+        let keys = shard.stats()?.keys;
+        assert!(keys > 100 && keys < 400, "{} keys in a shard", keys);
+    }
+
+    assert!(ShardedKvStore::open(temp_dir.path(), 8).is_err());
+    let store = ShardedKvStore::open(temp_dir.path(), 4)?;
+    assert_eq!(ShardedKvStore::existing_shards(temp_dir.path())?, 4);
+    assert_eq!(store.get("key0-0".to_owned())?, None);
+    assert_eq!(
+        store.get("key3-249".to_owned())?,
+        Some("value249".to_owned())
+    );
+    let mut scanned = 0;
+    store.scan(|_, _| {
+        scanned += 1;
+        Ok(())
+    })?;
+    assert_eq!(scanned, 999);
+    Ok(())
+}
+
+#[test]
+fn sharded_namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_namespaces(|| ShardedKvStore::open(temp_dir.path(), 3))
+}
+
+#[test]
+fn sharded_store_rejects_unsharded_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::open(temp_dir.path())?.set("key".to_owned(), "value".to_owned())?;
+    assert!(ShardedKvStore::open(temp_dir.path(), 2).is_err());
+    Ok(())
+}
+
+// The shard count is fixed by the first open, even if it stopped before every shard existed
+#[test]
+fn sharded_store_partial_first_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    ShardedKvStore::open(temp_dir.path(), 4)?.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(ShardedKvStore::existing_shards(temp_dir.path())?, 4);
+
+    // an open interrupted after creating only some of the shards
+    let partial_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::copy(
+        temp_dir.path().join("shards"),
+        partial_dir.path().join("shards"),
+    )?;
+    KvStore::open(partial_dir.path().join("shard_0"))?;
+    KvStore::open(partial_dir.path().join("shard_1"))?;
+    assert_eq!(ShardedKvStore::existing_shards(partial_dir.path())?, 4);
+    assert!(ShardedKvStore::open(partial_dir.path(), 2).is_err());
+    let store = ShardedKvStore::open(partial_dir.path(), 4)?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    drop(store);
+    assert!(partial_dir.path().join("shard_3").is_dir());
+
+    // a store without the marker is counted by its shard directories
+    fs::remove_file(temp_dir.path().join("shards"))?;
+    assert_eq!(ShardedKvStore::existing_shards(temp_dir.path())?, 4);
+    assert!(ShardedKvStore::open(temp_dir.path(), 2).is_err());
+    let store = ShardedKvStore::open(temp_dir.path(), 4)?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    assert!(temp_dir.path().join("shards").is_file());
+    Ok(())
+}
+
+// The memory engine behaves like the others without touching the disk
+#[test]
+fn memory_engine() -> Result<()> {
+    let store = MemoryKvsEngine::new();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.remove("key2".to_owned())?;
+    assert!(matches!(
+        store.remove("key2".to_owned()),
+        Err(KVStoreError::KeyNotFound)
+    ));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // clones share the keys
+    let clone = store.clone();
+    thread::spawn(move || clone.set("key3".to_owned(), "value4".to_owned()))
+        .join()
+        .unwrap()?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+
+    let stats = store.stats()?;
+    assert_eq!(stats.engine, "memory");
+    assert_eq!(stats.keys, 2);
+    assert_eq!(stats.disk_size, 0);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let target = KvStore::open(temp_dir.path())?;
+    assert_eq!(migrate(&store, &target)?, 2);
+    assert_eq!(target.get("key3".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn memory_namespaces() -> Result<()> {
+    let store = MemoryKvsEngine::new();
+    check_namespaces(|| Ok(store.clone()))
+}
+
+#[test]
+fn lsm_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsEngine::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert!(matches!(
+        store.remove("key2".to_owned()),
+        Err(KVStoreError::KeyNotFound)
+    ));
+    assert_eq!(store.stats()?.engine, "lsm");
+    drop(store);
+
+    // the memtable is rebuilt from the write-ahead log
+    let store = LsmKvsEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.stats()?.keys, 1);
+    Ok(())
+}
+
+fn small_lsm_options() -> LsmOptions {
+    LsmOptions {
+        memtable_size: 4 * 1024,
+        table_size: 8 * 1024,
+        level0_tables: 2,
+        ..LsmOptions::default()
+    }
+}
+
+#[test]
+fn lsm_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsEngine::open_with_options(temp_dir.path(), small_lsm_options())?;
+    for round in 0..3 {
+        for i in 0..1000 {
+            store.set(format!("key{:04}", i), format!("value{}-{}", i, round))?;
+        }
+    }
+    for i in (0..1000).step_by(2) {
+        store.remove(format!("key{:04}", i))?;
+    }
+    let stats = store.stats()?;
+    assert!(stats.compactions.unwrap() > 0);
+    assert!(!stats.data_files.is_empty());
+    assert_eq!(stats.keys, 500);
+    drop(store);
+
+    let store = LsmKvsEngine::open_with_options(temp_dir.path(), small_lsm_options())?;
+    for i in 0..1000 {
+        let expected = (i % 2 == 1).then(|| format!("value{}-2", i));
+        assert_eq!(store.get(format!("key{:04}", i))?, expected);
+    }
+    // scan returns the keys in order
+    let mut keys = Vec::new();
+    store.scan(|key, _| {
+        keys.push(key);
+        Ok(())
+    })?;
+    let expected: Vec<String> = (1..1000)
+        .step_by(2)
+        .map(|i| format!("key{:04}", i))
+        .collect();
+    assert_eq!(keys, expected);
+    Ok(())
+}
+
+#[test]
+fn lsm_namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_namespaces(|| LsmKvsEngine::open_with_options(temp_dir.path(), small_lsm_options()))
+}
+
+#[test]
+fn lsm_torn_wal_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsEngine::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    // a write which was cut short by a crash
+    let wal = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| {
+            path.extension()
+                .map_or(false, |extension| extension == "log")
+        })
+        .unwrap();
+    OpenOptions::new()
+        .append(true)
+        .open(&wal)?
+        .write_all(&[20, 0, 0, 0, 1, 2])?;
+
+    let store = LsmKvsEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let store = LsmKvsEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn btree_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BTreeKvsEngine::open(temp_dir.path())?;
+    for i in 0..3000 {
+        store.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+    // values which take several overflow pages
+    let large = "v".repeat(10_000);
+    store.set("key0001".to_owned(), large.clone())?;
+    store.set("key0002".to_owned(), large.clone())?;
+    store.set("key0002".to_owned(), "small".to_owned())?;
+    for i in (0..3000).step_by(3) {
+        store.remove(format!("key{:04}", i))?;
+    }
+    assert!(matches!(
+        store.remove("key0000".to_owned()),
+        Err(KVStoreError::KeyNotFound)
+    ));
+    assert!(store.set("k".repeat(600), "value".to_owned()).is_err());
+    assert_eq!(store.stats()?.engine, "btree");
+    drop(store);
+
+    // the writes since the last checkpoint are replayed from the log
+    let store = BTreeKvsEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key0001".to_owned())?, Some(large));
+    assert_eq!(store.get("key0002".to_owned())?, Some("small".to_owned()));
+    assert_eq!(store.get("key0003".to_owned())?, None);
+    assert_eq!(
+        store.get("key2999".to_owned())?,
+        Some("value2999".to_owned())
+    );
+    let mut keys = Vec::new();
+    store.scan(|key, _| {
+        keys.push(key);
+        Ok(())
+    })?;
+    let expected: Vec<String> = (0..3000)
+        .filter(|i| i % 3 != 0)
+        .map(|i| format!("key{:04}", i))
+        .collect();
+    assert_eq!(keys, expected);
+    assert_eq!(store.stats()?.keys, 2000);
+    Ok(())
+}
+
+#[test]
+fn btree_checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BTreeOptions {
+        cache_pages: 16,
+        checkpoint_size: 64 * 1024,
+        ..BTreeOptions::default()
+    };
+    let store = BTreeKvsEngine::open_with_options(temp_dir.path(), options.clone())?;
+    for i in 0..2000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let store = BTreeKvsEngine::open_with_options(temp_dir.path(), options)?;
+    for i in 0..2000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    let stats = store.stats()?;
+    assert!(stats.cache_hits.unwrap() > 0);
+    assert!(stats.cache_misses.unwrap() > 0);
+    Ok(())
+}
+
+#[test]
+fn btree_sync_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BTreeOptions {
+        sync_writes: true,
+        ..BTreeOptions::default()
+    };
+    let store = BTreeKvsEngine::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    // the log is not emptied until a checkpoint
+    assert!(fs::metadata(temp_dir.path().join("btree.wal"))?.len() > 0);
+    drop(store);
+
+    let store = BTreeKvsEngine::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn btree_namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_namespaces(|| BTreeKvsEngine::open(temp_dir.path()))
+}
+
+// the keys of a dropped namespace are removed after the drop, even after a reopen
+#[test]
+fn btree_drop_namespace_lazily() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BTreeKvsEngine::open(temp_dir.path())?;
+    // values in overflow pages, which removing their keys frees
+    let value = "v".repeat(1000);
+    store.create_namespace("customers")?;
+    let customers = store.namespace("customers")?;
+    for i in 0..2000 {
+        customers.set(format!("key{}", i), value.clone())?;
+    }
+    store.compact()?;
+    let full_size = store.stats()?.disk_size;
+    store.drop_namespace("customers")?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    let store = BTreeKvsEngine::open(temp_dir.path())?;
+    store.create_namespace("customers")?;
+    assert_eq!(store.namespace("customers")?.stats()?.keys, 0);
+    assert_eq!(store.stats()?.keys, 1);
+    store.compact()?;
+
+    // the overflow pages of the removed keys are used again
+    let customers = store.namespace("customers")?;
+    for i in 0..2000 {
+        customers.set(format!("key{}", i), value.clone())?;
+    }
+    store.compact()?;
+    assert!(store.stats()?.disk_size <= full_size + 64 * 4096);
+    assert_eq!(customers.stats()?.keys, 2000);
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn btree_torn_wal_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BTreeKvsEngine::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    // a write which was cut short by a crash
+    OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("btree.wal"))?
+        .write_all(&[20, 0, 0, 0, 1, 2])?;
+
+    let store = BTreeKvsEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let store = BTreeKvsEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+fn memory_storage_options(storage: &MemoryStorage, index: IndexMode) -> KvStoreOptions {
+    KvStoreOptions {
+        index,
+        storage: Some(Arc::new(storage.clone())),
+        ..KvStoreOptions::default()
+    }
+}
+
+#[test]
+fn memory_storage_store() -> Result<()> {
+    for index in &[IndexMode::Memory, IndexMode::disk()] {
+        let storage = MemoryStorage::new();
+        let store =
+            KvStore::open_with_options("/store", memory_storage_options(&storage, index.clone()))?;
+        store.create_namespace("ns")?;
+        store
+            .namespace("ns")?
+            .set("key".to_owned(), "ns value".to_owned())?;
+        // overwrite the keys until compaction runs
+        let value = "v".repeat(1000);
+        for iter in 0..20 {
+            for key_id in 0..100 {
+                store.set(format!("key{}", key_id), format!("{}{}", value, iter))?;
+            }
+        }
+        assert!(store.stats()?.compactions.unwrap() > 0);
+        drop(store);
+
+        let store =
+            KvStore::open_with_options("/store", memory_storage_options(&storage, index.clone()))?;
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("{}19", value))
+            );
+        }
+        assert_eq!(
+            store.namespace("ns")?.get("key".to_owned())?,
+            Some("ns value".to_owned())
+        );
+        assert!(storage
+            .files()
+            .iter()
+            .all(|path| path.starts_with("/store")));
+    }
+    Ok(())
+}
+
+#[test]
+fn memory_storage_short_writes() -> Result<()> {
+    let storage = MemoryStorage::new();
+    storage.set_faults(Faults {
+        max_write_size: 7,
+        ..Faults::default()
+    });
+    let store = KvStore::open_with_options(
+        "/store",
+        memory_storage_options(&storage, IndexMode::Memory),
+    )?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+
+    let store = KvStore::open_with_options(
+        "/store",
+        memory_storage_options(&storage, IndexMode::Memory),
+    )?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn memory_storage_injected_errors() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let store = KvStore::open_with_options(
+        "/store",
+        memory_storage_options(&storage, IndexMode::Memory),
+    )?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    storage.set_faults(Faults {
+        fail_after_writes: Some(0),
+        ..Faults::default()
+    });
+    assert!(matches!(
+        store.set("key2".to_owned(), "value2".to_owned()),
+        Err(KVStoreError::Io(_))
+    ));
+    assert!(store.create_namespace("ns").is_err());
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    storage.set_faults(Faults {
+        fail_reads: true,
+        ..Faults::default()
+    });
+    assert!(KvStore::open_with_options(
+        "/store",
+        memory_storage_options(&storage, IndexMode::Memory)
+    )
+    .is_err());
+    Ok(())
+}
+
+#[test]
+fn memory_storage_crash() -> Result<()> {
+    let storage = MemoryStorage::new();
+    storage.create_dir_all("/dir".as_ref())?;
+    let mut file = storage.create("/dir/file".as_ref())?;
+    file.write_all(b"synced")?;
+    file.sync()?;
+    file.write_all(b" lost")?;
+    storage.crash();
+    assert!(file.write_all(b"after the crash").is_err());
+    let mut contents = String::new();
+    storage
+        .open("/dir/file".as_ref())?
+        .read_to_string(&mut contents)?;
+    assert_eq!(contents, "synced");
+
+    // the namespaces are synced when they change, the data files are not
+    let store = KvStore::open_with_options(
+        "/store",
+        memory_storage_options(&storage, IndexMode::Memory),
+    )?;
+    store.create_namespace("ns")?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    storage.crash();
+    assert!(store.set("key".to_owned(), "value".to_owned()).is_err());
+
+    let store = KvStore::open_with_options(
+        "/store",
+        memory_storage_options(&storage, IndexMode::Memory),
+    )?;
+    assert_eq!(store.list_namespaces()?, vec!["ns".to_owned()]);
+    assert_eq!(store.get("key".to_owned())?, None);
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// the garbage a crash leaves in place of the unsynced writes is cut off like a torn write
+#[test]
+fn crash_garbage_tail() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let options = || memory_storage_options(&storage, IndexMode::Memory);
+    let store = KvStore::open_with_options("/store", options())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.sync()?;
+    let synced_size = storage.open("/store/data_0.txt".as_ref())?.size()?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), "lost".to_owned())?;
+    }
+    storage.crash_tearing(|_, _| 0, Some(7));
+    drop(store);
+    let size = storage.open("/store/data_0.txt".as_ref())?.size()?;
+    assert!(size > synced_size);
+
+    let store = KvStore::open_with_options("/store", options())?;
+    assert_eq!(
+        storage.open("/store/data_0.txt".as_ref())?.size()?,
+        synced_size
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    // what is written after the cut is read again
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let store = KvStore::open_with_options("/store", options())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+/// Overwrite big values until the store has compacted once more.
+fn compact_once(store: &KvStore) -> Result<()> {
+    let compactions = store.stats()?.compactions.unwrap();
+    let value = "v".repeat(10000);
+    for iter in 0.. {
+        store.set(format!("filler{}", iter % 100), value.clone())?;
+        if store.stats()?.compactions.unwrap() > compactions {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// Removed keys stay removed when compaction drops their remove commands
+#[test]
+fn delete_compact_reopen() -> Result<()> {
+    for index in &[IndexMode::Memory, IndexMode::disk()] {
+        let storage = MemoryStorage::new();
+        let open = || {
+            KvStore::open_with_options("/store", memory_storage_options(&storage, index.clone()))
+        };
+        let store = open()?;
+        store.create_namespace("ns")?;
+        let ns = store.namespace("ns")?;
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+            ns.set(format!("key{}", i), format!("ns{}", i))?;
+        }
+        // the keys are set before the compaction and removed after it, or the other way
+        compact_once(&store)?;
+        for i in (0..100).step_by(2) {
+            store.remove(format!("key{}", i))?;
+        }
+        for i in (0..100).step_by(3) {
+            ns.remove(format!("key{}", i))?;
+        }
+        compact_once(&store)?;
+        for i in (0..100).step_by(4) {
+            store.set(format!("key{}", i), format!("again{}", i))?;
+        }
+        drop(ns);
+        drop(store);
+
+        let check = |store: &KvStore| -> Result<()> {
+            let ns = store.namespace("ns")?;
+            for i in 0..100 {
+                let expected = match i {
+                    i if i % 4 == 0 => Some(format!("again{}", i)),
+                    i if i % 2 == 0 => None,
+                    i => Some(format!("value{}", i)),
+                };
+                assert_eq!(store.get(format!("key{}", i))?, expected);
+                let expected = match i % 3 {
+                    0 => None,
+                    _ => Some(format!("ns{}", i)),
+                };
+                assert_eq!(ns.get(format!("key{}", i))?, expected);
+            }
+            Ok(())
+        };
+        let store = open()?;
+        check(&store)?;
+        compact_once(&store)?;
+        drop(store);
+        check(&open()?)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn failed_file_removal_keeps_tombstones() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let open = || {
+        KvStore::open_with_options(
+            "/store",
+            memory_storage_options(&storage, IndexMode::Memory),
+        )
+    };
+    let store = open()?;
+    store.set("doomed".to_owned(), "1".to_owned())?;
+    // the set goes into the file compaction writes and the remove into the next one
+    compact_once(&store)?;
+    store.remove("doomed".to_owned())?;
+    let data_files = |storage: &MemoryStorage| {
+        storage
+            .files()
+            .iter()
+            .filter(|path| path.to_string_lossy().contains("data_"))
+            .count()
+    };
+    let before = data_files(&storage);
+
+    // the oldest file is not deleted, so neither is the file with the remove command
+    storage.set_faults(Faults {
+        failing_removes: 1,
+        ..Faults::default()
+    });
+    compact_once(&store)?;
+    storage.clear_faults();
+    drop(store);
+    assert!(data_files(&storage) > before);
+
+    let store = open()?;
+    assert_eq!(store.get("doomed".to_owned())?, None);
+    // the next compaction deletes them
+    compact_once(&store)?;
+    drop(store);
+    assert_eq!(data_files(&storage), 2);
+    assert_eq!(open()?.get("doomed".to_owned())?, None);
+    Ok(())
+}
+
+fn compaction_options(compaction: CompactionOptions) -> KvStoreOptions {
+    KvStoreOptions {
+        compaction,
+        ..KvStoreOptions::default()
+    }
+}
+
+/// Overwrite one key with big values until there is more than a megabyte of garbage.
+fn make_garbage(store: &KvStore) -> Result<()> {
+    let value = "v".repeat(10000);
+    for iter in 0..150 {
+        store.set("garbage".to_owned(), format!("{}{}", iter, value))?;
+    }
+    Ok(())
+}
+
+#[test]
+fn manual_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in (0..100).step_by(2) {
+        store.remove(format!("key{}", i))?;
+    }
+    let stats = store.stats()?;
+    assert_eq!(stats.compactions, Some(0));
+    assert!(stats.garbage_bytes.unwrap() > 0);
+
+    store.compact()?;
+    let stats = store.stats()?;
+    assert_eq!(stats.compactions, Some(1));
+    assert_eq!(stats.garbage_bytes, Some(0));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        let expected = if i % 2 == 0 {
+            None
+        } else {
+            Some(format!("value{}", i))
+        };
+        assert_eq!(store.get(format!("key{}", i))?, expected);
+    }
+    Ok(())
+}
+
+#[test]
+fn paused_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_compaction_paused(true)?;
+    make_garbage(&store)?;
+    let stats = store.stats()?;
+    assert_eq!(stats.compactions, Some(0));
+    assert_eq!(stats.compaction_paused, Some(true));
+    assert!(stats.garbage_bytes.unwrap() > 1024 * 1024);
+
+    // the next write after resuming compacts
+    store.set_compaction_paused(false)?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.compactions, Some(1));
+    assert_eq!(stats.compaction_paused, Some(false));
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    assert!(store.get("garbage".to_owned())?.unwrap().starts_with("149"));
+
+    // the other engines which compact by themselves can not be paused
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled = SledKvsEngine::open(temp_dir.path())?;
+    assert!(sled.set_compaction_paused(true).is_err());
+    sled.set_compaction_paused(false)?;
+    sled.compact()?;
+    Ok(())
+}
+
+#[test]
+fn compaction_windows() -> Result<()> {
+    assert_eq!(
+        "22:30-05:00".parse::<CompactionWindow>()?,
+        CompactionWindow {
+            start: 22 * 60 + 30,
+            end: 5 * 60
+        }
+    );
+    for invalid in &["22:30", "24:00-01:00", "01:60-02:00", "01:00-01:00", "a-b"] {
+        assert!(invalid.parse::<CompactionWindow>().is_err(), "{}", invalid);
+    }
+    let night = CompactionWindow {
+        start: 22 * 60,
+        end: 5 * 60,
+    };
+    assert!(night.contains(23 * 60));
+    assert!(night.contains(60));
+    assert!(!night.contains(12 * 60));
+    assert!(!night.contains(5 * 60));
+
+    let now = (SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        / 60
+        % 1440) as u32;
+    let window = |from: u32, to: u32| CompactionWindow {
+        start: (now + from) % 1440,
+        end: (now + to) % 1440,
+    };
+
+    // outside its windows a store waits with compaction, unless asked to compact
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        compaction_options(CompactionOptions {
+            windows: vec![window(120, 180), window(600, 660)],
+            ..CompactionOptions::default()
+        }),
+    )?;
+    make_garbage(&store)?;
+    assert_eq!(store.stats()?.compactions, Some(0));
+    store.compact()?;
+    assert_eq!(store.stats()?.compactions, Some(1));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        compaction_options(CompactionOptions {
+            windows: vec![window(120, 180), window(1380, 60)],
+            ..CompactionOptions::default()
+        }),
+    )?;
+    make_garbage(&store)?;
+    assert_eq!(store.stats()?.compactions, Some(1));
+    Ok(())
+}
+
+#[test]
+fn compaction_rate_limit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        compaction_options(CompactionOptions {
+            rate_limit: Some(1024 * 1024),
+            ..CompactionOptions::default()
+        }),
+    )?;
+    // half a megabyte of live records takes half a second to copy
+    let value = "v".repeat(5000);
+    for i in 0..100 {
+        store.set(format!("key{}", i), value.clone())?;
+    }
+    let start = Instant::now();
+    store.compact()?;
+    assert!(start.elapsed() >= Duration::from_millis(450));
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value.clone()));
+    }
+    Ok(())
+}
+
+// writes go on while a compaction copies at its rate limit
+#[test]
+fn writes_during_throttled_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = compaction_options(CompactionOptions {
+        rate_limit: Some(512 * 1024),
+        ..CompactionOptions::default()
+    });
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    // a megabyte of live records takes two seconds to copy
+    let value = "v".repeat(5000);
+    for i in 0..200 {
+        store.set(format!("key{}", i), value.clone())?;
+    }
+    let compaction = {
+        let store = store.clone();
+        thread::spawn(move || store.compact())
+    };
+    thread::sleep(Duration::from_millis(200));
+
+    let start = Instant::now();
+    for i in 0..100 {
+        store.set(format!("new{}", i), format!("new{}", i))?;
+        store.set(format!("key{}", i), format!("changed{}", i))?;
+    }
+    store.remove("key199".to_owned())?;
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert_eq!(store.stats()?.compactions, Some(0));
+
+    compaction.join().unwrap()?;
+    assert_eq!(store.stats()?.compactions, Some(1));
+    let check = |store: &KvStore| -> Result<()> {
+        for i in 0..200 {
+            let expected = match i {
+                i if i < 100 => Some(format!("changed{}", i)),
+                199 => None,
+                _ => Some(value.clone()),
+            };
+            assert_eq!(store.get(format!("key{}", i))?, expected);
+        }
+        for i in 0..100 {
+            assert_eq!(store.get(format!("new{}", i))?, Some(format!("new{}", i)));
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open_with_options(temp_dir.path(), options)?)
+}
+
+#[test]
+fn size_limit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = |max_size| KvStoreOptions {
+        max_size: Some(max_size),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options(64 * 1024))?;
+    let value = "v".repeat(1000);
+    let mut written = 0;
+    let err = loop {
+        match store.set(format!("key{}", written), value.clone()) {
+            Ok(()) => written += 1,
+            Err(err) => break err,
+        }
+    };
+    assert!(
+        matches!(&err, KVStoreError::ReadOnly(reason) if reason.contains("size limit")),
+        "{}",
+        err
+    );
+    assert!(written > 50);
+    assert!(matches!(
+        store.set("key0".to_owned(), value.clone()),
+        Err(KVStoreError::ReadOnly(_))
+    ));
+    let stats = store.stats()?;
+    assert_eq!(stats.read_only, Some(true));
+    assert!(stats.disk_size <= 64 * 1024);
+    for i in 0..written {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value.clone()));
+    }
+    drop(store);
+
+    // a larger limit takes writes again
+    let store = KvStore::open_with_options(temp_dir.path(), options(128 * 1024))?;
+    store.set("more".to_owned(), value.clone())?;
+    assert_eq!(store.stats()?.read_only, Some(false));
+
+    // a store at its limit compacts its garbage to make room
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), options(256 * 1024))?;
+    let value = "v".repeat(10000);
+    for iter in 0..100 {
+        store.set("key".to_owned(), format!("{}{}", iter, value))?;
+    }
+    assert!(store.stats()?.compactions.unwrap() > 0);
+    assert!(store.get("key".to_owned())?.unwrap().starts_with("99"));
+    Ok(())
+}
+
+// a compaction which makes room for a set must not drop the key from the bloom filters
+#[test]
+fn size_limit_with_bloom_filters() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions {
+            max_size: Some(5000),
+            ..bloom_options()
+        },
+    )?;
+    for i in 0..200 {
+        let key = format!("key{}", i);
+        store.set(key.clone(), format!("value{}", i))?;
+        assert_eq!(store.get(key.clone())?, Some(format!("value{}", i)));
+        if i % 2 == 1 {
+            store.remove(key)?;
+        }
+    }
+    assert!(store.stats()?.compactions.unwrap() > 0);
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), bloom_options())?;
+    for i in (0..200).step_by(2) {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// an encrypted record is bound to the place it lands in after a compaction made room
+#[test]
+fn size_limit_with_encryption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions {
+        max_size: Some(3000),
+        ..encryption_options(Encryption::new([5; 32]))
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for i in 0..100 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+    }
+    assert!(store.stats()?.compactions.unwrap() > 0);
+    assert_eq!(store.get("key".to_owned())?, Some("value99".to_owned()));
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value99".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn full_disk() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let open = || {
+        KvStore::open_with_options(
+            "/store",
+            memory_storage_options(&storage, IndexMode::Memory),
+        )
+    };
+    let used = |storage: &MemoryStorage| {
+        storage
+            .available_space(Path::new("/store"))
+            .unwrap()
+            .map_or(0, |available| 10_000_000 - available)
+    };
+    let store = open()?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    storage.set_faults(Faults {
+        capacity: Some(10_000_000),
+        ..Faults::default()
+    });
+    let capacity = used(&storage) + 20_000;
+    storage.set_faults(Faults {
+        capacity: Some(capacity),
+        ..Faults::default()
+    });
+
+    // the write which finds the disk full leaves nothing of itself behind
+    let value = "v".repeat(3000);
+    let mut written = 0;
+    let err = loop {
+        match store.set(format!("key{}", written), value.clone()) {
+            Ok(()) => written += 1,
+            Err(err) => break err,
+        }
+    };
+    assert!(
+        matches!(&err, KVStoreError::ReadOnly(reason) if reason == "the disk is full"),
+        "{}",
+        err
+    );
+    assert!(written > 0);
+    assert!(matches!(
+        store.set("small".to_owned(), "1".to_owned()),
+        Err(KVStoreError::ReadOnly(_))
+    ));
+    assert_eq!(store.stats()?.read_only, Some(true));
+    for i in 0..written {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value.clone()));
+    }
+
+    // once there is room the store takes writes again
+    storage.set_faults(Faults {
+        capacity: Some(capacity + 2 * 1024 * 1024),
+        ..Faults::default()
+    });
+    store.set("after".to_owned(), "1".to_owned())?;
+    assert_eq!(store.stats()?.read_only, Some(false));
+    drop(store);
+
+    let store = open()?;
+    for i in 0..written {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value.clone()));
+    }
+    assert_eq!(store.get(format!("key{}", written))?, None);
+    assert_eq!(store.get("after".to_owned())?, Some("1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn compaction_needs_headroom() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let store = KvStore::open_with_options(
+        "/store",
+        memory_storage_options(&storage, IndexMode::Memory),
+    )?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), "v".repeat(1000))?;
+    }
+    store.set_compaction_paused(true)?;
+    make_garbage(&store)?;
+    storage.set_faults(Faults {
+        capacity: Some(100 * 1024 * 1024),
+        ..Faults::default()
+    });
+    let available = storage.available_space(Path::new("/store"))?.unwrap();
+    let capacity = 100 * 1024 * 1024 - available + 200 * 1024;
+    storage.set_faults(Faults {
+        capacity: Some(capacity),
+        ..Faults::default()
+    });
+    let files = storage.files();
+
+    assert!(matches!(
+        store.compact(),
+        Err(KVStoreError::InsufficientSpace(..))
+    ));
+    assert_eq!(storage.files(), files);
+    // compaction which starts by itself waits for room without failing writes
+    store.set_compaction_paused(false)?;
+    store.set("key0".to_owned(), "new".to_owned())?;
+    assert_eq!(store.stats()?.compactions, Some(0));
+
+    storage.set_faults(Faults {
+        capacity: Some(capacity + 2 * 1024 * 1024),
+        ..Faults::default()
+    });
+    store.set("key1".to_owned(), "new".to_owned())?;
+    assert_eq!(store.stats()?.compactions, Some(1));
+    for i in 0..100 {
+        let expected = if i < 2 {
+            "new".to_owned()
+        } else {
+            "v".repeat(1000)
+        };
+        assert_eq!(store.get(format!("key{}", i))?, Some(expected));
+    }
+    Ok(())
+}
+
+const SMALL_LIMITS: SizeLimits = SizeLimits {
+    max_key_size: 16,
+    max_value_size: 100,
+};
+
+fn check_size_limits<E: KvsEngine>(store: E) -> Result<()> {
+    store.create_namespace("ns")?;
+    for store in [store.clone(), store.namespace("ns")?] {
+        store.set("k".repeat(16), "v".repeat(100))?;
+        assert!(matches!(
+            store.set("k".repeat(17), "value".to_owned()),
+            Err(KVStoreError::KeyTooLarge(17, 16))
+        ));
+        assert!(matches!(
+            store.set("key".to_owned(), "v".repeat(101)),
+            Err(KVStoreError::ValueTooLarge(101, 100))
+        ));
+        // the refused sets leave the keys as they were
+        assert_eq!(store.get("k".repeat(16))?, Some("v".repeat(100)));
+        assert_eq!(store.get("key".to_owned())?, None);
+    }
+    Ok(())
+}
+
+#[test]
+fn key_value_size_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        size_limits: SMALL_LIMITS,
+        ..KvStoreOptions::default()
+    };
+    check_size_limits(KvStore::open_with_options(
+        temp_dir.path().join("kvs"),
+        options.clone(),
+    )?)?;
+    check_size_limits(ShardedKvStore::open_with_options(
+        temp_dir.path().join("sharded"),
+        4,
+        options,
+    )?)?;
+    check_size_limits(SledKvsEngine::open_with_size_limits(
+        temp_dir.path().join("sled"),
+        SMALL_LIMITS,
+    )?)?;
+    check_size_limits(MemoryKvsEngine::with_size_limits(SMALL_LIMITS))?;
+    check_size_limits(LsmKvsEngine::open_with_options(
+        temp_dir.path().join("lsm"),
+        LsmOptions {
+            size_limits: SMALL_LIMITS,
+            ..LsmOptions::default()
+        },
+    )?)?;
+    check_size_limits(BTreeKvsEngine::open_with_options(
+        temp_dir.path().join("btree"),
+        BTreeOptions {
+            size_limits: SMALL_LIMITS,
+            ..BTreeOptions::default()
+        },
+    )?)?;
+    Ok(())
+}
+
+fn blob_options() -> KvStoreOptions {
+    KvStoreOptions {
+        blob_threshold: Some(1000),
+        ..KvStoreOptions::default()
+    }
+}
+
+fn blob_files(dir: &Path) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if name.starts_with("blob_") {
+            names.push(name);
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+#[test]
+fn blob_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), blob_options())?;
+    store.create_namespace("ns")?;
+    let namespace = store.namespace("ns")?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("{}{}", i, "v".repeat(2000)))?;
+        store.set(format!("small{}", i), format!("value{}", i))?;
+    }
+    namespace.set("key".to_owned(), "n".repeat(5000))?;
+    assert_eq!(blob_files(temp_dir.path())?, vec!["blob_1.blob"]);
+    let stats = store.stats()?;
+    assert!(stats.blob_bytes.unwrap() > 100 * 2000);
+    assert_eq!(stats.blob_garbage_bytes, Some(0));
+
+    // the data files hold only pointers to the large values
+    let data_size: u64 = stats.data_files.iter().map(|file| file.size).sum();
+    assert!(data_size < 100 * 2000);
+
+    store.remove("key0".to_owned())?;
+    store.set("key1".to_owned(), "small".to_owned())?;
+    let garbage = store.stats()?.blob_garbage_bytes.unwrap();
+    assert!(garbage > 2 * 2000);
+
+    // a reopened store counts the same garbage
+    drop(namespace);
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), blob_options())?;
+    let namespace = store.namespace("ns")?;
+    assert_eq!(store.stats()?.blob_garbage_bytes, Some(garbage));
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("small".to_owned()));
+    for i in 2..100 {
+        assert_eq!(
+            store.get(format!("key{}", i))?,
+            Some(format!("{}{}", i, "v".repeat(2000)))
+        );
+    }
+    assert_eq!(namespace.get("key".to_owned())?, Some("n".repeat(5000)));
+    Ok(())
+}
+
+#[test]
+fn compaction_keeps_blobs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), blob_options())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), "v".repeat(2000))?;
+    }
+    store.set_compaction_paused(true)?;
+    for iter in 0..150 {
+        store.set("garbage".to_owned(), format!("{}{}", iter, "g".repeat(500)))?;
+    }
+    let blob_bytes = store.stats()?.blob_bytes;
+
+    store.compact()?;
+    let stats = store.stats()?;
+    assert_eq!(stats.compactions, Some(1));
+    // no blob is garbage, so the blob file is neither copied nor collected
+    assert_eq!(stats.blob_bytes, blob_bytes);
+    assert_eq!(stats.blob_collections, Some(0));
+    assert_eq!(blob_files(temp_dir.path())?, vec!["blob_1.blob"]);
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some("v".repeat(2000)));
+    }
+    Ok(())
+}
+
+#[test]
+fn blob_garbage_collection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), blob_options())?;
+    store.create_namespace("ns")?;
+    let namespace = store.namespace("ns")?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), "v".repeat(2000))?;
+        namespace.set(format!("key{}", i), "n".repeat(2000))?;
+    }
+    // overwriting the large values makes the blob files mostly garbage, which is
+    // collected by itself
+    let value = "g".repeat(10000);
+    for iter in 0..150 {
+        store.set("garbage".to_owned(), format!("{}{}", iter, value))?;
+    }
+    let stats = store.stats()?;
+    assert!(stats.blob_collections.unwrap() > 0);
+    assert!(stats.blob_bytes.unwrap() < 150 * 10000);
+    assert!(!blob_files(temp_dir.path())?.contains(&"blob_1.blob".to_owned()));
+
+    store.remove("garbage".to_owned())?;
+    store.compact()?;
+    let stats = store.stats()?;
+    assert_eq!(stats.blob_garbage_bytes, Some(0));
+    assert!(stats.blob_bytes.unwrap() < 25 * 2000);
+    assert_eq!(blob_files(temp_dir.path())?.len(), 1);
+
+    drop(namespace);
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), blob_options())?;
+    let namespace = store.namespace("ns")?;
+    assert_eq!(store.stats()?.blob_garbage_bytes, Some(0));
+    assert_eq!(store.get("garbage".to_owned())?, None);
+    for i in 0..10 {
+        assert_eq!(store.get(format!("key{}", i))?, Some("v".repeat(2000)));
+        assert_eq!(namespace.get(format!("key{}", i))?, Some("n".repeat(2000)));
+    }
+    Ok(())
+}
+
+/// An operation of a crash workload.
+#[derive(Clone, Debug)]
+enum CrashOp {
+    Set(String, String),
+    Remove(String),
+    Sync,
+}
+
+fn apply_crash_op(model: &mut BTreeMap<String, String>, op: &CrashOp) {
+    match op {
+        CrashOp::Set(key, value) => {
+            model.insert(key.clone(), value.clone());
+        }
+        CrashOp::Remove(key) => {
+            model.remove(key);
+        }
+        CrashOp::Sync => {}
+    }
+}
+
+/** Run random workloads against a KvStore over a MemoryStorage and cut the power at a
+random point after each, tearing the unsynced writes and leaving garbage after some. After every crash the store must
+hold the state after some prefix of the operations since the previous crash, which
+includes every operation which returned before the last sync, and may include the
+operation which failed when the power went out.
+ */
+fn crash_workload(seed: u64, index: IndexMode) -> Result<()> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let storage = MemoryStorage::new();
+    let options = KvStoreOptions {
+        index,
+        sync_writes: rng.gen_bool(0.3),
+        storage: Some(Arc::new(storage.clone())),
+        ..KvStoreOptions::default()
+    };
+    let mut model = BTreeMap::new();
+    let mut next_value = 0;
+    for _ in 0..3 {
+        let store = KvStore::open_with_options("/store", options.clone())?;
+        storage.set_faults(Faults {
+            fail_after_writes: Some(rng.gen_range(0..400)),
+            tear_failing_write: rng.gen(),
+            ..Faults::default()
+        });
+        let mut acknowledged = model.clone();
+        let mut ops = Vec::new();
+        // number of the operations which returned before the last sync
+        let mut synced = 0;
+        let mut failed = None;
+        for _ in 0..rng.gen_range(0..200) {
+            let op = match rng.gen_range(0..10) {
+                0 => CrashOp::Sync,
+                1..=2 if !acknowledged.is_empty() => {
+                    let keys: Vec<&String> = acknowledged.keys().collect();
+                    CrashOp::Remove(keys[rng.gen_range(0..keys.len())].clone())
+                }
+                _ => {
+                    // large values make the overwritten ones trigger compaction
+                    next_value += 1;
+                    let value = format!("{}:", next_value) + &"v".repeat(rng.gen_range(0..20000));
+                    CrashOp::Set(format!("key{}", rng.gen_range(0..20)), value)
+                }
+            };
+            let result = match &op {
+                CrashOp::Set(key, value) => store.set(key.clone(), value.clone()),
+                CrashOp::Remove(key) => store.remove(key.clone()),
+                CrashOp::Sync => store.sync(),
+            };
+            if result.is_err() {
+                failed = Some(op);
+                break;
+            }
+            apply_crash_op(&mut acknowledged, &op);
+            ops.push(op);
+            if options.sync_writes || matches!(ops.last(), Some(CrashOp::Sync)) {
+                synced = ops.len();
+            }
+        }
+        // the end of a torn write is dropped or left as garbage
+        let garbage_seed = if rng.gen() { Some(rng.gen()) } else { None };
+        storage.crash_tearing(|_, unsynced| rng.gen_range(0..=unsynced), garbage_seed);
+        drop(store);
+
+        let store = KvStore::open_with_options("/store", options.clone())?;
+        let mut contents = BTreeMap::new();
+        store.scan(|key, value| {
+            contents.insert(key, value);
+            Ok(())
+        })?;
+        let candidates: Vec<&CrashOp> = ops.iter().chain(failed.as_ref()).collect();
+        let mut state = model;
+        let mut recovered = false;
+        for applied in 0..=candidates.len() {
+            if applied > 0 {
+                apply_crash_op(&mut state, candidates[applied - 1]);
+            }
+            if applied >= synced && state == contents {
+                recovered = true;
+                break;
+            }
+        }
+        assert!(
+            recovered,
+            "seed {}: the store does not hold the state after a synced prefix of {} operations",
+            seed,
+            candidates.len()
+        );
+        model = contents;
+    }
+    Ok(())
+}
+
+#[test]
+fn crash_consistency() {
+    for seed in 0..64 {
+        if let Err(err) = crash_workload(seed, IndexMode::Memory) {
+            panic!("seed {}: {}", seed, err);
+        }
+    }
+}
+
+#[test]
+fn crash_consistency_disk_index() {
+    for seed in 0..32 {
+        let index = IndexMode::Disk {
+            cache_pages: 16,
+            max_buffered_keys: 8,
+        };
+        if let Err(err) = crash_workload(seed, index) {
+            panic!("seed {}: {}", seed, err);
+        }
+    }
+}
+
+#[test]
+fn torn_write_is_truncated() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    // half of a command, as a crash while it was written leaves it
+    let data_path = temp_dir.path().join("data_0.txt");
+    let size = fs::metadata(&data_path)?.len();
+    OpenOptions::new()
+        .append(true)
+        .open(&data_path)?
+        .write_all(br#"{"SET":["key2","val"#)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(fs::metadata(&data_path)?.len(), size);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+/// The keys of every namespace of an engine, None for the default namespace.
+type Model = BTreeMap<Option<String>, BTreeMap<String, String>>;
+
+fn scan_all<E: KvsEngine>(engine: &E) -> Result<BTreeMap<String, String>> {
+    let mut contents = BTreeMap::new();
+    engine.scan(|key, value| {
+        contents.insert(key, value);
+        Ok(())
+    })?;
+    Ok(contents)
+}
+
+/// Check that the engine holds the namespaces and keys of the model.
+fn check_model_contents<E: KvsEngine>(store: &E, model: &Model, context: &str) -> Result<()> {
+    let names: Vec<String> = model.keys().flatten().cloned().collect();
+    assert_eq!(store.list_namespaces()?, names, "{}", context);
+    for (name, keys) in model {
+        let contents = match name {
+            None => scan_all(store)?,
+            Some(name) => scan_all(&store.namespace(name)?)?,
+        };
+        assert_eq!(&contents, keys, "{}: namespace {:?}", context, name);
+    }
+    Ok(())
+}
+
+/** Drive an engine with a random sequence of operations on its namespaces, reopening it
+now and then, and compare every result with a model in `BTreeMap`s. Bursts of large
+overwrites make the engines which compact or flush do so. Every engine must pass it.
+ */
+fn check_model<E: KvsEngine>(seed: u64, open: impl Fn() -> Result<E>) -> Result<()> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut model: Model = BTreeMap::new();
+    model.insert(None, BTreeMap::new());
+    let mut store = open()?;
+    let mut handles: HashMap<String, E> = HashMap::new();
+    let mut next_value = 0;
+    for step in 0..400 {
+        let context = format!("seed {} step {}", seed, step);
+        let namespaces: Vec<Option<String>> = model.keys().cloned().collect();
+        let namespace = namespaces[rng.gen_range(0..namespaces.len())].clone();
+        let handle = match &namespace {
+            None => store.clone(),
+            Some(name) => match handles.get(name) {
+                Some(handle) => handle.clone(),
+                None => {
+                    let handle = store.namespace(name)?;
+                    handles.insert(name.clone(), handle.clone());
+                    handle
+                }
+            },
+        };
+        let keys = model.get_mut(&namespace).unwrap();
+        let key = format!("key{}", rng.gen_range(0..30));
+        let name = format!("ns{}", rng.gen_range(0..3));
+        match rng.gen_range(0..100) {
+            0..=29 => {
+                next_value += 1;
+                let value = format!("{}:", next_value) + &"v".repeat(rng.gen_range(0..100));
+                handle.set(key.clone(), value.clone())?;
+                keys.insert(key, value);
+            }
+            30..=49 => {
+                assert_eq!(
+                    handle.get(key.clone())?,
+                    keys.get(&key).cloned(),
+                    "{}",
+                    context
+                );
+            }
+            50..=64 => match (keys.remove(&key), handle.remove(key.clone())) {
+                (Some(_), Ok(())) | (None, Err(KVStoreError::KeyNotFound)) => {}
+                (expected, result) => panic!(
+                    "{}: removing {} with value {:?} returned {:?}",
+                    context, key, expected, result
+                ),
+            },
+            65..=69 => {
+                assert_eq!(&scan_all(&handle)?, keys, "{}", context);
+            }
+            70..=72 => {
+                assert_eq!(handle.stats()?.keys, keys.len() as u64, "{}", context);
+            }
+            73..=77 => {
+                let result = store.create_namespace(&name);
+                match model.entry(Some(name.clone())) {
+                    btree_map::Entry::Occupied(_) => assert!(
+                        matches!(result, Err(KVStoreError::NamespaceExists(_))),
+                        "{}: creating {} again returned {:?}",
+                        context,
+                        name,
+                        result
+                    ),
+                    btree_map::Entry::Vacant(entry) => {
+                        result?;
+                        entry.insert(BTreeMap::new());
+                    }
+                }
+                let names: Vec<String> = model.keys().flatten().cloned().collect();
+                assert_eq!(store.list_namespaces()?, names, "{}", context);
+            }
+            78..=80 => {
+                let result = store.drop_namespace(&name);
+                if model.remove(&Some(name.clone())).is_some() {
+                    result?;
+                    if let Some(stale) = handles.remove(&name) {
+                        assert!(
+                            matches!(stale.get(key), Err(KVStoreError::NamespaceNotFound(_))),
+                            "{}: a handle of the dropped {} still works",
+                            context,
+                            name
+                        );
+                    }
+                } else {
+                    assert!(
+                        matches!(result, Err(KVStoreError::NamespaceNotFound(_))),
+                        "{}: dropping the missing {} returned {:?}",
+                        context,
+                        name,
+                        result
+                    );
+                }
+            }
+            81..=83 => {
+                drop(handle);
+                handles.clear();
+                drop(store);
+                store = open()?;
+                check_model_contents(&store, &model, &context)?;
+            }
+            84 => {
+                // large overwrites of one key, which leave more than a megabyte of garbage
+                for _ in 0..100 {
+                    next_value += 1;
+                    let value = format!("{}:", next_value) + &"v".repeat(12_000);
+                    handle.set(key.clone(), value.clone())?;
+                    keys.insert(key.clone(), value);
+                }
+            }
+            85 => {
+                store.compact()?;
+                check_model_contents(&store, &model, &context)?;
+            }
+            _ => {
+                assert_eq!(handle.get(format!("missing{}", step))?, None, "{}", context);
+            }
+        }
+    }
+    check_model_contents(&store, &model, &format!("seed {} at the end", seed))
+}
+
+#[test]
+fn model_kv_store() -> Result<()> {
+    for seed in 0..4 {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        check_model(seed, || KvStore::open(temp_dir.path()))?;
+    }
+    Ok(())
+}
+
+#[test]
+fn model_kv_store_disk_index() -> Result<()> {
+    for seed in 0..4 {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        check_model(seed, || {
+            KvStore::open_with_options(temp_dir.path(), disk_index_options())
+        })?;
+    }
+    Ok(())
+}
+
+#[test]
+fn model_kv_store_bloom_filters() -> Result<()> {
+    for seed in 0..4 {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        check_model(seed, || {
+            KvStore::open_with_options(temp_dir.path(), bloom_options())
+        })?;
+    }
+    Ok(())
+}
+
+#[test]
+fn model_kv_store_blob_files() -> Result<()> {
+    for seed in 0..4 {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        check_model(seed, || {
+            KvStore::open_with_options(
+                temp_dir.path(),
+                KvStoreOptions {
+                    blob_threshold: Some(8),
+                    ..KvStoreOptions::default()
+                },
+            )
+        })?;
+    }
+    Ok(())
+}
+
+#[test]
+fn model_sharded_store() -> Result<()> {
+    for seed in 0..4 {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        check_model(seed, || ShardedKvStore::open(temp_dir.path(), 3))?;
+    }
+    Ok(())
+}
+
+#[test]
+fn model_sled_engine() -> Result<()> {
+    for seed in 0..4 {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        check_model(seed, || SledKvsEngine::open(temp_dir.path()))?;
+    }
+    Ok(())
+}
+
+#[test]
+fn model_memory_engine() -> Result<()> {
+    for seed in 0..4 {
+        let store = MemoryKvsEngine::new();
+        check_model(seed, || Ok(store.clone()))?;
+    }
+    Ok(())
+}
+
+#[test]
+fn model_lsm_engine() -> Result<()> {
+    for seed in 0..4 {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        check_model(seed, || {
+            LsmKvsEngine::open_with_options(temp_dir.path(), small_lsm_options())
+        })?;
+    }
+    Ok(())
+}
+
+#[test]
+fn model_btree_engine() -> Result<()> {
+    for seed in 0..4 {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        check_model(seed, || BTreeKvsEngine::open(temp_dir.path()))?;
+    }
+    Ok(())
+}